@@ -113,7 +113,7 @@ fn main() {
     }
     if escape_stdout {
         let mut buf = Vec::new();
-        child.stdout.take().unwrap().read_to_end(&mut buf).unwrap();
+        child.take_stdout().unwrap().read_to_end(&mut buf).unwrap();
         writeln!(&mut stderr(), "{:?}",
             String::from_utf8_lossy(&buf[..])).unwrap();
     }
@@ -0,0 +1,54 @@
+/*
+ * C interface of the unshare crate, built with the `capi` feature
+ *
+ * Functions returning int return 0 on success and -1 on error, functions
+ * returning pointers return NULL on error. If `err` isn't NULL, the error
+ * is stored there, and must be freed by `unshare_error_free`.
+ */
+#ifndef UNSHARE_H
+#define UNSHARE_H
+
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct unshare_command unshare_command;
+typedef struct unshare_child unshare_child;
+typedef struct unshare_error unshare_error;
+
+/* Returns NULL if `program` is NULL */
+unshare_command *unshare_command_new(const char *program);
+void unshare_command_free(unshare_command *cmd);
+int unshare_command_arg(unshare_command *cmd, const char *arg,
+                        unshare_error **err);
+/* `flags` are CLONE_NEW* constants */
+int unshare_command_unshare(unshare_command *cmd, int flags,
+                            unshare_error **err);
+/* `nstype` is a CLONE_NEW* constant, `fd` is duplicated */
+int unshare_command_set_namespace(unshare_command *cmd, int fd, int nstype,
+                                  unshare_error **err);
+unshare_child *unshare_command_spawn(unshare_command *cmd,
+                                     unshare_error **err);
+
+pid_t unshare_child_pid(unshare_child *child);
+/* `status` is encoded like by waitpid(), use WIFEXITED() and alike */
+int unshare_child_wait(unshare_child *child, int *status,
+                       unshare_error **err);
+int unshare_child_kill(unshare_child *child, int signal,
+                       unshare_error **err);
+/* Neither kills nor waits for the process */
+void unshare_child_free(unshare_child *child);
+
+/* Valid until the error is freed */
+const char *unshare_error_message(const unshare_error *err);
+/* OS error code, or 0 */
+int unshare_error_errno(const unshare_error *err);
+void unshare_error_free(unshare_error *err);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* UNSHARE_H */
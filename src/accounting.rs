@@ -0,0 +1,286 @@
+//! Resource usage of exited children reported by the kernel's taskstats
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use libc::pid_t;
+
+use crate::{ChildEvent, child_events};
+use crate::netlink::{Message, Socket, attrs};
+use crate::zombies::ChildEventsIterator;
+
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const TASKSTATS_CMD_GET: u8 = 1;
+const TASKSTATS_CMD_ATTR_REGISTER_CPUMASK: u16 = 3;
+const TASKSTATS_CMD_ATTR_DEREGISTER_CPUMASK: u16 = 4;
+const TASKSTATS_TYPE_PID: u16 = 1;
+const TASKSTATS_TYPE_TGID: u16 = 2;
+const TASKSTATS_TYPE_STATS: u16 = 3;
+const TASKSTATS_TYPE_AGGR_PID: u16 = 4;
+const TASKSTATS_TYPE_AGGR_TGID: u16 = 5;
+
+/// Stats of processes which are not reaped yet, older ones are dropped
+const MAX_PENDING: usize = 1024;
+
+/// Resource usage of an exited process (see `Accounting`)
+///
+/// Delays are only accounted if enabled by `kernel.task_delayacct` sysctl
+/// or the `delayacct` boot option, otherwise they are zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaskStats {
+    /// Version of the `struct taskstats` reported by the kernel
+    pub version: u16,
+    /// Process id (in the pid namespace of the current process)
+    pub pid: pid_t,
+    /// Time spent waiting for a CPU while runnable
+    pub cpu_delay: Duration,
+    /// Number of block IO waits
+    pub blkio_count: u64,
+    /// Time spent waiting for block IO
+    pub blkio_delay: Duration,
+    /// Number of swap-ins
+    pub swapin_count: u64,
+    /// Time spent waiting for swap-ins
+    pub swapin_delay: Duration,
+    /// Time spent waiting for memory to be reclaimed
+    pub freepages_delay: Duration,
+    /// Time spent waiting for thrashing page cache
+    pub thrashing_delay: Duration,
+    /// Time spent in user mode
+    pub user_time: Duration,
+    /// Time spent in kernel mode
+    pub system_time: Duration,
+    /// Number of minor page faults
+    pub minor_faults: u64,
+    /// Number of major page faults
+    pub major_faults: u64,
+    /// Peak resident set size, in kilobytes
+    pub max_rss_kb: u64,
+    /// Bytes read by `read` and alike system calls
+    pub read_chars: u64,
+    /// Bytes written by `write` and alike system calls
+    pub write_chars: u64,
+    /// Bytes actually read from storage
+    pub read_bytes: u64,
+    /// Bytes actually written to storage
+    pub write_bytes: u64,
+    /// Number of voluntary context switches
+    pub voluntary_switches: u64,
+    /// Number of involuntary context switches
+    pub involuntary_switches: u64,
+}
+
+fn field(data: &[u8], offset: usize, size: usize) -> u64 {
+    let mut value = [0u8; 8];
+    match data.get(offset..offset+size) {
+        Some(bytes) => value[..size].copy_from_slice(bytes),
+        // older kernels report shorter structure
+        None => return 0,
+    }
+    u64::from_ne_bytes(value)
+}
+
+impl TaskStats {
+    /// Parses `struct taskstats`, returns it with the parent's pid
+    fn parse(data: &[u8]) -> (TaskStats, pid_t) {
+        let u64_at = |offset| field(data, offset, 8);
+        let nanos = |offset| Duration::from_nanos(u64_at(offset));
+        let stats = TaskStats {
+            version: field(data, 0, 2) as u16,
+            pid: field(data, 128, 4) as pid_t,
+            cpu_delay: nanos(24),
+            blkio_count: u64_at(32),
+            blkio_delay: nanos(40),
+            swapin_count: u64_at(48),
+            swapin_delay: nanos(56),
+            freepages_delay: nanos(320),
+            thrashing_delay: nanos(336),
+            user_time: Duration::from_micros(u64_at(152)),
+            system_time: Duration::from_micros(u64_at(160)),
+            minor_faults: u64_at(168),
+            major_faults: u64_at(176),
+            max_rss_kb: u64_at(200),
+            read_chars: u64_at(216),
+            write_chars: u64_at(224),
+            read_bytes: u64_at(248),
+            write_bytes: u64_at(256),
+            voluntary_switches: u64_at(272),
+            involuntary_switches: u64_at(280),
+        };
+        (stats, field(data, 132, 4) as pid_t)
+    }
+}
+
+fn genl_request(family: u16, flags: libc::c_int, cmd: u8) -> Message {
+    let mut msg = Message::new(family, flags);
+    // struct genlmsghdr
+    msg.push(&[cmd, 1, 0, 0]);
+    msg
+}
+
+/// Subscription to resource usage of exiting children
+///
+/// The kernel reports resource usage of every process when it exits over
+/// the taskstats netlink interface, this includes data not returned by
+/// `wait4`, like delays waiting for IO or swap. The stats of the children
+/// of the current process are kept until the child is reaped.
+///
+/// This requires `CAP_NET_ADMIN` and the process must be in the initial
+/// user and pid namespaces. The subscription must be created before the
+/// children exit.
+pub struct Accounting {
+    sock: Socket,
+    family: u16,
+    cpumask: String,
+    own_pid: pid_t,
+    pending: HashMap<pid_t, TaskStats>,
+    order: VecDeque<pid_t>,
+}
+
+impl Accounting {
+    /// Subscribes to exits on all CPUs
+    pub fn new() -> io::Result<Accounting> {
+        let mut sock = Socket::open(libc::NETLINK_GENERIC)?;
+        let reply = sock.request_reply(
+            genl_request(GENL_ID_CTRL, libc::NLM_F_REQUEST,
+                         CTRL_CMD_GETFAMILY)
+            .attr_str(CTRL_ATTR_FAMILY_NAME, "TASKSTATS"))?;
+        let family = attrs(reply.get(4..).unwrap_or(&[]))
+            .find(|&(kind, value)| {
+                kind == CTRL_ATTR_FAMILY_ID && value.len() >= 2
+            })
+            .map(|(_, value)| u16::from_ne_bytes([value[0], value[1]]))
+            .ok_or_else(|| io::Error::other("no taskstats family in reply"))?;
+        let cpumask = fs::read_to_string("/sys/devices/system/cpu/possible")?
+            .trim().to_string();
+        sock.request(genl_request(family,
+                libc::NLM_F_REQUEST|libc::NLM_F_ACK, TASKSTATS_CMD_GET)
+            .attr_str(TASKSTATS_CMD_ATTR_REGISTER_CPUMASK, &cpumask))?;
+        Ok(Accounting {
+            sock, family, cpumask,
+            own_pid: unsafe { libc::getpid() },
+            pending: HashMap::new(),
+            order: VecDeque::new(),
+        })
+    }
+
+    /// Reads all stats received so far
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let n = match self.sock.recv(&mut buf, true) {
+                Ok(Some(n)) => n,
+                Ok(None) => return Ok(()),
+                // the kernel has dropped some messages, nothing to do
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            for (kind, _, payload) in crate::netlink::messages(&buf[..n]) {
+                if kind != self.family {
+                    continue;
+                }
+                for (kind, value) in attrs(payload.get(4..).unwrap_or(&[])) {
+                    if kind == TASKSTATS_TYPE_AGGR_PID ||
+                        kind == TASKSTATS_TYPE_AGGR_TGID
+                    {
+                        self.add(kind == TASKSTATS_TYPE_AGGR_TGID, value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, group: bool, aggr: &[u8]) {
+        let mut id = None;
+        let mut stats = None;
+        for (kind, value) in attrs(aggr) {
+            match kind {
+                TASKSTATS_TYPE_PID | TASKSTATS_TYPE_TGID
+                    if value.len() >= 4
+                => id = Some(field(value, 0, 4) as pid_t),
+                TASKSTATS_TYPE_STATS => stats = Some(TaskStats::parse(value)),
+                _ => {}
+            }
+        }
+        let (id, (mut stats, ppid)) = match (id, stats) {
+            (Some(id), Some(stats)) => (id, stats),
+            _ => return,
+        };
+        if ppid != self.own_pid {
+            return;
+        }
+        if !group && stats.pid != id {
+            return;
+        }
+        // stats of the whole thread group replace the ones of the leader
+        stats.pid = id;
+        if self.pending.insert(id, stats).is_none() {
+            self.order.push_back(id);
+            if self.order.len() > MAX_PENDING {
+                let old = self.order.pop_front().unwrap();
+                self.pending.remove(&old);
+            }
+        }
+    }
+
+    /// Returns stats of the exited child `pid` and forgets them
+    ///
+    /// Use this after the child is reaped by other means than
+    /// `Accounting::child_events` (e.g. `Child::wait` or `reap_zombies`).
+    pub fn take_stats(&mut self, pid: pid_t) -> io::Result<Option<TaskStats>>
+    {
+        self.receive()?;
+        let stats = self.pending.remove(&pid);
+        if stats.is_some() {
+            self.order.retain(|&x| x != pid);
+        }
+        Ok(stats)
+    }
+
+    /// Works like `child_events()` but yields stats of dead children too
+    ///
+    /// Stats are `None` for events other than `ChildEvent::Death`, or if
+    /// the kernel has not reported them (for example, because of receive
+    /// buffer overflow).
+    pub fn child_events(&mut self) -> AccountedEvents<'_> {
+        AccountedEvents { accounting: self, events: child_events() }
+    }
+}
+
+impl Drop for Accounting {
+    fn drop(&mut self) {
+        self.sock.request(genl_request(self.family,
+                libc::NLM_F_REQUEST|libc::NLM_F_ACK, TASKSTATS_CMD_GET)
+            .attr_str(TASKSTATS_CMD_ATTR_DEREGISTER_CPUMASK, &self.cpumask))
+            .ok();
+    }
+}
+
+/// Iterator returned by `Accounting::child_events`
+pub struct AccountedEvents<'a> {
+    accounting: &'a mut Accounting,
+    events: ChildEventsIterator,
+}
+
+impl<'a> Iterator for AccountedEvents<'a> {
+    type Item = (ChildEvent, Option<TaskStats>);
+
+    fn next(&mut self) -> Option<(ChildEvent, Option<TaskStats>)> {
+        let event = self.events.next()?;
+        let stats = match event {
+            ChildEvent::Death(pid, _) => {
+                self.accounting.take_stats(pid).ok().flatten()
+            }
+            _ => None,
+        };
+        Some((event, stats))
+    }
+}
@@ -0,0 +1,54 @@
+//! Types and constants that differ between architectures and C libraries
+//!
+//! Everything else comes from `libc` (system call numbers, `c_char`,
+//! ioctl request types), so only the things `libc` doesn't abstract over
+//! are here.
+
+
+/// Type of the `request` argument of `ptrace`
+#[cfg(target_env="musl")]
+pub type PtraceRequest = libc::c_int;
+/// Type of the `request` argument of `ptrace`
+#[cfg(not(target_env="musl"))]
+pub type PtraceRequest = libc::c_uint;
+
+/// `AUDIT_ARCH_*` value of the native system call convention
+///
+/// It's `EM_*` machine of the ELF format, with flags for 64 bit and
+/// little endian architectures. `None` if not known for the platform.
+#[cfg(target_arch="x86_64")]
+pub const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+#[cfg(target_arch="x86")]
+pub const AUDIT_ARCH: Option<u32> = Some(0x4000_0003);
+#[cfg(target_arch="aarch64")]
+pub const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+#[cfg(all(target_arch="arm", target_endian="little"))]
+pub const AUDIT_ARCH: Option<u32> = Some(0x4000_0028);
+#[cfg(target_arch="riscv64")]
+pub const AUDIT_ARCH: Option<u32> = Some(0xC000_00F3);
+#[cfg(not(any(target_arch="x86_64", target_arch="x86",
+              target_arch="aarch64",
+              all(target_arch="arm", target_endian="little"),
+              target_arch="riscv64")))]
+pub const AUDIT_ARCH: Option<u32> = None;
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::elf::{read_header, elf_machine};
+    use super::AUDIT_ARCH;
+
+    #[test]
+    fn test_audit_arch_matches_elf() {
+        let arch = match AUDIT_ARCH {
+            Some(arch) => arch,
+            None => return,
+        };
+        let header = read_header(Path::new("/proc/self/exe")).unwrap();
+        assert_eq!(elf_machine(&header), Some(arch as u16));
+        // ELFCLASS64 and ELFDATA2LSB
+        assert_eq!(arch & 0x8000_0000 != 0, header[4] == 2);
+        assert_eq!(arch & 0x4000_0000 != 0, header[5] == 1);
+    }
+}
@@ -0,0 +1,529 @@
+//! Async `Command` and `Child` in the style of `tokio::process` (the
+//! `async-process` feature)
+//!
+//! The futures don't depend on any runtime: readiness of the pidfd and of
+//! the pipes is watched by a single background thread using `poll`, which
+//! wakes the tasks. So they can be awaited in tokio, async-std or a simple
+//! `block_on` alike. Our ends of the pipes are switched to non-blocking
+//! mode, reading and writing are inherent async methods, as there are no
+//! `AsyncRead`/`AsyncWrite` traits in std.
+//!
+//! ```rust,no_run
+//! # async fn run() -> Result<(), unshare::Error> {
+//! use unshare::{Namespace, Stdio};
+//! use unshare::async_process::Command;
+//!
+//! let mut cmd = Command::new("/bin/hostname");
+//! cmd.unshare(&[Namespace::Uts]);
+//! cmd.stdout(Stdio::piped());
+//! let (status, output) = cmd.output().await?;
+//! println!("{:?} {}", status, String::from_utf8_lossy(&output.stdout));
+//! # Ok(())
+//! # }
+//! ```
+use std::ffi::OsStr;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use libc::c_short;
+
+use crate::{Error, ExitStatus, Output, PipeReader, PipeWriter, Stdio};
+use crate::sys;
+
+
+/// Descriptors waited for by the reactor thread, and its wakeup pipe
+struct Reactor {
+    waiting: Vec<(RawFd, c_short, Waker)>,
+    wakeup: OwnedFd,
+}
+
+static REACTOR: Mutex<Option<Reactor>> = Mutex::new(None);
+
+/// Process builder, which spawns a `Child` that can be awaited
+///
+/// Dereferences to `unshare::Command`, so all of its configuration is
+/// available. Note that chained calls return the inner command, so call
+/// `spawn`, `status` or `output` of this wrapper separately, like:
+///
+/// ```rust,no_run
+/// # async fn run() -> Result<(), unshare::Error> {
+/// let mut cmd = unshare::async_process::Command::new("/bin/true");
+/// cmd.arg("x").env("A", "b");
+/// assert!(cmd.status().await?.success());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Command(crate::Command);
+
+/// Handle of the process spawned by `Command::spawn`
+///
+/// Like in `tokio::process`, pipes (configured by `Stdio::piped()`) are
+/// the public fields, so they can be moved into other tasks.
+#[derive(Debug)]
+pub struct Child {
+    inner: crate::Child,
+    /// Writing end of the stdin pipe, if it's piped
+    pub stdin: Option<AsyncPipeWriter>,
+    /// Reading end of the stdout pipe, if it's piped
+    pub stdout: Option<AsyncPipeReader>,
+    /// Reading end of the stderr pipe, if it's piped
+    pub stderr: Option<AsyncPipeReader>,
+}
+
+/// Reading end of a pipe that can be awaited
+#[derive(Debug)]
+pub struct AsyncPipeReader(PipeReader);
+
+/// Writing end of a pipe that can be awaited
+#[derive(Debug)]
+pub struct AsyncPipeWriter(PipeWriter);
+
+/// Resolves when any of the descriptors is ready for its events
+struct Ready<'a>(&'a [(RawFd, c_short)]);
+
+impl Command {
+    /// Constructs a new `Command`, see `unshare::Command::new`
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
+        Command(crate::Command::new(program))
+    }
+
+    /// Spawn the command
+    ///
+    /// Spawning itself is synchronous, as the setup of the child is done
+    /// while it's frozen. The child gets a pidfd (see `Command::pidfd`,
+    /// requires linux 5.3), which is used to wait for it.
+    pub fn spawn(&mut self) -> Result<Child, Error> {
+        self.0.pidfd();
+        let mut inner = self.0.spawn()?;
+        let mut pipes = || -> io::Result<_> {
+            Ok((inner.take_stdin().map(AsyncPipeWriter::new).transpose()?,
+                inner.take_stdout().map(AsyncPipeReader::new).transpose()?,
+                inner.take_stderr().map(AsyncPipeReader::new).transpose()?))
+        };
+        match pipes() {
+            Ok((stdin, stdout, stderr)) => {
+                Ok(Child { inner, stdin, stdout, stderr })
+            }
+            Err(e) => {
+                inner.kill().ok();
+                inner.wait().ok();
+                Err(Error::PipeError(e.raw_os_error().unwrap_or(-1)))
+            }
+        }
+    }
+
+    /// Run the command and wait for it to exit, see `Command::status`
+    pub async fn status(&mut self) -> Result<ExitStatus, Error> {
+        let (status, _) = self.spawn()?.wait_with_output().await
+            .map_err(|e| Error::WaitError(e.raw_os_error().unwrap_or(-1)))?;
+        Ok(status)
+    }
+
+    /// Run the command and capture its output, see `Command::output`
+    pub async fn output(&mut self) -> Result<(ExitStatus, Output), Error> {
+        self.0.stdout(Stdio::piped());
+        self.0.stderr(Stdio::piped());
+        self.spawn()?.wait_with_output().await
+            .map_err(|e| Error::WaitError(e.raw_os_error().unwrap_or(-1)))
+    }
+}
+
+impl From<crate::Command> for Command {
+    fn from(cmd: crate::Command) -> Command {
+        Command(cmd)
+    }
+}
+
+impl Deref for Command {
+    type Target = crate::Command;
+    fn deref(&self) -> &crate::Command {
+        &self.0
+    }
+}
+
+impl DerefMut for Command {
+    fn deref_mut(&mut self) -> &mut crate::Command {
+        &mut self.0
+    }
+}
+
+impl Child {
+    /// Returns pid of the process
+    pub fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    /// The underlying synchronous handle, e.g. to send signals
+    pub fn inner(&self) -> &crate::Child {
+        &self.inner
+    }
+
+    /// Kill the process with `SIGKILL`, without waiting for it
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.inner.kill()
+    }
+
+    /// Wait for the process to exit
+    ///
+    /// Stdin is closed before waiting (as in `tokio::process`), so that
+    /// the process doesn't wait for the input forever. Other pipes are
+    /// left as is, so with a lot of output waiting without reading them
+    /// may never finish, use `wait_with_output` then.
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        drop(self.stdin.take());
+        if self.inner.status.is_none() {
+            // there is no pidfd for a daemon, which isn't our child anyway
+            if let Some(ref pidfd) = self.inner.pidfd {
+                Ready(&[(pidfd.as_raw_fd(), libc::POLLIN)]).await?;
+            }
+        }
+        self.inner.wait()
+    }
+
+    /// Read stdout and stderr until end of file, and wait for the process
+    ///
+    /// Only the pipes not taken out of the handle are read.
+    pub async fn wait_with_output(mut self)
+        -> io::Result<(ExitStatus, Output)>
+    {
+        drop(self.stdin.take());
+        let mut output = Output::default();
+        let mut stdout = self.stdout.take();
+        let mut stderr = self.stderr.take();
+        while stdout.is_some() || stderr.is_some() {
+            let fds = stdout.iter().chain(stderr.iter())
+                .map(|pipe| (pipe.as_raw_fd(), libc::POLLIN))
+                .collect::<Vec<_>>();
+            Ready(&fds).await?;
+            for (pipe, buf) in [(&mut stdout, &mut output.stdout),
+                                (&mut stderr, &mut output.stderr)]
+            {
+                let eof = match *pipe {
+                    Some(ref mut pipe) => pipe.read_available(buf)?,
+                    None => false,
+                };
+                if eof {
+                    *pipe = None;
+                }
+            }
+        }
+        let status = self.wait().await?;
+        Ok((status, output))
+    }
+}
+
+impl AsyncPipeReader {
+    /// Switch the pipe to non-blocking mode for async reading
+    pub fn new(pipe: PipeReader) -> io::Result<AsyncPipeReader> {
+        set_nonblocking(pipe.as_raw_fd())?;
+        Ok(AsyncPipeReader(pipe))
+    }
+
+    /// Read some data, returns zero on end of file
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+            Ready(&[(self.as_raw_fd(), libc::POLLIN)]).await?;
+        }
+    }
+
+    /// Read all the data until end of file, returns number of bytes read
+    pub async fn read_to_end(&mut self, buf: &mut Vec<u8>)
+        -> io::Result<usize>
+    {
+        let start = buf.len();
+        while !self.read_available(buf)? {
+            Ready(&[(self.as_raw_fd(), libc::POLLIN)]).await?;
+        }
+        Ok(buf.len() - start)
+    }
+
+    /// Reads what is in the pipe now, returns whether it's end of file
+    fn read_available(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        let mut chunk = [0u8; 8192];
+        loop {
+            match self.0.read(&mut chunk) {
+                Ok(0) => return Ok(true),
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(false);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl AsyncPipeWriter {
+    /// Switch the pipe to non-blocking mode for async writing
+    pub fn new(pipe: PipeWriter) -> io::Result<AsyncPipeWriter> {
+        set_nonblocking(pipe.as_raw_fd())?;
+        Ok(AsyncPipeWriter(pipe))
+    }
+
+    /// Write some data, returns number of bytes written
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.0.write(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => return res,
+            }
+            Ready(&[(self.as_raw_fd(), libc::POLLOUT)]).await?;
+        }
+    }
+
+    /// Write all the data
+    ///
+    /// Fails with `BrokenPipe` if the process has closed the pipe.
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for AsyncPipeReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl AsRawFd for AsyncPipeWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Drop for AsyncPipeReader {
+    fn drop(&mut self) {
+        deregister(self.as_raw_fd());
+    }
+}
+
+impl Drop for AsyncPipeWriter {
+    fn drop(&mut self) {
+        deregister(self.as_raw_fd());
+    }
+}
+
+impl Future for Ready<'_> {
+    type Output = io::Result<()>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut fds = self.0.iter()
+            .map(|&(fd, events)| libc::pollfd { fd, events, revents: 0 })
+            .collect::<Vec<_>>();
+        let rc = unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 0)
+        };
+        if rc > 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Poll::Ready(Err(err));
+            }
+        }
+        // the reactor polls them again, so readiness isn't missed
+        match register(self.0, cx.waker()) {
+            Ok(()) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 ||
+        unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Asks the reactor thread to wake the task when any of `fds` is ready
+///
+/// Replaces previous registrations of the same task for these descriptors.
+fn register(fds: &[(RawFd, c_short)], waker: &Waker) -> io::Result<()> {
+    let mut reactor = REACTOR.lock().unwrap_or_else(|e| e.into_inner());
+    if reactor.is_none() {
+        *reactor = Some(start_thread()?);
+    }
+    let reactor = reactor.as_mut().unwrap();
+    reactor.waiting.retain(|(fd, _, w)| {
+        !(fds.iter().any(|x| x.0 == *fd) && w.will_wake(waker))
+    });
+    reactor.waiting.extend(
+        fds.iter().map(|&(fd, events)| (fd, events, waker.clone())));
+    // a full pipe wakes the thread anyway
+    unsafe {
+        libc::write(reactor.wakeup.as_raw_fd(),
+                    b"x".as_ptr() as *const libc::c_void, 1);
+    }
+    Ok(())
+}
+
+/// Drops registrations of the descriptor, which is going to be closed
+fn deregister(fd: RawFd) {
+    let mut reactor = REACTOR.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(ref mut reactor) = *reactor {
+        reactor.waiting.retain(|x| x.0 != fd);
+    }
+}
+
+fn start_thread() -> io::Result<Reactor> {
+    let (rd, wr) = sys::pipe2(libc::O_CLOEXEC | libc::O_NONBLOCK)?;
+    let (rd, wr) = unsafe {
+        (OwnedFd::from_raw_fd(rd), OwnedFd::from_raw_fd(wr))
+    };
+    thread::Builder::new()
+        .name("unshare-reactor".into())
+        .spawn(move || run_reactor(rd))?;
+    Ok(Reactor { waiting: Vec::new(), wakeup: wr })
+}
+
+fn run_reactor(wakeup: OwnedFd) {
+    let mut fds = Vec::new();
+    loop {
+        fds.clear();
+        fds.push(libc::pollfd {
+            fd: wakeup.as_raw_fd(), events: libc::POLLIN, revents: 0,
+        });
+        {
+            let reactor = REACTOR.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(ref reactor) = *reactor {
+                fds.extend(reactor.waiting.iter().map(|&(fd, events, _)| {
+                    libc::pollfd { fd, events, revents: 0 }
+                }));
+            }
+        }
+        let rc = unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1)
+        };
+        // on errors (like too many descriptors) all the tasks are woken,
+        // so they get the error polling the descriptors themselves
+        let failed = rc < 0 && io::Error::last_os_error().kind()
+            != io::ErrorKind::Interrupted;
+        if fds[0].revents != 0 {
+            let mut buf = [0u8; 64];
+            while unsafe {
+                libc::read(wakeup.as_raw_fd(),
+                           buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+            } > 0 {}
+        }
+        let mut wakers = Vec::new();
+        {
+            let mut reactor = REACTOR.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(ref mut reactor) = *reactor {
+                reactor.waiting.retain(|(fd, _, waker)| {
+                    let ready = failed || fds[1..].iter()
+                        .any(|x| x.fd == *fd && x.revents != 0);
+                    if ready {
+                        wakers.push(waker.clone());
+                    }
+                    !ready
+                });
+            }
+        }
+        // not under the lock, in case waking registers the task again
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::{ExitStatus, Stdio};
+    use super::Command;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(x) = Pin::as_mut(&mut future).poll(&mut cx) {
+                return x;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_pipes() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(r#"read x; echo "got $x"; echo err >&2; exit 3"#);
+        cmd.stdin(Stdio::piped());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let (status, output) = block_on(async move {
+            child.stdin.as_mut().unwrap().write_all(b"hi\n").await?;
+            child.wait_with_output().await
+        }).unwrap();
+        assert_eq!(status, ExitStatus::Exited(3));
+        assert_eq!(output.stdout, b"got hi\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[test]
+    fn test_large_output() {
+        let mut cmd = Command::new("/usr/bin/head");
+        cmd.arg("-c").arg("1000000").arg("/dev/zero");
+        let (status, output) = block_on(cmd.output()).unwrap();
+        assert!(status.success());
+        assert_eq!(output.stdout.len(), 1000000);
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("0.2");
+        let start = Instant::now();
+        let mut child = cmd.spawn().unwrap();
+        let mut stdout = Vec::new();
+        assert!(block_on(child.wait()).unwrap().success());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        // the pipe of a reaped process is read to the end immediately
+        let mut cmd = Command::new("/bin/echo");
+        cmd.arg("done").stdout(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let mut pipe = child.stdout.take().unwrap();
+        assert!(block_on(child.wait()).unwrap().success());
+        assert_eq!(block_on(pipe.read_to_end(&mut stdout)).unwrap(), 5);
+        assert_eq!(stdout, b"done\n");
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::{pid_t, uid_t, gid_t};
+
+use crate::{Command, Child, Namespace, UidMap, GidMap, MountOp};
+use crate::error::Error;
+use crate::namespace::namespaces_of;
+use crate::stdio::dup_file_cloexec;
+
+
+/// A privileged operation done when spawning a child
+///
+/// See `Command::audit`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditOp {
+    /// Creating new namespaces
+    Namespaces(Vec<Namespace>),
+    /// Writing uid and gid maps of the user namespace
+    IdMaps(Vec<UidMap>, Vec<GidMap>),
+    /// Joining existing namespaces
+    JoinNamespaces(Vec<Namespace>),
+    /// Mounts, pivot root and chroot
+    Mounts(Vec<MountOp>),
+    /// Changing user, group and supplementary groups
+    SetUser {
+        /// New user id
+        uid: Option<uid_t>,
+        /// New group id
+        gid: Option<gid_t>,
+        /// New supplementary groups
+        groups: Option<Vec<gid_t>>,
+    },
+    /// Limiting capabilities to the listed numbers (see `Capability`)
+    Capabilities(Vec<u32>),
+}
+
+pub(crate) type AuditCallback = Box<dyn FnMut(&AuditRecord)>;
+
+/// Record of a privileged operation passed to the `Command::audit` callback
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    /// When the record is emitted (i.e. when `spawn()` finished)
+    pub timestamp: SystemTime,
+    /// Pid of the child, or `None` if spawning has failed
+    pub pid: Option<pid_t>,
+    /// The operation
+    pub operation: AuditOp,
+    /// Error message if the operation failed
+    pub error: Option<String>,
+    /// Labels of the command, see `Command::label`
+    pub labels: BTreeMap<String, String>,
+}
+
+pub(crate) fn json_str(buf: &mut String, value: &str) {
+    buf.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(buf, "\\u{:04x}", c as u32)
+                .unwrap(),
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+fn json_path(buf: &mut String, path: &Path) {
+    // non-utf8 bytes are replaced, there is no way to put them into json
+    json_str(buf, &String::from_utf8_lossy(path.as_os_str().as_bytes()));
+}
+
+pub(crate) fn json_list<T, F>(buf: &mut String, items: &[T], mut f: F)
+    where F: FnMut(&mut String, &T)
+{
+    buf.push('[');
+    for (idx, item) in items.iter().enumerate() {
+        if idx > 0 {
+            buf.push(',');
+        }
+        f(buf, item);
+    }
+    buf.push(']');
+}
+
+pub(crate) fn json_mount(buf: &mut String, op: &MountOp) {
+    match *op {
+        MountOp::MakePrivate => buf.push_str(r#"{"op":"make_private"}"#),
+        MountOp::Provided { ref target } => {
+            buf.push_str(r#"{"op":"provided","target":"#);
+            json_path(buf, target);
+            buf.push('}');
+        }
+        MountOp::Bind { ref source, ref target, read_only } => {
+            buf.push_str(r#"{"op":"bind","source":"#);
+            json_path(buf, source);
+            buf.push_str(r#","target":"#);
+            json_path(buf, target);
+            write!(buf, r#","read_only":{}}}"#, read_only).unwrap();
+        }
+        MountOp::PivotRoot { ref new_root, ref put_old, unmount_old_root }
+        => {
+            buf.push_str(r#"{"op":"pivot_root","new_root":"#);
+            json_path(buf, new_root);
+            buf.push_str(r#","put_old":"#);
+            json_path(buf, put_old);
+            write!(buf, r#","unmount_old_root":{}}}"#, unmount_old_root)
+                .unwrap();
+        }
+        MountOp::Chroot { ref dir } => {
+            buf.push_str(r#"{"op":"chroot","dir":"#);
+            json_path(buf, dir);
+            buf.push('}');
+        }
+        MountOp::Cgroupfs { ref target, read_only } => {
+            buf.push_str(r#"{"op":"cgroupfs","target":"#);
+            json_path(buf, target);
+            write!(buf, r#","read_only":{}}}"#, read_only).unwrap();
+        }
+    }
+}
+
+pub(crate) fn json_opt<T: ToString>(buf: &mut String, value: Option<T>) {
+    match value {
+        Some(x) => buf.push_str(&x.to_string()),
+        None => buf.push_str("null"),
+    }
+}
+
+impl AuditRecord {
+    /// Formats the record as a single line JSON object (without newline)
+    ///
+    /// For example:
+    /// `{"timestamp":1700000000.000000000,"pid":123,"operation":"namespaces",
+    /// "namespaces":["mount","pid"],"outcome":"ok"}`. Failed operations
+    /// have `"outcome":"error"` and the `"error"` message. Labels of the
+    /// command, if any, follow the pid as `"labels":{"job":"1"}`.
+    pub fn to_json(&self) -> String {
+        let mut buf = String::with_capacity(128);
+        let time = self.timestamp.duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(buf, r#"{{"timestamp":{}.{:09},"pid":"#,
+            time.as_secs(), time.subsec_nanos()).unwrap();
+        json_opt(&mut buf, self.pid);
+        if !self.labels.is_empty() {
+            buf.push_str(r#","labels":{"#);
+            for (idx, (k, v)) in self.labels.iter().enumerate() {
+                if idx > 0 {
+                    buf.push(',');
+                }
+                json_str(&mut buf, k);
+                buf.push(':');
+                json_str(&mut buf, v);
+            }
+            buf.push('}');
+        }
+        let names = |buf: &mut String, ns: &Namespace| {
+            json_str(buf, &format!("{:?}", ns).to_lowercase());
+        };
+        match self.operation {
+            AuditOp::Namespaces(ref list) => {
+                buf.push_str(r#","operation":"namespaces","namespaces":"#);
+                json_list(&mut buf, list, names);
+            }
+            AuditOp::JoinNamespaces(ref list) => {
+                buf.push_str(
+                    r#","operation":"join_namespaces","namespaces":"#);
+                json_list(&mut buf, list, names);
+            }
+            AuditOp::IdMaps(ref uids, ref gids) => {
+                buf.push_str(r#","operation":"id_maps","uid_map":"#);
+                json_list(&mut buf, uids, |buf, m| {
+                    write!(buf, "[{},{},{}]",
+                        m.inside_uid, m.outside_uid, m.count).unwrap();
+                });
+                buf.push_str(r#","gid_map":"#);
+                json_list(&mut buf, gids, |buf, m| {
+                    write!(buf, "[{},{},{}]",
+                        m.inside_gid, m.outside_gid, m.count).unwrap();
+                });
+            }
+            AuditOp::Mounts(ref ops) => {
+                buf.push_str(r#","operation":"mounts","mounts":"#);
+                json_list(&mut buf, ops, json_mount);
+            }
+            AuditOp::SetUser { uid, gid, ref groups } => {
+                buf.push_str(r#","operation":"set_user","uid":"#);
+                json_opt(&mut buf, uid);
+                buf.push_str(r#","gid":"#);
+                json_opt(&mut buf, gid);
+                buf.push_str(r#","groups":"#);
+                match *groups {
+                    Some(ref groups) => json_list(&mut buf, groups,
+                        |buf, g| write!(buf, "{}", g).unwrap()),
+                    None => buf.push_str("null"),
+                }
+            }
+            AuditOp::Capabilities(ref caps) => {
+                buf.push_str(r#","operation":"capabilities","caps":"#);
+                json_list(&mut buf, caps,
+                    |buf, c| write!(buf, "{}", c).unwrap());
+            }
+        }
+        match self.error {
+            Some(ref err) => {
+                buf.push_str(r#","outcome":"error","error":"#);
+                json_str(&mut buf, err);
+            }
+            None => buf.push_str(r#","outcome":"ok""#),
+        }
+        buf.push('}');
+        buf
+    }
+}
+
+impl Command {
+    /// Call `callback` for every privileged operation done for the child
+    ///
+    /// Operations are namespace creation, id map writes, mounts, changing
+    /// user and capabilities. Records are emitted in this order when
+    /// `spawn()` finishes, so they all have the same timestamp. If spawning
+    /// fails in one of the operations, only that operation is reported,
+    /// with the error: the child is killed and its namespaces are
+    /// destroyed anyway.
+    ///
+    /// Use `AuditRecord::to_json` for structured logs, or `audit_fd`.
+    pub fn audit<F>(&mut self, callback: F) -> &mut Command
+        where F: FnMut(&AuditRecord) + 'static
+    {
+        self.audit = Some(Box::new(callback));
+        self
+    }
+
+    /// Write audit records to the file as JSON, one record per line
+    ///
+    /// The descriptor is duplicated. Write errors are ignored, so that an
+    /// unavailable log doesn't prevent the command from running. See
+    /// `audit` for more info.
+    pub fn audit_fd<F: AsRawFd>(&mut self, file: &F)
+        -> io::Result<&mut Command>
+    {
+        let fd = dup_file_cloexec(file)?.into_fd();
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        Ok(self.audit(move |record| {
+            let mut line = record.to_json();
+            line.push('\n');
+            file.write_all(line.as_bytes()).ok();
+        }))
+    }
+
+    fn audit_ops(&self, child: Option<&Child>) -> Vec<AuditOp> {
+        let mut ops = Vec::new();
+        if self.config.namespaces != 0 {
+            ops.push(AuditOp::Namespaces(
+                namespaces_of(self.config.namespaces)));
+        }
+        if let Some((ref uids, ref gids)) = self.config.id_maps {
+            ops.push(AuditOp::IdMaps(uids.clone(), gids.clone()));
+        }
+        let joined = self.config.joined_namespaces().collect::<Vec<_>>();
+        if !joined.is_empty() {
+            ops.push(AuditOp::JoinNamespaces(joined));
+        }
+        let mounts = child.map_or_else(|| self.mount_plan(),
+                                       |c| c.mounts_applied().to_vec());
+        if !mounts.is_empty() {
+            ops.push(AuditOp::Mounts(mounts));
+        }
+        if self.config.uid.is_some() || self.config.gid.is_some() ||
+            self.config.supplementary_gids.is_some()
+        {
+            ops.push(AuditOp::SetUser {
+                uid: self.config.uid,
+                gid: self.config.gid,
+                groups: self.config.supplementary_gids.clone(),
+            });
+        }
+        if let Some(caps) = self.keep_caps {
+            ops.push(AuditOp::Capabilities((0..64)
+                .filter(|&idx| caps[idx >> 5] & (1 << (idx & 31)) != 0)
+                .map(|idx| idx as u32)
+                .collect()));
+        }
+        ops
+    }
+
+    /// Emits audit records for the result of `spawn()`
+    pub(crate) fn audit_spawn(&mut self, result: &Result<Child, Error>) {
+        if self.audit.is_none() {
+            return;
+        }
+        let timestamp = SystemTime::now();
+        let records = match *result {
+            Ok(ref child) => {
+                self.audit_ops(Some(child)).into_iter()
+                .map(|operation| AuditRecord {
+                    timestamp,
+                    pid: Some(child.pid()),
+                    operation,
+                    error: None,
+                    labels: self.labels.clone(),
+                }).collect::<Vec<_>>()
+            }
+            Err(ref e) => {
+                self.audit_ops(None).into_iter()
+                .filter(|op| failed_in(op, e))
+                .map(|operation| AuditRecord {
+                    timestamp,
+                    pid: None,
+                    operation,
+                    error: Some(e.to_string()),
+                    labels: self.labels.clone(),
+                }).collect()
+            }
+        };
+        let callback = self.audit.as_mut().unwrap();
+        for record in &records {
+            callback(record);
+        }
+    }
+}
+
+/// Returns true if the error is the failure of the operation
+fn failed_in(op: &AuditOp, err: &Error) -> bool {
+    use crate::Error as E;
+    matches!((op, err),
+        (AuditOp::Namespaces(..), E::Fork(_)) |
+        (AuditOp::IdMaps(..), E::SetIdMap(_) | E::AuxCommandExited(_) |
+                              E::AuxCommandKilled(_)) |
+        (AuditOp::JoinNamespaces(..), E::SetNs(_)) |
+        (AuditOp::Mounts(..), E::MakePrivate(_) | E::ChangeRoot(_) |
+                              E::MountProvider(_) | E::AttachMount(_) |
+                              E::InjectHostFile(_) |
+                              E::ForeignInterpreter(_)) |
+        (AuditOp::SetUser { .. }, E::SetUser(_)) |
+        (AuditOp::Capabilities(..), E::CapSet(_) | E::AmbientCaps(_)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::{Command, Namespace};
+    use super::{AuditOp, AuditRecord};
+
+    fn audited(cmd: &mut Command) -> Vec<AuditRecord> {
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let copy = records.clone();
+        cmd.audit(move |r| copy.borrow_mut().push(r.clone()));
+        if let Ok(mut child) = cmd.spawn() {
+            child.wait().unwrap();
+        }
+        let result = records.borrow().clone();
+        result
+    }
+
+    #[test]
+    fn test_audit() {
+        let records = audited(Command::new("/bin/true")
+            .unshare(&[Namespace::Uts]).uid(65534).label("job", "1"));
+        let ops = records.iter().map(|r| r.operation.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ops, vec![
+            AuditOp::Namespaces(vec![Namespace::Uts]),
+            AuditOp::SetUser { uid: Some(65534), gid: None, groups: None },
+        ]);
+        assert!(records.iter().all(|r| r.error.is_none()));
+        let json = records[1].to_json();
+        assert!(json.contains(r#""operation":"set_user","uid":65534,"#),
+                "{}", json);
+        assert!(json.ends_with(r#","outcome":"ok"}"#), "{}", json);
+        assert!(json.contains(r#","labels":{"job":"1"},"#), "{}", json);
+    }
+
+    #[test]
+    fn test_audit_failure() {
+        let records = audited(Command::new("/bin/true")
+            .unshare(&[Namespace::Uts])
+            .pivot_root("/nonexistent", "/nonexistent/old", true));
+        assert_eq!(records.len(), 1);
+        assert!(matches!(records[0].operation, AuditOp::Mounts(..)));
+        assert!(records[0].pid.is_none());
+        let json = records[0].to_json();
+        assert!(json.contains(
+            r#""op":"pivot_root","new_root":"/nonexistent""#), "{}", json);
+        assert!(json.contains(r#""outcome":"error","error":"#), "{}", json);
+    }
+}
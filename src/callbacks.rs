@@ -14,7 +14,7 @@ impl Command {
     /// done (setting uid namespaces). It always run before ``pre_exec``
     /// callback in child.
     ///
-    /// If callback returns error, process is shut down.
+    /// If callback returns error or panics, process is killed and reaped.
     ///
     /// Each invocation **replaces** callback,
     /// so there is only one of them can be called.
@@ -27,6 +27,30 @@ impl Command {
         self
     }
 
+    /// Set a callback to run in the parent right after uid/gid maps are
+    /// written
+    ///
+    /// This is the point where an external tool (e.g. a hook setting up
+    /// the container) can act on the child as the user namespace owner
+    /// sees it: `set_id_maps` are written (the callback runs at the same
+    /// point if there are no maps), limits of `inherit_limits_from` are
+    /// applied, and the child is already in its `cgroup` (or the scope of
+    /// `register_systemd_scope`). The callback runs before
+    /// `PrivilegedOps::setup`, `userspace_network`, mount providers and
+    /// `before_unfreeze`, so the network isn't configured yet.
+    ///
+    /// If callback returns error (`Error::AfterIdmap`) or panics, process
+    /// is killed and reaped.
+    ///
+    /// Each invocation **replaces** callback.
+    pub fn after_idmap(
+        &mut self,
+        f: impl FnMut(u32) -> Result<(), BoxError> + 'static,
+    ) -> &mut Self {
+        self.after_idmap = Some(Box::new(f));
+        self
+    }
+
     /// Set a callback to run in the child before calling exec
     ///
     /// The callback is executed right before `execve` system calls.
@@ -35,11 +59,15 @@ impl Command {
     ///
     /// **Warning** this callback must not do any memory (de)allocations,
     /// use mutexes, otherwise process may crash or deadlock. Only bare
-    /// syscalls are allowed (use `libc` crate).
+    /// syscalls are allowed (use `libc` crate). There are few helpers for
+    /// formatting numbers and paths in the `no_alloc` module, and system
+    /// call wrappers in `preexec::ops`.
     ///
     /// The closure is allowed to return an I/O error whose
     /// OS error code will be communicated back to the parent
     /// and returned as an error from when the spawn was requested.
+    /// The closure must not panic, as the panic hook allocates (see
+    /// `preexec` module).
     ///
     /// Note: unlike same method in stdlib,
     /// each invocation of this method **replaces** callback,
@@ -52,3 +80,138 @@ impl Command {
         self
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::fs;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use crate::{Command, Error, Namespace, UidMap, GidMap};
+    use crate::{ExitStatus, Signal};
+
+    fn is_reaped(pid: i32) -> bool {
+        let rc = unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+        rc < 0 && std::io::Error::last_os_error().raw_os_error() ==
+            Some(libc::ECHILD)
+    }
+
+    #[test]
+    fn test_error_reaps_child() {
+        let pid = Rc::new(Cell::new(0));
+        let copy = pid.clone();
+        let result = Command::new("/bin/true")
+            .before_unfreeze(move |pid| {
+                copy.set(pid as i32);
+                Err("setup failed".into())
+            })
+            .spawn();
+        assert!(matches!(result, Err(Error::BeforeUnfreeze(_))));
+        assert!(pid.get() > 0 && is_reaped(pid.get()));
+    }
+
+    #[test]
+    fn test_panic_reaps_child() {
+        let marker = std::env::temp_dir().join("unshare-test-panic-marker");
+        let _ = std::fs::remove_file(&marker);
+        let pid = Rc::new(Cell::new(0));
+        let copy = pid.clone();
+        let mut cmd = Command::new("/usr/bin/touch");
+        // the orphan would continue and run the program if not killed
+        cmd.arg(&marker).allow_daemonize();
+        cmd.before_unfreeze(move |pid| {
+            copy.set(pid as i32);
+            panic!("setup panicked");
+        });
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cmd.spawn()));
+        assert!(result.is_err());
+        assert!(pid.get() > 0 && is_reaped(pid.get()));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_after_idmap() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let (first, second) = (events.clone(), events.clone());
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::User])
+            .set_id_maps(
+                vec![UidMap { inside_uid: 0, outside_uid: 65534, count: 1 }],
+                vec![GidMap { inside_gid: 0, outside_gid: 65534, count: 1 }])
+            .before_unfreeze(move |_| {
+                first.borrow_mut().push("before_unfreeze".to_string());
+                Ok(())
+            })
+            .after_idmap(move |pid| {
+                let path = format!("/proc/{}/uid_map", pid);
+                let map = fs::read_to_string(path)?;
+                second.borrow_mut().push(map.split_whitespace()
+                    .collect::<Vec<_>>().join(" "));
+                Ok(())
+            });
+        assert!(cmd.status().unwrap().success());
+        assert_eq!(*events.borrow(), ["0 65534 1", "before_unfreeze"]);
+
+        let pid = Rc::new(Cell::new(0));
+        let copy = pid.clone();
+        let result = cmd.after_idmap(move |pid| {
+            copy.set(pid as i32);
+            Err("hook failed".into())
+        }).spawn();
+        assert!(matches!(result, Err(Error::AfterIdmap(_))));
+        assert!(pid.get() > 0 && is_reaped(pid.get()));
+    }
+
+    fn died_with(result: Result<crate::Child, Error>) -> ExitStatus {
+        match result {
+            Err(Error::ChildDiedDuringSetup(status)) => status,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(child) => panic!("child {} reported as spawned", child.pid()),
+        }
+    }
+
+    #[test]
+    fn test_killed_while_frozen() {
+        let result = Command::new("/bin/true")
+            .before_unfreeze(|pid| {
+                unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+                Ok(())
+            })
+            .spawn();
+        assert!(matches!(died_with(result),
+                         ExitStatus::Signaled(Signal::SIGKILL, _)));
+    }
+
+    #[test]
+    fn test_killed_before_exec() {
+        let mut cmd = Command::new("/bin/true");
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::kill(libc::getpid(), libc::SIGKILL);
+                Ok(())
+            });
+        }
+        assert!(matches!(died_with(cmd.spawn()),
+                         ExitStatus::Signaled(Signal::SIGKILL, _)));
+    }
+
+    #[test]
+    fn test_exit_while_pipe_is_open() {
+        let mut cmd = Command::new("/bin/true");
+        unsafe {
+            cmd.pre_exec(|| {
+                // the grandchild keeps the error pipe open for a while
+                if libc::fork() == 0 {
+                    libc::sleep(5);
+                    libc::_exit(0);
+                }
+                libc::_exit(3);
+            });
+        }
+        let start = Instant::now();
+        assert_eq!(died_with(cmd.spawn()), ExitStatus::Exited(3));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
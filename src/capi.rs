@@ -0,0 +1,283 @@
+//! C interface for embedding in supervisors written in other languages
+//!
+//! Enabled by the `capi` feature. The declarations are in
+//! `include/unshare.h`, the library is built with:
+//!
+//! ```text
+//! cargo rustc --release --features capi --crate-type staticlib
+//! ```
+//!
+//! Commands and children are opaque handles which must be freed by the
+//! respective `_free` function. Functions which may fail return `-1` (or
+//! `NULL`) and, if the `err` argument isn't `NULL`, store the error there,
+//! it's freed by `unshare_error_free`. Panics don't unwind into the
+//! caller, they are reported as errors.
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::BorrowedFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use libc::{c_char, c_int, pid_t};
+
+use crate::{Command, Child, ExitStatus, Signal};
+use crate::namespace::{from_clone_flag, namespaces_of, to_clone_flag};
+
+
+/// Error returned through the `err` argument
+pub struct UnshareError {
+    message: CString,
+    errno: c_int,
+}
+
+impl UnshareError {
+    fn new<E: ToString>(err: E, errno: Option<i32>) -> UnshareError {
+        let mut message = err.to_string().into_bytes();
+        message.retain(|&b| b != 0);
+        UnshareError {
+            message: CString::new(message).unwrap(),
+            errno: errno.unwrap_or(0),
+        }
+    }
+    fn invalid(message: &str) -> UnshareError {
+        UnshareError::new(message, Some(libc::EINVAL))
+    }
+}
+
+impl From<crate::Error> for UnshareError {
+    fn from(err: crate::Error) -> UnshareError {
+        UnshareError::new(&err, err.raw_os_error())
+    }
+}
+
+impl From<std::io::Error> for UnshareError {
+    fn from(err: std::io::Error) -> UnshareError {
+        let errno = err.raw_os_error().or_else(|| {
+            (err.kind() == std::io::ErrorKind::InvalidInput)
+                .then_some(libc::EINVAL)
+        });
+        UnshareError::new(&err, errno)
+    }
+}
+
+/// Runs `f` storing the error (or the panic) into `err`
+unsafe fn call<T>(err: *mut *mut UnshareError, failed: T,
+    f: impl FnOnce() -> Result<T, UnshareError>)
+    -> T
+{
+    let error = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(e)) => e,
+        Err(_) => UnshareError::new("panic in unshare library", None),
+    };
+    if !err.is_null() {
+        *err = Box::into_raw(Box::new(error));
+    }
+    failed
+}
+
+unsafe fn to_str<'a>(value: *const c_char) -> Result<&'a OsStr, UnshareError>
+{
+    if value.is_null() {
+        return Err(UnshareError::invalid("unexpected NULL string"));
+    }
+    Ok(OsStr::from_bytes(CStr::from_ptr(value).to_bytes()))
+}
+
+unsafe fn to_ref<'a, T>(value: *mut T) -> Result<&'a mut T, UnshareError> {
+    value.as_mut().ok_or_else(|| UnshareError::invalid("unexpected NULL"))
+}
+
+/// Encodes the status like `waitpid` does, so `WIFEXITED` and alike work
+fn wait_status(status: ExitStatus) -> c_int {
+    match status {
+        ExitStatus::Exited(code) => (code as u8 as c_int) << 8,
+        ExitStatus::Signaled(sig, core) => {
+            sig.as_raw() | if core { 0x80 } else { 0 }
+        }
+    }
+}
+
+/// Creates a command for the program, `NULL` if `program` is `NULL`
+#[no_mangle]
+pub unsafe extern "C" fn unshare_command_new(program: *const c_char)
+    -> *mut Command
+{
+    call(ptr::null_mut(), ptr::null_mut(), || {
+        Ok(Box::into_raw(Box::new(Command::new(to_str(program)?))))
+    })
+}
+
+/// Frees the command, `NULL` is ignored
+#[no_mangle]
+pub unsafe extern "C" fn unshare_command_free(cmd: *mut Command) {
+    if !cmd.is_null() {
+        drop(Box::from_raw(cmd));
+    }
+}
+
+/// Adds an argument
+#[no_mangle]
+pub unsafe extern "C" fn unshare_command_arg(cmd: *mut Command,
+    arg: *const c_char, err: *mut *mut UnshareError)
+    -> c_int
+{
+    call(err, -1, || {
+        to_ref(cmd)?.arg(to_str(arg)?);
+        Ok(0)
+    })
+}
+
+/// Unshares namespaces, `flags` are `CLONE_NEW*` constants
+#[no_mangle]
+pub unsafe extern "C" fn unshare_command_unshare(cmd: *mut Command,
+    flags: c_int, err: *mut *mut UnshareError)
+    -> c_int
+{
+    call(err, -1, || {
+        let namespaces = namespaces_of(flags);
+        let known = namespaces.iter().fold(0, |x, &ns| x | to_clone_flag(ns));
+        if known != flags {
+            return Err(UnshareError::invalid("unknown namespace flags"));
+        }
+        to_ref(cmd)?.unshare(&namespaces);
+        Ok(0)
+    })
+}
+
+/// Joins the namespace, `nstype` is one of the `CLONE_NEW*` constants
+///
+/// The descriptor is duplicated, so the caller may close it.
+#[no_mangle]
+pub unsafe extern "C" fn unshare_command_set_namespace(cmd: *mut Command,
+    fd: c_int, nstype: c_int, err: *mut *mut UnshareError)
+    -> c_int
+{
+    call(err, -1, || {
+        let ns = from_clone_flag(nstype)
+            .ok_or_else(|| UnshareError::invalid("unknown namespace type"))?;
+        if fd < 0 {
+            return Err(UnshareError::new("invalid descriptor",
+                                        Some(libc::EBADF)));
+        }
+        to_ref(cmd)?.set_namespace(&BorrowedFd::borrow_raw(fd), ns)?;
+        Ok(0)
+    })
+}
+
+/// Spawns the child, returns `NULL` on error
+#[no_mangle]
+pub unsafe extern "C" fn unshare_command_spawn(cmd: *mut Command,
+    err: *mut *mut UnshareError)
+    -> *mut Child
+{
+    call(err, ptr::null_mut(), || {
+        Ok(Box::into_raw(Box::new(to_ref(cmd)?.spawn()?)))
+    })
+}
+
+/// Returns the pid of the child, `-1` if `child` is `NULL`
+#[no_mangle]
+pub unsafe extern "C" fn unshare_child_pid(child: *mut Child) -> pid_t {
+    child.as_ref().map_or(-1, |child| child.pid())
+}
+
+/// Waits for the child to exit, the `status` is encoded like in `waitpid`
+#[no_mangle]
+pub unsafe extern "C" fn unshare_child_wait(child: *mut Child,
+    status: *mut c_int, err: *mut *mut UnshareError)
+    -> c_int
+{
+    call(err, -1, || {
+        let value = wait_status(to_ref(child)?.wait()?);
+        if !status.is_null() {
+            *status = value;
+        }
+        Ok(0)
+    })
+}
+
+/// Sends the signal to the child
+#[no_mangle]
+pub unsafe extern "C" fn unshare_child_kill(child: *mut Child,
+    signal: c_int, err: *mut *mut UnshareError)
+    -> c_int
+{
+    call(err, -1, || {
+        to_ref(child)?.signal(Signal::from_raw(signal))?;
+        Ok(0)
+    })
+}
+
+/// Frees the handle, the process is neither killed nor waited for
+#[no_mangle]
+pub unsafe extern "C" fn unshare_child_free(child: *mut Child) {
+    if !child.is_null() {
+        drop(Box::from_raw(child));
+    }
+}
+
+/// Returns the message of the error, valid until the error is freed
+#[no_mangle]
+pub unsafe extern "C" fn unshare_error_message(err: *const UnshareError)
+    -> *const c_char
+{
+    err.as_ref().map_or(ptr::null(), |err| err.message.as_ptr())
+}
+
+/// Returns the OS error code of the error, or `0`
+#[no_mangle]
+pub unsafe extern "C" fn unshare_error_errno(err: *const UnshareError)
+    -> c_int
+{
+    err.as_ref().map_or(0, |err| err.errno)
+}
+
+/// Frees the error, `NULL` is ignored
+#[no_mangle]
+pub unsafe extern "C" fn unshare_error_free(err: *mut UnshareError) {
+    if !err.is_null() {
+        drop(Box::from_raw(err));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CStr;
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn test_spawn() {
+        unsafe {
+            let mut err = ptr::null_mut();
+            let cmd = unshare_command_new(b"/bin/sh\0".as_ptr() as _);
+            assert_eq!(unshare_command_arg(cmd, b"-c\0".as_ptr() as _,
+                                           &mut err), 0);
+            assert_eq!(unshare_command_arg(cmd, b"exit 3\0".as_ptr() as _,
+                                           &mut err), 0);
+            let child = unshare_command_spawn(cmd, &mut err);
+            assert!(!child.is_null());
+            assert!(unshare_child_pid(child) > 0);
+            let mut status = 0;
+            assert_eq!(unshare_child_wait(child, &mut status, &mut err), 0);
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 3);
+            assert_eq!(unshare_child_kill(child, libc::SIGTERM, &mut err), -1);
+            assert!(!err.is_null());
+            unshare_error_free(err);
+            unshare_child_free(child);
+
+            let mut err = ptr::null_mut();
+            assert_eq!(unshare_command_set_namespace(cmd, 0,
+                libc::CLONE_NEWNET, &mut err), -1);
+            assert_eq!(unshare_error_errno(err), libc::EINVAL);
+            let message = CStr::from_ptr(unshare_error_message(err));
+            assert!(!message.to_bytes().is_empty());
+            unshare_error_free(err);
+            assert_eq!(unshare_command_unshare(cmd, 1, ptr::null_mut()), -1);
+            unshare_command_free(cmd);
+        }
+    }
+}
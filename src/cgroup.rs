@@ -0,0 +1,156 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libc::pid_t;
+
+use crate::{Command, Namespace};
+use crate::freeze::cgroup2_mount;
+use crate::chroot::in_root;
+
+
+/// Which cgroup the child is put into, see `Command::cgroup`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CgroupPolicy {
+    /// Stay in the cgroup of the current process (the default)
+    Inherit,
+    /// Move to the cgroup at the path in the cgroup v2 hierarchy, `/` is
+    /// the root cgroup
+    MoveTo(PathBuf),
+}
+
+/// Where `Command::mount_cgroupfs` mounts the cgroup file system
+pub(crate) const CGROUPFS_TARGET: &str = "/sys/fs/cgroup";
+
+/// How the cgroup file system is mounted, see `Command::mount_cgroupfs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupMount {
+    /// The child can only read limits and statistics
+    ReadOnly,
+    /// The child can also create cgroups and change limits (as far as
+    /// the permissions of the files allow)
+    ReadWrite,
+}
+
+impl Command {
+    /// Choose the cgroup of the child
+    ///
+    /// By default, the child inherits the cgroup of the current process,
+    /// so it's accounted and limited together with it (e.g. within the
+    /// slice of a systemd service). With `CgroupPolicy::MoveTo` the child
+    /// is moved to the cgroup (v2) while frozen, before `before_unfreeze`
+    /// and `privileged_ops`, so all its descendants are there too. The
+    /// path is as in `/proc/self/cgroup` (relative to the mount point of
+    /// the hierarchy), so `MoveTo("/".into())` escapes to the root cgroup.
+    ///
+    /// The cgroup must exist, and the current process must be allowed to
+    /// write its `cgroup.procs` (which usually requires root for cgroups
+    /// outside of its own subtree). Otherwise `spawn()` fails with
+    /// `Error::Cgroup` (`ENOENT` if no cgroup v2 hierarchy is mounted),
+    /// rather than running the child in the unexpected cgroup.
+    ///
+    /// Each invocation **replaces** previously set policy.
+    pub fn cgroup(&mut self, policy: CgroupPolicy) -> &mut Command {
+        self.config.cgroup = match policy {
+            CgroupPolicy::Inherit => None,
+            CgroupPolicy::MoveTo(path) => Some(path),
+        };
+        self
+    }
+
+    /// Mount the cgroup (v2) of the child at `/sys/fs/cgroup`
+    ///
+    /// Many runtimes look there for their limits (e.g. container detection
+    /// of the JVM), but otherwise the child sees the whole hierarchy of
+    /// the host, or nothing in the new root. This unshares mount and cgroup
+    /// namespaces. The child enters a new cgroup namespace again after it
+    /// is moved by `cgroup()`, so the cgroup it ends up in is the root of
+    /// the mount (and `0::/` in `/proc/self/cgroup`). The file system is
+    /// mounted after the root is changed, and the mount point must exist
+    /// in the new root, otherwise `spawn()` fails with
+    /// `Error::MountCgroup(ENOENT)`.
+    ///
+    /// Each invocation **replaces** the previous mode.
+    pub fn mount_cgroupfs(&mut self, mode: CgroupMount) -> &mut Command {
+        self.config.cgroupfs = Some(mode);
+        self.unshare(&[Namespace::Mount, Namespace::Cgroup])
+    }
+}
+
+/// Moves the process into the cgroup at `path` in the v2 hierarchy
+pub(crate) fn move_to_cgroup(pid: pid_t, path: &Path) -> io::Result<()> {
+    let mount = cgroup2_mount()
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+    fs::write(in_root(&mount, path).join("cgroup.procs"), pid.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{self, Read};
+    use std::path::PathBuf;
+
+    use crate::{Command, Error, Stdio};
+    use crate::freeze::cgroup2_mount;
+    use super::{CgroupPolicy, CgroupMount};
+
+    #[test]
+    fn test_move_to_cgroup() {
+        let mount = match cgroup2_mount() {
+            Some(mount) => mount,
+            None => return,
+        };
+        let name = format!("unshare-test-cgroup-{}", std::process::id());
+        let dir = mount.join(&name);
+        match fs::create_dir(&dir) {
+            Ok(()) => {}
+            // unprivileged
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return;
+            }
+            Err(e) => panic!("{}: {}", dir.display(), e),
+        }
+        let mut cmd = Command::new("/bin/grep");
+        cmd.arg("^0::").arg("/proc/self/cgroup")
+            .stdout(Stdio::piped())
+            .cgroup(CgroupPolicy::MoveTo(PathBuf::from("/").join(&name)));
+        let mut child = cmd.spawn().unwrap();
+        let mut out = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut out).unwrap();
+        child.wait().unwrap();
+        fs::remove_dir(&dir).unwrap();
+        assert_eq!(out, format!("0::/{}\n", name));
+
+        let err = cmd.cgroup(CgroupPolicy::MoveTo(dir.join("nonexistent")))
+            .spawn().unwrap_err();
+        assert!(matches!(err, Error::Cgroup(libc::ENOENT)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_mount_cgroupfs() {
+        let mount = match cgroup2_mount() {
+            Some(mount) => mount,
+            None => return,
+        };
+        let name = format!("unshare-test-cgroupfs-{}", std::process::id());
+        let dir = mount.join(&name);
+        match fs::create_dir(&dir) {
+            Ok(()) => {}
+            // unprivileged
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return;
+            }
+            Err(e) => panic!("{}: {}", dir.display(), e),
+        }
+        // `cgroup.freeze` is missing in the root cgroup of the host
+        let status = Command::new("/bin/sh").arg("-c")
+            .arg("grep -qx 0::/ /proc/self/cgroup && \
+                  [ -f /sys/fs/cgroup/cgroup.freeze ] && \
+                  ! mkdir /sys/fs/cgroup/x 2>/dev/null")
+            .cgroup(CgroupPolicy::MoveTo(PathBuf::from("/").join(&name)))
+            .mount_cgroupfs(CgroupMount::ReadOnly)
+            .status().unwrap();
+        fs::remove_dir(&dir).unwrap();
+        assert!(status.success());
+    }
+}
@@ -1,16 +1,40 @@
-use std::os::unix::io::RawFd;
+use std::ffi::CStr;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::mem;
 use std::ptr;
+use std::time::Duration;
 
 use libc;
-use nix;
-use libc::{c_void, c_ulong, sigset_t, size_t};
+use libc::{c_char, c_void, c_ulong, sigset_t, size_t};
 use libc::{kill, signal};
 use libc::{F_GETFD, F_SETFD, F_DUPFD_CLOEXEC, FD_CLOEXEC, MNT_DETACH};
+use libc::{MS_REC, MS_PRIVATE};
+use libc::{c_int, O_PATH, O_DIRECTORY, O_CLOEXEC, O_NOFOLLOW};
+use libc::{O_RDONLY, O_WRONLY, O_CREAT, O_TRUNC};
+use libc::{AT_SYMLINK_NOFOLLOW, S_IFMT, S_IFLNK};
+use libc::{RESOLVE_BENEATH, RESOLVE_NO_SYMLINKS};
 use libc::{SIG_DFL, SIG_SETMASK};
 
-use crate::run::{ChildInfo, MAX_PID_LEN};
+use crate::run::ChildInfo;
+use crate::chroot::Beneath;
+use crate::copy::CopyFile;
+use crate::linux::reassert_parent_death_signal;
+use crate::hardening::HardeningStep;
+use crate::config::CredentialStep;
+use crate::mounts::umount_raw;
+use crate::mount_provider::{recv_fd, send_fd};
+use crate::no_alloc::{MAX_PID_LEN, format_pid, format_u64};
 use crate::error::ErrorCode as Err;
+use crate::error::{encode_error, EXEC_FRAME};
+use crate::daemon::DAEMON_PID_FRAME;
+use crate::core_dump::CorePolicy;
+use crate::cgroup::CgroupMount;
+use crate::sys::errno;
+
+const ROOT: &[u8] = b"/\0";
+const CURDIR: &[u8] = b".\0";
+const EMPTY: &[u8] = b"\0";
+const MOVE_MOUNT_F_EMPTY_PATH: c_int = 0x4;
 
 // And at this point we've reached a special time in the life of the
 // child. The child must now be considered hamstrung and unable to
@@ -26,13 +50,19 @@ use crate::error::ErrorCode as Err;
 // deallocating (parts of) it.
 pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
     let mut epipe = child.error_pipe;
+    let ppid = libc::getppid();
 
     child.cfg.death_sig.as_ref().map(|&sig| {
-        if libc::prctl(ffi::PR_SET_PDEATHSIG, sig as c_ulong, 0, 0, 0) != 0 {
+        if libc::prctl(ffi::PR_SET_PDEATHSIG, sig.as_raw() as c_ulong, 0, 0, 0) != 0 {
             fail(Err::ParentDeathSignal, epipe);
         }
     });
 
+    if child.allow_ptrace {
+        // the tracer is not our ancestor, which Yama LSM would reject
+        libc::prctl(libc::PR_SET_PTRACER, libc::PR_SET_PTRACER_ANY, 0, 0, 0);
+    }
+
     // Now we must wait until parent set some environment for us. It's mostly
     // for uid_map/gid_map. But also used for attaching debugger and maybe
     // other things
@@ -45,18 +75,17 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
             // Parent already dead presumably before we had a chance to
             // set PDEATHSIG, so just send signal ourself in that case
             if let Some(sig) = child.cfg.death_sig {
-                kill(libc::getpid(), sig as i32);
+                kill(libc::getpid(), sig.as_raw());
+                libc::_exit(127);
+            } else if child.abort_orphaned {
+                // Parent might have failed to setup uid/gid map for us
                 libc::_exit(127);
             } else {
                 // In case we wanted to daemonize, just continue
-                //
-                // TODO(tailhook) not sure it's best thing to do. Maybe parent
-                // failed to setup uid/gid map for us. Do we want to check
-                // specific options? Or should we just always die?
                 break;
             }
         } else if rc < 0 {
-            let errno = nix::errno::errno();
+            let errno = errno();
             if errno == libc::EINTR as i32 ||
                errno == libc::EAGAIN as i32
             {
@@ -79,102 +108,253 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         epipe = nerr;
     }
 
+    // before joining namespaces, needs `CAP_AUDIT_CONTROL` in the initial
+    // user namespace and `/proc` of the host
+    if let Some(uid) = child.cfg.loginuid {
+        if let Err(e) = set_loginuid(uid) {
+            fail_errno(Err::LoginUid, e, epipe);
+        }
+    }
+
+    // as the root of the user namespace, the maps are written by now
+    if child.deferred_namespaces != 0 &&
+        libc::unshare(child.deferred_namespaces) != 0
+    {
+        fail(Err::Unshare, epipe);
+    }
+
     for &(nstype, fd) in child.setns_namespaces {
-        if libc::setns(fd, nstype.bits()) != 0 {
+        if libc::setns(fd, nstype) != 0 {
             fail(Err::SetNs, epipe);
         }
     }
 
+    if let Some(offsets) = child.time_offsets {
+        if let Err(e) = enter_time_namespace(offsets) {
+            fail_errno(Err::TimeNamespace, e, epipe);
+        }
+    }
+
+    if let Some((ref root, ref cwd)) = child.cfg.enter_root {
+        if libc::fchdir(root.as_raw_fd()) != 0 ||
+            libc::chroot(CURDIR.as_ptr() as *const c_char) != 0 ||
+            libc::fchdir(cwd.as_raw_fd()) != 0
+        {
+            fail(Err::ChangeRoot, epipe);
+        }
+    }
+
+    // written by the parent after `finalize_with` callback
+    let (args, environ, pid_base) = match child.final_exec {
+        Some(exec) => {
+            let (args, environ, pid_base) = exec.arrays();
+            (args, environ, Some(pid_base))
+        }
+        None => (child.args, child.environ, None),
+    };
+
     if !child.pid_env_vars.is_empty() {
         let mut buf = [0u8; MAX_PID_LEN+1];
-        let data = format_pid_fixed(&mut buf, libc::getpid());
-        for &(index, offset) in child.pid_env_vars {
+        let data = format_pid(&mut buf, libc::getpid());
+        for (i, &(index, offset)) in child.pid_env_vars.iter().enumerate() {
+            let index = pid_base.map_or(index, |base| base + i);
             // we know that there are at least MAX_PID_LEN+1 bytes in buffer
-            child.environ[index].offset(offset as isize)
+            environ[index].offset(offset as isize)
                 .copy_from(data.as_ptr() as *const libc::c_char, data.len());
         }
     }
 
+    if child.make_private &&
+        libc::mount(ptr::null(), b"/\0".as_ptr() as *const c_char,
+                    ptr::null(), MS_REC|MS_PRIVATE, ptr::null()) != 0
+    {
+        fail(Err::MakePrivate, epipe);
+    }
+
+    for target in child.mount_targets {
+        let fd = match recv_fd(child.mount_socket) {
+            // already mounted by the provider
+            Ok(None) => continue,
+            Ok(Some(fd)) => fd,
+            Err(e) => fail_errno(Err::AttachMount, e, epipe),
+        };
+        if libc::syscall(libc::SYS_move_mount, fd, EMPTY.as_ptr(),
+                         libc::AT_FDCWD, target.as_ptr(),
+                         MOVE_MOUNT_F_EMPTY_PATH) != 0
+        {
+            fail(Err::AttachMount, epipe);
+        }
+        libc::close(fd);
+    }
+
     child.pivot.as_ref().map(|piv| {
+        if let Some(ref put_old) = piv.put_old_beneath {
+            match open_beneath(child, piv.new_root.as_ptr(), put_old) {
+                Ok(fd) => { libc::close(fd); }
+                Err(e) => fail_errno(Err::ChangeRoot, e, epipe),
+            }
+        }
         if ffi::pivot_root(piv.new_root.as_ptr(), piv.put_old.as_ptr()) != 0 {
             fail(Err::ChangeRoot, epipe);
         }
-        if libc::chdir(piv.workdir.as_ptr()) != 0 {
+        if let Some(ref workdir) = piv.workdir_beneath {
+            if let Err(e) = chdir_beneath(child, ROOT.as_ptr() as *const c_char,
+                                          workdir)
+            {
+                fail_errno(Err::ChangeRoot, e, epipe);
+            }
+        } else if libc::chdir(piv.workdir.as_ptr()) != 0 {
             fail(Err::ChangeRoot, epipe);
         }
         if piv.unmount_old_root {
-            if libc::umount2(piv.old_inside.as_ptr(), MNT_DETACH) != 0 {
+            if umount_raw(piv.old_inside.as_ptr(), MNT_DETACH) != 0 {
                 fail(Err::ChangeRoot, epipe);
             }
         }
     });
 
     child.chroot.as_ref().map(|chroot| {
-        if libc::chroot(chroot.root.as_ptr()) != 0 {
+        if let Some(ref root) = chroot.root_beneath {
+            if let Err(e) = chdir_beneath(child, ROOT.as_ptr() as *const c_char, root)
+            {
+                fail_errno(Err::ChangeRoot, e, epipe);
+            }
+            if libc::chroot(CURDIR.as_ptr() as *const c_char) != 0 {
+                fail(Err::ChangeRoot, epipe);
+            }
+        } else if libc::chroot(chroot.root.as_ptr()) != 0 {
             fail(Err::ChangeRoot, epipe);
         }
-        if libc::chdir(chroot.workdir.as_ptr()) != 0 {
+        if let Some(ref workdir) = chroot.workdir_beneath {
+            if let Err(e) = chdir_beneath(child, ROOT.as_ptr() as *const c_char,
+                                          workdir)
+            {
+                fail_errno(Err::ChangeRoot, e, epipe);
+            }
+        } else if libc::chdir(chroot.workdir.as_ptr()) != 0 {
             fail(Err::ChangeRoot, epipe);
         }
     });
 
-    child.keep_caps.as_ref().map(|_| {
-        // Don't use securebits because on older systems it doesn't work
-        if libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
-            fail(Err::CapSet, epipe);
+    if let Some(mode) = child.cfg.cgroupfs {
+        if let Err(e) = mount_cgroupfs(mode) {
+            fail_errno(Err::MountCgroup, e, epipe);
         }
-    });
+    }
 
-    child.cfg.gid.as_ref().map(|&gid| {
-        if libc::setgid(gid) != 0 {
-            fail(Err::SetUser, epipe);
+    for file in child.copy_files {
+        if let Err(e) = copy_file(child, file) {
+            fail_errno(Err::CopyFile, e, epipe);
         }
-    });
+    }
 
-    child.cfg.supplementary_gids.as_ref().map(|groups| {
-        if libc::setgroups(groups.len() as size_t, groups.as_ptr()) != 0 {
-            fail(Err::SetUser, epipe);
+    if let Some(limit) = child.cfg.memlock_limit {
+        let limit = libc::rlimit { rlim_cur: limit, rlim_max: limit };
+        if libc::setrlimit(libc::RLIMIT_MEMLOCK, &limit) != 0 {
+            fail(Err::SetLimits, epipe);
         }
-    });
-
-    child.cfg.uid.as_ref().map(|&uid| {
-        if libc::setuid(uid) != 0 {
-            fail(Err::SetUser, epipe);
+    }
+    if let Some(lock) = child.cfg.lock_memory {
+        if libc::mlockall(lock.flags()) != 0 {
+            fail(Err::LockMemory, epipe);
         }
-    });
+    }
+    match child.cfg.core_dumps {
+        Some(CorePolicy::Disabled) => {
+            let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 ||
+                libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) != 0
+            {
+                fail(Err::CoreDumps, epipe);
+            }
+        }
+        Some(CorePolicy::ToDirectory(_)) => {
+            let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) != 0 {
+                fail(Err::CoreDumps, epipe);
+            }
+            limit.rlim_cur = limit.rlim_max;
+            if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+                fail(Err::CoreDumps, epipe);
+            }
+        }
+        None => {}
+    }
 
-    child.keep_caps.as_ref().map(|caps| {
-        let header = ffi::CapsHeader {
-            version: ffi::CAPS_V3,
-            pid: 0,
-        };
-        let data = ffi::CapsData {
-            effective_s0: caps[0],
-            permitted_s0: caps[0],
-            inheritable_s0: caps[0],
-            effective_s1: caps[1],
-            permitted_s1: caps[1],
-            inheritable_s1: caps[1],
-        };
-        if libc::syscall(libc::SYS_capset, &header, &data) != 0 {
+    child.keep_caps.as_ref().map(|_| {
+        // Don't use securebits because on older systems it doesn't work
+        if libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
             fail(Err::CapSet, epipe);
         }
-        for idx in 0..caps.len()*32 {
-            if caps[(idx >> 5) as usize] & (1 << (idx & 31)) != 0 {
-                let rc = libc::prctl(
-                    libc::PR_CAP_AMBIENT,
-                    libc::PR_CAP_AMBIENT_RAISE,
-                    idx, 0, 0);
-                if rc != 0 && nix::errno::errno() == libc::ENOTSUP {
-                    // no need to iterate if ambient caps are notsupported
-                    break;
+    });
+
+    for &step in &child.cfg.credential_order {
+        match step {
+            CredentialStep::Limits => {
+                if let Some(limits) = child.limits {
+                    if let Err(e) = limits.apply(0) {
+                        fail_errno(Err::SetLimits,
+                                   e.raw_os_error().unwrap_or(0), epipe);
+                    }
+                }
+            }
+            CredentialStep::Gid => {
+                if let Some(gid) = child.cfg.gid {
+                    if libc::setgid(gid) != 0 {
+                        fail(Err::SetUser, epipe);
+                    }
+                }
+            }
+            CredentialStep::Groups => {
+                if let Some(ref groups) = child.cfg.supplementary_gids {
+                    if libc::setgroups(groups.len() as size_t,
+                                       groups.as_ptr()) != 0
+                    {
+                        fail(Err::SetUser, epipe);
+                    }
+                }
+            }
+            CredentialStep::Uid => {
+                if let Some(uid) = child.cfg.uid {
+                    if libc::setuid(uid) != 0 {
+                        fail(Err::SetUser, epipe);
+                    }
+                }
+            }
+            CredentialStep::Caps => {
+                if let Some(ref caps) = *child.keep_caps {
+                    set_caps(child, caps, epipe);
                 }
             }
         }
-    });
+    }
+
+    if let Some(mask) = child.cfg.umask {
+        libc::umask(mask);
+    }
+
+    if child.cfg.uid.is_some() || child.cfg.gid.is_some() ||
+        child.cfg.supplementary_gids.is_some() || child.keep_caps.is_some()
+    {
+        // the kernel clears the signal when credentials change
+        if let Some(sig) = child.cfg.death_sig {
+            if let Err(e) = reassert_parent_death_signal(sig, ppid) {
+                fail_errno(Err::ParentDeathSignal,
+                           e.raw_os_error().unwrap_or(0), epipe);
+            }
+        }
+    }
 
     child.cfg.work_dir.as_ref().map(|dir| {
-        if libc::chdir(dir.as_ptr()) != 0 {
+        if let Some(ref beneath) = *child.work_dir_beneath {
+            let absolute = *dir.as_ptr() == b'/' as c_char;
+            let base = if absolute { ROOT } else { CURDIR };
+            if let Err(e) = chdir_beneath(child, base.as_ptr() as *const c_char,
+                                          beneath)
+            {
+                fail_errno(Err::Chdir, e, epipe);
+            }
+        } else if libc::chdir(dir.as_ptr()) != 0 {
             fail(Err::Chdir, epipe);
         }
     });
@@ -195,14 +375,11 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         }
     }
 
+    // descriptors in use are cut out of the ranges by the parent
     for &(start, end) in child.close_fds {
-        if start < end {
-            for fd in start..end {
-                if child.fds.iter().find(|&&(cfd, _)| cfd == fd).is_none() {
-                    // Close may fail with ebadf, and it's okay
-                    libc::close(fd);
-                }
-            }
+        for fd in start..end {
+            // Close may fail with ebadf, and it's okay
+            libc::close(fd);
         }
     }
 
@@ -213,6 +390,8 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         for sig in 1..32 {
             signal(sig, SIG_DFL);
         }
+    } else if child.cfg.restore_sigpipe {
+        signal(libc::SIGPIPE, SIG_DFL);
     }
 
     if let Some(callback) = child.pre_exec {
@@ -223,50 +402,366 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         }
     }
 
-    libc::execve(child.filename,
-                 child.args.as_ptr(),
-                 // cancelling mutability, it should be fine
-                 child.environ.as_ptr() as *const *const libc::c_char);
-    fail(Err::Exec, epipe);
+    if let Some(filter) = child.seccomp_filter {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            fail(Err::SeccompNotify, epipe);
+        }
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+        };
+        let listener = libc::syscall(libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_NEW_LISTENER, &prog);
+        if listener < 0 {
+            fail(Err::SeccompNotify, epipe);
+        }
+        // listener is closed on exec
+        if let Err(e) = send_fd(child.seccomp_socket, Some(listener as c_int))
+        {
+            fail_errno(Err::SeccompNotify, e.raw_os_error().unwrap_or(0),
+                       epipe);
+        }
+    }
+
+    if child.cfg.daemonize {
+        daemonize(epipe);
+    }
+    // errors of probe and exec are sent after this frame
+    let frame = [EXEC_FRAME, 0, 0, 0, 0];
+    libc::write(epipe, frame.as_ptr() as *const c_void, frame.len());
+
+    if child.cfg.probe {
+        // everything is set up, check whether exec would find the program
+        let rc = match child.exec_fd {
+            Some(fd) => libc::syscall(libc::SYS_faccessat2, fd,
+                b"\0".as_ptr(), libc::X_OK,
+                libc::AT_EACCESS | libc::AT_EMPTY_PATH) as c_int,
+            None => libc::faccessat(libc::AT_FDCWD, child.filename,
+                                    libc::X_OK, libc::AT_EACCESS),
+        };
+        if rc != 0 {
+            fail(Err::Exec, epipe);
+        }
+        libc::_exit(0);
+    }
+
+    if !child.cfg.blocked_signals.is_empty() {
+        let mut sigmask: sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut sigmask);
+        for sig in &child.cfg.blocked_signals {
+            if libc::sigaddset(&mut sigmask, sig.as_raw()) != 0 {
+                fail(Err::BlockSignals, epipe);
+            }
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &sigmask, ptr::null_mut());
+    }
+
+    let (mut retries, delay) = child.cfg.retry_etxtbsy
+        .unwrap_or((0, Duration::from_secs(0)));
+    loop {
+        if let Some(fd) = child.exec_fd {
+            libc::syscall(libc::SYS_execveat, fd, b"\0".as_ptr(),
+                          args.as_ptr(), environ.as_ptr(),
+                          libc::AT_EMPTY_PATH);
+        } else {
+            libc::execve(child.filename,
+                         args.as_ptr(),
+                         // cancelling mutability, it should be fine
+                         environ.as_ptr() as *const *const libc::c_char);
+        }
+        if retries == 0 || errno() != libc::ETXTBSY {
+            fail(Err::Exec, epipe);
+        }
+        retries -= 1;
+        let ts = libc::timespec {
+            tv_sec: delay.as_secs() as libc::time_t,
+            tv_nsec: delay.subsec_nanos() as libc::c_long,
+        };
+        libc::nanosleep(&ts, ptr::null_mut());
+    }
+}
+
+/// Double-forks, reporting the pid of the grandchild through the error pipe
+///
+/// Returns in the grandchild, which continues to exec.
+unsafe fn daemonize(epipe: c_int) {
+    if libc::setsid() < 0 {
+        fail(Err::Daemonize, epipe);
+    }
+    // raw clone instead of `fork` which runs `pthread_atfork` handlers
+    let pid = libc::syscall(libc::SYS_clone, libc::SIGCHLD, 0, 0, 0, 0);
+    match pid {
+        -1 => fail(Err::Daemonize, epipe),
+        0 => {}
+        pid => {
+            let pid = pid as i32;
+            let frame = [DAEMON_PID_FRAME, (pid >> 24) as u8,
+                         (pid >> 16) as u8, (pid >> 8) as u8, pid as u8];
+            libc::write(epipe, frame.as_ptr() as *const c_void, frame.len());
+            libc::_exit(0);
+        }
+    }
+}
+
+/// Sets capabilities including the ambient set, for `CredentialStep::Caps`
+unsafe fn set_caps(child: &ChildInfo, caps: &[u32; 2], epipe: c_int) {
+    let header = ffi::CapsHeader {
+        version: ffi::CAPS_V3,
+        pid: 0,
+    };
+    let data = ffi::CapsData {
+        effective_s0: caps[0],
+        permitted_s0: caps[0],
+        inheritable_s0: caps[0],
+        effective_s1: caps[1],
+        permitted_s1: caps[1],
+        inheritable_s1: caps[1],
+    };
+    if libc::syscall(libc::SYS_capset, &header, &data) != 0 {
+        fail(Err::CapSet, epipe);
+    }
+    for idx in 0..caps.len()*32 {
+        if caps[idx >> 5] & (1 << (idx & 31)) != 0 {
+            let rc = libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE,
+                idx, 0, 0);
+            if rc != 0 {
+                let err = errno();
+                // EINVAL is for kernels before 4.3
+                let unsupported = err == libc::ENOTSUP ||
+                                  err == libc::EINVAL;
+                if !unsupported ||
+                    !skip(child, HardeningStep::AmbientCapabilities)
+                {
+                    fail_errno(Err::AmbientCaps, err, epipe);
+                }
+                // no need to iterate if ambient caps are notsupported
+                break;
+            }
+        }
+    }
+}
+
+/// Opens directory `path` relative to `root` without following symlinks
+///
+/// Returns `O_PATH` file descriptor or errno
+unsafe fn open_beneath(child: &ChildInfo, root: *const c_char,
+                       path: &Beneath)
+    -> Result<c_int, c_int>
+{
+    let flags = O_PATH | O_DIRECTORY | O_CLOEXEC;
+    let dirfd = libc::open(root, flags);
+    if dirfd < 0 {
+        return Err(errno());
+    }
+    let mut how: libc::open_how = mem::zeroed();
+    how.flags = flags as u64;
+    how.resolve = RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS;
+    let fd = libc::syscall(libc::SYS_openat2, dirfd, path.path.as_ptr(),
+        &how as *const libc::open_how, mem::size_of::<libc::open_how>());
+    let err = errno();
+    if fd >= 0 || err != libc::ENOSYS {
+        libc::close(dirfd);
+        return if fd >= 0 { Ok(fd as c_int) } else { Err(err) };
+    }
+    // No openat2 (linux < 5.6), so walk components one by one. Path is
+    // normalized already, so there are no `..` components
+    if !skip(child, HardeningStep::ResolveBeneath) {
+        libc::close(dirfd);
+        return Err(err);
+    }
+    let mut fd = dirfd;
+    for cmp in &path.components {
+        let mut stat: libc::stat = mem::zeroed();
+        if libc::fstatat(fd, cmp.as_ptr(), &mut stat, AT_SYMLINK_NOFOLLOW) != 0
+        {
+            let err = errno();
+            libc::close(fd);
+            return Err(err);
+        }
+        if stat.st_mode & S_IFMT == S_IFLNK {
+            libc::close(fd);
+            return Err(libc::ELOOP);
+        }
+        let next = libc::openat(fd, cmp.as_ptr(), flags | O_NOFOLLOW);
+        let err = errno();
+        libc::close(fd);
+        if next < 0 {
+            return Err(err);
+        }
+        fd = next;
+    }
+    Ok(fd)
+}
+
+unsafe fn chdir_beneath(child: &ChildInfo, root: *const c_char,
+                        path: &Beneath)
+    -> Result<(), c_int>
+{
+    let fd = open_beneath(child, root, path)?;
+    let rc = libc::fchdir(fd);
+    let err = errno();
+    libc::close(fd);
+    if rc != 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Writes `/proc/self/loginuid`, the kernel allows it only for itself
+unsafe fn set_loginuid(uid: libc::uid_t) -> Result<(), c_int> {
+    let mut buf = [0u8; 21];
+    let data = match format_u64(&mut buf, uid as u64) {
+        // without the nul byte
+        Some(data) => &data[..data.len()-1],
+        None => return Err(libc::EINVAL),
+    };
+    let fd = libc::open(b"/proc/self/loginuid\0".as_ptr() as *const c_char,
+                        O_WRONLY | O_CLOEXEC);
+    if fd < 0 {
+        return Err(errno());
+    }
+    let rc = libc::write(fd, data.as_ptr() as *const c_void, data.len());
+    let err = errno();
+    libc::close(fd);
+    if rc != data.len() as isize {
+        return Err(if rc < 0 { err } else { libc::EIO });
+    }
+    Ok(())
+}
+
+/// Mounts cgroup2 at `/sys/fs/cgroup` of the current root
+unsafe fn mount_cgroupfs(mode: CgroupMount) -> Result<(), c_int> {
+    // the namespace created on clone is rooted at the cgroup of the parent,
+    // and the child may have been moved since then
+    if libc::unshare(libc::CLONE_NEWCGROUP) != 0 {
+        return Err(errno());
+    }
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+    if mode == CgroupMount::ReadOnly {
+        flags |= libc::MS_RDONLY;
+    }
+    let fstype = b"cgroup2\0".as_ptr() as *const c_char;
+    if libc::mount(fstype, b"/sys/fs/cgroup\0".as_ptr() as *const c_char,
+                   fstype, flags, ptr::null()) != 0
+    {
+        return Err(errno());
+    }
+    Ok(())
+}
+
+/// Creates the time namespace with the offsets and enters it
+unsafe fn enter_time_namespace(offsets: &CStr) -> Result<(), c_int> {
+    if libc::unshare(libc::CLONE_NEWTIME) != 0 {
+        return Err(errno());
+    }
+    // offsets can only be set before any process enters the namespace
+    let fd = libc::open(b"/proc/self/timens_offsets\0".as_ptr()
+                        as *const c_char, O_WRONLY | O_CLOEXEC);
+    if fd < 0 {
+        return Err(errno());
+    }
+    let data = offsets.to_bytes();
+    let rc = libc::write(fd, data.as_ptr() as *const c_void, data.len());
+    let err = errno();
+    libc::close(fd);
+    if rc != data.len() as isize {
+        return Err(if rc < 0 { err } else { libc::EIO });
+    }
+    let fd = libc::open(b"/proc/self/ns/time_for_children\0".as_ptr()
+                        as *const c_char, O_RDONLY | O_CLOEXEC);
+    if fd < 0 {
+        return Err(errno());
+    }
+    let rc = libc::setns(fd, libc::CLONE_NEWTIME);
+    let err = errno();
+    libc::close(fd);
+    if rc != 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Writes a file set by `copy_into_root`, returns errno on error
+unsafe fn copy_file(child: &ChildInfo, file: &CopyFile) -> Result<(), c_int> {
+    let dirfd = match file.dir_beneath {
+        Some(ref dir) => {
+            open_beneath(child, ROOT.as_ptr() as *const c_char, dir)?
+        }
+        None => {
+            let fd = libc::open(file.dir.as_ptr(),
+                                O_PATH | O_DIRECTORY | O_CLOEXEC);
+            if fd < 0 {
+                return Err(errno());
+            }
+            fd
+        }
+    };
+    let mut flags = O_WRONLY | O_CREAT | O_TRUNC | O_CLOEXEC;
+    if file.dir_beneath.is_some() {
+        flags |= O_NOFOLLOW;
+    }
+    let fd = libc::openat(dirfd, file.name.as_ptr(), flags, file.mode);
+    let err = errno();
+    libc::close(dirfd);
+    if fd < 0 {
+        return Err(err);
+    }
+    let mut data = &file.data[..];
+    let mut err = 0;
+    while !data.is_empty() {
+        let rc = libc::write(fd, data.as_ptr() as *const c_void, data.len());
+        if rc < 0 {
+            if errno() == libc::EINTR {
+                continue;
+            }
+            err = errno();
+            break;
+        }
+        data = &data[rc as usize..];
+    }
+    if err == 0 && libc::fchmod(fd, file.mode) != 0 {
+        err = errno();
+    }
+    for (name, value) in &file.xattrs {
+        if err != 0 {
+            break;
+        }
+        if libc::fsetxattr(fd, name.as_ptr(),
+            value.as_ptr() as *const c_void, value.len(), 0) != 0
+        {
+            err = errno();
+        }
+    }
+    libc::close(fd);
+    if err != 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Marks the best effort step as skipped, returns false in strict mode
+fn skip(child: &ChildInfo, step: HardeningStep) -> bool {
+    if child.cfg.strict {
+        return false;
+    }
+    child.skipped.mark(step);
+    true
 }
 
 unsafe fn fail(code: Err, output: RawFd) -> ! {
-    fail_errno(code, nix::errno::errno(), output)
+    fail_errno(code, errno(), output)
 }
 unsafe fn fail_errno(code: Err, errno: i32, output: RawFd) -> ! {
-    let bytes = [
-        code as u8,
-        (errno >> 24) as u8,
-        (errno >> 16) as u8,
-        (errno >>  8) as u8,
-        (errno >>  0)  as u8,
-        // TODO(tailhook) rustc adds a special sentinel at the end of error
-        // code. Do we really need it? Assuming our pipes are always cloexec'd.
-        ];
+    let bytes = encode_error(code, errno);
     // Writes less than PIPE_BUF should be atomic. It's also unclear what
     // to do if error happened anyway
-    libc::write(output, bytes.as_ptr() as *const c_void, 5);
+    libc::write(output, bytes.as_ptr() as *const c_void, bytes.len());
     libc::_exit(127);
 }
 
-fn format_pid_fixed<'a>(buf: &'a mut [u8], pid: libc::pid_t) -> &'a [u8] {
-    buf[buf.len()-1] = 0;
-    if pid == 0 {
-        buf[buf.len()-2] = b'0';
-        return &buf[buf.len()-2..]
-    } else {
-        let mut tmp = pid;
-        // can't use stdlib function because that can allocate
-        for n in (0..buf.len()-1).rev() {
-            buf[n] = (tmp % 10) as u8 + b'0';
-            tmp /= 10;
-            if tmp == 0 {
-                return &buf[n..];
-            }
-        }
-        unreachable!("can't format pid");
-    };
-}
 /// We don't use functions from nix here because they may allocate memory
 /// which we can't to this this module.
 mod ffi {
@@ -296,36 +791,3 @@ mod ffi {
             -> c_int;
     }
 }
-
-#[cfg(test)]
-mod test {
-    use rand::{thread_rng, Rng};
-    use crate::run::MAX_PID_LEN;
-    use std::ffi::CStr;
-    use super::format_pid_fixed;
-
-    fn fmt_normal(val: i32) -> String {
-        let mut buf = [0u8; MAX_PID_LEN+1];
-        let slice = format_pid_fixed(&mut buf, val);
-        return CStr::from_bytes_with_nul(slice).unwrap()
-            .to_string_lossy().to_string();
-    }
-    #[test]
-    fn test_format() {
-        assert_eq!(fmt_normal(0), "0");
-        assert_eq!(fmt_normal(1), "1");
-        assert_eq!(fmt_normal(7), "7");
-        assert_eq!(fmt_normal(79), "79");
-        assert_eq!(fmt_normal(254), "254");
-        assert_eq!(fmt_normal(1158), "1158");
-        assert_eq!(fmt_normal(77839), "77839");
-    }
-    #[test]
-    fn test_random() {
-        for _ in 0..100000 {
-            let x = thread_rng().gen();
-            if x < 0 { continue; }
-            assert_eq!(fmt_normal(x), format!("{}", x));
-        }
-    }
-}
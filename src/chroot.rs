@@ -1,4 +1,9 @@
 use std::ffi::CString;
+use std::path::{Component, Path, PathBuf};
+
+use crate::Command;
+use crate::error::Error;
+use crate::ffi_util::ToCString;
 
 
 pub struct Pivot {
@@ -7,9 +12,96 @@ pub struct Pivot {
     pub old_inside: CString,
     pub workdir: CString,
     pub unmount_old_root: bool,
+    /// Set for `ResolvePaths::NoFollow`, relative to `new_root`
+    pub put_old_beneath: Option<Beneath>,
+    /// Set for `ResolvePaths::NoFollow`, relative to the new root
+    pub workdir_beneath: Option<Beneath>,
 }
 
 pub struct Chroot {
     pub root: CString,
     pub workdir: CString,
+    /// Set for `ResolvePaths::NoFollow`, relative to the current root
+    pub root_beneath: Option<Beneath>,
+    /// Set for `ResolvePaths::NoFollow`, relative to the new root
+    pub workdir_beneath: Option<Beneath>,
+}
+
+/// Path prepared for resolving in the child without following symlinks
+///
+/// The path is relative to some directory (root), it's normalized
+/// lexically which is correct as long as there are no symlinks. Components
+/// are used when `openat2` is not supported by the kernel.
+pub struct Beneath {
+    pub path: CString,
+    pub components: Vec<CString>,
+}
+
+impl Beneath {
+    /// Prepares path, leading slash is ignored (i.e. absolute path is
+    /// treated as relative to the root)
+    ///
+    /// Returns `ChangeRoot(EXDEV)` if `..` is pointing outside of the root.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Beneath, Error> {
+        let mut components = Vec::new();
+        for cmp in path.as_ref().components() {
+            match cmp {
+                Component::Normal(x) => components.push(x),
+                Component::ParentDir => {
+                    if components.pop().is_none() {
+                        return Err(Error::ChangeRoot(libc::EXDEV));
+                    }
+                }
+                Component::RootDir | Component::CurDir => {}
+                Component::Prefix(..) => unreachable!(),
+            }
+        }
+        let path = if components.is_empty() {
+            PathBuf::from(".")
+        } else {
+            components.iter().collect()
+        };
+        Ok(Beneath {
+            path: path.to_cstring(),
+            components: components.iter().map(|x| x.to_cstring()).collect(),
+        })
+    }
+}
+
+/// Returns path of the `path` inside the `root`, even if it's absolute
+pub fn in_root(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+impl Command {
+    /// Path to the new root of the child in the current mount namespace
+    pub(crate) fn host_root(&self) -> PathBuf {
+        let pivot = self.pivot_root.as_ref().map(|(new, _, _)| new.as_path());
+        match (pivot, self.chroot_dir.as_ref()) {
+            (Some(pivot), Some(chroot)) => in_root(pivot, chroot),
+            (Some(pivot), None) => pivot.to_path_buf(),
+            (None, Some(chroot)) => chroot.clone(),
+            (None, None) => PathBuf::from("/"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Beneath;
+
+    fn parts(path: &str) -> (String, Vec<String>) {
+        let b = Beneath::new(path).unwrap();
+        (b.path.into_string().unwrap(),
+         b.components.into_iter().map(|x| x.into_string().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(parts("/"), (".".into(), vec![]));
+        assert_eq!(parts("/a/./b/"),
+                   ("a/b".into(), vec!["a".into(), "b".into()]));
+        assert_eq!(parts("a/../b"), ("b".into(), vec!["b".into()]));
+        assert!(Beneath::new("/a/../..").is_err());
+    }
 }
@@ -0,0 +1,214 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use libc::pid_t;
+
+use crate::sys;
+
+
+/// Undo actions of the parent-side setup of a single spawn
+///
+/// Steps of `spawn()` which change something outside of the child (e.g.
+/// create mount points in the new root) register an action here. The
+/// actions are run in reverse order when it's dropped, so every early
+/// return on error (or a panic in a callback) rolls the changes back,
+/// unless the spawn succeeded and `commit` was called.
+///
+/// Other steps don't need actions, as they are undone by killing the
+/// child (done before the actions are run) or by dropping what they
+/// return: the cgroup and the systemd scope only hold the child (the
+/// scope is stopped and collected by systemd once it's empty), idmapped
+/// and provided mounts are detached mounts closed on drop, `Teardown`
+/// runs the callbacks of mount providers, and the network helper and the
+/// data of `finalize_with` are freed on drop.
+pub(crate) struct SpawnCleanup {
+    actions: Vec<Box<dyn FnOnce()>>,
+}
+
+impl SpawnCleanup {
+    pub fn new() -> SpawnCleanup {
+        SpawnCleanup { actions: Vec::new() }
+    }
+    /// Register the action undoing the last step
+    pub fn push<F: FnOnce() + 'static>(&mut self, action: F) {
+        self.actions.push(Box::new(action));
+    }
+    /// Keep the changes, the spawn has succeeded
+    pub fn commit(mut self) {
+        self.actions.clear();
+    }
+    /// Creates the directory with missing parents, registering the removal
+    /// of each directory created
+    pub fn create_dir_all(&mut self, dir: &Path) -> io::Result<()> {
+        let mut missing = Vec::new();
+        let mut cur = Some(dir);
+        while let Some(path) = cur {
+            if fs::symlink_metadata(path).is_ok() {
+                break;
+            }
+            missing.push(path.to_path_buf());
+            cur = path.parent();
+        }
+        for path in missing.into_iter().rev() {
+            match fs::create_dir(&path) {
+                Ok(()) => self.remove_dir_on_failure(path),
+                // created concurrently
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    /// Registers the removal of the (empty) directory
+    pub fn remove_dir_on_failure(&mut self, dir: PathBuf) {
+        self.push(move || { fs::remove_dir(dir).ok(); });
+    }
+    /// Registers the removal of the file
+    pub fn remove_file_on_failure(&mut self, file: PathBuf) {
+        self.push(move || { fs::remove_file(file).ok(); });
+    }
+    /// Registers killing the process which is not our child (the daemon)
+    ///
+    /// It's killed through a pidfd, as it may be reaped by someone else
+    /// and the pid reused.
+    pub fn kill_on_failure(&mut self, pid: pid_t) {
+        let pidfd = match sys::pidfd_open(pid) {
+            Ok(fd) => unsafe { File::from_raw_fd(fd) },
+            // exited already
+            Err(_) => return,
+        };
+        self.push(move || unsafe {
+            libc::syscall(libc::SYS_pidfd_send_signal, pidfd.as_raw_fd(),
+                          libc::SIGKILL, 0, 0);
+        });
+    }
+}
+
+impl Drop for SpawnCleanup {
+    fn drop(&mut self) {
+        while let Some(action) = self.actions.pop() {
+            action();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::fs;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::{Command, Namespace, Error, BoxError, UidMap, GidMap};
+    use crate::{MountProvider, ProvidedMount};
+    use super::SpawnCleanup;
+
+    /// Command creating a scratch mount point, returns the directory which
+    /// must be removed if the spawn fails
+    fn scratch(name: &str, program: &str) -> (Command, PathBuf) {
+        let base = std::env::temp_dir()
+            .join(format!("unshare-test-cleanup-{}", name));
+        fs::remove_dir_all(&base).ok();
+        fs::create_dir_all(base.join("host")).unwrap();
+        let mut cmd = Command::new(program);
+        cmd.unshare(&[Namespace::Mount])
+            .scratch_dir(base.join("host"), base.join("created/work"));
+        (cmd, base)
+    }
+
+    fn check_removed(base: PathBuf) {
+        assert!(!base.join("created").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    struct Provider(Option<Arc<AtomicBool>>);
+
+    impl MountProvider for Provider {
+        fn provide(&mut self, _pid: u32) -> Result<ProvidedMount, BoxError> {
+            let torn_down = self.0.clone().ok_or("injected")?;
+            Ok(ProvidedMount {
+                mount: None,
+                teardown: Some(Box::new(move || {
+                    torn_down.store(true, Ordering::SeqCst);
+                })),
+            })
+        }
+    }
+
+    #[test]
+    fn test_mount_provider_fails() {
+        let (mut cmd, base) = scratch("provider", "/bin/true");
+        let torn_down = Arc::new(AtomicBool::new(false));
+        cmd.mount_provider("/mnt", Provider(Some(torn_down.clone())));
+        cmd.mount_provider("/opt", Provider(None));
+        assert!(matches!(cmd.spawn(), Err(Error::MountProvider(_))));
+        assert!(torn_down.load(Ordering::SeqCst));
+        check_removed(base);
+    }
+
+    #[test]
+    fn test_idmapped_mount_fails() {
+        let (mut cmd, base) = scratch("idmapped", "/bin/true");
+        cmd.unshare(&[Namespace::User])
+            .set_id_maps(
+                vec![UidMap { inside_uid: 0, outside_uid: 0, count: 1 }],
+                vec![GidMap { inside_gid: 0, outside_gid: 0, count: 1 }])
+            .idmapped_mount(base.join("nonexistent"), "/data");
+        assert!(matches!(cmd.spawn(),
+                         Err(Error::IdmappedMount(libc::ENOENT))));
+        check_removed(base);
+    }
+
+    #[test]
+    fn test_finalize_panics() {
+        let (mut cmd, base) = scratch("finalize", "/bin/true");
+        cmd.finalize_with(|_| panic!("injected"));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cmd.spawn()));
+        assert!(result.is_err());
+        check_removed(base);
+    }
+
+    #[test]
+    fn test_daemon_fails() {
+        let (mut cmd, base) = scratch("daemon", "/nonexistent");
+        cmd.daemonize();
+        assert!(matches!(cmd.spawn(), Err(Error::Exec(libc::ENOENT))));
+        check_removed(base);
+    }
+
+    #[test]
+    fn test_order_and_commit() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut cleanup = SpawnCleanup::new();
+        for step in 0..3 {
+            let log = log.clone();
+            cleanup.push(move || log.borrow_mut().push(step));
+        }
+        drop(cleanup);
+        assert_eq!(*log.borrow(), [2, 1, 0]);
+        let mut cleanup = SpawnCleanup::new();
+        let copy = log.clone();
+        cleanup.push(move || copy.borrow_mut().push(10));
+        cleanup.commit();
+        assert_eq!(*log.borrow(), [2, 1, 0]);
+    }
+
+    #[test]
+    fn test_create_dir_all() {
+        let base = std::env::temp_dir().join("unshare-test-cleanup");
+        fs::remove_dir_all(&base).ok();
+        fs::create_dir(&base).unwrap();
+        let mut cleanup = SpawnCleanup::new();
+        cleanup.create_dir_all(&base.join("a/b/c")).unwrap();
+        assert!(base.join("a/b/c").is_dir());
+        drop(cleanup);
+        assert!(!base.join("a").exists());
+        assert!(base.exists());
+        fs::remove_dir(&base).unwrap();
+    }
+}
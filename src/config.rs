@@ -1,43 +1,195 @@
 use std::default::Default;
 use std::ffi::CString;
 use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
-use nix::sys::signal::{Signal, SIGKILL};
-use nix::sched::CloneFlags;
-use libc::{uid_t, gid_t};
+use libc::{c_int, uid_t, gid_t, mode_t};
 
 use crate::idmap::{UidMap, GidMap};
+use crate::signal::Signal;
 use crate::namespace::Namespace;
 use crate::stdio::Closing;
+use crate::mlock::MemoryLock;
+use crate::core_dump::CorePolicy;
+use crate::cgroup::CgroupMount;
 
 
+/// Defines which exit triggers the parent death signal
+///
+/// See `Command::pdeathsig_scope` for more info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeathSigScope {
+    /// Signal is sent when the thread that spawned the child exits
+    ///
+    /// This is how `PR_SET_PDEATHSIG` works in the kernel
+    Thread,
+    /// Signal is sent only when the whole parent process exits
+    Process,
+}
+
+/// What child does if parent dies before unfreezing it
+///
+/// See `Command::on_orphaned_setup` for more info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanedSetup {
+    /// Child exits with code 127 without executing the program
+    Abort,
+    /// Child continues setup and executes the program
+    Continue,
+}
+
+/// A step changing credentials of the child
+///
+/// See `Command::credential_order` for more info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CredentialStep {
+    /// Applying limits of `inherit_limits_from`
+    Limits,
+    /// Setting primary group (`setgid`)
+    Gid,
+    /// Setting supplementary groups (`setgroups`)
+    Groups,
+    /// Setting user (`setuid`)
+    Uid,
+    /// Setting capabilities of `keep_caps` (`capset` and ambient set)
+    Caps,
+}
+
+pub const DEFAULT_CREDENTIAL_ORDER: [CredentialStep; 5] = [
+    CredentialStep::Limits,
+    CredentialStep::Gid,
+    CredentialStep::Groups,
+    CredentialStep::Uid,
+    CredentialStep::Caps,
+];
+
+/// How the child is waited for
+///
+/// See `Command::wait_backend` for more info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitBackend {
+    /// By pid, the way `SIGCHLD` handlers and `reap_zombies` do
+    Sigchld,
+    /// Only by pidfd, independently of `SIGCHLD` handling
+    Pidfd,
+}
+
+/// How symlinks are handled when resolving paths inside the new root
+///
+/// See `Command::resolve_paths` for more info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolvePaths {
+    /// Symlinks are followed as usual
+    Follow,
+    /// Paths must not contain symlinks and must not escape the root
+    NoFollow,
+}
+
 pub struct Config {
     pub death_sig: Option<Signal>,
+    pub death_sig_scope: DeathSigScope,
+    pub orphaned_setup: Option<OrphanedSetup>,
     pub work_dir: Option<CString>,
     pub uid: Option<uid_t>,
     pub gid: Option<gid_t>,
     pub supplementary_gids: Option<Vec<gid_t>>,
     pub id_maps: Option<(Vec<UidMap>, Vec<GidMap>)>,
-    pub namespaces: CloneFlags,
+    pub namespaces: c_int,
     pub setns_namespaces: HashMap<Namespace, Closing>,
+    /// Pid namespace joined by the thread cloning the child
+    pub clone_pid_ns: Option<Closing>,
+    /// Root and working directory entered after joining namespaces
+    pub enter_root: Option<(Closing, Closing)>,
     pub restore_sigmask: bool,
+    pub restore_sigpipe: bool,
+    pub blocked_signals: Vec<Signal>,
     pub make_group_leader: bool,
+    pub packet_pipes: bool,
+    pub init_mode: bool,
+    pub make_private_rec: Option<bool>,
+    pub resolve_paths: ResolvePaths,
+    pub strict: bool,
+    pub umask: Option<mode_t>,
+    pub internal_fd_floor: RawFd,
+    pub probe: bool,
+    pub credential_order: [CredentialStep; 5],
+    pub privileged_ports: bool,
+    pub shell: bool,
+    pub pidfd: bool,
+    pub wait_backend: WaitBackend,
+    pub loginuid: Option<uid_t>,
+    pub cgroupfs: Option<CgroupMount>,
+    pub userns_first: bool,
+    pub strict_close_fds: bool,
+    pub proc_path: Option<PathBuf>,
+    pub retry_etxtbsy: Option<(u32, Duration)>,
+    pub virtual_clock: Option<SystemTime>,
+    pub daemonize: bool,
+    pub lock_memory: Option<MemoryLock>,
+    pub memlock_limit: Option<u64>,
+    pub core_dumps: Option<CorePolicy>,
+    pub null_inherited_pipes: bool,
+    /// Cgroup the child is moved to, see `Command::cgroup`
+    pub cgroup: Option<PathBuf>,
     // TODO(tailhook) session leader
 }
 
+impl Config {
+    /// Namespaces joined by the child, including the pid namespace joined
+    /// by the cloning thread
+    pub fn joined_namespaces(&self) -> impl Iterator<Item=Namespace> + '_ {
+        self.setns_namespaces.keys().cloned()
+            .chain(self.clone_pid_ns.as_ref().map(|_| Namespace::Pid))
+    }
+}
+
 impl Default for Config {
     fn default() -> Config {
         Config {
-            death_sig: Some(SIGKILL),
+            death_sig: Some(Signal::SIGKILL),
+            death_sig_scope: DeathSigScope::Thread,
+            orphaned_setup: None,
             work_dir: None,
             uid: None,
             gid: None,
             supplementary_gids: None,
             id_maps: None,
-            namespaces: CloneFlags::empty(),
+            namespaces: 0,
             setns_namespaces: HashMap::new(),
+            clone_pid_ns: None,
+            enter_root: None,
             restore_sigmask: true,
+            restore_sigpipe: true,
+            blocked_signals: Vec::new(),
             make_group_leader: false,
+            packet_pipes: true,
+            init_mode: false,
+            make_private_rec: None,
+            resolve_paths: ResolvePaths::Follow,
+            strict: false,
+            umask: None,
+            internal_fd_floor: 100,
+            probe: false,
+            credential_order: DEFAULT_CREDENTIAL_ORDER,
+            privileged_ports: false,
+            shell: false,
+            pidfd: false,
+            wait_backend: WaitBackend::Sigchld,
+            loginuid: None,
+            cgroupfs: None,
+            userns_first: false,
+            strict_close_fds: false,
+            proc_path: None,
+            retry_etxtbsy: None,
+            virtual_clock: None,
+            daemonize: false,
+            lock_memory: None,
+            memlock_limit: None,
+            core_dumps: None,
+            null_inherited_pipes: false,
+            cgroup: None,
         }
     }
 }
@@ -0,0 +1,387 @@
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::{Command, Child, Namespace, ExitStatus, CgroupPolicy};
+use crate::error::Error;
+use crate::freeze::cgroup2_mount;
+use crate::namespace::check_namespace_fd;
+use crate::chroot::in_root;
+
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Configuration of a `Container`, see `Container::builder`
+#[derive(Debug, Clone)]
+pub struct ContainerBuilder {
+    image_root: Option<PathBuf>,
+    netns: Option<PathBuf>,
+    memory_limit: Option<u64>,
+    pids_limit: Option<u64>,
+    cgroup_parent: PathBuf,
+}
+
+/// A cgroup created for the container, removed on drop
+#[derive(Debug)]
+struct CgroupDir {
+    /// Path in the cgroup v2 hierarchy, as for `CgroupPolicy::MoveTo`
+    name: PathBuf,
+    /// Path in the file system
+    path: PathBuf,
+}
+
+/// Prepared isolation for running a program, see `Container::builder`
+///
+/// This is a shortcut for the common case: the program gets its own
+/// mount, pid, UTS, IPC and network namespaces (or joins the network
+/// namespace given), its own root, and a cgroup of its own with the limits
+/// configured. The cgroup is created by `ContainerBuilder::build` and
+/// removed when the container is dropped, so it can be started once. Use
+/// `Command` directly for anything else.
+#[derive(Debug)]
+pub struct Container {
+    image_root: Option<PathBuf>,
+    netns: Option<File>,
+    cgroup: CgroupDir,
+}
+
+/// Running `Container`, returned by `Container::start`
+///
+/// The container is killed, reaped and its cgroup is removed when this is
+/// dropped, use `teardown` to get the exit status or the error.
+#[derive(Debug)]
+pub struct RunningContainer {
+    child: Child,
+    cgroup: CgroupDir,
+}
+
+/// Resource usage of a `RunningContainer`, see `RunningContainer::metrics`
+///
+/// Values are `None` if the controller isn't enabled for the cgroup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContainerMetrics {
+    /// Memory used by the processes (`memory.current`), in bytes
+    pub memory: Option<u64>,
+    /// Number of the processes (`pids.current`)
+    pub pids: Option<u64>,
+    /// CPU time used by the processes (`usage_usec` of `cpu.stat`)
+    pub cpu_time: Option<Duration>,
+}
+
+impl ContainerBuilder {
+    /// Use the directory as the root of the container (`chroot_dir`)
+    ///
+    /// By default the root is shared with the current process (although
+    /// mounts made in the container aren't visible outside). Note that
+    /// `/proc` isn't mounted in the new root.
+    pub fn image_root<P: AsRef<Path>>(&mut self, dir: P)
+        -> &mut ContainerBuilder
+    {
+        self.image_root = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Join the network namespace at the path, e.g. `/run/netns/<name>`
+    ///
+    /// By default the container gets a new network namespace with only
+    /// the loopback interface (which is down).
+    pub fn netns<P: AsRef<Path>>(&mut self, path: P) -> &mut ContainerBuilder
+    {
+        self.netns = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Limit memory of the container (`memory.max` of its cgroup)
+    ///
+    /// The memory controller is enabled in the parent cgroup if it's not.
+    pub fn memory_limit(&mut self, bytes: u64) -> &mut ContainerBuilder {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Limit the number of processes of the container (`pids.max`)
+    ///
+    /// The pids controller is enabled in the parent cgroup if it's not.
+    pub fn pids_limit(&mut self, max: u64) -> &mut ContainerBuilder {
+        self.pids_limit = Some(max);
+        self
+    }
+
+    /// The cgroup to create the cgroup of the container in
+    ///
+    /// The path is in the cgroup v2 hierarchy, as in `/proc/self/cgroup`.
+    /// The default is the root cgroup, `/`.
+    pub fn cgroup_parent<P: AsRef<Path>>(&mut self, path: P)
+        -> &mut ContainerBuilder
+    {
+        self.cgroup_parent = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Check the configuration and create the cgroup of the container
+    ///
+    /// Fails if the root isn't an absolute path to a directory, the
+    /// network namespace can't be opened (or it's not a network
+    /// namespace), or the cgroup can't be created or configured (usually
+    /// requires root). The cgroup is named `unshare-container-<pid>-<n>`,
+    /// where pid is of the current process.
+    pub fn build(&self) -> io::Result<Container> {
+        if let Some(ref root) = self.image_root {
+            if !root.is_absolute() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("image root {:?} must be absolute", root)));
+            }
+            if !fs::metadata(root)?.is_dir() {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+            }
+        }
+        let netns = match self.netns {
+            Some(ref path) => {
+                let file = File::open(path)?;
+                check_namespace_fd(file.as_raw_fd(), Namespace::Net)?;
+                Some(file)
+            }
+            None => None,
+        };
+        let mount = cgroup2_mount()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let parent = in_root(&mount, &self.cgroup_parent);
+        for (controller, limit) in [("memory", self.memory_limit),
+                                    ("pids", self.pids_limit)]
+        {
+            if limit.is_some() {
+                enable_controller(&parent, controller)?;
+            }
+        }
+        let name = self.cgroup_parent.join(format!("unshare-container-{}-{}",
+            std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let path = in_root(&mount, &name);
+        fs::create_dir(&path)?;
+        let cgroup = CgroupDir { name, path };
+        if let Some(bytes) = self.memory_limit {
+            fs::write(cgroup.path.join("memory.max"), bytes.to_string())?;
+        }
+        if let Some(max) = self.pids_limit {
+            fs::write(cgroup.path.join("pids.max"), max.to_string())?;
+        }
+        Ok(Container {
+            image_root: self.image_root.clone(),
+            netns,
+            cgroup,
+        })
+    }
+}
+
+fn enable_controller(cgroup: &Path, name: &str) -> io::Result<()> {
+    let enabled = fs::read_to_string(cgroup.join("cgroup.subtree_control"))?;
+    if enabled.split_whitespace().any(|x| x == name) {
+        return Ok(());
+    }
+    fs::write(cgroup.join("cgroup.subtree_control"), format!("+{}", name))
+}
+
+fn read_stat(path: &Path, key: Option<&str>) -> io::Result<Option<u64>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let value = match key {
+        Some(key) => data.lines()
+            .filter_map(|line| line.strip_prefix(key))
+            .find_map(|x| x.strip_prefix(' ')),
+        None => Some(data.trim()),
+    };
+    Ok(value.and_then(|x| x.parse().ok()))
+}
+
+fn program_command<I, S>(args: I) -> Command
+    where I: IntoIterator<Item=S>, S: AsRef<OsStr>
+{
+    let mut args = args.into_iter();
+    let mut cmd = Command::new(args.next().expect("no program to run"));
+    for arg in args {
+        cmd.arg(arg);
+    }
+    cmd
+}
+
+impl Drop for CgroupDir {
+    fn drop(&mut self) {
+        fs::remove_dir(&self.path).ok();
+    }
+}
+
+impl Container {
+    /// Returns the builder of a container with the default configuration
+    pub fn builder() -> ContainerBuilder {
+        ContainerBuilder {
+            image_root: None,
+            netns: None,
+            memory_limit: None,
+            pids_limit: None,
+            cgroup_parent: PathBuf::from("/"),
+        }
+    }
+
+    /// Returns the path of the cgroup in the cgroup v2 hierarchy
+    pub fn cgroup(&self) -> &Path {
+        &self.cgroup.name
+    }
+
+    /// Returns the command which runs `args` in the container
+    ///
+    /// The command may be configured further (environment, stdio, user)
+    /// before spawning it, but then the container must outlive the child.
+    /// Panics if `args` is empty.
+    pub fn command<I, S>(&self, args: I) -> io::Result<Command>
+        where I: IntoIterator<Item=S>, S: AsRef<OsStr>
+    {
+        let mut cmd = program_command(args);
+        match self.netns {
+            Some(ref file) => {
+                cmd.unshare(&[Namespace::Mount, Namespace::Pid,
+                              Namespace::Uts, Namespace::Ipc]);
+                cmd.set_namespace(file, Namespace::Net)?;
+            }
+            None => {
+                cmd.unshare(&[Namespace::Mount, Namespace::Pid,
+                              Namespace::Uts, Namespace::Ipc,
+                              Namespace::Net]);
+            }
+        }
+        if let Some(ref root) = self.image_root {
+            cmd.chroot_dir(root);
+            cmd.current_dir("/");
+        }
+        cmd.cgroup(CgroupPolicy::MoveTo(self.cgroup.name.clone()));
+        Ok(cmd)
+    }
+
+    /// Run the program with arguments in the container
+    ///
+    /// The program is the pid 1 of the container, so when it exits the
+    /// rest of the processes are killed. Panics if `args` is empty.
+    pub fn start<I, S>(self, args: I) -> Result<RunningContainer, Error>
+        where I: IntoIterator<Item=S>, S: AsRef<OsStr>
+    {
+        // the namespace is checked by `build`, so only `dup` may fail here
+        let mut cmd = self.command(args).map_err(|e| {
+            Error::SetNs(e.raw_os_error().unwrap_or(libc::EINVAL))
+        })?;
+        let child = cmd.spawn()?;
+        Ok(RunningContainer { child, cgroup: self.cgroup })
+    }
+}
+
+impl RunningContainer {
+    /// The process started by `Container::start` (pid 1 of the container)
+    pub fn child(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// Returns the path of the cgroup in the cgroup v2 hierarchy
+    pub fn cgroup(&self) -> &Path {
+        &self.cgroup.name
+    }
+
+    /// Run another program in the container (see `Child::exec_in`)
+    ///
+    /// The process is put into the cgroup of the container too. Panics if
+    /// `args` is empty.
+    pub fn exec<I, S>(&self, args: I) -> Result<Child, Error>
+        where I: IntoIterator<Item=S>, S: AsRef<OsStr>
+    {
+        let mut cmd = program_command(args);
+        cmd.cgroup(CgroupPolicy::MoveTo(self.cgroup.name.clone()));
+        self.child.exec_in(cmd)
+    }
+
+    /// Freeze all the processes of the container (see `Child::pause`)
+    pub fn pause(&self) -> io::Result<()> {
+        self.child.pause()
+    }
+
+    /// Resume the container paused with `pause()`
+    pub fn resume(&self) -> io::Result<()> {
+        self.child.resume()
+    }
+
+    /// Returns the current resource usage of the container
+    pub fn metrics(&self) -> io::Result<ContainerMetrics> {
+        let dir = &self.cgroup.path;
+        Ok(ContainerMetrics {
+            memory: read_stat(&dir.join("memory.current"), None)?,
+            pids: read_stat(&dir.join("pids.current"), None)?,
+            cpu_time: read_stat(&dir.join("cpu.stat"), Some("usage_usec"))?
+                .map(Duration::from_micros),
+        })
+    }
+
+    /// Wait for the container to exit
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.child.wait()
+    }
+
+    /// Kill the container, wait for it and remove its cgroup
+    ///
+    /// All the processes of the cgroup are killed (by `cgroup.kill` which
+    /// requires linux 5.14, otherwise only pid 1 is killed, which is
+    /// enough for the container but not for processes of `exec`).
+    pub fn teardown(mut self) -> io::Result<ExitStatus> {
+        let status = self.kill_and_wait()?;
+        fs::remove_dir(&self.cgroup.path)?;
+        Ok(status)
+    }
+
+    fn kill_and_wait(&mut self) -> io::Result<ExitStatus> {
+        if self.child.peek_status()?.is_none() {
+            let kill = self.cgroup.path.join("cgroup.kill");
+            if fs::write(kill, "1").is_err() {
+                self.child.kill()?;
+            }
+        }
+        self.child.wait()
+    }
+}
+
+impl Drop for RunningContainer {
+    fn drop(&mut self) {
+        self.kill_and_wait().ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ExitStatus, Signal};
+    use super::Container;
+
+    #[test]
+    fn test_container() {
+        let container = match Container::builder().build() {
+            Ok(container) => container,
+            // unprivileged
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) ||
+                          e.raw_os_error() == Some(libc::EACCES) => return,
+            Err(e) => panic!("{}", e),
+        };
+        let name = container.cgroup().to_path_buf();
+        let running = container.start(["/bin/sleep", "10"]).unwrap();
+        assert_eq!(running.cgroup(), name);
+        // the second process, and in the cgroup of the container
+        let check = format!("[ $$ = 2 ] && grep -qx 0::{} /proc/self/cgroup",
+                            name.display());
+        let mut child = running.exec(["/bin/sh", "-c", &check]).unwrap();
+        assert!(child.wait().unwrap().success());
+        let metrics = running.metrics().unwrap();
+        assert!(metrics.cpu_time.is_some());
+        running.pause().unwrap();
+        running.resume().unwrap();
+        assert_eq!(running.teardown().unwrap(),
+                   ExitStatus::Signaled(Signal::SIGKILL, false));
+    }
+}
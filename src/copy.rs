@@ -0,0 +1,192 @@
+use std::ffi::CString;
+use std::fs;
+use std::path::Path;
+
+use libc::mode_t;
+
+use crate::{Command, Capability};
+use crate::chroot::Beneath;
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::ffi_util::ToCString;
+
+
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+/// Extended attributes (name and value) of the copied file
+pub type Xattrs = Vec<(CString, Vec<u8>)>;
+
+/// Value of `security.capability` giving `caps` (permitted and effective)
+fn file_caps(caps: &[Capability]) -> Vec<u8> {
+    // struct vfs_cap_data, revision 2, little endian
+    let mut words = [VFS_CAP_REVISION_2 | VFS_CAP_FLAGS_EFFECTIVE,
+                     0, 0, 0, 0];
+    for &cap in caps {
+        let cap = cap as u32;
+        // permitted of the lower half in words[1], upper half in words[3]
+        words[1 + 2 * (cap >> 5) as usize] |= 1 << (cap & 31);
+    }
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+/// File prepared for writing in the child
+pub struct CopyFile {
+    /// Directory which the file is written to
+    pub dir: CString,
+    /// Set for `ResolvePaths::NoFollow`, relative to the new root
+    pub dir_beneath: Option<Beneath>,
+    pub name: CString,
+    pub data: Vec<u8>,
+    pub mode: mode_t,
+    pub xattrs: Xattrs,
+}
+
+impl CopyFile {
+    pub fn read(host_path: &Path, container_path: &Path, mode: mode_t,
+        xattrs: &Xattrs, nofollow: bool)
+        -> Result<CopyFile, Error>
+    {
+        let dir = container_path.parent().unwrap();
+        let dir_beneath = if nofollow { Some(Beneath::new(dir)?) } else { None };
+        Ok(CopyFile {
+            dir: dir.to_cstring(),
+            dir_beneath,
+            name: container_path.file_name().unwrap().to_cstring(),
+            data: result(Err::CopyFile, fs::read(host_path))?,
+            mode,
+            xattrs: xattrs.clone(),
+        })
+    }
+}
+
+impl Command {
+    /// Copy a file from the host into the new root of the child
+    ///
+    /// The file at `host_path` is read by the parent at `spawn()` and
+    /// written by the child to the `container_path` after `pivot_root` and
+    /// `chroot_dir` are applied (and before uid is changed). So it's useful
+    /// for small files like configs or entrypoint scripts, as the whole file
+    /// is kept in memory. The file is created or truncated and then gets
+    /// exactly the `mode` (i.e. umask doesn't apply).
+    ///
+    /// Note: this writes into whatever file system is mounted at the
+    /// destination. To keep the image directory intact, copy files into
+    /// a directory where a `tmpfs` is mounted.
+    ///
+    /// Directories in `container_path` are resolved according to
+    /// `resolve_paths`. Errors are reported as `Error::CopyFile`.
+    ///
+    /// # Panics
+    ///
+    /// If `container_path` is not absolute or has no file name.
+    pub fn copy_into_root<A: AsRef<Path>, B: AsRef<Path>>(&mut self,
+        host_path: A, container_path: B, mode: mode_t)
+        -> &mut Command
+    {
+        let container_path = container_path.as_ref();
+        if !container_path.is_absolute() {
+            panic!("Container path must be absolute");
+        }
+        if container_path.file_name().is_none() {
+            panic!("Container path must have a file name");
+        }
+        self.copy_files.push((host_path.as_ref().to_path_buf(),
+                              container_path.to_path_buf(), mode,
+                              Vec::new()));
+        self
+    }
+
+    /// Copy a file into the new root and give it file capabilities
+    ///
+    /// Works like `copy_into_root`, and then sets `security.capability`, so
+    /// the program gets `caps` (permitted and effective) when executed,
+    /// like `setcap caps+ep`. This is needed for tools like `ping` in images
+    /// that rely on file capabilities rather than set-user-ID bit.
+    ///
+    /// Setting file capabilities requires `CAP_SETFCAP` in the child. In a
+    /// user namespace the kernel (linux 4.14+) records the root user of the
+    /// namespace in the attribute, so the capabilities are only effective
+    /// in the namespace (and its children). The file system must support
+    /// the attribute (`tmpfs` and `overlay` do), otherwise `spawn()` fails
+    /// with `Error::CopyFile(ENOTSUP)`.
+    pub fn copy_into_root_with_caps<A, B>(&mut self,
+        host_path: A, container_path: B, mode: mode_t, caps: &[Capability])
+        -> &mut Command
+        where A: AsRef<Path>, B: AsRef<Path>,
+    {
+        let container_path = container_path.as_ref();
+        self.copy_into_root(host_path, container_path, mode);
+        self.copy_xattr(container_path, "security.capability",
+                        &file_caps(caps))
+    }
+
+    /// Set an extended attribute of a file copied by `copy_into_root`
+    ///
+    /// The attribute is set by the child right after writing the file, e.g.
+    /// `user.*` attributes, or `security.*` ones if the child has
+    /// `CAP_SYS_ADMIN`. Each invocation adds an attribute, in order.
+    ///
+    /// # Panics
+    ///
+    /// If there is no file copied to the `container_path` or the name
+    /// contains a nul byte.
+    pub fn copy_xattr<P: AsRef<Path>>(&mut self, container_path: P,
+        name: &str, value: &[u8])
+        -> &mut Command
+    {
+        let container_path = container_path.as_ref();
+        let name = CString::new(name).expect("no nul bytes in xattr name");
+        let file = self.copy_files.iter_mut().rev()
+            .find(|(_, dest, _, _)| dest == container_path)
+            .expect("xattr is set for the file copied by copy_into_root");
+        file.3.push((name, value.to_vec()));
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{Command, Capability};
+    use crate::ffi_util::ToCString;
+    use super::file_caps;
+
+    fn getxattr(path: &std::path::Path, name: &str) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let rc = unsafe {
+            libc::getxattr(path.to_cstring().as_ptr(),
+                name.to_cstring().as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+        };
+        assert!(rc >= 0, "{}", std::io::Error::last_os_error());
+        buf[..rc as usize].to_vec()
+    }
+
+    #[test]
+    fn test_copy_with_caps() {
+        let path = std::env::temp_dir().join("unshare-test-ping");
+        let status = Command::new("/bin/true")
+            .copy_into_root_with_caps("/bin/true", &path, 0o755,
+                                      &[Capability::CAP_NET_RAW])
+            .copy_xattr(&path, "user.origin", b"host")
+            .status().unwrap();
+        assert!(status.success());
+        assert_eq!(getxattr(&path, "security.capability"),
+                   file_caps(&[Capability::CAP_NET_RAW]));
+        assert_eq!(getxattr(&path, "user.origin"), b"host");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_caps() {
+        // what `setcap cap_net_raw+ep` writes
+        assert_eq!(file_caps(&[Capability::CAP_NET_RAW]),
+            b"\x01\x00\x00\x02\x00\x20\x00\x00\x00\x00\x00\x00\
+              \x00\x00\x00\x00\x00\x00\x00\x00");
+        let caps = file_caps(&[Capability::CAP_SYSLOG]);
+        assert_eq!(&caps[12..16], b"\x04\x00\x00\x00");
+    }
+}
+
@@ -0,0 +1,161 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::Command;
+use crate::cleanup::SpawnCleanup;
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::ffi_util::ToCString;
+use crate::host_files::mount_point;
+use crate::mount_provider::clone_tree;
+use crate::chroot::in_root;
+
+
+/// Where core dumps of the child go, see `Command::core_dumps`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorePolicy {
+    /// Don't dump core
+    Disabled,
+    /// Write core dumps into the host directory
+    ToDirectory(PathBuf),
+}
+
+/// Returns the directory of the (absolute) `core_pattern`
+///
+/// `None` for patterns which can't be redirected by a bind mount: pipes,
+/// relative patterns (written to the working directory of the process)
+/// and patterns with specifiers in the directory part.
+fn pattern_dir(pattern: &str) -> Option<&Path> {
+    let pattern = pattern.trim_end_matches('\n');
+    if !pattern.starts_with('/') {
+        return None;
+    }
+    let dir = Path::new(pattern).parent()?;
+    if dir == Path::new("/") || dir.to_str()?.contains('%') {
+        return None;
+    }
+    Some(dir)
+}
+
+impl Command {
+    /// Control core dumps of the child, instead of following host policy
+    ///
+    /// With `CorePolicy::Disabled` the child sets both soft and hard
+    /// `RLIMIT_CORE` to zero, so the program can't raise it. The child is
+    /// also made non-dumpable until the exec (the kernel resets this flag
+    /// on `execve`). Note: when `core_pattern` pipes cores to a helper
+    /// (e.g. `systemd-coredump`), it's up to the helper to respect the
+    /// limit.
+    ///
+    /// With `CorePolicy::ToDirectory` the host directory is bind-mounted
+    /// over the directory of `/proc/sys/kernel/core_pattern` in the new
+    /// mount namespace (the kernel resolves the pattern in the file system
+    /// of the crashing process), and the soft `RLIMIT_CORE` is raised to
+    /// the hard one. This requires the mount namespace to be unshared and
+    /// an absolute pattern with a fixed directory, e.g.
+    /// `/var/crash/core.%e.%p`. Spawn fails with `Error::CoreDumps(EINVAL)`
+    /// if the mount namespace isn't unshared and `Error::CoreDumps(ENOTSUP)`
+    /// for other patterns.
+    ///
+    /// Each invocation **replaces** previously set policy.
+    pub fn core_dumps(&mut self, policy: CorePolicy) -> &mut Command {
+        self.config.core_dumps = Some(policy);
+        self
+    }
+
+    /// Returns the source and the target (host path) of the mount over
+    /// the directory of core dumps
+    pub(crate) fn core_dump_dir(&self)
+        -> Result<Option<(&Path, PathBuf)>, Error>
+    {
+        let host_dir = match self.config.core_dumps {
+            Some(CorePolicy::ToDirectory(ref dir)) => dir,
+            _ => return Ok(None),
+        };
+        if self.config.namespaces & libc::CLONE_NEWNS == 0 {
+            // the mount would be visible by all processes
+            return Err(Error::CoreDumps(libc::EINVAL));
+        }
+        let pattern = result(Err::CoreDumps,
+            fs::read_to_string("/proc/sys/kernel/core_pattern"))?;
+        let dir = pattern_dir(&pattern)
+            .ok_or(Error::CoreDumps(libc::ENOTSUP))?;
+        Ok(Some((host_dir, in_root(&self.host_root(), dir))))
+    }
+
+    pub(crate) fn core_dump_mount(&self, cleanup: &mut SpawnCleanup)
+        -> Result<Option<(CString, File)>, Error>
+    {
+        let (host_dir, target) = match self.core_dump_dir()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let mount = result(Err::CoreDumps, clone_tree(host_dir))?;
+        result(Err::CoreDumps, mount_point(&target, true, cleanup))?;
+        Ok(Some((target.to_cstring(), mount)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::{Command, Namespace, Error, CorePolicy};
+    use super::pattern_dir;
+
+    #[test]
+    fn test_pattern_dir() {
+        assert_eq!(pattern_dir("/var/crash/core.%e.%p\n"),
+                   Some(Path::new("/var/crash")));
+        assert_eq!(pattern_dir("core"), None);
+        assert_eq!(pattern_dir("|/usr/lib/systemd/systemd-coredump %P"),
+                   None);
+        assert_eq!(pattern_dir("/core.%p"), None);
+        assert_eq!(pattern_dir("/var/crash/%e/core"), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let status = Command::new("/bin/sh").arg("-c")
+            .arg("test $(ulimit -c) = 0 && test $(ulimit -Hc) = 0")
+            .core_dumps(CorePolicy::Disabled)
+            .status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_to_directory() {
+        let host = std::env::temp_dir().join("unshare-test-cores");
+        fs::create_dir_all(&host).unwrap();
+        fs::write(host.join("marker"), b"").unwrap();
+        let pattern = fs::read_to_string("/proc/sys/kernel/core_pattern")
+            .unwrap();
+        let result = Command::new("/bin/sh").arg("-c")
+            .arg(format!("test -e {}/marker",
+                pattern_dir(&pattern).unwrap_or(Path::new("/")).display()))
+            .unshare(&[Namespace::Mount])
+            .core_dumps(CorePolicy::ToDirectory(host.clone()))
+            .status();
+        fs::remove_dir_all(&host).unwrap();
+        match (pattern_dir(&pattern), result) {
+            (Some(_), Ok(status)) => assert!(status.success()),
+            (None, Err(Error::CoreDumps(code))) => {
+                assert_eq!(code, libc::ENOTSUP)
+            }
+            (_, other) => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_needs_mount_namespace() {
+        match Command::new("/bin/true")
+            .core_dumps(CorePolicy::ToDirectory("/tmp".into()))
+            .spawn()
+        {
+            Err(Error::CoreDumps(code)) => assert_eq!(code, libc::EINVAL),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use libc::pid_t;
+
+use crate::{Command, Stdio};
+use crate::error::{Error, ERROR_FRAME_LEN};
+
+
+/// Code of the frame carrying the pid of the daemon in the error pipe
+pub const DAEMON_PID_FRAME: u8 = 0xFF;
+
+impl Command {
+    /// Run the program as a daemon, detached from the current process
+    ///
+    /// The child does the classic double fork after the setup: it calls
+    /// `setsid`, forks, and exits, so the program is run by the grandchild
+    /// which is not a session leader (and can't acquire a controlling
+    /// terminal) and is reparented to init (or to the subreaper, see
+    /// `init_mode`). The `spawn()` returns when the program is executed,
+    /// and errors of the grandchild are reported as usual. The intermediate
+    /// process is reaped by `spawn()` too.
+    ///
+    /// This also sets `allow_daemonize`, redirects stdio to `/dev/null`
+    /// and, unless `current_dir` is set, changes the working directory to
+    /// `/`, so the daemon doesn't keep the directory busy. Call `stdout`,
+    /// etc. after this method to keep the output.
+    ///
+    /// The returned `Child` refers to the daemon, so `pid()`, `kill()` and
+    /// `signal()` work, but `wait()` fails with `ECHILD` unless the current
+    /// process is a subreaper, and it has no pidfd.
+    ///
+    /// Pid namespace can't be unshared or joined, because the intermediate
+    /// process would be the init of the namespace, see `Error::Daemonize`.
+    pub fn daemonize(&mut self) -> &mut Command {
+        self.config.daemonize = true;
+        self.allow_daemonize();
+        self.stdin(Stdio::null());
+        self.stdout(Stdio::null());
+        self.stderr(Stdio::null());
+        if self.config.work_dir.is_none() {
+            self.current_dir("/");
+        }
+        self
+    }
+
+    /// Checks the pid of the daemon is valid in the current process
+    pub(crate) fn check_daemonize(&self) -> Result<(), Error> {
+        if self.config.daemonize &&
+            (self.config.namespaces & libc::CLONE_NEWPID != 0 ||
+             self.config.clone_pid_ns.is_some())
+        {
+            return Err(Error::Daemonize(libc::EINVAL));
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the pid of the daemon sent by the intermediate process
+pub(crate) fn decode_pid(frame: &[u8; ERROR_FRAME_LEN]) -> pid_t {
+    (frame[1] as i32) << 24 | (frame[2] as i32) << 16 |
+        (frame[3] as i32) << 8 | frame[4] as i32
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{Command, Namespace, Error};
+
+    fn stat_field(pid: i32, idx: usize) -> i32 {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+        // fields after the command name, which is in parentheses
+        let rest = &stat[stat.rfind(')').unwrap() + 2..];
+        rest.split(' ').nth(idx).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn test_daemonize() {
+        let child = Command::new("/bin/sleep").arg("10")
+            .daemonize().spawn().unwrap();
+        let pid = child.pid();
+        assert_eq!(fs::read(format!("/proc/{}/cmdline", pid)).unwrap(),
+                   b"/bin/sleep\x0010\x00");
+        let own = std::process::id() as i32;
+        // ppid, session
+        assert_ne!(stat_field(pid, 1), own);
+        let session = stat_field(pid, 3);
+        assert_ne!(session, pid);
+        assert_ne!(session, stat_field(own, 3));
+        assert_eq!(fs::read_link(format!("/proc/{}/cwd", pid)).unwrap(),
+                   std::path::Path::new("/"));
+        child.kill().unwrap();
+    }
+
+    #[test]
+    fn test_daemonize_errors() {
+        match Command::new("/nonexistent").daemonize().spawn() {
+            Err(Error::Exec(code)) => assert_eq!(code, libc::ENOENT),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match Command::new("/bin/true").daemonize()
+            .unshare(&[Namespace::Pid]).spawn()
+        {
+            Err(Error::Daemonize(code)) => assert_eq!(code, libc::EINVAL),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
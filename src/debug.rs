@@ -1,6 +1,7 @@
+use std::ffi::OsStr;
 use std::fmt::{self, Display};
-
-use nix::sched::CloneFlags;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
 
 use crate::Command;
 
@@ -13,6 +14,21 @@ pub struct Style {
     cmd_only: bool,
     print_env: bool,
     show_path: bool,
+    redact: EnvRedaction,
+}
+
+/// Environment variables which values are hidden, see `Style::redact_env`
+///
+/// Usually created by `into()` from a list of names or a predicate.
+#[derive(Clone)]
+pub enum EnvRedaction {
+    /// Names ending with `_TOKEN` or `_SECRET`, or containing `PASSWORD`
+    /// (case insensitive)
+    Default,
+    /// Variables with exactly these names
+    Names(Vec<String>),
+    /// Variables for which the function returns true
+    Predicate(Arc<dyn Fn(&OsStr) -> bool + Send + Sync>),
 }
 
 /// A temporary value returned from `Command::display` for the sole purpose
@@ -27,6 +43,7 @@ impl Style {
             cmd_only: false,
             print_env: true,
             show_path: true,
+            redact: EnvRedaction::Default,
         }
     }
     /// Create a simple clean user-friendly display of the command
@@ -38,6 +55,7 @@ impl Style {
             cmd_only: true,
             print_env: false,
             show_path: false,
+            redact: EnvRedaction::Default,
         }
     }
     /// Toggle printing of environment
@@ -65,6 +83,69 @@ impl Style {
         self.show_path = enable;
         self
     }
+    /// Set which environment variables are shown as `<redacted>`
+    ///
+    /// Accepts a list of names (`&["API_KEY"]`) or a predicate on the name
+    /// (`|name: &OsStr| ...`). This **replaces** the default rule (see
+    /// `EnvRedaction::Default`), pass an empty list to show all values.
+    pub fn redact_env<R: Into<EnvRedaction>>(mut self, redact: R) -> Style {
+        self.redact = redact.into();
+        self
+    }
+}
+
+impl EnvRedaction {
+    fn hides(&self, name: &OsStr) -> bool {
+        match *self {
+            EnvRedaction::Default => {
+                let name = name.as_bytes().to_ascii_uppercase();
+                name.ends_with(b"_TOKEN") || name.ends_with(b"_SECRET") ||
+                    name.windows(8).any(|x| x == b"PASSWORD")
+            }
+            EnvRedaction::Names(ref names) => {
+                names.iter().any(|x| x.as_bytes() == name.as_bytes())
+            }
+            EnvRedaction::Predicate(ref f) => f(name),
+        }
+    }
+}
+
+impl fmt::Debug for EnvRedaction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EnvRedaction::Default => write!(fmt, "Default"),
+            EnvRedaction::Names(ref names) => {
+                fmt.debug_tuple("Names").field(names).finish()
+            }
+            EnvRedaction::Predicate(_) => write!(fmt, "Predicate(..)"),
+        }
+    }
+}
+
+impl<'a> From<&'a [&'a str]> for EnvRedaction {
+    fn from(names: &'a [&'a str]) -> EnvRedaction {
+        EnvRedaction::Names(names.iter().map(|x| x.to_string()).collect())
+    }
+}
+
+impl<'a, const N: usize> From<&'a [&'a str; N]> for EnvRedaction {
+    fn from(names: &'a [&'a str; N]) -> EnvRedaction {
+        EnvRedaction::from(&names[..])
+    }
+}
+
+impl From<Vec<String>> for EnvRedaction {
+    fn from(names: Vec<String>) -> EnvRedaction {
+        EnvRedaction::Names(names)
+    }
+}
+
+impl<F: Fn(&OsStr) -> bool + Send + Sync + 'static> From<F>
+    for EnvRedaction
+{
+    fn from(predicate: F) -> EnvRedaction {
+        EnvRedaction::Predicate(Arc::new(predicate))
+    }
 }
 
 impl<'a> fmt::Display for Printer<'a> {
@@ -107,7 +188,11 @@ impl<'a> fmt::Display for Printer<'a> {
                 if let Some(ref env) = cmd.environ {
                     write!(fmt, "; environ: {{")?;
                     for (ref k, ref v) in env.iter() {
-                        write!(fmt, "{:?}={:?},", k, v)?;
+                        if opt.redact.hides(k) {
+                            write!(fmt, "{:?}=<redacted>,", k)?;
+                        } else {
+                            write!(fmt, "{:?}={:?},", k, v)?;
+                        }
                     }
                     write!(fmt, "}}")?;
                 }
@@ -122,7 +207,7 @@ impl<'a> fmt::Display for Printer<'a> {
             if let Some((ref new, ref old, unmount)) = cmd.pivot_root {
                 write!(fmt, "; pivot_root=({:?};{:?};{})", new, old, unmount)?;
             }
-            if cmd.config.namespaces != CloneFlags::empty() {
+            if cmd.config.namespaces != 0 {
                 // TODO(tailhook)
             }
             if let Some(ref dir) = cmd.config.work_dir {
@@ -141,6 +226,16 @@ impl<'a> fmt::Display for Printer<'a> {
             if let Some(ref gids) = cmd.config.supplementary_gids {
                 write!(fmt, "; gids={:?}", gids)?;
             }
+            if !cmd.labels.is_empty() {
+                write!(fmt, "; labels={{")?;
+                for (idx, (k, v)) in cmd.labels.iter().enumerate() {
+                    if idx > 0 {
+                        write!(fmt, ",")?;
+                    }
+                    write!(fmt, "{}={:?}", k, v)?;
+                }
+                write!(fmt, "}}")?;
+            }
             // TODO(tailhook) stdio, sigchld, death_sig,
             // sigmask, id-map-commands
             write!(fmt, ">")?
@@ -164,6 +259,8 @@ impl fmt::Debug for Command {
 
 #[cfg(test)]
 mod test {
+    use std::ffi::OsStr;
+
     use crate::{Command, Style};
 
     #[test]
@@ -193,6 +290,31 @@ mod test {
             r#""hello" "world!""#);
     }
 
+    #[test]
+    fn test_redact_env() {
+        let mut cmd = Command::new("/bin/hello");
+        cmd.env_clear();
+        cmd.env("GITHUB_TOKEN", "x");
+        assert_eq!(&format!("{:?}", cmd), concat!(r#"<Command "/bin/hello"; "#,
+            r#"environ: {"GITHUB_TOKEN"=<redacted>,}>"#));
+        cmd.env_clear();
+        cmd.env("db_password", "x");
+        assert_eq!(&format!("{}", cmd.display(&Style::debug())),
+            r#"<Command "/bin/hello"; environ: {"db_password"=<redacted>,}>"#);
+        let style = Style::debug().redact_env(&["A"]);
+        cmd.env_clear();
+        cmd.env("A", "B");
+        cmd.env("A_SECRET", "C");
+        let text = format!("{}", cmd.display(&style));
+        assert!(text.contains(r#""A"=<redacted>,"#), "{}", text);
+        assert!(text.contains(r#""A_SECRET"="C","#), "{}", text);
+        let style = Style::debug()
+            .redact_env(|name: &OsStr| name.len() == 8);
+        let text = format!("{}", cmd.display(&style));
+        assert!(text.contains(r#""A"="B","#), "{}", text);
+        assert!(text.contains(r#""A_SECRET"=<redacted>,"#), "{}", text);
+    }
+
     #[test]
     fn test_no_env() {
         let mut cmd = Command::new("/bin/hello");
@@ -0,0 +1,103 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::Command;
+use crate::stdio::{Fd, Closing};
+
+
+/// The environment variable listing directories of `donate_dir_fd`
+pub const DONATED_DIRS_VAR: &str = "UNSHARE_DIR_FDS";
+
+fn open_path(path: &Path) -> io::Result<Closing> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let fd = unsafe {
+        libc::open(path.as_ptr(),
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Closing::new(fd))
+}
+
+impl Command {
+    /// Give the child a directory by descriptor instead of a path
+    ///
+    /// The directory is opened right away with `O_PATH`, so it's the
+    /// directory in the mount namespace of the current process. The
+    /// descriptor is put at the lowest number (starting from 3) which is
+    /// not configured yet, and all the donated directories are listed in
+    /// the `UNSHARE_DIR_FDS` environment variable as `name=fd` separated
+    /// by colons, e.g. `data=3:cache=4`. Donating the same name again
+    /// replaces the directory keeping the number. Since the variable is
+    /// set here, call this after `env_clear`.
+    ///
+    /// The child uses `openat(fd, ..)` (or `/proc/self/fd/N/..` paths), so
+    /// it can access these directories and nothing else, even if it has
+    /// no filesystem view at all (e.g. an empty `pivot_root`), and no
+    /// mounts are needed. Note that `..` isn't restricted by the kernel,
+    /// use `openat2` with `RESOLVE_BENEATH` in the child for that.
+    ///
+    /// Returns `InvalidInput` if the name is empty or contains `=`, `:`
+    /// or a zero byte, and an error if the path isn't a directory.
+    pub fn donate_dir_fd<N, P>(&mut self, name: N, path: P)
+        -> io::Result<&mut Command>
+        where N: Into<String>, P: AsRef<Path>
+    {
+        let name = name.into();
+        if name.is_empty() || name.contains(['=', ':', '\0']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "invalid name of the donated directory"));
+        }
+        let fd = open_path(path.as_ref())?;
+        let target = match self.donated_dirs.iter().find(|x| x.0 == name) {
+            Some(&(_, target)) => target,
+            None => {
+                let target = (3..).find(|x| !self.fds.contains_key(x))
+                    .unwrap();
+                self.donated_dirs.push((name, target));
+                target
+            }
+        };
+        self.fds.insert(target, Fd::Fd(fd));
+        let manifest = self.donated_dirs.iter()
+            .map(|(name, fd)| format!("{}={}", name, fd))
+            .collect::<Vec<_>>()
+            .join(":");
+        Ok(self.env(DONATED_DIRS_VAR, manifest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{ErrorKind, Read};
+
+    use crate::{Command, Stdio};
+
+    #[test]
+    fn test_donate_dir_fd() {
+        let name = format!("unshare-test-donate-{}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hello"), "world").unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("echo $UNSHARE_DIR_FDS; cat /proc/self/fd/4/hello")
+            .stdout(Stdio::piped());
+        cmd.donate_dir_fd("data", "/").unwrap();
+        cmd.donate_dir_fd("tmp", &dir).unwrap();
+        cmd.donate_dir_fd("data", "/tmp").unwrap();
+        assert_eq!(cmd.donate_dir_fd("a:b", "/").unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+        assert!(cmd.donate_dir_fd("file", dir.join("hello")).is_err());
+        let mut child = cmd.spawn().unwrap();
+        let mut output = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output, "data=3:tmp=4\nworld");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+//! Just enough of ELF parsing to inspect the program being run
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+
+const PT_INTERP: u32 = 3;
+
+fn read_uint(data: &[u8], offset: usize, size: usize, big_endian: bool)
+    -> Option<u64>
+{
+    let bytes = data.get(offset..offset+size)?;
+    let mut value = 0u64;
+    for i in 0..size {
+        let byte = if big_endian { bytes[i] } else { bytes[size-1-i] };
+        value = (value << 8) | byte as u64;
+    }
+    Some(value)
+}
+
+/// Checks whether ELF file requests a dynamic loader
+///
+/// Returns `None` if data is not an ELF file or is truncated
+pub fn elf_has_interp(data: &[u8]) -> Option<bool> {
+    if !data.starts_with(b"\x7fELF") {
+        return None;
+    }
+    let big_endian = *data.get(5)? == 2;
+    // offsets of e_phoff, e_phentsize, e_phnum
+    let (phoff, entsize, num) = match *data.get(4)? {
+        1 => (read_uint(data, 0x1C, 4, big_endian)?,
+              read_uint(data, 0x2A, 2, big_endian)?,
+              read_uint(data, 0x2C, 2, big_endian)?),
+        2 => (read_uint(data, 0x20, 8, big_endian)?,
+              read_uint(data, 0x36, 2, big_endian)?,
+              read_uint(data, 0x38, 2, big_endian)?),
+        _ => return None,
+    };
+    for i in 0..num {
+        let off = (phoff + i*entsize) as usize;
+        if read_uint(data, off, 4, big_endian)? as u32 == PT_INTERP {
+            return Some(true);
+        }
+    }
+    Some(false)
+}
+
+/// Returns `e_machine` of the ELF file
+///
+/// Returns `None` if data is not an ELF file or is truncated
+pub fn elf_machine(data: &[u8]) -> Option<u16> {
+    if !data.starts_with(b"\x7fELF") {
+        return None;
+    }
+    let big_endian = *data.get(5)? == 2;
+    read_uint(data, 0x12, 2, big_endian).map(|x| x as u16)
+}
+
+/// Reads the beginning of the file, which is enough to find program
+/// headers in all practical cases
+pub fn read_header(path: &Path) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|f| f.take(65536).read_to_end(&mut data))
+        .ok()?;
+    Some(data)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{elf_has_interp, elf_machine};
+
+    #[test]
+    fn test_elf() {
+        let data = fs::read("/bin/sh").unwrap();
+        assert_eq!(elf_has_interp(&data), Some(true));
+        assert_eq!(elf_has_interp(b"#!/bin/sh\n"), None);
+        // 64-bit little endian header with a single PT_LOAD segment
+        let mut data = vec![0u8; 0x78];
+        data[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        data[0x20] = 0x40;
+        data[0x36] = 0x38;
+        data[0x38] = 1;
+        data[0x40] = 1;
+        assert_eq!(elf_has_interp(&data), Some(false));
+        data[0x40] = 3;
+        assert_eq!(elf_has_interp(&data), Some(true));
+        assert_eq!(elf_has_interp(&data[..0x50]), Some(true));
+        assert_eq!(elf_has_interp(&data[..0x3C]), None);
+    }
+
+    #[test]
+    fn test_machine() {
+        let own = fs::read("/proc/self/exe").unwrap();
+        let shell = fs::read("/bin/sh").unwrap();
+        assert!(elf_machine(&own).is_some());
+        assert_eq!(elf_machine(&own), elf_machine(&shell));
+        assert_eq!(elf_machine(b"\x7fELF\x02\x01"), None);
+        let mut data = vec![0u8; 0x14];
+        data[..6].copy_from_slice(b"\x7fELF\x02\x02");
+        data[0x13] = 183;
+        assert_eq!(elf_machine(&data), Some(183));
+    }
+}
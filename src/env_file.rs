@@ -0,0 +1,239 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+
+use crate::Command;
+use crate::error::Error;
+
+
+fn invalid(line: usize, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("line {}: {}", line, message))
+}
+
+fn is_name(name: &[u8]) -> bool {
+    match name.split_first() {
+        Some((&first, rest)) => {
+            (first.is_ascii_alphabetic() || first == b'_') &&
+            rest.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_')
+        }
+        None => false,
+    }
+}
+
+/// Parses `KEY=VALUE` lines in the format of systemd's `EnvironmentFile`
+///
+/// Values may be quoted by single quotes (taken literally) or double
+/// quotes (where `\` escapes the next character), quoted values may span
+/// multiple lines. In unquoted values `\` escapes the next character, and
+/// at the end of line continues the value on the next one, trailing
+/// whitespace is stripped. Empty lines and lines starting with `#` or `;`
+/// are skipped, `export ` before the name is allowed (as in `.env` files).
+pub fn parse(data: &[u8]) -> io::Result<Vec<(OsString, OsString)>> {
+    let mut vars = Vec::new();
+    let mut pos = 0;
+    let mut line = 1;
+    let skip_blank = |pos: &mut usize| {
+        while *pos < data.len() && (data[*pos] == b' ' || data[*pos] == b'\t')
+        {
+            *pos += 1;
+        }
+    };
+    while pos < data.len() {
+        skip_blank(&mut pos);
+        let end = data[pos..].iter().position(|&c| c == b'\n')
+            .map_or(data.len(), |x| pos + x);
+        match data.get(pos) {
+            None | Some(b'\n') | Some(b'#') | Some(b';') => {
+                pos = end + 1;
+                line += 1;
+                continue;
+            }
+            _ => {}
+        }
+        if data[pos..end].starts_with(b"export ") {
+            pos += b"export ".len();
+            skip_blank(&mut pos);
+        }
+        let eq = data[pos..end].iter().position(|&c| c == b'=')
+            .ok_or_else(|| invalid(line, "no `=` in the line"))?;
+        let name = data[pos..pos+eq].trim_ascii_end();
+        if !is_name(name) {
+            return Err(invalid(line, "invalid variable name"));
+        }
+        let start_line = line;
+        pos += eq + 1;
+        skip_blank(&mut pos);
+        let mut value = Vec::new();
+        // length of the value without trailing whitespace
+        let mut trimmed = 0;
+        let mut quote = None;
+        loop {
+            let c = match data.get(pos) {
+                Some(&c) => c,
+                None if quote.is_some() => {
+                    return Err(invalid(start_line, "unterminated quote"));
+                }
+                None => break,
+            };
+            pos += 1;
+            if c == b'\n' && quote.is_some() {
+                line += 1;
+            }
+            match (quote, c) {
+                (None, b'\n') => break,
+                (None, q @ b'\'') | (None, q @ b'"') => quote = Some(q),
+                (Some(q), c) if c == q => {
+                    quote = None;
+                    trimmed = value.len();
+                }
+                (Some(b'\''), c) => value.push(c),
+                (_, b'\\') => match data.get(pos) {
+                    Some(b'\n') => {
+                        // line continuation
+                        pos += 1;
+                        line += 1;
+                    }
+                    Some(&c) => {
+                        value.push(c);
+                        trimmed = value.len();
+                        pos += 1;
+                    }
+                    None => {}
+                },
+                (Some(_), c) => {
+                    value.push(c);
+                    trimmed = value.len();
+                }
+                (None, c) => {
+                    value.push(c);
+                    if c != b' ' && c != b'\t' {
+                        trimmed = value.len();
+                    }
+                }
+            }
+        }
+        line += 1;
+        value.truncate(trimmed);
+        vars.push((OsString::from_vec(name.to_vec()),
+                   OsString::from_vec(value)));
+    }
+    Ok(vars)
+}
+
+impl Command {
+    /// Set environment variables from the file at spawn time
+    ///
+    /// The file has `KEY=VALUE` lines, with the quoting of systemd's
+    /// `EnvironmentFile` (see `man 5 systemd.exec`): values may be in
+    /// single or double quotes, `\` escapes characters, lines starting
+    /// with `#` are comments. `export KEY=VALUE` lines of `.env` files
+    /// work too.
+    ///
+    /// The file is read and parsed as a whole on every `spawn()`, so
+    /// the child either gets every variable of the file, or `spawn()`
+    /// fails with `Error::EnvFile` (e.g. if the file doesn't exist) and
+    /// nothing is run.
+    ///
+    /// Like in systemd, variables of the file override the ones set by
+    /// `env` (and inherited ones) irrespective of the order of calls, but
+    /// not `env_template` and `env_var_with_pid`. If the method is called
+    /// multiple times, the files are applied in order, so the last one
+    /// wins. `env_clear` doesn't remove files.
+    pub fn env_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Command {
+        self.env_files.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Returns the environment with variables of `env_file` applied
+    pub(crate) fn environ_with_files(&self)
+        -> Result<Cow<'_, HashMap<OsString, OsString>>, Error>
+    {
+        let environ = self.environ.as_ref().unwrap();
+        if self.env_files.is_empty() {
+            return Ok(Cow::Borrowed(environ));
+        }
+        let mut environ = environ.clone();
+        for path in &self.env_files {
+            let vars = fs::read(path).and_then(|data| parse(&data))
+                .map_err(|e| Error::EnvFile(path.clone(), e))?;
+            for (key, value) in vars {
+                if !self.pid_env_vars.contains(&key) {
+                    environ.insert(key, value);
+                }
+            }
+        }
+        Ok(Cow::Owned(environ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Read;
+
+    use crate::{Command, Stdio, Error};
+    use super::parse;
+
+    fn vars(data: &str) -> Vec<(String, String)> {
+        parse(data.as_bytes()).unwrap().into_iter()
+            .map(|(k, v)| (k.into_string().unwrap(), v.into_string().unwrap()))
+            .collect()
+    }
+
+    fn pair(k: &str, v: &str) -> (String, String) {
+        (k.to_string(), v.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(vars("A=1\n\n# comment\n; comment\n  B = two words  \n"),
+                   vec![pair("A", "1"), pair("B", "two words")]);
+        assert_eq!(vars("export X='a \\n $b'\nY=\"q\\\"uote\\\\\"\n"),
+                   vec![pair("X", "a \\n $b"), pair("Y", "q\"uote\\")]);
+        assert_eq!(vars("M=\"line1\nline2\"\nN=a\\\nb\nE="),
+                   vec![pair("M", "line1\nline2"), pair("N", "ab"),
+                        pair("E", "")]);
+        assert_eq!(vars("Q='  padded  '  \nZ=x\\ "),
+                   vec![pair("Q", "  padded  "), pair("Z", "x ")]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |data: &str| parse(data.as_bytes()).unwrap_err()
+            .to_string();
+        assert_eq!(err("A=1\nnovalue\n"), "line 2: no `=` in the line");
+        assert_eq!(err("A=1\n1A=2"), "line 2: invalid variable name");
+        assert_eq!(err("A=\"\n\nB=2"), "line 1: unterminated quote");
+        assert_eq!(err("M='a\nb'\nbad"), "line 3: no `=` in the line");
+    }
+
+    #[test]
+    fn test_env_file() {
+        let path = std::env::temp_dir().join("unshare-test-env-file");
+        fs::write(&path, "A='from file'\nB=from file\n").unwrap();
+        let mut child = Command::new("/bin/sh")
+            .arg("-c").arg("echo \"$A|$B|$C\"")
+            .env("A", "explicit").env("C", "explicit")
+            .env_file(&path)
+            .env_template("B", "template")
+            .stdout(Stdio::piped())
+            .spawn().unwrap();
+        let mut output = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output, "from file|template|explicit\n");
+        fs::remove_file(&path).unwrap();
+        match Command::new("/bin/true").env_file(&path).spawn() {
+            Err(Error::EnvFile(p, e)) => {
+                assert_eq!(p, path);
+                assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+use crate::Command;
+use crate::error::Error;
+
+
+enum Part<'a> {
+    Literal(&'a [u8]),
+    Var(&'a OsStr),
+}
+
+impl Command {
+    /// Set environment variable to a template expanded at spawn time
+    ///
+    /// The `${VAR}` references in the `template` are replaced by values of
+    /// the variables in the final environment of the child, i.e. after all
+    /// `env`, `env_remove` and other calls. References to other templates
+    /// are expanded recursively, reference to the variable itself is
+    /// replaced by its value before applying the template, so this works:
+    ///
+    /// ```rust
+    /// # use unshare::Command;
+    /// let mut cmd = Command::new("/bin/sh");
+    /// cmd.env("PREFIX", "/opt/app");
+    /// cmd.env_template("PATH", "${PREFIX}/bin:${PATH}");
+    /// ```
+    ///
+    /// Undefined variables are replaced by an empty string. Use `$$` to
+    /// put a literal `$`. Cycles between templates are reported as
+    /// `Error::EnvTemplateCycle` on spawn.
+    ///
+    /// Subsequent `env`, `env_remove` or `env_var_with_pid` for the same
+    /// variable replace the template.
+    pub fn env_template<K, V>(&mut self, key: K, template: V) -> &mut Command
+        where K: AsRef<OsStr>, V: AsRef<OsStr>
+    {
+        self.env_templates.insert(
+            key.as_ref().to_os_string(),
+            template.as_ref().to_os_string());
+        self.pid_env_vars.remove(key.as_ref());
+        self
+    }
+}
+
+fn parse(template: &OsStr) -> Vec<Part<'_>> {
+    let bytes = template.as_bytes();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] != b'$' {
+            idx += 1;
+            continue;
+        }
+        match bytes.get(idx+1) {
+            Some(b'$') => {
+                parts.push(Part::Literal(&bytes[start..idx+1]));
+                idx += 2;
+                start = idx;
+            }
+            Some(b'{') => {
+                let end = bytes[idx+2..].iter().position(|&c| c == b'}');
+                if let Some(end) = end {
+                    parts.push(Part::Literal(&bytes[start..idx]));
+                    let name = &bytes[idx+2..idx+2+end];
+                    parts.push(Part::Var(OsStr::from_bytes(name)));
+                    idx += end + 3;
+                    start = idx;
+                } else {
+                    // not a reference, keep as is
+                    idx += 1;
+                }
+            }
+            _ => idx += 1,
+        }
+    }
+    parts.push(Part::Literal(&bytes[start..]));
+    parts
+}
+
+struct Expander<'a> {
+    environ: &'a HashMap<OsString, OsString>,
+    templates: &'a HashMap<OsString, OsString>,
+    done: HashMap<&'a OsStr, OsString>,
+    stack: Vec<&'a OsStr>,
+}
+
+impl<'a> Expander<'a> {
+    fn resolve(&mut self, key: &'a OsStr) -> Result<(), Error> {
+        if self.done.contains_key(key) {
+            return Ok(());
+        }
+        if self.stack.contains(&key) {
+            return Err(Error::EnvTemplateCycle(key.to_os_string()));
+        }
+        self.stack.push(key);
+        let mut value = Vec::new();
+        for part in parse(&self.templates[key]) {
+            match part {
+                Part::Literal(x) => value.extend(x),
+                Part::Var(name) if name != key &&
+                    self.templates.contains_key(name) =>
+                {
+                    let (name, _) = self.templates.get_key_value(name)
+                        .unwrap();
+                    self.resolve(name)?;
+                    value.extend(self.done[&name[..]].as_bytes());
+                }
+                Part::Var(name) => {
+                    if let Some(x) = self.environ.get(name) {
+                        value.extend(x.as_bytes());
+                    }
+                }
+            }
+        }
+        self.stack.pop();
+        self.done.insert(key, OsString::from_vec(value));
+        Ok(())
+    }
+}
+
+/// Returns values of all the templates expanded in the `environ`
+pub fn expand_templates(environ: &HashMap<OsString, OsString>,
+    templates: &HashMap<OsString, OsString>)
+    -> Result<Vec<(OsString, OsString)>, Error>
+{
+    let mut expander = Expander {
+        environ,
+        templates,
+        done: HashMap::new(),
+        stack: Vec::new(),
+    };
+    for key in templates.keys() {
+        expander.resolve(key)?;
+    }
+    Ok(expander.done.into_iter()
+        .map(|(k, v)| (k.to_os_string(), v))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+
+    use crate::Error;
+    use super::expand_templates;
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<OsString, OsString> {
+        pairs.iter().map(|&(k, v)| (k.into(), v.into())).collect()
+    }
+
+    fn expand(env: &[(&str, &str)], tpl: &[(&str, &str)])
+        -> Result<HashMap<OsString, OsString>, Error>
+    {
+        expand_templates(&map(env), &map(tpl))
+            .map(|x| x.into_iter().collect())
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand(
+            &[("PATH", "/bin"), ("PREFIX", "/opt")],
+            &[("PATH", "${PREFIX}/bin:${PATH}")]).unwrap(),
+            map(&[("PATH", "/opt/bin:/bin")]));
+        assert_eq!(expand(&[],
+            &[("A", "${B}-${C}"), ("B", "b${C}"), ("C", "c")]).unwrap(),
+            map(&[("A", "bc-c"), ("B", "bc"), ("C", "c")]));
+    }
+
+    #[test]
+    fn test_syntax() {
+        assert_eq!(expand(&[("X", "x")],
+            &[("A", "$$X $X ${X}"), ("B", "${Y}$"), ("C", "${X")]).unwrap(),
+            map(&[("A", "$X $X x"), ("B", "$"), ("C", "${X")]));
+    }
+
+    #[test]
+    fn test_cycle() {
+        match expand(&[], &[("A", "${B}"), ("B", "${C}"), ("C", "${A}")]) {
+            Err(Error::EnvTemplateCycle(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+use libc::pid_t;
+
+use crate::Command;
+use crate::Namespace;
+
+
+/// Variables ignored by glibc's dynamic loader in setuid programs
+/// (except `LD_*` ones, which are all stripped)
+const UNSECURE_VARS: &[&str] = &[
+    "GCONV_PATH",
+    "GETCONF_DIR",
+    "HOSTALIASES",
+    "LOCALDOMAIN",
+    "LOCPATH",
+    "MALLOC_TRACE",
+    "NIS_PATH",
+    "NLSPATH",
+    "RESOLV_HOST_CONF",
+    "RES_OPTIONS",
+    "TMPDIR",
+    "TZDIR",
+];
+
+fn is_dangerous(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"LD_") ||
+        UNSECURE_VARS.iter().any(|x| OsStr::new(x) == name)
+}
+
+/// Parses the `NUL`-separated `KEY=VALUE` pairs of `/proc/<pid>/environ`
+///
+/// Entries without `=` (a process may overwrite its environment) are
+/// skipped.
+fn parse_environ(data: &[u8]) -> Vec<(OsString, OsString)> {
+    data.split(|&c| c == 0).filter_map(|entry| {
+        let eq = entry.iter().position(|&c| c == b'=')?;
+        Some((OsString::from_vec(entry[..eq].to_vec()),
+              OsString::from_vec(entry[eq+1..].to_vec())))
+    }).collect()
+}
+
+impl Command {
+    /// Replace the environment by the one of the process `pid`
+    ///
+    /// The environment is read from `/proc/<pid>/environ` right away, so
+    /// it's a snapshot of the initial environment of the process (changes
+    /// made by `setenv` in the process are not visible there). This works
+    /// as `env_clear` followed by `env` for every variable, so further
+    /// calls may adjust the environment. Reading needs the permission to
+    /// ptrace the process, the error is returned if it fails (e.g. the
+    /// process has exited).
+    pub fn env_from_pid(&mut self, pid: pid_t) -> io::Result<&mut Command> {
+        let data = fs::read(format!("/proc/{}/environ", pid))?;
+        self.env_clear();
+        Ok(self.envs(parse_environ(&data)))
+    }
+
+    /// Set `PATH` for the child if it's not set in its environment
+    ///
+    /// The check is done at spawn time on the final environment, so this is
+    /// mostly useful after `env_clear()`, which leaves no `PATH` at all.
+    /// Note that `PATH` is never used to find the program itself (it's
+    /// executed by exact path), it's only for the child to run commands.
+    pub fn env_default_path<S: AsRef<OsStr>>(&mut self, path: S)
+        -> &mut Command
+    {
+        self.default_path = Some(path.as_ref().to_os_string());
+        self
+    }
+
+    /// Remove variables that affect dynamic loader when crossing privilege
+    /// boundary
+    ///
+    /// These are all `LD_*` ones (like `LD_PRELOAD` and `LD_LIBRARY_PATH`)
+    /// and others which glibc ignores in setuid programs (`GCONV_PATH`,
+    /// `LOCPATH`, `TMPDIR` and so on). The variables are only removed if the
+    /// child changes its uid or gid to one different from the effective ids
+    /// of the current process, or if it enters a new or existing user
+    /// namespace. They are removed from the final environment, i.e.
+    /// variables set explicitly by `env` are removed too.
+    pub fn scrub_dangerous_env(&mut self) -> &mut Command {
+        self.scrub_env = true;
+        self
+    }
+
+    fn crosses_privilege_boundary(&self) -> bool {
+        let uid = unsafe { libc::geteuid() };
+        let gid = unsafe { libc::getegid() };
+        self.config.uid.is_some_and(|x| x != uid) ||
+        self.config.gid.is_some_and(|x| x != gid) ||
+        self.config.namespaces & libc::CLONE_NEWUSER != 0 ||
+        self.config.setns_namespaces.contains_key(&Namespace::User)
+    }
+
+    /// Applies `env_default_path` and `scrub_dangerous_env` to the final
+    /// environment
+    pub(crate) fn apply_env_policy<'a>(&'a self,
+        vars: &mut Vec<(&'a OsStr, &'a OsStr)>)
+    {
+        if let Some(ref path) = self.default_path {
+            let name = OsStr::new("PATH");
+            if !vars.iter().any(|&(k, _)| k == name) &&
+                !self.pid_env_vars.contains(name)
+            {
+                vars.push((name, path));
+            }
+        }
+        if self.scrub_env && self.crosses_privilege_boundary() {
+            vars.retain(|&(k, _)| !is_dangerous(k));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::io::Read;
+
+    use crate::{Command, Stdio};
+    use super::{is_dangerous, parse_environ};
+
+    fn env_of(cmd: &mut Command) -> String {
+        let mut child = cmd.stdout(Stdio::piped()).spawn().unwrap();
+        let mut output = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        output
+    }
+
+    #[test]
+    fn test_dangerous() {
+        assert!(is_dangerous(OsStr::new("LD_PRELOAD")));
+        assert!(is_dangerous(OsStr::new("LD_LIBRARY_PATH")));
+        assert!(is_dangerous(OsStr::new("TMPDIR")));
+        assert!(!is_dangerous(OsStr::new("PATH")));
+        assert!(!is_dangerous(OsStr::new("OLD_PRELOAD")));
+    }
+
+    #[test]
+    fn test_cleared_env() {
+        // Nothing is added by the library, not even PATH or HOME
+        let mut cmd = Command::new("/usr/bin/env");
+        cmd.env_clear();
+        assert_eq!(env_of(&mut cmd), "");
+        cmd.env_default_path("/bin");
+        assert_eq!(env_of(&mut cmd), "PATH=/bin\n");
+        cmd.env("PATH", "/usr/bin");
+        assert_eq!(env_of(&mut cmd), "PATH=/usr/bin\n");
+    }
+
+    #[test]
+    fn test_env_from_pid() {
+        let vars = parse_environ(b"A=1\0B=x=y\0junk\0C=\0");
+        assert_eq!(vars, [("A".into(), "1".into()), ("B".into(), "x=y".into()),
+                          ("C".into(), "".into())]);
+        let mut source = Command::new("/bin/sleep").arg("10")
+            .env_clear().env("SOURCE", "yes")
+            .spawn().unwrap();
+        let mut cmd = Command::new("/usr/bin/env");
+        cmd.env("OTHER", "1");
+        cmd.env_from_pid(source.pid()).unwrap();
+        source.kill().unwrap();
+        source.wait().unwrap();
+        assert_eq!(env_of(&mut cmd), "SOURCE=yes\n");
+        assert!(cmd.env_from_pid(source.pid()).is_err());
+    }
+
+    #[test]
+    fn test_scrub() {
+        let mut cmd = Command::new("/usr/bin/env");
+        cmd.env_clear().env("LD_PRELOAD", "x.so").scrub_dangerous_env();
+        // no privilege boundary
+        assert_eq!(env_of(&mut cmd), "LD_PRELOAD=x.so\n");
+        cmd.uid(unsafe { libc::geteuid() } + 1);
+        cmd.gid(unsafe { libc::getegid() } + 1);
+        if unsafe { libc::geteuid() } == 0 {
+            assert_eq!(env_of(&mut cmd), "");
+        }
+    }
+}
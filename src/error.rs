@@ -1,8 +1,10 @@
+use std::ffi::OsString;
 use std::io;
 use std::fmt;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
 use crate::status::ExitStatus;
-
-use nix;
+use crate::BoxError;
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,6 +23,33 @@ pub enum ErrorCode {
     SetNs = 12,
     CapSet = 13,
     PreExec = 14,
+    SetSubreaper = 15,
+    MakePrivate = 16,
+    CopyFile = 17,
+    SetLimits = 18,
+    Preload = 19,
+    UserspaceNetwork = 20,
+    AttachMount = 21,
+    ForeignInterpreter = 22,
+    InjectHostFile = 23,
+    KillFd = 24,
+    DebugSyscalls = 25,
+    SeccompNotify = 26,
+    AmbientCaps = 27,
+    ScratchDir = 28,
+    TimeNamespace = 29,
+    UserNamespace = 30,
+    IdmappedMount = 31,
+    Daemonize = 32,
+    LockMemory = 33,
+    CoreDumps = 34,
+    SharedMemory = 35,
+    Cgroup = 36,
+    BlockSignals = 37,
+    LoginUid = 38,
+    MountCgroup = 39,
+    Unshare = 40,
+    ProcPath = 41,
 }
 
 /// Error runnning process
@@ -91,8 +120,128 @@ pub enum Error {
     CapSet(i32),
     /// Before unfreeze callback error
     BeforeUnfreeze(Box<dyn (::std::error::Error) + Send + Sync + 'static>),
-    /// Before exec callback error
+    /// Error returned by the `after_idmap` callback
+    AfterIdmap(BoxError),
+    /// Before exec callback error (`0` if the callback panicked)
     PreExec(i32),
+    /// Error returned by one of the `PrivilegedOps` methods
+    PrivilegedOps(BoxError),
+    /// Error making parent process a subreaper (see `Command::init_mode`)
+    SetSubreaper(i32),
+    /// Error making mounts private (see `Command::make_private_rec`)
+    MakePrivate(i32),
+    /// Error reading or writing a file set by `Command::copy_into_root`
+    CopyFile(i32),
+    /// Environment templates (see `Command::env_template`) reference each
+    /// other in a loop, contains name of one of the variables
+    EnvTemplateCycle(OsString),
+    /// Error reading or parsing the file set by `Command::env_file`
+    EnvFile(PathBuf, io::Error),
+    /// Error applying limits set by `Command::inherit_limits_from`
+    SetLimits(i32),
+    /// Library added by `Command::preload` can't be found in the new root
+    Preload(i32),
+    /// Error running helper set by `Command::userspace_network`
+    UserspaceNetwork(i32),
+    /// Error returned by `MountProvider::provide`
+    MountProvider(BoxError),
+    /// Error passing mount from `MountProvider` to the child or attaching it
+    AttachMount(i32),
+    /// Interpreter set by `Command::foreign_arch_interpreter` can't be
+    /// found or made reachable in the new root
+    ForeignInterpreter(i32),
+    /// Error bind-mounting a file set by `Command::inject_host_file`
+    InjectHostFile(i32),
+    /// Error starting the watcher of descriptors set by `Command::kill_fd`,
+    /// `EINVAL` if `kill_namespace_on_timeout` is set, but the pid
+    /// namespace isn't unshared
+    KillFd(i32),
+    /// Error opening the log or attaching the tracer set by
+    /// `Command::debug_syscalls`
+    DebugSyscalls(i32),
+    /// Error installing the filter set by `Command::seccomp_notify` or
+    /// passing its listener to the parent
+    SeccompNotify(i32),
+    /// Error raising capabilities into the ambient set
+    ///
+    /// Not returned for kernels not supporting ambient capabilities unless
+    /// `Command::strict` mode is enabled.
+    AmbientCaps(i32),
+    /// Error bind-mounting the directory set by `Command::scratch_dir`
+    ScratchDir(i32),
+    /// Error setting up the time namespace of `Command::virtual_clock`
+    TimeNamespace(i32),
+    /// User namespace can't be created: `EPERM` if creating it is
+    /// forbidden (e.g. by a sysctl, seccomp or in a chroot) or if the
+    /// `outside` ids of the id maps aren't mapped in the user namespace of
+    /// the current process (see `UidMap::current`), `EUSERS` if the limit
+    /// of 32 nested user namespaces is reached, `ENOSPC` if user namespaces
+    /// are disabled by `user.max_user_namespaces`
+    UserNamespace(i32),
+    /// Error making the mount set by `Command::idmapped_mount`
+    IdmappedMount(i32),
+    /// Error daemonizing the child (see `Command::daemonize`), `EINVAL` if
+    /// the pid namespace is unshared or joined
+    Daemonize(i32),
+    /// Error locking memory of the child (see `Command::lock_memory`),
+    /// usually `ENOMEM` if `RLIMIT_MEMLOCK` is exceeded
+    LockMemory(i32),
+    /// Error setting up core dumps (see `Command::core_dumps`)
+    CoreDumps(i32),
+    /// Error creating the memory set by `Command::shared_memory`
+    SharedMemory(i32),
+    /// Error moving the child into the cgroup (see `Command::cgroup`)
+    Cgroup(i32),
+    /// The program can't be executed in the mount namespace joined by
+    /// `Command::set_namespace` (or in the root set for the child there):
+    /// it's missing (`ENOENT`) or not accessible. The `exists_on_host`
+    /// tells whether the path exists in the mount namespace of the current
+    /// process, see `Command::exec_fd` to run the host file.
+    ExecInNamespace {
+        /// Error of the `execve`
+        errno: i32,
+        /// The program path exists in the current mount namespace
+        exists_on_host: bool,
+    },
+    /// The limit of the `SpawnLimiter` (see `Command::limiter`) is reached
+    /// and it's set to `fail_fast`, the code is `EAGAIN`
+    SpawnLimit(i32),
+    /// Error blocking signals set by `Command::blocked_signals` (i.e. an
+    /// invalid signal number)
+    BlockSignals(i32),
+    /// Error writing the login uid set by `Command::loginuid`
+    ///
+    /// `ENOENT` means that the kernel has no audit support (or `/proc` is
+    /// not mounted), `EPERM` that the parent has no `CAP_AUDIT_CONTROL` or
+    /// the login uid is already set and immutable.
+    LoginUid(i32),
+    /// Error mounting the cgroup file system for `Command::mount_cgroupfs`
+    ///
+    /// `ENOENT` means that there is no `/sys/fs/cgroup` in the new root.
+    MountCgroup(i32),
+    /// Error unsharing namespaces after the user namespace is set up (see
+    /// `Command::userns_first`)
+    Unshare(i32),
+    /// The proc file system (see `Command::proc_path`) is unusable
+    ///
+    /// `ENOENT` means it's not mounted there (or the current process isn't
+    /// visible in it), `EINVAL` means the directory is not a proc file
+    /// system.
+    ProcPath(i32),
+    /// The child was reaped elsewhere and its pid now belongs to another
+    /// process, so it's not signaled (see `Child::signal`)
+    PidReused,
+    /// The descriptor is in a `close_fds` range, while it's configured for
+    /// the child or used internally (see `Command::strict_close_fds`)
+    FdPlanConflict(RawFd),
+    /// Error starting the scope unit (see `Command::register_systemd_scope`)
+    SystemdScope(BoxError),
+    /// Child process died before executing the program without reporting
+    /// an error (i.e. while the parent was setting up uid maps and running
+    /// callbacks, or during the setup in the child), for example because
+    /// of OOM killer. The child is already reaped, the exit status is
+    /// returned.
+    ChildDiedDuringSetup(ExitStatus),
 }
 
 impl Error {
@@ -119,13 +268,51 @@ impl Error {
             &SetNs(x) => Some(x),
             &CapSet(x) => Some(x),
             &BeforeUnfreeze(..) => None,
+            &AfterIdmap(..) => None,
             &PreExec(x) => Some(x),
+            &PrivilegedOps(..) => None,
+            &ChildDiedDuringSetup(..) => None,
+            &SetSubreaper(x) => Some(x),
+            &MakePrivate(x) => Some(x),
+            &CopyFile(x) => Some(x),
+            &EnvTemplateCycle(..) => None,
+            EnvFile(_, e) => e.raw_os_error(),
+            &SetLimits(x) => Some(x),
+            &Preload(x) => Some(x),
+            &UserspaceNetwork(x) => Some(x),
+            &MountProvider(..) => None,
+            &AttachMount(x) => Some(x),
+            &ForeignInterpreter(x) => Some(x),
+            &InjectHostFile(x) => Some(x),
+            &KillFd(x) => Some(x),
+            &DebugSyscalls(x) => Some(x),
+            &SeccompNotify(x) => Some(x),
+            &AmbientCaps(x) => Some(x),
+            &ScratchDir(x) => Some(x),
+            &TimeNamespace(x) => Some(x),
+            &UserNamespace(x) => Some(x),
+            &IdmappedMount(x) => Some(x),
+            &Daemonize(x) => Some(x),
+            &LockMemory(x) => Some(x),
+            &CoreDumps(x) => Some(x),
+            &SharedMemory(x) => Some(x),
+            &Cgroup(x) => Some(x),
+            &BlockSignals(x) => Some(x),
+            &LoginUid(x) => Some(x),
+            &MountCgroup(x) => Some(x),
+            &Unshare(x) => Some(x),
+            &ProcPath(x) => Some(x),
+            &PidReused => None,
+            &FdPlanConflict(_) => None,
+            &SpawnLimit(x) => Some(x),
+            &ExecInNamespace { errno, .. } => Some(errno),
+            &SystemdScope(..) => None,
         }
     }
 }
 
 impl Error {
-    fn title(&self) -> &'static str {
+    pub(crate) fn title(&self) -> &'static str {
         use self::Error::*;
         match self {
             &UnknownError => "unexpected value received via signal pipe",
@@ -147,7 +334,45 @@ impl Error {
             &SetNs(_) => "error when calling setns",
             &CapSet(_) => "error when setting capabilities",
             &BeforeUnfreeze(_) => "error in before_unfreeze callback",
+            &AfterIdmap(_) => "error in after_idmap callback",
             &PreExec(_) => "error in pre_exec callback",
+            &PrivilegedOps(_) => "error in privileged helper",
+            &ChildDiedDuringSetup(_) => "child died during setup",
+            &SetSubreaper(_) => "error when setting child subreaper",
+            &MakePrivate(_) => "error when making mounts private",
+            &CopyFile(_) => "error copying file into new root",
+            &EnvTemplateCycle(_) => "cycle in environment templates",
+            &EnvFile(..) => "error reading environment file",
+            &SetLimits(_) => "error setting resource limits",
+            &Preload(_) => "error checking preloaded library",
+            &UserspaceNetwork(_) => "error running userspace network helper",
+            &MountProvider(_) => "error in mount provider",
+            &AttachMount(_) => "error attaching provided mount",
+            &ForeignInterpreter(_) => "error setting up foreign interpreter",
+            &InjectHostFile(_) => "error injecting host file",
+            &KillFd(_) => "error watching kill switch descriptor",
+            &DebugSyscalls(_) => "error starting syscall tracer",
+            &SeccompNotify(_) => "error setting up seccomp notifications",
+            &AmbientCaps(_) => "error raising ambient capabilities",
+            &ScratchDir(_) => "error mounting scratch directory",
+            &TimeNamespace(_) => "error setting up time namespace",
+            &UserNamespace(_) => "error creating user namespace",
+            &IdmappedMount(_) => "error making idmapped mount",
+            &Daemonize(_) => "error daemonizing",
+            &LockMemory(_) => "error locking memory",
+            &CoreDumps(_) => "error setting up core dumps",
+            &SharedMemory(_) => "error creating shared memory",
+            &Cgroup(_) => "error moving into cgroup",
+            &BlockSignals(_) => "error blocking signals",
+            &LoginUid(_) => "error setting login uid",
+            &MountCgroup(_) => "error mounting cgroup file system",
+            &Unshare(_) => "error when calling unshare",
+            &ProcPath(_) => "proc file system is unusable",
+            &PidReused => "pid is reused by another process",
+            &FdPlanConflict(_) => "descriptor is in a close_fds range",
+            &SpawnLimit(_) => "spawn limit reached",
+            &ExecInNamespace { .. } => "error executing in joined namespace",
+            &SystemdScope(_) => "error registering systemd scope",
         }
     }
 }
@@ -155,28 +380,136 @@ impl Error {
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         use crate::Error::*;
+        if let EnvFile(path, err) = self {
+            return write!(fmt, "{} {:?}: {}", self.title(), path, err);
+        }
+        if let ExecInNamespace { errno, exists_on_host } = *self {
+            return write!(fmt, "{} ({}): {}", self.title(),
+                if exists_on_host {
+                    "program exists on the host"
+                } else {
+                    "program is missing on the host too"
+                },
+                io::Error::from_raw_os_error(errno));
+        }
+        if let LoginUid(libc::ENOENT) = *self {
+            return write!(fmt, "{}: no /proc/self/loginuid (kernel has no \
+                audit support?)", self.title());
+        }
         if let Some(code) = self.raw_os_error() {
-            let errno = nix::errno::from_i32(code);
-            if let nix::errno::Errno::UnknownErrno = errno {
-                // May be OS knows error name better
-                write!(fmt, "{}: {}", self.title(),
-                    io::Error::from_raw_os_error(code))
-            } else {
-                // Format similar to that of std::io::Error
-                write!(fmt, "{}: {} (os error {})", self.title(),
-                    errno.desc(), code)
-            }
+            // Formats as "description (os error N)"
+            write!(fmt, "{}: {}", self.title(),
+                io::Error::from_raw_os_error(code))
         } else {
             match self {
-                BeforeUnfreeze(err) => {
+                BeforeUnfreeze(err) | PrivilegedOps(err) | MountProvider(err)
+                | SystemdScope(err) | AfterIdmap(err)
+                => {
                     write!(fmt, "{}: {}", self.title(), err)
                 }
+                FdPlanConflict(fd) => {
+                    write!(fmt, "{}: {}", self.title(), fd)
+                }
+                ChildDiedDuringSetup(status) => {
+                    write!(fmt, "{}: {}", self.title(), status)
+                }
+                EnvTemplateCycle(name) => {
+                    write!(fmt, "{}: {}", self.title(), name.to_string_lossy())
+                }
                 _ => write!(fmt, "{}", self.title()),
             }
         }
     }
 }
 
+impl std::error::Error for Error {}
+
+/// Invalid configuration passed to a `try_*` method of `Command`
+///
+/// The methods without the prefix (e.g. `chroot_dir` for `try_chroot_dir`)
+/// panic with the same message instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The directory of `chroot_dir` is not absolute
+    RelativeChrootDir(PathBuf),
+    /// The `new_root` of `pivot_root` is not absolute
+    RelativeNewRoot(PathBuf),
+    /// The `put_old` of `pivot_root` is not absolute
+    RelativePutOld(PathBuf),
+    /// The `new_root` of `pivot_root` is not a prefix of `put_old`
+    PutOldOutsideNewRoot(PathBuf, PathBuf),
+    /// Stdio descriptor passed to `file_descriptor` or `close_fds`
+    StdioFd(RawFd),
+    /// Error getting `RLIMIT_NOFILE` for `close_fds` range without upper
+    /// bound
+    FdLimit(i32),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use self::ConfigError::*;
+        match self {
+            RelativeChrootDir(dir) => {
+                write!(fmt, "chroot dir {:?} must be absolute", dir)
+            }
+            RelativeNewRoot(dir) => {
+                write!(fmt, "new root {:?} must be absolute", dir)
+            }
+            RelativePutOld(dir) => {
+                write!(fmt, "the `put_old` dir {:?} must be absolute", dir)
+            }
+            PutOldOutsideNewRoot(new_root, put_old) => {
+                write!(fmt, "the new root {:?} is not a prefix of \
+                    `put_old` {:?}", new_root, put_old)
+            }
+            StdioFd(fd) => {
+                write!(fmt, "stdio file descriptors must be configured \
+                    with respective methods, got fd {}", fd)
+            }
+            FdLimit(code) => {
+                write!(fmt, "can't get limit of file descriptors: {}",
+                    io::Error::from_raw_os_error(*code))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Size of the error frame sent by the child through the error pipe
+pub const ERROR_FRAME_LEN: usize = 5;
+
+/// Code of the frame sent by the child right before the exec
+///
+/// Both exec and death of the child close the error pipe, so the end of
+/// file without this frame means the child died during setup.
+pub const EXEC_FRAME: u8 = 0xFE;
+
+/// Encodes error to be sent through the error pipe
+///
+/// Must not allocate, as it's used in the child
+// TODO(tailhook) rustc adds a special sentinel at the end of error
+// code. Do we really need it? Assuming our pipes are always cloexec'd.
+pub fn encode_error(code: ErrorCode, errno: i32) -> [u8; ERROR_FRAME_LEN] {
+    [
+        code as u8,
+        (errno >> 24) as u8,
+        (errno >> 16) as u8,
+        (errno >>  8) as u8,
+        errno as u8,
+    ]
+}
+
+/// Decodes a single frame read from the error pipe
+pub fn decode_error(frame: &[u8]) -> Error {
+    if frame.len() != ERROR_FRAME_LEN {
+        return Error::UnknownError;
+    }
+    let errno = ((frame[1] as i32) << 24) | ((frame[2] as i32) << 16) |
+        ((frame[3] as i32) << 8) | (frame[4] as i32);
+    ErrorCode::from_i32(frame[0] as i32, errno)
+}
+
 #[inline]
 pub fn result<T, E: IntoError>(code: ErrorCode, r: Result<T, E>)
     -> Result<T, Error>
@@ -191,7 +524,7 @@ pub fn cmd_result<E: IntoError>(def_code: ErrorCode, r: Result<ExitStatus, E>)
     match r.map_err(|e| e.into_error(def_code))? {
         ExitStatus::Exited(0) => Ok(()),
         ExitStatus::Exited(x) => Err(Error::AuxCommandExited(x as i32)),
-        ExitStatus::Signaled(x, _) => Err(Error::AuxCommandKilled(x as i32)),
+        ExitStatus::Signaled(x, _) => Err(Error::AuxCommandKilled(x.as_raw())),
     }
 }
 
@@ -199,15 +532,6 @@ pub trait IntoError {
     fn into_error(self, code: ErrorCode) -> Error;
 }
 
-impl IntoError for nix::Error {
-    fn into_error(self, code: ErrorCode) -> Error {
-        match self {
-            nix::Error::Sys(x) => code.wrap(x as i32),
-            _ => Error::NixError,
-        }
-    }
-}
-
 impl IntoError for io::Error {
     fn into_error(self, code: ErrorCode) -> Error {
         code.wrap(self.raw_os_error().unwrap_or(-1))
@@ -240,6 +564,33 @@ impl ErrorCode {
             C::SetNs => E::SetNs(errno),
             C::CapSet => E::CapSet(errno),
             C::PreExec => E::PreExec(errno),
+            C::SetSubreaper => E::SetSubreaper(errno),
+            C::MakePrivate => E::MakePrivate(errno),
+            C::CopyFile => E::CopyFile(errno),
+            C::SetLimits => E::SetLimits(errno),
+            C::Preload => E::Preload(errno),
+            C::UserspaceNetwork => E::UserspaceNetwork(errno),
+            C::AttachMount => E::AttachMount(errno),
+            C::ForeignInterpreter => E::ForeignInterpreter(errno),
+            C::InjectHostFile => E::InjectHostFile(errno),
+            C::KillFd => E::KillFd(errno),
+            C::DebugSyscalls => E::DebugSyscalls(errno),
+            C::SeccompNotify => E::SeccompNotify(errno),
+            C::AmbientCaps => E::AmbientCaps(errno),
+            C::ScratchDir => E::ScratchDir(errno),
+            C::TimeNamespace => E::TimeNamespace(errno),
+            C::UserNamespace => E::UserNamespace(errno),
+            C::IdmappedMount => E::IdmappedMount(errno),
+            C::Daemonize => E::Daemonize(errno),
+            C::LockMemory => E::LockMemory(errno),
+            C::CoreDumps => E::CoreDumps(errno),
+            C::SharedMemory => E::SharedMemory(errno),
+            C::Cgroup => E::Cgroup(errno),
+            C::BlockSignals => E::BlockSignals(errno),
+            C::LoginUid => E::LoginUid(errno),
+            C::MountCgroup => E::MountCgroup(errno),
+            C::Unshare => E::Unshare(errno),
+            C::ProcPath => E::ProcPath(errno),
         }
     }
     pub fn from_i32(code: i32, errno: i32) -> Error {
@@ -262,7 +613,69 @@ impl ErrorCode {
             c if c == C::CapSet as i32 => E::CapSet(errno),
             // no BeforeUnfreeze, because can't be in a child
             c if c == C::PreExec as i32 => E::PreExec(errno),
+            c if c == C::SetSubreaper as i32 => E::SetSubreaper(errno),
+            c if c == C::MakePrivate as i32 => E::MakePrivate(errno),
+            c if c == C::CopyFile as i32 => E::CopyFile(errno),
+            c if c == C::SetLimits as i32 => E::SetLimits(errno),
+            c if c == C::Preload as i32 => E::Preload(errno),
+            c if c == C::UserspaceNetwork as i32
+                                            => E::UserspaceNetwork(errno),
+            c if c == C::AttachMount as i32 => E::AttachMount(errno),
+            c if c == C::ForeignInterpreter as i32
+                                            => E::ForeignInterpreter(errno),
+            c if c == C::InjectHostFile as i32 => E::InjectHostFile(errno),
+            c if c == C::KillFd as i32 => E::KillFd(errno),
+            c if c == C::DebugSyscalls as i32 => E::DebugSyscalls(errno),
+            c if c == C::SeccompNotify as i32 => E::SeccompNotify(errno),
+            c if c == C::AmbientCaps as i32 => E::AmbientCaps(errno),
+            c if c == C::ScratchDir as i32 => E::ScratchDir(errno),
+            c if c == C::TimeNamespace as i32 => E::TimeNamespace(errno),
+            c if c == C::UserNamespace as i32 => E::UserNamespace(errno),
+            c if c == C::IdmappedMount as i32 => E::IdmappedMount(errno),
+            c if c == C::Daemonize as i32 => E::Daemonize(errno),
+            c if c == C::LockMemory as i32 => E::LockMemory(errno),
+            c if c == C::CoreDumps as i32 => E::CoreDumps(errno),
+            c if c == C::SharedMemory as i32 => E::SharedMemory(errno),
+            c if c == C::Cgroup as i32 => E::Cgroup(errno),
+            c if c == C::BlockSignals as i32 => E::BlockSignals(errno),
+            c if c == C::LoginUid as i32 => E::LoginUid(errno),
+            c if c == C::MountCgroup as i32 => E::MountCgroup(errno),
+            c if c == C::Unshare as i32 => E::Unshare(errno),
+            c if c == C::ProcPath as i32 => E::ProcPath(errno),
             _ => E::UnknownError,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Error, ErrorCode, encode_error, decode_error};
+
+    #[test]
+    fn test_frame_roundtrip() {
+        for &errno in &[0, 1, libc::ENOENT, 255, 256, 65537, i32::MAX] {
+            match decode_error(&encode_error(ErrorCode::Exec, errno)) {
+                Error::Exec(x) => assert_eq!(x, errno),
+                e => panic!("wrong error {:?}", e),
+            }
+        }
+        match decode_error(&encode_error(ErrorCode::PreExec, -1)) {
+            Error::PreExec(x) => assert_eq!(x, -1),
+            e => panic!("wrong error {:?}", e),
+        }
+        match decode_error(&encode_error(ErrorCode::AmbientCaps, 1)) {
+            Error::AmbientCaps(x) => assert_eq!(x, libc::EPERM),
+            e => panic!("wrong error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_bad_frames() {
+        let frame = encode_error(ErrorCode::Chdir, 2);
+        assert!(matches!(decode_error(&frame[..4]), Error::UnknownError));
+        assert!(matches!(decode_error(&[0, 0, 0, 0, 0]), Error::UnknownError));
+        let mut long = frame.to_vec();
+        long.extend(&frame);
+        assert!(matches!(decode_error(&long), Error::UnknownError));
+    }
+}
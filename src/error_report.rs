@@ -0,0 +1,246 @@
+use crate::{Command, Namespace, MountOp};
+use crate::audit::{json_str, json_list, json_mount, json_opt};
+use crate::error::Error;
+use crate::namespace::{namespaces_of, to_clone_flag};
+
+
+/// Structured description of a spawn failure, see `Error::to_report`
+///
+/// Meant for aggregating failures of many hosts, so unlike the message
+/// every field except `message` is the same for the same failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReport {
+    /// Name of the `Error` variant, e.g. `SetNs`
+    pub kind: String,
+    /// The step which has failed, e.g. `error when calling setns`
+    pub step: &'static str,
+    /// OS error code, if any
+    pub errno: Option<i32>,
+    /// Symbolic name of the error code, e.g. `EPERM`, if it's a common one
+    pub errno_name: Option<&'static str>,
+    /// The full message, as formatted by `Display`
+    pub message: String,
+    /// The likely cause, e.g. a kernel feature which is disabled
+    pub hint: Option<&'static str>,
+    /// Namespaces created for the child (see `Command::error_report`)
+    pub namespaces: Vec<Namespace>,
+    /// Namespaces joined by the child (see `Command::error_report`)
+    pub joined_namespaces: Vec<Namespace>,
+    /// Mount operations planned (see `Command::error_report`)
+    pub mounts: Vec<MountOp>,
+}
+
+fn errno_name(errno: i32) -> Option<&'static str> {
+    use libc::*;
+    Some(match errno {
+        EPERM => "EPERM",
+        ENOENT => "ENOENT",
+        ESRCH => "ESRCH",
+        EINTR => "EINTR",
+        EIO => "EIO",
+        E2BIG => "E2BIG",
+        ENOEXEC => "ENOEXEC",
+        EBADF => "EBADF",
+        ECHILD => "ECHILD",
+        EAGAIN => "EAGAIN",
+        ENOMEM => "ENOMEM",
+        EACCES => "EACCES",
+        EFAULT => "EFAULT",
+        EBUSY => "EBUSY",
+        EEXIST => "EEXIST",
+        EXDEV => "EXDEV",
+        ENOTDIR => "ENOTDIR",
+        EISDIR => "EISDIR",
+        EINVAL => "EINVAL",
+        ENFILE => "ENFILE",
+        EMFILE => "EMFILE",
+        ETXTBSY => "ETXTBSY",
+        ENOSPC => "ENOSPC",
+        EROFS => "EROFS",
+        ENAMETOOLONG => "ENAMETOOLONG",
+        ENOSYS => "ENOSYS",
+        ELOOP => "ELOOP",
+        EUSERS => "EUSERS",
+        EOPNOTSUPP => "EOPNOTSUPP",
+        _ => return None,
+    })
+}
+
+fn hint(err: &Error) -> Option<&'static str> {
+    use crate::Error::*;
+    use libc::*;
+    Some(match *err {
+        _ if err.raw_os_error() == Some(ENOSYS) => {
+            "the system call is not supported by the kernel or is blocked \
+             by seccomp of the current process"
+        }
+        Fork(EPERM) | UserNamespace(EPERM) | SetNs(EPERM) => {
+            "not enough privileges: needs CAP_SYS_ADMIN, or unprivileged \
+             user namespaces are disabled (sysctl, AppArmor)"
+        }
+        Fork(EINVAL) => {
+            "a namespace is not supported by the kernel (CONFIG_*_NS) or \
+             the combination of namespaces is invalid"
+        }
+        Fork(ENOSPC) | Fork(EUSERS) | UserNamespace(ENOSPC) => {
+            "the limit of namespaces is reached, see \
+             /proc/sys/user/max_*_namespaces"
+        }
+        Fork(EAGAIN) => {
+            "the limit of processes is reached (RLIMIT_NPROC or pids cgroup)"
+        }
+        SetIdMap(EPERM) => {
+            "the id maps are not allowed, unprivileged users may map only \
+             their own ids (or use newuidmap with /etc/subuid)"
+        }
+        Exec(ENOENT) => {
+            "the program or its interpreter (the ELF loader or #! line) is \
+             missing"
+        }
+        Exec(EACCES) => {
+            "the program isn't executable or is on a noexec mount"
+        }
+        Exec(ENOEXEC) => {
+            "unknown binary format, see Command::foreign_arch_interpreter"
+        }
+        ExecInNamespace { .. } => {
+            "the program is missing in the joined mount namespace, see \
+             Command::exec_fd"
+        }
+        ChangeRoot(EINVAL) => {
+            "the new root must be a mount point and must not be on a \
+             shared mount"
+        }
+        TimeNamespace(EINVAL) => "time namespaces require linux 5.6",
+        IdmappedMount(EINVAL) => {
+            "idmapped mounts require linux 5.12 and filesystem support"
+        }
+        LoginUid(ENOENT) => "the kernel has no audit support",
+        MountCgroup(ENOENT) => {
+            "the mount point /sys/fs/cgroup is missing in the new root"
+        }
+        LoginUid(EPERM) => {
+            "needs CAP_AUDIT_CONTROL, or the login uid is immutable"
+        }
+        _ => return None,
+    })
+}
+
+impl Error {
+    /// Returns the structured description of the error
+    ///
+    /// Namespaces and mounts are empty here, as the error doesn't know
+    /// the command, use `Command::error_report` to fill them in.
+    pub fn to_report(&self) -> ErrorReport {
+        let debug = format!("{:?}", self);
+        let errno = self.raw_os_error();
+        ErrorReport {
+            kind: debug.chars().take_while(|c| c.is_alphanumeric())
+                .collect(),
+            step: self.title(),
+            errno,
+            errno_name: errno.and_then(errno_name),
+            message: self.to_string(),
+            hint: hint(self),
+            namespaces: Vec::new(),
+            joined_namespaces: Vec::new(),
+            mounts: Vec::new(),
+        }
+    }
+}
+
+impl Command {
+    /// Returns the description of the error of `spawn()` of this command
+    ///
+    /// Same as `Error::to_report` but includes namespaces and mounts of
+    /// the command. Mounts are the ones of `Command::mount_plan`, so the
+    /// failed one isn't necessarily the last.
+    pub fn error_report(&self, err: &Error) -> ErrorReport {
+        let joined = self.config.joined_namespaces()
+            .fold(0, |flags, ns| flags | to_clone_flag(ns));
+        ErrorReport {
+            namespaces: namespaces_of(self.config.namespaces),
+            joined_namespaces: namespaces_of(joined),
+            mounts: self.mount_plan(),
+            .. err.to_report()
+        }
+    }
+}
+
+impl ErrorReport {
+    /// Formats the report as a single line JSON object (without newline)
+    ///
+    /// For example: `{"kind":"Exec","step":"error when executing","errno":2,
+    /// "errno_name":"ENOENT","message":"...","hint":"...",
+    /// "namespaces":["net"],"joined_namespaces":[],"mounts":[]}`. Mounts
+    /// are formatted like in `AuditRecord::to_json`.
+    pub fn to_json(&self) -> String {
+        let mut buf = String::with_capacity(256);
+        buf.push_str(r#"{"kind":"#);
+        json_str(&mut buf, &self.kind);
+        buf.push_str(r#","step":"#);
+        json_str(&mut buf, self.step);
+        buf.push_str(r#","errno":"#);
+        json_opt(&mut buf, self.errno);
+        buf.push_str(r#","errno_name":"#);
+        match self.errno_name {
+            Some(name) => json_str(&mut buf, name),
+            None => buf.push_str("null"),
+        }
+        buf.push_str(r#","message":"#);
+        json_str(&mut buf, &self.message);
+        buf.push_str(r#","hint":"#);
+        match self.hint {
+            Some(hint) => json_str(&mut buf, hint),
+            None => buf.push_str("null"),
+        }
+        let names = |buf: &mut String, ns: &Namespace| {
+            json_str(buf, &format!("{:?}", ns).to_lowercase());
+        };
+        buf.push_str(r#","namespaces":"#);
+        json_list(&mut buf, &self.namespaces, names);
+        buf.push_str(r#","joined_namespaces":"#);
+        json_list(&mut buf, &self.joined_namespaces, names);
+        buf.push_str(r#","mounts":"#);
+        json_list(&mut buf, &self.mounts, json_mount);
+        buf.push('}');
+        buf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Error, Namespace};
+
+    #[test]
+    fn test_report() {
+        let report = Error::Exec(libc::ENOENT).to_report();
+        assert_eq!(report.kind, "Exec");
+        assert_eq!(report.step, "error when executing");
+        assert_eq!(report.errno, Some(libc::ENOENT));
+        assert_eq!(report.errno_name, Some("ENOENT"));
+        assert!(report.hint.is_some());
+        let report = Error::ExecInNamespace {
+            errno: libc::ENOENT,
+            exists_on_host: true,
+        }.to_report();
+        assert_eq!(report.kind, "ExecInNamespace");
+        assert_eq!(Error::UnknownError.to_report().kind, "UnknownError");
+    }
+
+    #[test]
+    fn test_command_report() {
+        let mut cmd = Command::new("/nonexistent/program");
+        cmd.unshare(&[Namespace::Net]);
+        let err = Error::Fork(libc::EPERM);
+        let report = cmd.error_report(&err);
+        assert_eq!(report.namespaces, vec![Namespace::Net]);
+        let json = report.to_json();
+        assert!(json.starts_with(
+            r#"{"kind":"Fork","step":"error when forking","errno":1,"#),
+            "{}", json);
+        assert!(json.ends_with(
+            r#""namespaces":["net"],"joined_namespaces":[],"mounts":[]}"#),
+            "{}", json);
+    }
+}
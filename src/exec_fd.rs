@@ -0,0 +1,116 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::path::Path;
+
+use crate::{Command, Namespace};
+use crate::error::Error;
+use crate::stdio::Closing;
+
+
+impl Command {
+    /// Execute the program from the open file instead of the path
+    ///
+    /// The file is executed with `execveat(fd, "", .., AT_EMPTY_PATH)`, so
+    /// it's the file opened in the current mount namespace, whatever the
+    /// program path resolves to in the child (after `set_namespace` joined
+    /// a mount namespace, `chroot_dir` or `pivot_root`). This is the robust
+    /// way to run a host binary in a container. The program path is still
+    /// used as `argv[0]` and for host-side checks.
+    ///
+    /// The descriptor is owned by the command and can be used for many
+    /// `spawn()` calls. It's closed on exec, so scripts (they are opened by
+    /// the interpreter by the `/dev/fd/N` path) fail with `Error::Exec`;
+    /// use the interpreter as a program instead. Not combined with
+    /// `foreign_arch_interpreter`: the interpreter gets the program path.
+    pub fn exec_fd<F: IntoRawFd>(&mut self, fd: F) -> &mut Command {
+        self.exec_fd = Some(Closing::new(fd.into_raw_fd()));
+        self
+    }
+
+    pub(crate) fn exec_fd_raw(&self) -> Option<i32> {
+        self.exec_fd.as_ref().map(|fd| fd.as_raw_fd())
+    }
+
+    /// Tells whether the program is missing in the joined mount namespace
+    ///
+    /// The child executes the path in the mount namespace (and the root)
+    /// it joined, so the error is the same whether the program is missing
+    /// there or everywhere. This checks the host to tell one from another.
+    pub(crate) fn exec_error(&self, err: Error) -> Error {
+        let ns = &self.config.setns_namespaces;
+        if !ns.contains_key(&Namespace::Mount) || self.exec_fd.is_some() {
+            return err;
+        }
+        match err {
+            Error::Exec(errno)
+                if errno == libc::ENOENT || errno == libc::ENOTDIR ||
+                   errno == libc::EACCES
+            => {
+                let program = Path::new(
+                    OsStr::from_bytes(self.filename.as_bytes()));
+                Error::ExecInNamespace {
+                    errno,
+                    exists_on_host: program.is_absolute() && program.exists(),
+                }
+            }
+            err => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+
+    use crate::{Command, Error, Namespace};
+
+    #[test]
+    fn test_exec_fd() {
+        let mut cmd = Command::new("/nonexistent/true");
+        cmd.exec_fd(File::open("/bin/true").unwrap());
+        assert!(cmd.status().unwrap().success());
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_missing_in_namespace() {
+        let name = format!("unshare-test-exec-in-ns-{}", std::process::id());
+        let base = std::env::temp_dir().join(name);
+        let empty = base.join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        let program = base.join("true");
+        fs::copy("/bin/true", &program).unwrap();
+        // the namespace of the current process where the program exists
+        let mut cmd = Command::new(&program);
+        let ns = File::open("/proc/self/ns/mnt").unwrap();
+        match cmd.set_namespace(&ns, Namespace::Mount).unwrap().status() {
+            Ok(_) => {}
+            // unprivileged
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) ||
+                          e.raw_os_error() == Some(libc::EACCES) => {
+                fs::remove_dir_all(&base).unwrap();
+                return;
+            }
+            Err(e) => panic!("{}", e),
+        }
+        // the target root doesn't contain the program
+        cmd.chroot_dir(&empty);
+        match cmd.spawn() {
+            Err(Error::ExecInNamespace { errno, exists_on_host }) => {
+                assert_eq!(errno, libc::ENOENT);
+                assert!(exists_on_host);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::remove_file(&program).unwrap();
+        cmd.chroot_dir("/");
+        match cmd.spawn() {
+            Err(Error::ExecInNamespace { exists_on_host, .. }) => {
+                assert!(!exists_on_host);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::path::PathBuf;
+
+use crate::{Command, Child, Namespace};
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::namespace::{ALL, proc_name};
+use crate::stdio::Closing;
+
+
+/// Makes the current thread create children in the pid namespace
+///
+/// Joining a pid namespace affects only children created afterwards, so
+/// it's done by the thread which clones the child. The original namespace
+/// is restored on drop, the process is aborted if that fails.
+pub(crate) struct PidNsScope(File);
+
+impl PidNsScope {
+    pub(crate) fn enter(fd: RawFd) -> io::Result<PidNsScope> {
+        let own = File::open("/proc/thread-self/ns/pid_for_children")?;
+        if unsafe { libc::setns(fd, libc::CLONE_NEWPID) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PidNsScope(own))
+    }
+}
+
+impl Drop for PidNsScope {
+    fn drop(&mut self) {
+        let rc = unsafe {
+            libc::setns(self.0.as_raw_fd(), libc::CLONE_NEWPID)
+        };
+        if rc != 0 {
+            // unrelated children (of any spawn by the thread) would be
+            // created in the namespace, and panicking on the spawner thread
+            // would kill children tied to it by the parent death signal
+            unsafe { libc::abort() };
+        }
+    }
+}
+
+fn open_dir(path: PathBuf) -> io::Result<Closing> {
+    let file = OpenOptions::new().read(true)
+        .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+        .open(path)?;
+    Ok(Closing::new(file.into_raw_fd()))
+}
+
+impl Child {
+    /// Spawn the command in the namespaces of this child (like
+    /// `docker exec` or `nsenter`)
+    ///
+    /// Every namespace of the child that differs from the namespace of the
+    /// current process is joined (as if by `Command::set_namespace`). The
+    /// pid namespace is joined by the thread which clones the new process,
+    /// so the process itself is in the namespace (not just its children).
+    /// The process starts in the root and the working directory of this
+    /// child, `current_dir`, if set, is relative to that root.
+    ///
+    /// If the child is in a user namespace, the process gets `uid` and
+    /// `gid` of the current process unless they are set, which are often
+    /// not mapped there (so set them, e.g. to `0`). Joining namespaces
+    /// requires `CAP_SYS_ADMIN` (in the user namespace of the child).
+    ///
+    /// Fails with `Error::SetNs(ESRCH)` if the child has already been
+    /// reaped.
+    pub fn exec_in(&self, mut cmd: Command) -> Result<Child, Error> {
+        if self.status.is_some() {
+            return Err(Error::SetNs(libc::ESRCH));
+        }
+        let root = result(Err::ChangeRoot,
+            open_dir(self.proc_dir().join(format!("{}/root", self.pid))))?;
+        let cwd = result(Err::ChangeRoot,
+            open_dir(self.proc_dir().join(format!("{}/cwd", self.pid))))?;
+        for &ns in ALL {
+            let file = File::from(result(Err::SetNs, self.ns_fd(ns))?);
+            let own = result(Err::SetNs,
+                fs::metadata(self.proc_dir()
+                    .join(format!("self/ns/{}", proc_name(ns)))))?;
+            let meta = result(Err::SetNs, file.metadata())?;
+            if meta.dev() == own.dev() && meta.ino() == own.ino() {
+                // joining own user namespace is an error, others are no-op
+                continue;
+            }
+            let fd = Closing::new(file.into_raw_fd());
+            if ns == Namespace::Pid {
+                cmd.config.clone_pid_ns = Some(fd);
+            } else {
+                cmd.config.setns_namespaces.insert(ns, fd);
+            }
+        }
+        cmd.config.enter_root = Some((root, cwd));
+        cmd.spawn()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Namespace};
+
+    #[test]
+    fn test_exec_in() {
+        let dir = std::env::temp_dir().join("unshare-test-exec-in");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut target = Command::new("/bin/sleep");
+        target.arg("10").current_dir(&dir)
+            .unshare(&[Namespace::Uts, Namespace::Pid, Namespace::Mount]);
+        let mut target = target.spawn().unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        // pid 1 is the target, pid namespace is not the one we are in
+        cmd.arg("-c").arg(format!(
+            "test $$ -ne 1 && test $(pwd) = {} && \
+             test $(readlink /proc/self/ns/pid) != {}",
+            dir.display(),
+            std::fs::read_link("/proc/self/ns/pid").unwrap().display()));
+        let mut child = target.exec_in(cmd).unwrap();
+        assert!(child.wait().unwrap().success());
+        target.kill().unwrap();
+        target.wait().unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+//! Choosing descriptors so that setting up the child's table is safe
+//!
+//! The child does `dup2(src, dest)` for every configured descriptor in
+//! arbitrary order. That is correct only if no `src` is also some other
+//! `dest`, which would be clobbered before being duplicated. Descriptors
+//! used internally by the child must not be a `dest` either. The functions
+//! here pick such descriptors, by duplicating with the `dup` callback
+//! (`fcntl(F_DUPFD_CLOEXEC)` normally, a simulated table in tests).
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+
+
+/// Returns `fd`, or its duplicate which is not a destination (unless it's
+/// the `dest_fd` itself)
+pub fn out_of_the_way<D, F>(mut fd: RawFd, dest_fd: RawFd,
+    dests: &HashMap<RawFd, D>, mut dup: F)
+    -> io::Result<RawFd>
+    where F: FnMut(RawFd, RawFd) -> io::Result<RawFd>,
+{
+    while fd != dest_fd && dests.contains_key(&fd) {
+        fd = dup(fd, 3)?;
+    }
+    Ok(fd)
+}
+
+/// Returns `fd`, or its duplicate which is not below the `floor` and
+/// not a destination
+///
+/// The `floor` is ignored if it's above the limit of open files (`dup`
+/// fails with `EINVAL`).
+pub fn move_above<D, F>(mut fd: RawFd, mut floor: RawFd,
+    dests: &HashMap<RawFd, D>, mut dup: F)
+    -> io::Result<RawFd>
+    where F: FnMut(RawFd, RawFd) -> io::Result<RawFd>,
+{
+    while fd < floor || dests.contains_key(&fd) {
+        let min = if fd < floor { floor } else { fd + 1 };
+        match dup(fd, min) {
+            Ok(new) => fd = new,
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL)
+                && floor > 3
+            => floor = 3,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(fd)
+}
+
+/// Returns ranges of `close_fds` with the `spared` descriptors cut out
+///
+/// Ranges are merged, so the result is sorted and has no overlaps.
+pub fn close_set(ranges: &[(RawFd, RawFd)], spared: &[RawFd])
+    -> Vec<(RawFd, RawFd)>
+{
+    let mut merged: Vec<(RawFd, RawFd)> = Vec::new();
+    let mut ranges = ranges.iter().filter(|&&(start, end)| start < end)
+        .cloned().collect::<Vec<_>>();
+    ranges.sort();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    let mut spared = spared.to_vec();
+    spared.sort();
+    let mut result = Vec::new();
+    for (start, end) in merged {
+        let mut cur = start;
+        for &fd in spared.iter().filter(|&&fd| fd >= start && fd < end) {
+            if fd > cur {
+                result.push((cur, fd));
+            }
+            cur = cur.max(fd + 1);
+        }
+        if cur < end {
+            result.push((cur, end));
+        }
+    }
+    result
+}
+
+/// Returns the first of `fds` which is in one of `ranges`
+pub fn first_covered(ranges: &[(RawFd, RawFd)], fds: &[RawFd])
+    -> Option<RawFd>
+{
+    fds.iter().cloned()
+        .find(|&fd| ranges.iter().any(|&(start, end)| fd >= start && fd < end))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+
+    use super::{out_of_the_way, move_above, close_set, first_covered};
+
+    /// Simulated descriptor table: descriptor to the id of open file
+    struct Table {
+        files: BTreeMap<RawFd, u32>,
+        limit: RawFd,
+        next_file: u32,
+    }
+
+    impl Table {
+        fn open(&mut self) -> RawFd {
+            self.next_file += 1;
+            let fd = self.lowest_free(0).unwrap();
+            self.files.insert(fd, self.next_file);
+            fd
+        }
+        fn lowest_free(&self, min: RawFd) -> io::Result<RawFd> {
+            if min >= self.limit {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            (min..self.limit).find(|fd| !self.files.contains_key(fd))
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EMFILE))
+        }
+        fn dup(&mut self, fd: RawFd, min: RawFd) -> io::Result<RawFd> {
+            let file = self.files[&fd];
+            let new = self.lowest_free(min)?;
+            self.files.insert(new, file);
+            Ok(new)
+        }
+        fn dup2(&mut self, old: RawFd, new: RawFd) {
+            let file = self.files[&old];
+            self.files.insert(new, file);
+        }
+    }
+
+    fn random_table(rng: &mut StdRng) -> Table {
+        let mut table = Table {
+            files: BTreeMap::new(),
+            limit: rng.gen_range(40..200),
+            next_file: 0,
+        };
+        for _ in 0..rng.gen_range(0..30) {
+            table.open();
+        }
+        // leave some holes
+        let open = table.files.keys().cloned().collect::<Vec<_>>();
+        for fd in open {
+            if rng.gen_bool(0.3) {
+                table.files.remove(&fd);
+            }
+        }
+        table
+    }
+
+    #[test]
+    fn test_child_table() {
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut table = random_table(&mut rng);
+            let mut dests = HashMap::new();
+            for _ in 0..rng.gen_range(0..12) {
+                dests.insert(rng.gen_range(0..20), ());
+            }
+            // like `prepare_descriptors`: inherited, passed by the user or
+            // created (pipes, /dev/null)
+            let mut plan = Vec::new();
+            let mut expected = HashMap::new();
+            for &dest in dests.keys() {
+                let open = table.files.keys().cloned().collect::<Vec<_>>();
+                let fd = match rng.gen_range(0..3) {
+                    0 if table.files.contains_key(&dest) => dest,
+                    1 if !open.is_empty() => *open.choose(&mut rng).unwrap(),
+                    _ => table.open(),
+                };
+                expected.insert(dest, table.files[&fd]);
+                let fd = out_of_the_way(fd, dest, &dests,
+                                        |fd, min| table.dup(fd, min))
+                    .unwrap();
+                assert!(fd == dest || !dests.contains_key(&fd),
+                        "seed {}", seed);
+                plan.push((dest, fd));
+            }
+            // the child iterates over a hash map, so in any order
+            plan.shuffle(&mut rng);
+            for &(dest, src) in &plan {
+                if src != dest {
+                    table.dup2(src, dest);
+                }
+            }
+            for (dest, file) in expected {
+                assert_eq!(table.files[&dest], file,
+                           "seed {}, fd {}, plan {:?}", seed, dest, plan);
+            }
+        }
+    }
+
+    #[test]
+    fn test_internal_fd() {
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut table = random_table(&mut rng);
+            let mut dests = HashMap::new();
+            for _ in 0..rng.gen_range(0..12) {
+                dests.insert(rng.gen_range(0..120), ());
+            }
+            let floor = rng.gen_range(0..250);
+            let fd = table.open();
+            let file = table.files[&fd];
+            let moved = match move_above(fd, floor, &dests,
+                                         |fd, min| table.dup(fd, min))
+            {
+                Ok(moved) => moved,
+                Err(e) => {
+                    // only when there is no free descriptor at all
+                    assert_eq!(e.raw_os_error(), Some(libc::EMFILE));
+                    continue;
+                }
+            };
+            assert_eq!(table.files[&moved], file, "seed {}", seed);
+            assert!(!dests.contains_key(&moved), "seed {}", seed);
+            if floor < table.limit {
+                assert!(moved >= floor, "seed {}", seed);
+            } else {
+                assert!(moved >= 3, "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_close_set() {
+        assert_eq!(close_set(&[(3, 10), (8, 20), (30, 30)], &[5, 6, 19, 50]),
+                   [(3, 5), (7, 19)]);
+        assert_eq!(close_set(&[(3, 5)], &[3, 4, 4]), []);
+        assert_eq!(close_set(&[(40, 50), (3, 10)], &[]), [(3, 10), (40, 50)]);
+        assert_eq!(first_covered(&[(3, 10)], &[100, 9, 4]), Some(9));
+        assert_eq!(first_covered(&[(3, 10)], &[10, 2]), None);
+    }
+}
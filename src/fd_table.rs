@@ -0,0 +1,117 @@
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::{Child, Fd};
+use crate::pipe::PipeHolder;
+
+
+/// How a descriptor of the child was set up, see `Child::fd_table`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FdKind {
+    /// Reading end of a pipe, the parent has the writing end
+    ReadPipe,
+    /// Writing end of a pipe, the parent has the reading end
+    WritePipe,
+    /// One end of a `SOCK_SEQPACKET` socket pair, the parent has the other
+    SeqPacket,
+    /// Opened `/dev/null` for reading
+    ReadNull,
+    /// Opened `/dev/null` for writing
+    WriteNull,
+    /// Inherited from the parent process
+    Inherit,
+    /// Descriptor passed by the application (e.g. `Fd::from_file`)
+    Raw,
+}
+
+/// Descriptor of the child, see `Child::fd_table`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdEntry {
+    /// Descriptor number in the child
+    pub fd: RawFd,
+    /// How it was set up
+    pub kind: FdKind,
+    /// The parent's end of the pipe or socket, while it's owned by `Child`
+    ///
+    /// It's `None` for other kinds and once the end is taken by
+    /// `take_stdin`, `take_pipe_reader`, `take_socket` and similar.
+    pub parent_fd: Option<RawFd>,
+}
+
+impl FdKind {
+    pub(crate) fn of(fd: &Fd) -> FdKind {
+        match *fd {
+            Fd::ReadPipe => FdKind::ReadPipe,
+            Fd::WritePipe => FdKind::WritePipe,
+            Fd::SeqPacket => FdKind::SeqPacket,
+            Fd::ReadNull => FdKind::ReadNull,
+            Fd::WriteNull => FdKind::WriteNull,
+            Fd::Inherit | Fd::InheritOrNull => FdKind::Inherit,
+            Fd::Fd(_) => FdKind::Raw,
+        }
+    }
+}
+
+impl Child {
+    /// Returns descriptors configured for the child, ordered by number
+    ///
+    /// This lists every descriptor set by `stdin`, `stdout`, `stderr`,
+    /// `file_descriptor` and similar methods, so frameworks can wire
+    /// protocols over extra descriptors without tracking the configuration.
+    /// Empty for children created by `Child::from_pid`.
+    #[allow(deprecated)]
+    pub fn fd_table(&self) -> Vec<FdEntry> {
+        self.fd_kinds.iter().map(|&(fd, kind)| {
+            let parent_fd = match fd {
+                0 => self.stdin.as_ref().map(|x| x.as_raw_fd()),
+                1 => self.stdout.as_ref().map(|x| x.as_raw_fd()),
+                2 => self.stderr.as_ref().map(|x| x.as_raw_fd()),
+                _ => None,
+            }.or_else(|| self.fds.get(&fd).map(|holder| match *holder {
+                PipeHolder::Reader(ref x) => x.as_raw_fd(),
+                PipeHolder::Writer(ref x) => x.as_raw_fd(),
+                PipeHolder::Socket(ref x) => x.as_raw_fd(),
+            }));
+            FdEntry { fd, kind, parent_fd }
+        }).collect()
+    }
+
+    /// Returns descriptors closed by the child for `close_fds`, in order
+    ///
+    /// These are the ranges of `Command::close_fds` merged, with the
+    /// configured, kept (`keep_fds`) and internal descriptors cut out.
+    /// Empty for children created by `Child::from_pid`.
+    pub fn closed_fds(&self) -> Vec<Range<RawFd>> {
+        self.closed_fds.iter().map(|&(start, end)| start..end).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::io::AsRawFd;
+
+    use crate::{Command, Stdio, Fd};
+    use super::{FdEntry, FdKind};
+
+    #[test]
+    fn test_fd_table() {
+        let mut child = Command::new("/bin/true")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .file_descriptor(3, Fd::seqpacket())
+            .spawn().unwrap();
+        let table = child.fd_table();
+        let stdout = child.take_stdout().unwrap();
+        let socket = child.take_socket(3).unwrap();
+        assert_eq!(table, [
+            FdEntry { fd: 0, kind: FdKind::ReadNull, parent_fd: None },
+            FdEntry { fd: 1, kind: FdKind::WritePipe,
+                      parent_fd: Some(stdout.as_raw_fd()) },
+            FdEntry { fd: 2, kind: FdKind::Inherit, parent_fd: None },
+            FdEntry { fd: 3, kind: FdKind::SeqPacket,
+                      parent_fd: Some(socket.as_raw_fd()) },
+        ]);
+        assert!(child.fd_table().iter().all(|x| x.parent_fd.is_none()));
+        child.wait().unwrap();
+    }
+}
@@ -1,13 +1,17 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
 use std::mem::zeroed;
 use std::ops::{Range, RangeTo, RangeFrom, RangeFull};
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd, OwnedFd};
 
-use nix::errno::errno;
-use libc::getrlimit;
-use libc::RLIMIT_NOFILE;
+use libc::{getrlimit, fcntl};
+use libc::{RLIMIT_NOFILE, F_GETFD, F_SETFD, FD_CLOEXEC};
 
-use crate::stdio::{Fd};
+use crate::stdio::{Fd, FdConfig, Closing};
 use crate::Command;
+use crate::error::ConfigError;
+use crate::sys::errno;
 
 
 /// This is just a temporary enum to coerce `std::ops::Range*` variants
@@ -17,25 +21,113 @@ pub enum AnyRange {
     Range(RawFd, RawFd),
 }
 
+/// A mapping of the file descriptor of the current process to the file
+/// descriptor in the child
+///
+/// Used in `Command::fd_mappings`.
+#[derive(Debug)]
+pub struct FdMapping {
+    /// The file descriptor in the current process (it is closed by `unshare`)
+    pub parent_fd: OwnedFd,
+    /// The target file descriptor number in the child process
+    pub child_fd: RawFd,
+}
+
+/// Error returned from `Command::fd_mappings` when the same child file
+/// descriptor is mapped more than once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdMappingCollision {
+    /// The child file descriptor that has been mapped twice
+    pub child_fd: RawFd,
+}
+
+impl fmt::Display for FdMappingCollision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "child file descriptor {} is mapped more than once",
+            self.child_fd)
+    }
+}
+
+impl Error for FdMappingCollision {}
+
 
 impl Command {
 
+    /// Configure any file descriptor, including stdio
+    ///
+    /// This is a generic form of `stdin`, `stdout`, `stderr` and
+    /// `file_descriptor`, useful for code building the whole descriptor
+    /// plan at once. A `Stdio` value has the same meaning as for the
+    /// respective methods: `Stdio::piped()` makes a pipe the child reads
+    /// from for fd 0 and writes to for any other fd. An `Fd` value is used
+    /// as is, so, for example, `Fd::piped_read()` works for fd 1 too (use
+    /// `Child::take_pipe_writer(1)` to get the other end of such pipe).
+    ///
+    /// # Panics
+    ///
+    /// Panics for negative `target_fd`.
+    pub fn fd<C: Into<FdConfig>>(&mut self, target_fd: RawFd, cfg: C)
+        -> &mut Command
+    {
+        assert!(target_fd >= 0, "negative file descriptor {}", target_fd);
+        let fd = match cfg.into() {
+            FdConfig::Stdio(cfg) => cfg.to_fd(target_fd != 0),
+            FdConfig::Fd(fd) => fd,
+        };
+        self.fds.insert(target_fd, fd);
+        self
+    }
+
     /// Configuration for any other file descriptor (panics for fds < 3) use
-    /// stdin/stdout/stderr for them
+    /// stdin/stdout/stderr for them (or `fd` for generic code)
     ///
     /// Rust creates file descriptors with CLOEXEC flag by default, so no
     /// descriptors are inherited except ones specifically configured here
     /// (and stdio which is inherited by default)
     pub fn file_descriptor(&mut self, target_fd: RawFd, cfg: Fd)
         -> &mut Command
+    {
+        self.try_file_descriptor(target_fd, cfg)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Configure the file descriptor, returning an error for fds < 3
+    /// instead of panicking
+    ///
+    /// See `file_descriptor`.
+    pub fn try_file_descriptor(&mut self, target_fd: RawFd, cfg: Fd)
+        -> Result<&mut Command, ConfigError>
     {
         if target_fd <= 2 {
-            panic!("Stdio file descriptors must be configured with respective \
-                    methods instead of passing fd {} to `file_descritor()`",
-                    target_fd)
+            return Err(ConfigError::StdioFd(target_fd));
         }
         self.fds.insert(target_fd, cfg);
-        self
+        Ok(self)
+    }
+
+    /// Configure multiple file descriptors at once
+    ///
+    /// This works similarly to `file_descriptor(child_fd,
+    /// Fd::from_file(parent_fd))` for each mapping, but also allows to map
+    /// stdio descriptors (0, 1, 2). The dup2 ordering needed to put
+    /// descriptors in place even if they overlap, is done by the library.
+    ///
+    /// If any child fd appears twice in the list an error is returned and no
+    /// mappings are applied (the descriptors are closed). Mappings replace
+    /// previously configured descriptors with the same number.
+    pub fn fd_mappings(&mut self, mappings: Vec<FdMapping>)
+        -> Result<&mut Command, FdMappingCollision>
+    {
+        let mut seen = HashSet::new();
+        for mapping in &mappings {
+            if !seen.insert(mapping.child_fd) {
+                return Err(FdMappingCollision { child_fd: mapping.child_fd });
+            }
+        }
+        for FdMapping { parent_fd, child_fd } in mappings {
+            self.fds.insert(child_fd, Fd::from_file(parent_fd));
+        }
+        Ok(self)
     }
 
     /// Close a range of file descriptors as soon as process forks
@@ -50,6 +142,7 @@ impl Command {
     ///   methods
     /// * internal file descriptors used for parent child notification by
     ///   unshare crate itself (they are guaranteed to have CLOEXEC)
+    /// * descriptors listed in `keep_fds`
     ///
     /// You should avoid this method if possilble and rely on CLOEXEC to
     /// do the work. But sometimes it's inevitable:
@@ -74,24 +167,108 @@ impl Command {
     ///
     /// Panics when lower range of fd is < 3 (stdio file descriptors)
     ///
+    /// See `try_close_fds` for the version returning errors instead.
     pub fn close_fds<A: Into<AnyRange>>(&mut self, range: A)
         -> &mut Command
     {
-        self.close_fds.push(match range.into() {
-            AnyRange::Range(x, y) => {
-                assert!(x >= 3);
-                (x, y)
-            }
-            AnyRange::RangeFrom(x) => unsafe {
-                assert!(x >= 3);
+        self.try_close_fds(range).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Close a range of file descriptors, returning an error instead of
+    /// panicking
+    ///
+    /// See `close_fds`.
+    pub fn try_close_fds<A: Into<AnyRange>>(&mut self, range: A)
+        -> Result<&mut Command, ConfigError>
+    {
+        let (start, end) = match range.into() {
+            AnyRange::Range(x, y) => (x, Some(y)),
+            AnyRange::RangeFrom(x) => (x, None),
+        };
+        if start < 3 {
+            return Err(ConfigError::StdioFd(start));
+        }
+        let end = match end {
+            Some(end) => end,
+            None => unsafe {
                 let mut rlim = zeroed();
-                let rc = getrlimit(RLIMIT_NOFILE, &mut rlim);
-                if rc < 0 {
-                    panic!("Can't get rlimit: errno {}", errno());
+                if getrlimit(RLIMIT_NOFILE, &mut rlim) < 0 {
+                    return Err(ConfigError::FdLimit(errno()));
                 }
-                (x, rlim.rlim_cur as RawFd)
+                rlim.rlim_cur as RawFd
             }
-        });
+        };
+        self.close_fds.push((start, end));
+        Ok(self)
+    }
+
+    /// Fail the spawn if `close_fds` ranges cover descriptors in use
+    ///
+    /// Normally, descriptors configured for the child (by `file_descriptor`
+    /// and similar) and ones used by the library internally are silently
+    /// left open when they're in a range. With this enabled, `spawn()`
+    /// fails with `Error::FdPlanConflict` before the child is created
+    /// instead. Internal descriptors are at or above `internal_fd_floor`,
+    /// so limit the ranges to stay below it. Descriptors of `keep_fds`
+    /// are never a conflict. See `Child::closed_fds` for the descriptors
+    /// actually closed.
+    pub fn strict_close_fds(&mut self, enable: bool) -> &mut Command {
+        self.config.strict_close_fds = enable;
+        self
+    }
+
+    /// Don't close these file descriptors when closing ranges of fds
+    ///
+    /// This is useful for descriptors that you don't configure explicitly,
+    /// but want to survive `close_fds(..)`, for example ones owned by a
+    /// logging library. The descriptors are kept as is: no `dup2` is done and
+    /// the `CLOEXEC` flag is not cleared (so only ones without the flag are
+    /// inherited by the executed program).
+    ///
+    /// Subsequent calls add more descriptors. Use `reset_fds` to clear the
+    /// list.
+    pub fn keep_fds(&mut self, fds: &[RawFd]) -> &mut Command {
+        self.keep_fds.extend_from_slice(fds);
+        self
+    }
+
+    /// Notify external observers when the child executes the program
+    ///
+    /// The descriptor is kept open in the child until `execve` succeeds (or
+    /// the child dies), and it's closed in the current process right after
+    /// the child is cloned. So if `fd` is a writing end of a pipe, the
+    /// process holding the reading end gets end of file exactly when the
+    /// child has executed, or has failed to do so. Unlike waiting for the
+    /// `spawn()` to return, this works for any third process too.
+    ///
+    /// The descriptor is owned by command, the `CLOEXEC` flag is set on it,
+    /// and it's used only for the next `spawn()`. Make sure there are no
+    /// other copies of it open, or no end of file will be observed.
+    pub fn notify_exec<F: IntoRawFd>(&mut self, fd: F) -> &mut Command {
+        let fd = Closing::new(fd.into_raw_fd());
+        unsafe {
+            let flags = fcntl(fd.as_raw_fd(), F_GETFD);
+            if flags >= 0 {
+                fcntl(fd.as_raw_fd(), F_SETFD, flags | FD_CLOEXEC);
+            }
+        }
+        self.exec_notify = Some(fd);
+        self
+    }
+
+    /// Keep descriptors used internally by the library at or above `floor`
+    ///
+    /// The child uses a few descriptors of its own until the program is
+    /// executed (for reporting errors, `notify_exec` and so on). They are
+    /// moved out of the way of the descriptors configured for the child
+    /// anyway, but keeping them apart from the range used by the
+    /// application makes the descriptor numbers predictable, e.g. for
+    /// `keep_fds` or for a `pre_exec` callback. If the floor is above the
+    /// limit of open files, it's ignored.
+    ///
+    /// Default is 100.
+    pub fn internal_fd_floor(&mut self, floor: RawFd) -> &mut Command {
+        self.config.internal_fd_floor = floor.max(3);
         self
     }
 
@@ -105,6 +282,8 @@ impl Command {
                 (2, Fd::inherit()),
                 ].into_iter().collect();
         self.close_fds.clear();
+        self.keep_fds.clear();
+        self.exec_notify = None;
         self
     }
 }
@@ -132,3 +311,98 @@ impl Into<AnyRange> for RangeFull {
         return AnyRange::RangeFrom(3);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    use crate::{Command, ConfigError, Error, Fd, FdConfig, Stdio};
+    use crate::sys;
+
+    #[test]
+    fn test_try_variants() {
+        let mut cmd = Command::new("/bin/true");
+        let err = cmd.try_file_descriptor(1, Fd::ReadNull).unwrap_err();
+        assert_eq!(err, ConfigError::StdioFd(1));
+        assert!(err.to_string().contains("got fd 1"), "{}", err);
+        assert_eq!(cmd.try_close_fds(2..10).unwrap_err(),
+                   ConfigError::StdioFd(2));
+        cmd.try_file_descriptor(3, Fd::ReadNull).unwrap()
+            .try_close_fds(4..).unwrap();
+        assert_eq!(cmd.close_fds.len(), 1);
+    }
+
+    #[test]
+    fn test_strict_close_fds() {
+        let mut child = Command::new("/bin/true")
+            .file_descriptor(5, Fd::ReadNull)
+            .close_fds(3..20)
+            .keep_fds(&[7])
+            .spawn().unwrap();
+        assert_eq!(child.closed_fds(), [3..5, 6..7, 8..20]);
+        child.wait().unwrap();
+        let err = Command::new("/bin/true")
+            .file_descriptor(5, Fd::ReadNull)
+            .close_fds(3..20)
+            .strict_close_fds(true)
+            .spawn().unwrap_err();
+        assert!(matches!(err, Error::FdPlanConflict(5)), "{:?}", err);
+        // the error pipe is above the floor
+        let err = Command::new("/bin/true")
+            .close_fds(..)
+            .strict_close_fds(true)
+            .spawn().unwrap_err();
+        assert!(matches!(err, Error::FdPlanConflict(fd) if fd >= 100),
+                "{:?}", err);
+        let mut child = Command::new("/bin/true")
+            .close_fds(3..100)
+            .strict_close_fds(true)
+            .spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_error_survives_close_fds() {
+        let err = Command::new("/nonexistent").close_fds(..)
+            .spawn().unwrap_err();
+        assert!(matches!(err, Error::Exec(libc::ENOENT)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_error_survives_low_fds() {
+        let mut cmd = Command::new("/nonexistent");
+        cmd.internal_fd_floor(50);
+        let file = File::open("/dev/null").unwrap();
+        for fd in 3..60 {
+            // keep low descriptors free, so internal ones are created there
+            let high = sys::dup_cloexec(file.as_raw_fd(), 200).unwrap();
+            let high = unsafe { OwnedFd::from_raw_fd(high) };
+            cmd.file_descriptor(fd, Fd::from_file(high));
+        }
+        drop(file);
+        let err = cmd.spawn().unwrap_err();
+        assert!(matches!(err, Error::Exec(libc::ENOENT)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_generic_fd_plan() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("cat; echo three >&3");
+        let plan: Vec<(RawFd, FdConfig)> = vec![
+            (0, Fd::read_null().into()),
+            (1, Stdio::piped().into()),
+            (3, Stdio::piped().into()),
+        ];
+        for (fd, cfg) in plan {
+            cmd.fd(fd, cfg);
+        }
+        let mut child = cmd.spawn().unwrap();
+        let mut out = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut out).unwrap();
+        child.take_pipe_reader(3).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "three\n");
+        assert!(child.wait().unwrap().success());
+    }
+}
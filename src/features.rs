@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::Path;
+use std::ptr;
+use std::sync::Mutex;
+
+use crate::Capability;
+use crate::freeze::cgroup2_mount;
+use crate::sys::{self, errno};
+
+
+static FEATURES: Mutex<Option<Features>> = Mutex::new(None);
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+
+/// Kernel features available to the current process
+///
+/// See `kernel_features`. Each flag tells whether the feature works right
+/// now, so a feature missing in the kernel and one blocked (e.g. by a
+/// seccomp filter of the container we are running in) are both `false`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// `pidfd_open` (linux 5.3), used for `Command::pidfd` and `kill_fd`
+    pub pidfd: bool,
+    /// `clone3` system call (linux 5.3)
+    pub clone3: bool,
+    /// `close_range` system call (linux 5.9)
+    pub close_range: bool,
+    /// Ambient capabilities (linux 4.3), see `allow_privileged_ports`
+    pub ambient_caps: bool,
+    /// Unified cgroup hierarchy is mounted, see `Child::freeze`
+    pub cgroup2: bool,
+    /// Time namespace (linux 5.6), see `virtual_clock`
+    pub time_namespace: bool,
+    /// Landlock LSM is enabled (linux 5.13)
+    pub landlock: bool,
+    /// `mount_setattr` (linux 5.12), required by `idmapped_mount`, the
+    /// file system must support idmapped mounts too
+    pub idmapped_mounts: bool,
+    /// User namespace can be created by the current process
+    pub user_namespaces: bool,
+}
+
+/// Probe kernel features available to the current process
+///
+/// Features are probed on the first call by invoking the respective
+/// system calls in the way which has no side effects (user namespace is
+/// checked by creating a process in a new one which exits immediately).
+/// The result is cached for the lifetime of the process, so changes
+/// (e.g. of sysctls) after the first call are not noticed.
+pub fn kernel_features() -> Features {
+    let mut cached = FEATURES.lock().unwrap_or_else(|e| e.into_inner());
+    *cached.get_or_insert_with(Features::probe)
+}
+
+impl Features {
+    fn probe() -> Features {
+        Features {
+            pidfd: probe_pidfd(),
+            clone3: probe_clone3(),
+            close_range: unsafe {
+                // closes nothing, the range is valid
+                libc::syscall(libc::SYS_close_range, !0u32, !0u32, 0) == 0
+            },
+            ambient_caps: unsafe {
+                libc::prctl(libc::PR_CAP_AMBIENT,
+                            libc::PR_CAP_AMBIENT_IS_SET,
+                            Capability::CAP_CHOWN as libc::c_ulong,
+                            0, 0) >= 0
+            },
+            cgroup2: cgroup2_mount().is_some(),
+            time_namespace: Path::new("/proc/self/ns/time").exists(),
+            landlock: unsafe {
+                libc::syscall(libc::SYS_landlock_create_ruleset,
+                              ptr::null::<libc::c_void>(), 0,
+                              LANDLOCK_CREATE_RULESET_VERSION) >= 1
+            },
+            idmapped_mounts: probe_mount_setattr(),
+            user_namespaces: probe_user_namespaces(),
+        }
+    }
+}
+
+fn probe_pidfd() -> bool {
+    match sys::pidfd_open(unsafe { libc::getpid() }) {
+        Ok(fd) => {
+            unsafe { libc::close(fd) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn probe_clone3() -> bool {
+    // arguments of zero size are always rejected
+    let rc = unsafe {
+        libc::syscall(libc::SYS_clone3, ptr::null::<libc::c_void>(), 0)
+    };
+    rc < 0 && errno() == libc::EINVAL
+}
+
+fn probe_mount_setattr() -> bool {
+    let rc = unsafe {
+        libc::syscall(libc::SYS_mount_setattr, -1, ptr::null::<libc::c_char>(),
+                      0, ptr::null::<libc::c_void>(), 0)
+    };
+    rc < 0 && errno() != libc::ENOSYS && errno() != libc::EPERM
+}
+
+fn probe_user_namespaces() -> bool {
+    let max = fs::read_to_string("/proc/sys/user/max_user_namespaces");
+    if max.is_ok_and(|x| x.trim() == "0") {
+        return false;
+    }
+    // raw clone instead of `fork` which runs `pthread_atfork` handlers
+    let pid = unsafe {
+        libc::syscall(libc::SYS_clone, libc::CLONE_NEWUSER | libc::SIGCHLD,
+                      0, 0, 0, 0)
+    };
+    match pid {
+        -1 => false,
+        0 => unsafe { libc::_exit(0) },
+        pid => {
+            // may be reaped by `reap_zombies` in another thread first
+            sys::waitpid(pid as libc::pid_t, 0).ok();
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::kernel_features;
+
+    #[test]
+    fn test_features() {
+        let features = kernel_features();
+        assert_eq!(features, kernel_features());
+        assert_eq!(features.time_namespace,
+                   Path::new("/proc/self/ns/time").exists());
+        // required by the rest of the tests
+        assert!(features.pidfd);
+    }
+}
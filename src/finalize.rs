@@ -0,0 +1,273 @@
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+use std::slice;
+
+use libc::c_char;
+
+use crate::Command;
+use crate::error::Error;
+use crate::no_alloc::MAX_PID_LEN;
+
+
+/// Arguments and environment of the program, as passed to `execve`
+///
+/// Given to the callback set by `Command::finalize_with`.
+#[derive(Debug)]
+pub struct FinalSpec {
+    pub(crate) pid: u32,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+}
+
+impl FinalSpec {
+    /// Pid of the child (in the pid namespace of the current process)
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+    /// Arguments, including `argv[0]`
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+    /// Arguments to edit
+    pub fn args_mut(&mut self) -> &mut Vec<OsString> {
+        &mut self.args
+    }
+    /// Variables of the environment in no particular order, except ones
+    /// set by `env_var_with_pid`, which are set by the child afterwards
+    pub fn env(&self) -> &[(OsString, OsString)] {
+        &self.env
+    }
+    /// Environment to edit
+    pub fn env_mut(&mut self) -> &mut Vec<(OsString, OsString)> {
+        &mut self.env
+    }
+    /// Sets the variable, replacing the existing value
+    pub fn set_env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K,
+        value: V)
+    {
+        let (key, value) = (key.as_ref(), value.as_ref().to_os_string());
+        match self.env.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.env.push((key.to_os_string(), value)),
+        }
+    }
+}
+
+pub(crate) type FinalizeCallback = Box<dyn FnMut(&mut FinalSpec)>;
+
+/// Memory shared with the child, where the final exec arrays are written
+///
+/// The child is cloned without `CLONE_VM`, so the mapping has the same
+/// address in both processes, and pointers written by the parent are valid
+/// in the child. Pages are only allocated when written.
+pub(crate) struct SharedExec {
+    ptr: *mut u8,
+    len: usize,
+}
+
+/// Header at the start of `SharedExec`, followed by `argv` and `envp`
+/// arrays (with null terminators) and the strings
+#[repr(C)]
+struct Header {
+    args: usize,
+    env: usize,
+    /// Index in `envp` of the first `env_var_with_pid` variable
+    pid_vars: usize,
+}
+
+pub(crate) struct FinalExec {
+    pub spec: FinalSpec,
+    pub pid_vars: Vec<OsString>,
+    pub shared: SharedExec,
+}
+
+impl SharedExec {
+    fn new() -> io::Result<SharedExec> {
+        let arg_max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+        // pointers are not counted by the limit, but are at most as big
+        let len = (arg_max.max(1 << 17) as usize) * 4;
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len,
+                libc::PROT_READ|libc::PROT_WRITE,
+                libc::MAP_SHARED|libc::MAP_ANONYMOUS|libc::MAP_NORESERVE,
+                -1, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SharedExec { ptr: ptr as *mut u8, len })
+    }
+
+    /// Writes the arrays, fails with `Exec(E2BIG)` if they don't fit
+    fn write(&self, args: &[&[u8]], env: &[Vec<u8>], pid_vars: usize)
+        -> Result<(), Error>
+    {
+        let pointers = args.len() + 1 + env.len() + 1;
+        let strings = args.iter().map(|x| x.len() + 1).sum::<usize>() +
+            env.iter().map(|x| x.len() + 1).sum::<usize>();
+        let start = mem::size_of::<Header>() +
+            pointers * mem::size_of::<*const c_char>();
+        if start + strings > self.len {
+            return Err(Error::Exec(libc::E2BIG));
+        }
+        unsafe {
+            (self.ptr as *mut Header).write(Header {
+                args: args.len(),
+                env: env.len(),
+                pid_vars: env.len() - pid_vars,
+            });
+            let argv = self.ptr.add(mem::size_of::<Header>())
+                as *mut *const u8;
+            let envp = argv.add(args.len() + 1);
+            let mut pos = self.ptr.add(start);
+            let mut put = |slot: *mut *const u8, data: &[u8]| {
+                slot.write(pos);
+                pos.copy_from_nonoverlapping(data.as_ptr(), data.len());
+                pos.add(data.len()).write(0);
+                pos = pos.add(data.len() + 1);
+            };
+            for (idx, arg) in args.iter().enumerate() {
+                put(argv.add(idx), arg);
+            }
+            argv.add(args.len()).write(ptr::null());
+            for (idx, pair) in env.iter().enumerate() {
+                put(envp.add(idx), pair);
+            }
+            envp.add(env.len()).write(ptr::null());
+        }
+        Ok(())
+    }
+
+    /// Returns `argv`, `envp` and the index of the first pid variable
+    ///
+    /// Called in the child, doesn't allocate.
+    pub unsafe fn arrays(&self)
+        -> (&[*const c_char], &[*mut c_char], usize)
+    {
+        let header = &*(self.ptr as *const Header);
+        let argv = self.ptr.add(mem::size_of::<Header>())
+            as *const *const c_char;
+        let envp = argv.add(header.args + 1) as *const *mut c_char;
+        (slice::from_raw_parts(argv, header.args + 1),
+         slice::from_raw_parts(envp, header.env + 1),
+         header.pid_vars)
+    }
+}
+
+impl Drop for SharedExec {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+impl FinalExec {
+    pub fn new(args: Vec<OsString>, env: Vec<(OsString, OsString)>,
+        pid_vars: Vec<OsString>)
+        -> io::Result<FinalExec>
+    {
+        Ok(FinalExec {
+            spec: FinalSpec { pid: 0, args, env },
+            pid_vars,
+            shared: SharedExec::new()?,
+        })
+    }
+
+    /// Serializes the spec for the child, fails with `Exec(EINVAL)` if
+    /// there are null bytes
+    pub fn write(&self) -> Result<(), Error> {
+        let has_nul = |x: &OsStr| x.as_bytes().contains(&0);
+        if self.spec.args.iter().any(|x| has_nul(x)) ||
+            self.spec.env.iter().any(|(k, v)| has_nul(k) || has_nul(v))
+        {
+            return Err(Error::Exec(libc::EINVAL));
+        }
+        let args = self.spec.args.iter().map(|x| x.as_bytes())
+            .collect::<Vec<_>>();
+        let pair = |k: &OsStr, v: &[u8]| {
+            let mut pair = k.as_bytes().to_vec();
+            pair.push(b'=');
+            pair.extend(v);
+            pair
+        };
+        let mut env = self.spec.env.iter()
+            // set by the child afterwards
+            .filter(|(k, _)| !self.pid_vars.contains(k))
+            .map(|(k, v)| pair(k, v.as_bytes()))
+            .collect::<Vec<_>>();
+        for name in &self.pid_vars {
+            env.push(pair(name, &[0; MAX_PID_LEN+1]));
+        }
+        self.shared.write(&args, &env, self.pid_vars.len())
+    }
+}
+
+impl Command {
+    /// Set a callback to edit arguments and environment after the pid of
+    /// the child is known
+    ///
+    /// The callback runs in the parent right after `before_unfreeze`,
+    /// and the child executes the program with the arguments and the
+    /// environment edited by the callback (the program itself can't be
+    /// changed). This is for pid-dependent values beyond
+    /// `env_var_with_pid`, e.g. the path of the cgroup created for the
+    /// child in `before_unfreeze`.
+    ///
+    /// `spawn()` fails with `Error::Exec(EINVAL)` if the new values contain
+    /// null bytes and with `Error::Exec(E2BIG)` if they are too long.
+    ///
+    /// Each invocation **replaces** previously set callback.
+    pub fn finalize_with(&mut self,
+        f: impl FnMut(&mut FinalSpec) + 'static)
+        -> &mut Command
+    {
+        self.finalize = Some(Box::new(f));
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use crate::{Command, Stdio, Error};
+
+    #[test]
+    fn test_finalize_with() {
+        let mut child = Command::new("/bin/sh")
+            .arg("-c").arg("echo \"$0 $1 $APP_PID $SELF_PID $KEPT\" $$")
+            .env("APP_PID", "unknown").env("KEPT", "kept")
+            .env_var_with_pid("SELF_PID")
+            .finalize_with(|spec| {
+                let pid = spec.pid().to_string();
+                spec.args_mut().push("arg".into());
+                spec.args_mut().push(pid.clone().into());
+                spec.set_env("APP_PID", &pid);
+                // the child sets it afterwards
+                spec.set_env("SELF_PID", "wrong");
+            })
+            .stdout(Stdio::piped())
+            .spawn().unwrap();
+        let mut output = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        let pid = child.pid();
+        assert_eq!(output,
+            format!("arg {0} {0} {0} kept {0}\n", pid));
+    }
+
+    #[test]
+    fn test_null_byte() {
+        match Command::new("/bin/true")
+            .finalize_with(|spec| spec.args_mut().push("a\0b".into()))
+            .spawn()
+        {
+            Err(Error::Exec(code)) => assert_eq!(code, libc::EINVAL),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::Command;
+use crate::elf::{elf_machine, read_header};
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::ffi_util::ToCString;
+use crate::mount_provider::clone_tree;
+use crate::chroot::in_root;
+use crate::cleanup::SpawnCleanup;
+
+
+/// How to exec the program through the interpreter
+pub struct ForeignExec {
+    pub filename: CString,
+    pub args: Vec<CString>,
+    /// Host path of the bind mount target, and the detached bind mount
+    pub mount: Option<(CString, File)>,
+    /// The mount point created for this child, removed when it's reaped
+    pub created: Option<PathBuf>,
+}
+
+fn machine_of(path: &Path) -> Option<u16> {
+    read_header(path).and_then(|data| elf_machine(&data))
+}
+
+impl Command {
+    /// Run programs built for another architecture by `interpreter`
+    ///
+    /// This is meant for qemu-user (`qemu-aarch64` and alike, preferably
+    /// statically linked), so no global `binfmt_misc` configuration is
+    /// needed. On `spawn()` the ELF header of the program is read. If the
+    /// machine differs from the one of the current process, the
+    /// interpreter is executed instead as:
+    ///
+    /// ```text
+    /// <interpreter> -0 <argv[0]> <program> <args...>
+    /// ```
+    ///
+    /// The `interpreter` is a path in the current mount namespace. If the
+    /// root is changed (`pivot_root` or `chroot_dir`), and there is no file
+    /// at the same path in the new root, the interpreter is bind-mounted
+    /// there. If there is no file, an empty one is created as a mount point
+    /// and removed when the child is reaped (it's reused by children which
+    /// start meanwhile, and their mount is detached when it's removed, so
+    /// put a file there if children with that root overlap). This requires
+    /// mount namespace and linux 5.2.
+    ///
+    /// Like for `preload`, the checks are done in the parent's mount
+    /// namespace. Program given by relative path is only checked if root
+    /// is not changed.
+    pub fn foreign_arch_interpreter<P: AsRef<Path>>(&mut self, path: P)
+        -> &mut Command
+    {
+        self.foreign_interpreter = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Returns how to exec the program if it needs the interpreter
+    pub(crate) fn foreign_exec(&self, cleanup: &mut SpawnCleanup)
+        -> Result<Option<ForeignExec>, Error>
+    {
+        let interpreter = match self.foreign_interpreter {
+            Some(ref path) => path,
+            None => return Ok(None),
+        };
+        let root = self.host_root();
+        let changed_root = root != Path::new("/");
+        let program = Path::new(OsStr::from_bytes(self.filename.as_bytes()));
+        let host_program = if program.is_absolute() {
+            in_root(&root, program)
+        } else if !changed_root {
+            program.to_path_buf()
+        } else {
+            return Ok(None);
+        };
+        match (machine_of(&host_program),
+               machine_of(Path::new("/proc/self/exe")))
+        {
+            (Some(program), Some(own)) if program != own => {}
+            _ => return Ok(None),
+        }
+        result(Err::ForeignInterpreter, fs::metadata(interpreter))?;
+        let target = in_root(&root, interpreter);
+        let present = fs::metadata(&target).ok();
+        // empty file is a mount point of a child which is still running (or
+        // of a process which crashed)
+        let missing = match present {
+            Some(ref m) => m.len() == 0,
+            None => true,
+        };
+        let mut created = None;
+        let mount = if changed_root && missing {
+            if self.config.namespaces & libc::CLONE_NEWNS == 0 {
+                // the mount would be visible by all processes
+                return Err(Error::ForeignInterpreter(libc::EINVAL));
+            }
+            if present.is_none() {
+                result(Err::ForeignInterpreter, OpenOptions::new()
+                    .write(true).create_new(true).open(&target))?;
+                cleanup.remove_file_on_failure(target.clone());
+                created = Some(target.clone());
+            }
+            let tree = result(Err::ForeignInterpreter,
+                              clone_tree(interpreter))?;
+            Some((target.to_cstring(), tree))
+        } else {
+            None
+        };
+        let filename = interpreter.to_cstring();
+        let mut args = vec![filename.clone(), "-0".to_cstring()];
+        args.extend(self.args.first().cloned());
+        args.push(self.filename.clone());
+        args.extend(self.args.iter().skip(1).cloned());
+        Ok(Some(ForeignExec { filename, args, mount, created }))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    use crate::{Command, Namespace, Stdio};
+
+    #[test]
+    #[cfg_attr(not(target_env="gnu"), ignore="needs static glibc ldconfig")]
+    fn test_mount_point_removed() {
+        let interpreter = ["/sbin/ldconfig", "/usr/sbin/ldconfig"].iter()
+            .map(Path::new).find(|p| p.exists()).expect("no ldconfig");
+        let root = env::temp_dir()
+            .join(format!("unshare-test-foreign-{}", process::id()));
+        let target = root.join(interpreter.strip_prefix("/").unwrap());
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        // header of an executable of some other architecture
+        let mut header = [0u8; 64];
+        header[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        header[0x12] = if cfg!(target_arch="aarch64") { 62 } else { 183 };
+        fs::write(root.join("program"), &header[..]).unwrap();
+        let mut child = Command::new("/program")
+            .chroot_dir(&root).unshare(&[Namespace::Mount])
+            .foreign_arch_interpreter(interpreter)
+            .stdout(Stdio::null()).stderr(Stdio::null())
+            .spawn().unwrap();
+        assert_eq!(fs::metadata(&target).unwrap().len(), 0);
+        child.wait().unwrap();
+        assert!(!target.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use std::fs::File;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, Shutdown};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::Child;
+
+
+/// Handle of the port forwarding started by `Child::forward_port`
+///
+/// Forwarding stops accepting connections when this is dropped (or on
+/// `stop`), already established connections are served until closed by
+/// either side.
+#[derive(Debug)]
+pub struct PortForward {
+    listener: Arc<TcpListener>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl PortForward {
+    /// Returns address of the listening socket on the host
+    ///
+    /// Useful when port `0` is passed to `forward_port`
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Stop accepting connections
+    ///
+    /// Returns the error of `accept` if it has stopped forwarding before
+    /// (errors like running out of file descriptors are retried instead).
+    pub fn stop(mut self) -> io::Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wakes up thread blocked in accept()
+        unsafe { libc::shutdown(self.listener.as_raw_fd(), libc::SHUT_RDWR) };
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| Err(
+                io::Error::other("port forwarding thread panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for PortForward {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+    }
+}
+
+/// Accept errors after which accepting of the next connection may succeed
+fn is_transient(err: &io::Error) -> bool {
+    // network errors of the new connection are reported by accept(),
+    // see `man 2 accept`
+    matches!(err.raw_os_error(), Some(libc::EINTR) | Some(libc::ECONNABORTED)
+        | Some(libc::EPROTO) | Some(libc::ENETDOWN) | Some(libc::ENOPROTOOPT)
+        | Some(libc::EHOSTDOWN) | Some(libc::ENONET)
+        | Some(libc::EHOSTUNREACH) | Some(libc::EOPNOTSUPP)
+        | Some(libc::ENETUNREACH))
+}
+
+/// Accept errors which are retried after a pause, as resources may be
+/// freed by closing other connections
+fn is_exhausted(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE)
+        | Some(libc::ENOBUFS) | Some(libc::ENOMEM))
+}
+
+fn connect_in(netns: &File, addr: SocketAddr) -> io::Result<TcpStream> {
+    if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    TcpStream::connect(addr)
+}
+
+fn pump(mut src: TcpStream, mut dest: TcpStream) {
+    io::copy(&mut src, &mut dest).ok();
+    dest.shutdown(Shutdown::Write).ok();
+}
+
+/// Proxies a single connection, runs in a thread which joins the netns
+fn serve(netns: &File, addr: SocketAddr, outer: TcpStream) {
+    let inner = match connect_in(netns, addr) {
+        Ok(inner) => inner,
+        // nothing listens in the child, let the client see it
+        Err(_) => return,
+    };
+    let (outer2, inner2) = match (outer.try_clone(), inner.try_clone()) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => return,
+    };
+    let reverse = thread::spawn(move || pump(inner2, outer2));
+    pump(outer, inner);
+    reverse.join().ok();
+}
+
+impl Child {
+    /// Forward TCP connections from the host to the network namespace
+    /// of the child
+    ///
+    /// Listens on `host_addr` in the network namespace of the current
+    /// process, and for every accepted connection opens one to the
+    /// `child_addr` in the namespace of the child (by a thread that joins
+    /// the namespace with `setns`), copying data in both directions. If
+    /// the connection to the child can't be established, the accepted
+    /// connection is closed. Accepting stops on errors other than
+    /// transient ones, which are reported by `PortForward::stop`.
+    ///
+    /// Threads are used for accepting and for every connection, so this is
+    /// only suitable for a small number of connections, like for debugging
+    /// or administration. Joining the namespace requires `CAP_SYS_ADMIN` in
+    /// the user namespace owning it.
+    pub fn forward_port(&self, host_addr: SocketAddr, child_addr: SocketAddr)
+        -> Result<PortForward, io::Error>
+    {
+        if self.status.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid argument: can't forward to an exited process",
+            ))
+        }
+        let netns = Arc::new(
+            File::open(self.proc_dir().join(format!("{}/ns/net", self.pid)))?);
+        let listener = Arc::new(TcpListener::bind(host_addr)?);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_listener = listener.clone();
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            loop {
+                match thread_listener.accept() {
+                    Ok((conn, _)) => {
+                        let netns = netns.clone();
+                        thread::spawn(move || serve(&netns, child_addr, conn));
+                    }
+                    Err(ref e) if is_transient(e) => {}
+                    Err(ref e) if is_exhausted(e) => {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    // EINVAL when socket is shut down
+                    Err(_) if thread_stopped.load(Ordering::SeqCst) => {
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        });
+        Ok(PortForward { listener, stopped, thread: Some(thread) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use crate::Command;
+
+    #[test]
+    fn test_forward_loopback() {
+        // the child shares the network namespace, so it sees this listener
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let forward = child.forward_port("127.0.0.1:0".parse().unwrap(),
+                                         server_addr).unwrap();
+        let echo = thread::spawn(move || {
+            let (mut conn, _) = server.accept().unwrap();
+            let mut buf = [0u8; 5];
+            conn.read_exact(&mut buf).unwrap();
+            conn.write_all(&buf).unwrap();
+        });
+        let mut client = TcpStream::connect(forward.local_addr().unwrap())
+            .unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"hello");
+        echo.join().unwrap();
+        forward.stop().unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
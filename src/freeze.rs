@@ -0,0 +1,195 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libc::pid_t;
+
+use crate::{Child, Signal};
+
+
+/// Returns path of the cgroup v2 mount point
+pub(crate) fn cgroup2_mount() -> Option<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines()
+        .find(|line| line.split(" - ").nth(1)
+                         .is_some_and(|x| x.starts_with("cgroup2 ")))
+        .and_then(|line| line.split_whitespace().nth(4))
+        .map(PathBuf::from)
+}
+
+/// Returns path of the process in the cgroup v2 hierarchy
+fn cgroup_of(pid: Option<pid_t>) -> Option<String> {
+    let file = match pid {
+        Some(pid) => format!("/proc/{}/cgroup", pid),
+        None => "/proc/self/cgroup".into(),
+    };
+    fs::read_to_string(file).ok()?
+        .lines()
+        .find(|line| line.starts_with("0::"))
+        .map(|line| line[3..].to_string())
+}
+
+/// Checks that freezing `child` cgroup doesn't freeze the `current` one
+fn is_separate(child: &str, current: &str) -> bool {
+    let child = child.trim_end_matches('/');
+    if child.is_empty() {
+        // Root cgroup can't be frozen anyway
+        return false;
+    }
+    !(current == child || current.starts_with(&format!("{}/", child)))
+}
+
+/// Returns `cgroup.freeze` file of the child, if it should be used
+fn freezer(pid: pid_t) -> Option<PathBuf> {
+    let child = cgroup_of(Some(pid))?;
+    let current = cgroup_of(None)?;
+    if !is_separate(&child, &current) {
+        return None;
+    }
+    let path = cgroup2_mount()?
+        .join(Path::new(child.trim_start_matches('/')))
+        .join("cgroup.freeze");
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Appends processes of the cgroup at `dir` and of its descendants
+fn cgroup_pids(dir: &Path, pids: &mut Vec<pid_t>) -> io::Result<()> {
+    let procs = fs::read_to_string(dir.join("cgroup.procs"))?;
+    pids.extend(procs.lines().filter_map(|x| x.parse::<pid_t>().ok()));
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        if item.file_type()?.is_dir() {
+            cgroup_pids(&item.path(), pids)?;
+        }
+    }
+    Ok(())
+}
+
+impl Child {
+    /// Checks that the cgroup at `dir` only has processes of this child
+    fn check_cgroup_owned(&self, dir: &Path) -> io::Result<()> {
+        let mut pids = Vec::new();
+        cgroup_pids(dir, &mut pids)?;
+        // read after the cgroup, so it includes processes forked meanwhile
+        let tree = self.process_tree()?.pids();
+        let foreign = pids.iter()
+            .filter(|pid| !tree.contains(pid))
+            // skip the ones which have exited meanwhile
+            .any(|pid| Path::new(&format!("/proc/{}", pid)).exists());
+        if foreign {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("invalid argument: cgroup {:?} has processes \
+                         which are not descendants of the child", dir)));
+        }
+        Ok(())
+    }
+
+    /// Suspend the process
+    ///
+    /// If the child is in a cgroup v2 of its own (i.e. it's not the cgroup
+    /// of the current process or one of its parents), the whole cgroup is
+    /// frozen by writing to `cgroup.freeze`. This is invisible to the
+    /// processes inside and also suspends all their descendants. Note that
+    /// freezing is asynchronous, wait for `frozen 1` in `cgroup.events` if
+    /// you need the cgroup to be fully frozen (e.g. for checkpointing).
+    /// If the cgroup (or any cgroup below it) has other processes than the
+    /// child and its descendants, e.g. when children share the cgroup,
+    /// this fails with `InvalidInput` and doesn't freeze anything. It's
+    /// only checked at the time of the call: processes moved into the
+    /// cgroup later are frozen too.
+    ///
+    /// Otherwise, `SIGSTOP` is sent to the child only, which is observable
+    /// by the process' parent and tracer, and doesn't stop its children.
+    pub fn pause(&self) -> Result<(), io::Error> {
+        self.freeze(true)
+    }
+
+    /// Resume the process suspended with `pause()`
+    ///
+    /// Uses the same method as `pause()`, i.e. thaws the cgroup or sends
+    /// `SIGCONT` to the child.
+    pub fn resume(&self) -> Result<(), io::Error> {
+        self.freeze(false)
+    }
+
+    fn freeze(&self, frozen: bool) -> Result<(), io::Error> {
+        if self.status.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid argument: can't pause an exited process",
+            ))
+        }
+        match freezer(self.pid) {
+            Some(path) => {
+                if frozen {
+                    self.check_cgroup_owned(path.parent().unwrap())?;
+                }
+                fs::write(path, if frozen { "1" } else { "0" })
+            }
+            None if frozen => self.signal(Signal::SIGSTOP),
+            None => self.signal(Signal::SIGCONT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use crate::{Command, CgroupPolicy};
+    use super::{is_separate, cgroup2_mount};
+
+    #[test]
+    fn test_is_separate() {
+        assert!(is_separate("/sandbox", "/"));
+        assert!(is_separate("/sandbox", "/user.slice/session"));
+        assert!(is_separate("/a/b", "/a"));
+        assert!(is_separate("/sandbox", "/sandbox2"));
+        assert!(!is_separate("/", "/"));
+        assert!(!is_separate("/", "/a"));
+        assert!(!is_separate("/a", "/a"));
+        assert!(!is_separate("/a", "/a/b"));
+    }
+
+    #[test]
+    fn test_shared_cgroup() {
+        let mount = match cgroup2_mount() {
+            Some(mount) => mount,
+            None => return,
+        };
+        let name = format!("unshare-test-freeze-{}", std::process::id());
+        let dir = mount.join(&name);
+        match fs::create_dir(&dir) {
+            Ok(()) => {}
+            // unprivileged
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return;
+            }
+            Err(e) => panic!("{}: {}", dir.display(), e),
+        }
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10")
+            .cgroup(CgroupPolicy::MoveTo(PathBuf::from("/").join(&name)));
+        let mut child = cmd.spawn().unwrap();
+        let mut other = cmd.spawn().unwrap();
+        let err = child.pause().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs::read_to_string(dir.join("cgroup.freeze")).unwrap(),
+                   "0\n");
+        other.kill().unwrap();
+        other.wait().unwrap();
+        child.pause().unwrap();
+        assert_eq!(fs::read_to_string(dir.join("cgroup.freeze")).unwrap(),
+                   "1\n");
+        child.resume().unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{Command, Child};
+
+
+/// An isolation step done by the library when spawning a child
+///
+/// See `Child::hardening_report` for more info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HardeningStep {
+    /// Creating new namespaces or joining existing ones
+    Namespaces,
+    /// Writing uid and gid maps of the user namespace
+    IdMaps,
+    /// Changing root directory by `pivot_root` or `chroot`
+    ChangeRoot,
+    /// Switching user, group and supplementary groups
+    SetUser,
+    /// Limiting capabilities to the ones listed in `keep_caps`
+    Capabilities,
+    /// Raising capabilities listed in `keep_caps` into the ambient set
+    ///
+    /// Skipped on kernels not supporting ambient capabilities, so they are
+    /// lost on exec unless the program is root. Other failures (e.g. prctl
+    /// denied by a seccomp filter) make `spawn()` fail with
+    /// `Error::AmbientCaps`.
+    AmbientCapabilities,
+    /// Resolving paths beneath the root atomically (by `openat2`)
+    ///
+    /// Skipped on kernels older than 5.6, where paths are resolved one
+    /// component at a time, which isn't atomic against concurrent renames.
+    ResolveBeneath,
+    /// Closing file descriptors configured with `close_fds`
+    CloseFds,
+    /// Installing the filter of `seccomp_notify`
+    Seccomp,
+    /// Loading libraries of `preload` into the program
+    ///
+    /// Skipped if the program is statically linked, so it ignores
+    /// `LD_PRELOAD`. In strict mode `spawn()` fails with
+    /// `Error::Preload(ENOEXEC)` instead.
+    Preload,
+}
+
+const ALL: &[HardeningStep] = &[
+    HardeningStep::Namespaces,
+    HardeningStep::IdMaps,
+    HardeningStep::ChangeRoot,
+    HardeningStep::SetUser,
+    HardeningStep::Capabilities,
+    HardeningStep::AmbientCapabilities,
+    HardeningStep::ResolveBeneath,
+    HardeningStep::CloseFds,
+    HardeningStep::Seccomp,
+    HardeningStep::Preload,
+];
+
+impl HardeningStep {
+    fn bit(self) -> u32 {
+        1 << (self as u32)
+    }
+}
+
+impl fmt::Display for HardeningStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::HardeningStep::*;
+        f.write_str(match *self {
+            Namespaces => "namespaces",
+            IdMaps => "id maps",
+            ChangeRoot => "change root",
+            SetUser => "set user",
+            Capabilities => "capabilities",
+            AmbientCapabilities => "ambient capabilities",
+            ResolveBeneath => "resolve beneath",
+            CloseFds => "close fds",
+            Seccomp => "seccomp",
+            Preload => "preload",
+        })
+    }
+}
+
+/// Isolation steps requested for the child, split by whether they applied
+///
+/// Returned by `Child::hardening_report`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HardeningReport {
+    applied: Vec<HardeningStep>,
+    skipped: Vec<HardeningStep>,
+}
+
+impl HardeningReport {
+    pub(crate) fn new(requested: u32, skipped: u32) -> HardeningReport {
+        let mut report = HardeningReport::default();
+        for &step in ALL {
+            if skipped & step.bit() != 0 {
+                report.skipped.push(step);
+            } else if requested & step.bit() != 0 {
+                report.applied.push(step);
+            }
+        }
+        report
+    }
+    /// Steps that were done as requested
+    pub fn applied(&self) -> &[HardeningStep] {
+        &self.applied
+    }
+    /// Steps that the kernel didn't support, so they were skipped or done
+    /// in a weaker way
+    pub fn skipped(&self) -> &[HardeningStep] {
+        &self.skipped
+    }
+    /// Returns true if no steps were skipped
+    pub fn is_complete(&self) -> bool {
+        self.skipped.is_empty()
+    }
+}
+
+/// A word of memory shared with the child, where it marks skipped steps
+///
+/// The child is cloned without `CLONE_VM`, so can't write to our memory
+/// otherwise.
+pub(crate) struct SkippedSteps(*mut AtomicU32);
+
+impl SkippedSteps {
+    pub fn new() -> io::Result<SkippedSteps> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), mem::size_of::<AtomicU32>(),
+                libc::PROT_READ|libc::PROT_WRITE,
+                libc::MAP_SHARED|libc::MAP_ANONYMOUS, -1, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SkippedSteps(ptr as *mut AtomicU32))
+    }
+    /// Marks the step as skipped, doesn't allocate
+    pub fn mark(&self, step: HardeningStep) {
+        unsafe { &*self.0 }.fetch_or(step.bit(), Ordering::SeqCst);
+    }
+    pub fn get(&self) -> u32 {
+        unsafe { &*self.0 }.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for SkippedSteps {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.0 as *mut libc::c_void,
+                         mem::size_of::<AtomicU32>());
+        }
+    }
+}
+
+impl Command {
+    /// Fail spawning the child if any isolation step can't be done fully
+    ///
+    /// By default, some steps are done on the best effort basis: ambient
+    /// capabilities are not raised if the kernel doesn't support them (so
+    /// they're lost when executing non-root programs), and paths are
+    /// resolved with a fallback not atomic against renames when `openat2`
+    /// isn't available (see `HardeningStep` for the full list). In strict
+    /// mode such conditions make `spawn()` fail with the error of the
+    /// respective step instead.
+    ///
+    /// Use `Child::hardening_report` to find out what was skipped in
+    /// non-strict mode.
+    pub fn strict(&mut self, enable: bool) -> &mut Command {
+        self.config.strict = enable;
+        self
+    }
+
+    /// Bit mask of the steps requested by this command
+    pub(crate) fn requested_hardening(&self, resolve_beneath: bool) -> u32 {
+        use self::HardeningStep::*;
+        let mut steps = 0;
+        let mut add = |step: HardeningStep, enabled: bool| {
+            if enabled {
+                steps |= step.bit();
+            }
+        };
+        add(Namespaces, self.config.namespaces != 0 ||
+                        self.config.joined_namespaces().next().is_some());
+        add(IdMaps, self.config.id_maps.is_some());
+        add(ChangeRoot, self.pivot_root.is_some() ||
+                        self.chroot_dir.is_some());
+        add(SetUser, self.config.uid.is_some() ||
+                     self.config.gid.is_some() ||
+                     self.config.supplementary_gids.is_some());
+        add(Capabilities, self.keep_caps.is_some());
+        add(AmbientCapabilities, self.keep_caps
+            .is_some_and(|caps| caps.iter().any(|&x| x != 0)));
+        add(ResolveBeneath, resolve_beneath);
+        add(CloseFds, !self.close_fds.is_empty());
+        add(Seccomp, self.seccomp_notify.is_some());
+        add(Preload, !self.preload.is_empty());
+        steps
+    }
+}
+
+impl Child {
+    /// Returns which of the requested isolation steps were applied
+    ///
+    /// Steps skipped because the kernel doesn't support them are listed
+    /// separately, they are never skipped in `Command::strict` mode. The
+    /// report is empty for children created by `Child::from_pid`.
+    pub fn hardening_report(&self) -> &HardeningReport {
+        &self.hardening
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, ExitStatus};
+    use super::{HardeningReport, HardeningStep};
+
+    #[test]
+    fn test_report() {
+        let mut child = Command::new("/bin/true").close_fds(..)
+            .spawn().unwrap();
+        let report = child.hardening_report().clone();
+        assert_eq!(report.applied(), &[HardeningStep::CloseFds]);
+        assert!(report.is_complete());
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(0));
+    }
+
+    #[test]
+    fn test_skipped_not_applied() {
+        let all = HardeningStep::Seccomp.bit() | HardeningStep::IdMaps.bit();
+        let report = HardeningReport::new(all, HardeningStep::Seccomp.bit());
+        assert_eq!(report.applied(), &[HardeningStep::IdMaps]);
+        assert_eq!(report.skipped(), &[HardeningStep::Seccomp]);
+        assert!(!report.is_complete());
+    }
+}
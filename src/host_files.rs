@@ -0,0 +1,132 @@
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use crate::Command;
+use crate::cleanup::SpawnCleanup;
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::ffi_util::ToCString;
+use crate::mount_provider::{clone_tree, set_read_only};
+use crate::chroot::in_root;
+
+
+/// Host file made available in the new root by `Command::inject_host_file`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HostFile {
+    /// `/etc/resolv.conf`, the DNS servers
+    ResolvConf,
+    /// `/etc/nsswitch.conf`, how names, users and groups are looked up
+    Nsswitch,
+    /// `/etc/localtime`, the time zone (the file it links to is mounted)
+    Localtime,
+    /// CA certificates used to verify TLS peers: `/etc/ssl/certs` (Debian
+    /// and derivatives), `/etc/pki/tls/certs` (Fedora and derivatives) and
+    /// `/etc/ssl/cert.pem` (Alpine, Arch), the ones present on the host
+    CaCertificates,
+}
+
+impl HostFile {
+    /// Every host file, as injected by `inject_host_essentials`
+    pub fn all() -> &'static [HostFile] {
+        use self::HostFile::*;
+        &[ResolvConf, Nsswitch, Localtime, CaCertificates]
+    }
+    pub(crate) fn paths(&self) -> &'static [&'static str] {
+        match *self {
+            HostFile::ResolvConf => &["/etc/resolv.conf"],
+            HostFile::Nsswitch => &["/etc/nsswitch.conf"],
+            HostFile::Localtime => &["/etc/localtime"],
+            HostFile::CaCertificates => &["/etc/ssl/certs",
+                                          "/etc/pki/tls/certs",
+                                          "/etc/ssl/cert.pem"],
+        }
+    }
+}
+
+/// Creates an empty file or directory to mount on, unless there is one
+///
+/// Whatever is created is removed by `cleanup` if the spawn fails.
+pub(crate) fn mount_point(target: &Path, dir: bool,
+    cleanup: &mut SpawnCleanup)
+    -> io::Result<()>
+{
+    if fs::symlink_metadata(target).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        cleanup.create_dir_all(parent)?;
+    }
+    if dir {
+        fs::create_dir(target)?;
+        cleanup.remove_dir_on_failure(target.to_path_buf());
+    } else {
+        OpenOptions::new().write(true).create_new(true).open(target)?;
+        cleanup.remove_file_on_failure(target.to_path_buf());
+    }
+    Ok(())
+}
+
+impl Command {
+    /// Make host's DNS, name service, time zone and CA certificates
+    /// configuration available in the new root
+    ///
+    /// Shortcut for `inject_host_file` with every item of `HostFile::all()`
+    pub fn inject_host_essentials(&mut self) -> &mut Command {
+        for &item in HostFile::all() {
+            self.inject_host_file(item);
+        }
+        self
+    }
+
+    /// Bind-mount the host file read-only at the same path in the new root
+    ///
+    /// This does nothing if the root is not changed (`pivot_root` or
+    /// `chroot_dir`). Files absent on the host are skipped. If there is no
+    /// file at the path in the new root, an empty file (or directory) is
+    /// created as a mount point and left in place (it's removed if the
+    /// spawn fails), symlinks in the new root are followed.
+    ///
+    /// Mounts are made in the parent on `spawn()`, which requires
+    /// `CAP_SYS_ADMIN` and linux 5.12, and attached by the child after the
+    /// ones of `mount_provider`. Mount namespace must be unshared. Errors
+    /// are reported as `Error::InjectHostFile`.
+    pub fn inject_host_file(&mut self, item: HostFile) -> &mut Command {
+        if !self.host_files.contains(&item) {
+            self.host_files.push(item);
+        }
+        self
+    }
+
+    /// Returns mount points (host paths) and read-only bind mounts
+    pub(crate) fn host_file_mounts(&self, cleanup: &mut SpawnCleanup)
+        -> Result<Vec<(CString, File)>, Error>
+    {
+        let root = self.host_root();
+        if self.host_files.is_empty() || root == Path::new("/") {
+            return Ok(Vec::new());
+        }
+        if self.config.namespaces & libc::CLONE_NEWNS == 0 {
+            // the mounts would be visible by all processes
+            return Err(Error::InjectHostFile(libc::EINVAL));
+        }
+        let mut mounts = Vec::new();
+        for item in &self.host_files {
+            for path in item.paths() {
+                let path = Path::new(path);
+                let meta = match fs::metadata(path) {
+                    Ok(meta) => meta,
+                    Err(_) => continue,
+                };
+                let target = in_root(&root, path);
+                result(Err::InjectHostFile,
+                       mount_point(&target, meta.is_dir(), cleanup))?;
+                let mount = result(Err::InjectHostFile, clone_tree(path))?;
+                result(Err::InjectHostFile, set_read_only(&mount))?;
+                mounts.push((target.to_cstring(), mount));
+            }
+        }
+        Ok(mounts)
+    }
+}
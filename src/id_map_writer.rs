@@ -0,0 +1,260 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::{Child, Command, UidMap, GidMap};
+use crate::error::{Error, result, cmd_result};
+use crate::error::ErrorCode as Err;
+use crate::ffi_util::ToCString;
+
+
+/// Writes uid and gid maps of the child's user namespace
+///
+/// Called in the parent while the child is frozen, when `set_id_maps` is
+/// configured. Implement it for environments where maps are written by
+/// some privileged broker. Return `Error::SetIdMap` for OS errors and
+/// `Error::PrivilegedOps` for anything else.
+pub trait IdMapWriter {
+    /// Write the maps for the process `pid`
+    fn write_id_maps(&mut self, pid: u32, uid_map: &[UidMap],
+        gid_map: &[GidMap])
+        -> Result<(), Error>;
+}
+
+/// Writes `/proc/<pid>/uid_map` and `gid_map` directly (the default)
+///
+/// This requires `CAP_SETUID` and `CAP_SETGID` in the parent namespace,
+/// or the maps containing only the current user.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectWrite;
+
+/// Runs `newuidmap` and `newgidmap` compatible commands
+///
+/// See `Command::set_id_map_commands`
+#[derive(Debug, Clone)]
+pub struct NewXidmapCmd {
+    newuidmap: PathBuf,
+    newgidmap: PathBuf,
+}
+
+impl IdMapWriter for DirectWrite {
+    fn write_id_maps(&mut self, pid: u32, uid_map: &[UidMap],
+        gid_map: &[GidMap])
+        -> Result<(), Error>
+    {
+        write_maps(Path::new("/proc"), pid, uid_map, gid_map)
+    }
+}
+
+/// Writes the maps of `DirectWrite`, the proc file system is at `proc`
+pub(crate) fn write_maps(proc: &Path, pid: u32, uid_map: &[UidMap],
+    gid_map: &[GidMap])
+    -> Result<(), Error>
+{
+    let dir = proc.join(pid.to_string());
+    let mut buf = Vec::new();
+    for map in uid_map {
+        writeln!(&mut buf, "{} {} {}",
+            map.inside_uid, map.outside_uid, map.count).unwrap();
+    }
+    result(Err::SetIdMap,
+        File::create(dir.join("uid_map"))
+        .and_then(|mut f| f.write_all(&buf[..])))?;
+    let mut buf = Vec::new();
+    for map in gid_map {
+        writeln!(&mut buf, "{} {} {}",
+            map.inside_gid, map.outside_gid, map.count).unwrap();
+    }
+    result(Err::SetIdMap,
+        File::create(dir.join("gid_map"))
+        .and_then(|mut f| f.write_all(&buf[..])))?;
+    Ok(())
+}
+
+impl NewXidmapCmd {
+    /// Use the commands at the paths
+    pub fn new<A: AsRef<Path>, B: AsRef<Path>>(newuidmap: A, newgidmap: B)
+        -> NewXidmapCmd
+    {
+        NewXidmapCmd {
+            newuidmap: newuidmap.as_ref().to_path_buf(),
+            newgidmap: newgidmap.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl IdMapWriter for NewXidmapCmd {
+    fn write_id_maps(&mut self, pid: u32, uid_map: &[UidMap],
+        gid_map: &[GidMap])
+        -> Result<(), Error>
+    {
+        let mut cmd = Command::new(&self.newuidmap);
+        cmd.arg(format!("{}", pid));
+        for map in uid_map {
+            cmd.arg(format!("{}", map.inside_uid));
+            cmd.arg(format!("{}", map.outside_uid));
+            cmd.arg(format!("{}", map.count));
+        }
+        cmd_result(Err::SetIdMap, cmd.status())?;
+        let mut cmd = Command::new(&self.newgidmap);
+        cmd.arg(format!("{}", pid));
+        for map in gid_map {
+            cmd.arg(format!("{}", map.inside_gid));
+            cmd.arg(format!("{}", map.outside_gid));
+            cmd.arg(format!("{}", map.count));
+        }
+        cmd_result(Err::SetIdMap, cmd.status())?;
+        Ok(())
+    }
+}
+
+/// Checks that `proc` is a proc file system where this process is visible
+fn check_proc(proc: &Path) -> io::Result<()> {
+    let path = proc.to_cstring();
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if stat.f_type != libc::PROC_SUPER_MAGIC {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    // dangling if the proc is of another pid namespace
+    fs::metadata(proc.join("self")).map(|_| ())
+}
+
+impl Command {
+    /// Use the proc file system mounted at `path`
+    ///
+    /// It's used instead of `/proc` for accessing files of the child while
+    /// spawning: writing uid and gid maps by `DirectWrite` (the default
+    /// writer), opening the user namespace for idmapped mounts and so on.
+    /// This is for sandboxes where `/proc` is mounted elsewhere. The path
+    /// is checked at the start of `spawn()`, which fails with
+    /// `Error::ProcPath` if it's not a proc file system showing the current
+    /// process, so misconfiguration doesn't fail in the middle of the setup.
+    /// The same check is done for `/proc` when it's going to be used.
+    ///
+    /// The path is kept by the `Child` for opening its namespaces
+    /// (`Child::ns_fd`, `Child::exec_in`, `Child::forward_port`). The child
+    /// itself still uses `/proc/self` (e.g. for `set_loginuid`).
+    pub fn proc_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Command {
+        self.config.proc_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Returns the mount point of the proc file system
+    pub(crate) fn proc_dir(&self) -> &Path {
+        self.config.proc_path.as_deref().unwrap_or(Path::new("/proc"))
+    }
+
+    /// Fails early if the proc file system is needed and is unusable
+    pub(crate) fn check_proc_path(&self) -> Result<(), Error> {
+        let direct_maps = self.config.id_maps.is_some() &&
+            self.privileged_ops.is_none() && self.id_map_writer.is_none();
+        if self.config.proc_path.is_some() || direct_maps ||
+            !self.idmapped_mounts.is_empty()
+        {
+            result(Err::ProcPath, check_proc(self.proc_dir()))?;
+        }
+        Ok(())
+    }
+
+    /// Set the way uid and gid maps are written
+    ///
+    /// By default, maps are written by `DirectWrite`. The
+    /// `set_id_map_commands` is a shortcut for `NewXidmapCmd`. Ignored if
+    /// `privileged_ops` are set, which write the maps themselves.
+    ///
+    /// Each invocation **replaces** previously set writer.
+    pub fn id_map_writer(&mut self, writer: impl IdMapWriter + 'static)
+        -> &mut Command
+    {
+        self.id_map_writer = Some(Box::new(writer));
+        self
+    }
+}
+
+impl Child {
+    /// Returns the proc file system set by `Command::proc_path`
+    pub(crate) fn proc_dir(&self) -> &Path {
+        self.proc_path.as_deref().unwrap_or(Path::new("/proc"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::env;
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::process;
+    use std::ptr;
+    use std::rc::Rc;
+
+    use libc::c_char;
+
+    use crate::{Command, Namespace, UidMap, GidMap, Error};
+    use super::{IdMapWriter, DirectWrite};
+
+    struct Recording(Rc<RefCell<Vec<u32>>>);
+
+    impl IdMapWriter for Recording {
+        fn write_id_maps(&mut self, pid: u32, uid_map: &[UidMap],
+            gid_map: &[GidMap])
+            -> Result<(), Error>
+        {
+            self.0.borrow_mut().push(pid);
+            DirectWrite.write_id_maps(pid, uid_map, gid_map)
+        }
+    }
+
+    #[test]
+    fn test_custom_writer() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg(r#"test "$(cat /proc/self/uid_map)" = "$(printf \
+                '%10d %10d %10d' 0 65534 1)""#)
+            .set_id_maps(
+                vec![UidMap { inside_uid: 0, outside_uid: 65534, count: 1 }],
+                vec![GidMap { inside_gid: 0, outside_gid: 65534, count: 1 }])
+            .id_map_writer(Recording(calls.clone()))
+            .spawn().unwrap();
+        assert_eq!(*calls.borrow(), vec![child.pid() as u32]);
+        assert!(child.wait().unwrap().success());
+    }
+    #[test]
+    fn test_proc_path() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.set_id_maps(
+            vec![UidMap { inside_uid: 0, outside_uid: 65534, count: 1 }],
+            vec![GidMap { inside_gid: 0, outside_gid: 65534, count: 1 }]);
+        let err = cmd.proc_path("/nonexistent").spawn().unwrap_err();
+        assert!(matches!(err, Error::ProcPath(libc::ENOENT)), "{:?}", err);
+        let err = cmd.proc_path("/").spawn().unwrap_err();
+        assert!(matches!(err, Error::ProcPath(libc::EINVAL)), "{:?}", err);
+        assert!(cmd.proc_path("/proc").status().unwrap().success());
+    }
+
+    #[test]
+    fn test_child_proc_path() {
+        let dir = env::temp_dir().join(
+            format!("unshare-test-proc-{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        let path = CString::new(dir.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mount(b"/proc\0".as_ptr() as *const c_char,
+            path.as_ptr(), ptr::null(), libc::MS_BIND, ptr::null()) }, 0);
+        let mut child = Command::new("/bin/sleep").arg("10")
+            .proc_path(&dir).spawn().unwrap();
+        child.ns_fd(Namespace::Net).unwrap();
+        assert_eq!(unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) },
+                   0);
+        // opened from the (now empty) directory, not from `/proc`
+        let err = child.ns_fd(Namespace::Net).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        fs::remove_dir(&dir).unwrap();
+    }
+}
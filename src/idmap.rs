@@ -1,3 +1,6 @@
+use std::fs;
+use std::io;
+
 use libc::{uid_t, gid_t};
 
 
@@ -22,3 +25,84 @@ pub struct GidMap {
     /// Number of gids that this entry allows starting from inside/outside gid
     pub count: gid_t,
 }
+
+/// Parses `/proc/<pid>/uid_map` or `gid_map`
+pub(crate) fn read_map(path: &str) -> io::Result<Vec<(u32, u32, u32)>> {
+    let data = fs::read_to_string(path)?;
+    data.lines().map(|line| {
+        let mut items = line.split_whitespace().map(|x| x.parse::<u32>());
+        match (items.next(), items.next(), items.next()) {
+            (Some(Ok(inside)), Some(Ok(outside)), Some(Ok(count))) => {
+                Ok((inside, outside, count))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("invalid line in {}: {:?}", path, line))),
+        }
+    }).collect()
+}
+
+/// Checks that ids `start..start+count` are inside of the `map`
+pub(crate) fn is_mapped(map: &[(u32, u32, u32)], start: u32, count: u32)
+    -> bool
+{
+    let end = start as u64 + count as u64;
+    let mut pos = start as u64;
+    // ranges don't overlap, but may be adjacent
+    while pos < end {
+        match map.iter().find(|&&(inside, _, cnt)| {
+            inside as u64 <= pos && pos < inside as u64 + cnt as u64
+        }) {
+            Some(&(inside, _, cnt)) => pos = inside as u64 + cnt as u64,
+            None => return false,
+        }
+    }
+    true
+}
+
+impl UidMap {
+    /// Returns the uid map of the user namespace of the current process
+    ///
+    /// When running in a user namespace (e.g. in a rootless container),
+    /// `inside_uid` of these entries are the only uids which can be used
+    /// as `outside_uid` of the child's map. So nested maps are built
+    /// from the ids of the current namespace, not the host ones.
+    pub fn current() -> io::Result<Vec<UidMap>> {
+        Ok(read_map("/proc/self/uid_map")?.into_iter()
+            .map(|(inside_uid, outside_uid, count)| {
+                UidMap { inside_uid, outside_uid, count }
+            }).collect())
+    }
+}
+
+impl GidMap {
+    /// Returns the gid map of the user namespace of the current process
+    ///
+    /// See `UidMap::current`
+    pub fn current() -> io::Result<Vec<GidMap>> {
+        Ok(read_map("/proc/self/gid_map")?.into_iter()
+            .map(|(inside_gid, outside_gid, count)| {
+                GidMap { inside_gid, outside_gid, count }
+            }).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UidMap, is_mapped};
+
+    #[test]
+    fn test_is_mapped() {
+        let map = [(0, 1000, 1), (1, 100000, 65536)];
+        assert!(is_mapped(&map, 0, 1));
+        assert!(is_mapped(&map, 0, 65537));
+        assert!(!is_mapped(&map, 0, 65538));
+        assert!(!is_mapped(&map, 70000, 1));
+        assert!(is_mapped(&map, 5, 0));
+    }
+
+    #[test]
+    fn test_current() {
+        let map = UidMap::current().unwrap();
+        assert!(!map.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use libc::pid_t;
+
+use crate::Command;
+use crate::cleanup::SpawnCleanup;
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::ffi_util::ToCString;
+use crate::host_files::mount_point;
+use crate::mount_provider::clone_tree;
+use crate::chroot::in_root;
+
+
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+
+/// Makes the (detached) mount use id maps of the user namespace of `pid`
+///
+/// The proc file system is at `proc`.
+pub(crate) fn set_idmap(mount: &File, proc: &Path, pid: pid_t)
+    -> io::Result<()>
+{
+    let userns = File::open(proc.join(pid.to_string()).join("ns/user"))?;
+    // struct mount_attr: attr_set, attr_clr, propagation, userns_fd
+    let attr: [u64; 4] = [MOUNT_ATTR_IDMAP, 0, 0, userns.as_raw_fd() as u64];
+    let rc = unsafe {
+        libc::syscall(libc::SYS_mount_setattr, mount.as_raw_fd(),
+            b"\0".as_ptr(), libc::AT_EMPTY_PATH, attr.as_ptr(),
+            mem::size_of_val(&attr))
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Command {
+    /// Bind-mount the host directory (or file) read-write at
+    /// `container_path`, mapping file owners by the child's id maps
+    ///
+    /// Unlike `scratch_dir`, files owned by the `outside` ids of the
+    /// `set_id_maps` show the same ids in the sandbox (e.g. the directory
+    /// owned by root of the host is owned by root of the sandbox, instead
+    /// of `nobody`), and files created in the sandbox get their ids on the
+    /// host. Nothing is chowned on the host.
+    ///
+    /// The `container_path` is in the new root (`pivot_root` or
+    /// `chroot_dir`), a mount point is created there if missing. The mount
+    /// is made in the parent on `spawn()`, and made idmapped after the
+    /// id maps are written. This requires `CAP_SYS_ADMIN`, linux 5.12 and
+    /// the file system supporting idmapped mounts. User and mount
+    /// namespaces must be unshared and `set_id_maps` set. Errors are
+    /// reported as `Error::IdmappedMount` (`ENOSYS` on older kernels,
+    /// `EINVAL` if the file system doesn't support it).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `container_path` is not absolute
+    pub fn idmapped_mount<A: AsRef<Path>, B: AsRef<Path>>(&mut self,
+        host_path: A, container_path: B)
+        -> &mut Command
+    {
+        let container_path = container_path.as_ref();
+        assert!(container_path.is_absolute(),
+            "idmapped mount path must be absolute");
+        self.idmapped_mounts.push((host_path.as_ref().to_path_buf(),
+                                   container_path.to_path_buf()));
+        self
+    }
+
+    /// Returns mount points (host paths) and bind mounts to make idmapped
+    pub(crate) fn idmapped_mount_points(&self, cleanup: &mut SpawnCleanup)
+        -> Result<Vec<(CString, File)>, Error>
+    {
+        if self.idmapped_mounts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let namespaces = libc::CLONE_NEWNS | libc::CLONE_NEWUSER;
+        if self.config.namespaces & namespaces != namespaces ||
+            self.config.id_maps.is_none()
+        {
+            return Err(Error::IdmappedMount(libc::EINVAL));
+        }
+        let root = self.host_root();
+        let mut mounts = Vec::new();
+        for (host_path, container_path) in &self.idmapped_mounts {
+            let meta = result(Err::IdmappedMount, fs::metadata(host_path))?;
+            let target = in_root(&root, container_path);
+            result(Err::IdmappedMount, mount_point(&target, meta.is_dir(),
+                                                      cleanup))?;
+            let mount = result(Err::IdmappedMount, clone_tree(host_path))?;
+            mounts.push((target.to_cstring(), mount));
+        }
+        Ok(mounts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{Command, Namespace, Error, UidMap, GidMap};
+
+    fn maps() -> (Vec<UidMap>, Vec<GidMap>) {
+        (vec![UidMap { inside_uid: 0, outside_uid: 100000, count: 65536 }],
+         vec![GidMap { inside_gid: 0, outside_gid: 100000, count: 65536 }])
+    }
+
+    #[test]
+    fn test_idmapped_mount() {
+        let base = std::env::temp_dir().join("unshare-test-idmapped");
+        let _ = fs::remove_dir_all(&base);
+        let host = base.join("host");
+        fs::create_dir_all(&host).unwrap();
+        let work = base.join("work");
+        let (uids, gids) = maps();
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg(format!("test $(stat -c %u:%g {0}) = 0:0 && \
+                          touch {0}/created", work.display()))
+            .unshare(&[Namespace::Mount, Namespace::User])
+            .set_id_maps(uids, gids).uid(0).gid(0)
+            .idmapped_mount(&host, &work)
+            .spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+        let meta = fs::metadata(host.join("created")).unwrap();
+        assert_eq!(std::os::unix::fs::MetadataExt::uid(&meta), 0);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_idmapped_needs_user_ns() {
+        match Command::new("/bin/true")
+            .unshare(&[Namespace::Mount])
+            .idmapped_mount("/tmp", "/tmp/idmapped").spawn()
+        {
+            Err(Error::IdmappedMount(code)) => {
+                assert_eq!(code, libc::EINVAL)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
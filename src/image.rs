@@ -0,0 +1,358 @@
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc::{c_int, c_ulong, pid_t};
+
+use crate::{Command, Namespace, BoxError};
+use crate::ffi_util::ToCString;
+use crate::mount_provider::{MountProvider, ProvidedMount};
+use crate::namespace::ns_equal_in;
+
+
+/// File system of the image passed to `Command::root_from_image`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageType {
+    /// Mounted as `squashfs`, FUSE fallback is `squashfuse`
+    Squashfs,
+    /// Mounted as `erofs`, FUSE fallback is `erofsfuse`
+    Erofs,
+}
+
+impl ImageType {
+    fn fs_type(&self) -> &'static str {
+        match *self {
+            ImageType::Squashfs => "squashfs",
+            ImageType::Erofs => "erofs",
+        }
+    }
+    fn fuse_binary(&self) -> &'static str {
+        match *self {
+            ImageType::Squashfs => "squashfuse",
+            ImageType::Erofs => "erofsfuse",
+        }
+    }
+}
+
+const LOOP_SET_FD: c_ulong = 0x4C00;
+const LOOP_CLR_FD: c_ulong = 0x4C01;
+const LOOP_SET_STATUS64: c_ulong = 0x4C04;
+const LOOP_CONFIGURE: c_ulong = 0x4C0A;
+const LOOP_CTL_GET_FREE: c_ulong = 0x4C82;
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+const FSOPEN_CLOEXEC: c_int = 1;
+const FSCONFIG_SET_FLAG: c_int = 0;
+const FSCONFIG_SET_STRING: c_int = 1;
+const FSCONFIG_CMD_CREATE: c_int = 6;
+const FSMOUNT_CLOEXEC: c_int = 1;
+const MOUNT_ATTR_RDONLY: c_int = 1;
+const MOUNT_ATTR_NODEV: c_int = 4;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+/// Number of the temporary directories created by this process
+static MOUNTPOINTS: AtomicUsize = AtomicUsize::new(0);
+
+fn ioctl<T>(fd: RawFd, request: c_ulong, arg: T) -> io::Result<c_int> {
+    match unsafe { libc::ioctl(fd, request as _, arg) } {
+        -1 => Err(io::Error::last_os_error()),
+        x => Ok(x),
+    }
+}
+
+fn open_file(path: &Path, read_only: bool) -> io::Result<File> {
+    OpenOptions::new().read(true).write(!read_only).open(path)
+}
+
+/// Attaches image to a free loop device, which is detached automatically
+/// when the last mount of it is gone
+///
+/// Returns the open device and its path
+fn attach_loop(image: &File, read_only: bool) -> io::Result<(File, String)> {
+    let control = open_file(Path::new("/dev/loop-control"), false)?;
+    let mut config: LoopConfig = unsafe { mem::zeroed() };
+    config.fd = image.as_raw_fd() as u32;
+    config.info.lo_flags = LO_FLAGS_AUTOCLEAR |
+        if read_only { LO_FLAGS_READ_ONLY } else { 0 };
+    // other processes may take the device we've found, so retry
+    for _ in 0..16 {
+        let num = ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE, 0)?;
+        let path = format!("/dev/loop{}", num);
+        let dev = open_file(Path::new(&path), read_only)?;
+        let err = match ioctl(dev.as_raw_fd(), LOOP_CONFIGURE, &config) {
+            Ok(_) => return Ok((dev, path)),
+            Err(e) => e,
+        };
+        match err.raw_os_error() {
+            Some(libc::EBUSY) => continue,
+            // linux < 5.8, the read-only flag is taken from the file mode
+            Some(libc::EINVAL) | Some(libc::ENOTTY) => {}
+            _ => return Err(err),
+        }
+        match ioctl(dev.as_raw_fd(), LOOP_SET_FD, image.as_raw_fd()) {
+            Ok(_) => {}
+            Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) => continue,
+            Err(e) => return Err(e),
+        }
+        if let Err(e) = ioctl(dev.as_raw_fd(), LOOP_SET_STATUS64,
+                              &config.info)
+        {
+            ioctl(dev.as_raw_fd(), LOOP_CLR_FD, 0).ok();
+            return Err(e);
+        }
+        return Ok((dev, path));
+    }
+    Err(io::Error::from_raw_os_error(libc::EBUSY))
+}
+
+/// File system context created by `fsopen`
+struct FsContext(File);
+
+impl FsContext {
+    fn new(fs_type: &str) -> io::Result<FsContext> {
+        let fd = unsafe {
+            libc::syscall(libc::SYS_fsopen, fs_type.to_cstring().as_ptr(),
+                          FSOPEN_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FsContext(unsafe { File::from_raw_fd(fd as RawFd) }))
+    }
+    fn config(&self, cmd: c_int, key: Option<&str>, value: Option<&str>)
+        -> io::Result<()>
+    {
+        let key = key.map(|x| x.to_cstring());
+        let value = value.map(|x| x.to_cstring());
+        let as_ptr = |x: &Option<CString>| {
+            x.as_ref().map_or(ptr::null(), |x| x.as_ptr())
+        };
+        let rc = unsafe {
+            libc::syscall(libc::SYS_fsconfig, self.0.as_raw_fd(), cmd,
+                          as_ptr(&key), as_ptr(&value), 0)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    fn set_string(&self, key: &str, value: &str) -> io::Result<()> {
+        self.config(FSCONFIG_SET_STRING, Some(key), Some(value))
+    }
+    /// Creates the superblock and returns a detached mount of it
+    fn mount(&self, attrs: c_int) -> io::Result<File> {
+        self.config(FSCONFIG_CMD_CREATE, None, None)?;
+        let fd = unsafe {
+            libc::syscall(libc::SYS_fsmount, self.0.as_raw_fd(),
+                          FSMOUNT_CLOEXEC, attrs)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+    }
+}
+
+fn mkdir_at(dir: &File, name: &str) -> io::Result<()> {
+    let rc = unsafe {
+        libc::mkdirat(dir.as_raw_fd(), name.to_cstring().as_ptr(), 0o755)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Mounts the image from the loop device
+fn mount_image(kind: ImageType, device: &str) -> io::Result<File> {
+    let fs = FsContext::new(kind.fs_type())?;
+    fs.set_string("source", device)?;
+    // both file systems are read-only anyway
+    fs.config(FSCONFIG_SET_FLAG, Some("ro"), None)?;
+    fs.mount(MOUNT_ATTR_RDONLY)
+}
+
+/// Puts overlay with the `tmpfs` upper directory on top of the `lower`
+fn writable_overlay(lower: &File) -> io::Result<File> {
+    let upper = FsContext::new("tmpfs")?.mount(MOUNT_ATTR_NODEV)?;
+    mkdir_at(&upper, "upper")?;
+    mkdir_at(&upper, "work")?;
+    // detached mounts are reachable through the magic links only
+    let fs = FsContext::new("overlay")?;
+    fs.set_string("lowerdir",
+        &format!("/proc/self/fd/{}", lower.as_raw_fd()))?;
+    fs.set_string("upperdir",
+        &format!("/proc/self/fd/{}/upper", upper.as_raw_fd()))?;
+    fs.set_string("workdir",
+        &format!("/proc/self/fd/{}/work", upper.as_raw_fd()))?;
+    fs.mount(0)
+}
+
+/// Provides the image, see `Command::root_from_image`
+struct ImageProvider {
+    image: PathBuf,
+    kind: ImageType,
+    read_only: bool,
+    mountpoint: PathBuf,
+    proc: PathBuf,
+}
+
+impl ImageProvider {
+    fn mount_loop(&self, image: &File) -> io::Result<File> {
+        let (_dev, path) = attach_loop(image, self.read_only)?;
+        // the mount keeps the device attached after it's closed
+        let image = mount_image(self.kind, &path)?;
+        if self.read_only {
+            Ok(image)
+        } else {
+            writable_overlay(&image)
+        }
+    }
+
+    /// Runs FUSE daemon in the namespaces of the child, so it mounts the
+    /// image directly there
+    fn mount_fuse(&self, pid: u32) -> Result<(), BoxError> {
+        if !self.read_only {
+            return Err("writable image root requires loop devices".into());
+        }
+        let binary = self.kind.fuse_binary();
+        let path = crate::network::find_in_path(binary)
+            .ok_or_else(|| format!("{} is not found in PATH", binary))?;
+        let mut cmd = Command::new(path);
+        cmd.arg(&self.image).arg(&self.mountpoint);
+        let own = std::process::id() as pid_t;
+        if !ns_equal_in(&self.proc, own, pid as pid_t, Namespace::User)? {
+            let userns = File::open(
+                self.proc.join(format!("{}/ns/user", pid)))?;
+            cmd.set_namespace(&userns, Namespace::User)?;
+        }
+        let mntns = File::open(self.proc.join(format!("{}/ns/mnt", pid)))?;
+        cmd.set_namespace(&mntns, Namespace::Mount)?;
+        // daemon exits when the mount namespace of the child is gone
+        cmd.allow_daemonize();
+        let status = cmd.status()
+            .map_err(|e| format!("{}: {}", binary, e))?;
+        if !status.success() {
+            return Err(format!("{} {}", binary, status).into());
+        }
+        Ok(())
+    }
+}
+
+impl MountProvider for ImageProvider {
+    fn provide(&mut self, pid: u32) -> Result<ProvidedMount, BoxError> {
+        let image = open_file(&self.image, self.read_only)?;
+        let mount = match self.mount_loop(&image) {
+            Ok(mount) => Some(mount),
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) ||
+                e.raw_os_error() == Some(libc::EACCES) ||
+                e.raw_os_error() == Some(libc::ENOENT) => None,
+            Err(e) => return Err(Box::new(e)),
+        };
+        // the directory is on the file system shared with the child, it
+        // isn't visible as a mountpoint outside of the child's namespace
+        fs::create_dir(&self.mountpoint)?;
+        if mount.is_none() {
+            if let Err(e) = self.mount_fuse(pid) {
+                fs::remove_dir(&self.mountpoint).ok();
+                return Err(e);
+            }
+        }
+        let dir = self.mountpoint.clone();
+        Ok(ProvidedMount {
+            mount,
+            teardown: Some(Box::new(move || {
+                fs::remove_dir(&dir).ok();
+            })),
+        })
+    }
+}
+
+impl Command {
+    /// Use a file system image as the root of the child
+    ///
+    /// The image is attached to a loop device and mounted while the child
+    /// is frozen (see `Command::mount_provider`), then the child makes it
+    /// a root by `pivot_root`. This unshares mount namespace and replaces
+    /// `pivot_root` settings. The loop device is detached automatically
+    /// when the mount namespace of the child is gone.
+    ///
+    /// Both file systems are read-only. If `read_only` is false, the image
+    /// is the lower layer of `overlay` with changes kept in `tmpfs`
+    /// (which is lost when the child exits). Otherwise, the image file and
+    /// the loop device are opened read-only, so the image file may be
+    /// read-only too.
+    ///
+    /// When loop devices can't be used (usually due to lack of
+    /// privileges), and the image is `read_only`, the FUSE daemon
+    /// (`squashfuse` or `erofsfuse` found in `PATH`) is started in the user
+    /// and mount namespaces of the child. This requires user namespace
+    /// (so it's useful with `set_id_maps`) and linux 4.18.
+    ///
+    /// The image is mounted over a directory created in `env::temp_dir()`
+    /// (the mount is visible in the child's namespace only), the directory
+    /// is removed when the child is reaped. Use `current_dir` to set a
+    /// working directory inside the image and don't set `chroot_dir`.
+    /// The FUSE daemon uses `Command::proc_path` set before this call.
+    pub fn root_from_image<P: AsRef<Path>>(&mut self, path: P,
+        kind: ImageType, read_only: bool)
+        -> &mut Command
+    {
+        let mountpoint = env::temp_dir().join(format!("unshare-root-{}-{}",
+            unsafe { libc::getpid() },
+            MOUNTPOINTS.fetch_add(1, Ordering::SeqCst)));
+        self.unshare(&[Namespace::Mount]);
+        self.pivot_root(&mountpoint, &mountpoint, true);
+        self.mount_providers.insert(0, (PathBuf::from("/"),
+            Box::new(ImageProvider {
+                image: path.as_ref().to_path_buf(),
+                kind, read_only,
+                mountpoint,
+                proc: self.proc_dir().to_path_buf(),
+            })));
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+    use super::{LoopInfo64, LoopConfig};
+
+    #[test]
+    fn test_loop_structs() {
+        assert_eq!(size_of::<LoopInfo64>(), 232);
+        assert_eq!(size_of::<LoopConfig>(), 304);
+    }
+}
@@ -0,0 +1,237 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc::pid_t;
+
+use crate::{Command, Child, Signal};
+use crate::error::Error;
+use crate::stdio::{Closing, dup_file_cloexec};
+use crate::sys;
+
+
+const POLL_TRIGGER: libc::c_short =
+    libc::POLLIN | libc::POLLHUP | libc::POLLERR | libc::POLLRDHUP;
+
+impl Command {
+    /// Send `signal` to the child when `fd` becomes readable or closed
+    ///
+    /// This ties lifetime of the child to something outside, like a pipe
+    /// (signal is sent when the last writer closes it) or a client
+    /// connection (when the client disconnects or sends data, so it's
+    /// mostly useful for sockets that the client doesn't write to after
+    /// the request). The descriptor is duplicated, so may be closed after
+    /// the call. May be called multiple times.
+    ///
+    /// The descriptor is watched by a thread started in the parent, which
+    /// exits when the child exits. A pidfd is used to send the signal so
+    /// it can't hit an unrelated process, linux 5.3 is required.
+    pub fn kill_fd<F: AsRawFd>(&mut self, fd: &F, signal: Signal)
+        -> io::Result<&mut Command>
+    {
+        self.kill_fds.push((dup_file_cloexec(fd)?, signal));
+        Ok(self)
+    }
+
+    /// Kill the whole pid namespace of the child after `timeout`
+    ///
+    /// The child must unshare `Namespace::Pid`, so it's the pid 1 of the
+    /// namespace, and when it's killed by `SIGKILL` the kernel kills all
+    /// processes in the namespace, including daemonized ones. Otherwise
+    /// `spawn()` fails with `Error::KillFd(EINVAL)`.
+    ///
+    /// The time is counted from `spawn()` by a timer watched by the same
+    /// thread as `kill_fd` descriptors. Use `Child::timed_out` to tell the
+    /// kill by the timeout from other reasons of `SIGKILL`.
+    pub fn kill_namespace_on_timeout(&mut self, timeout: Duration)
+        -> &mut Command
+    {
+        self.namespace_timeout = Some((timeout, Arc::default()));
+        self
+    }
+
+    /// Checks the timeout kills the namespace and starts a new one
+    pub(crate) fn check_namespace_timeout(&mut self) -> Result<(), Error> {
+        let unshares_pid = self.config.namespaces & libc::CLONE_NEWPID != 0;
+        if let Some((_, ref mut fired)) = self.namespace_timeout {
+            if !unshares_pid {
+                return Err(Error::KillFd(libc::EINVAL));
+            }
+            *fired = Arc::default();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn namespace_timeout_flag(&self) -> Option<Arc<AtomicBool>> {
+        self.namespace_timeout.as_ref().map(|(_, fired)| fired.clone())
+    }
+}
+
+impl Child {
+    /// Returns true if the child is killed by `kill_namespace_on_timeout`
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.as_ref().is_some_and(|x| x.load(Ordering::SeqCst))
+    }
+}
+
+/// Timer of `kill_namespace_on_timeout` and the flag set when it fires
+struct Timer(Closing, Arc<AtomicBool>);
+
+impl Timer {
+    fn start(timeout: Duration, fired: &Arc<AtomicBool>) -> io::Result<Timer>
+    {
+        let fd = unsafe {
+            libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = Closing::new(fd);
+        let value = libc::itimerspec {
+            it_interval: libc::timespec { tv_sec: 0, tv_nsec: 0 },
+            it_value: libc::timespec {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                // zero disarms the timer
+                tv_nsec: timeout.subsec_nanos().max(1) as libc::c_long,
+            },
+        };
+        let rc = unsafe {
+            libc::timerfd_settime(fd.as_raw_fd(), 0, &value, ptr::null_mut())
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Timer(fd, fired.clone()))
+    }
+}
+
+/// Polls descriptors until child exits or any of them triggers
+fn watch(pidfd: Closing, mut fds: Vec<(Closing, Signal)>, timer: Option<Timer>)
+{
+    let fired = timer.map(|Timer(fd, fired)| {
+        fds.push((fd, Signal::SIGKILL));
+        fired
+    });
+    let mut pfds = vec![libc::pollfd {
+        fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0,
+    }];
+    pfds.extend(fds.iter().map(|(fd, _)| libc::pollfd {
+        fd: fd.as_raw_fd(), events: POLL_TRIGGER, revents: 0,
+    }));
+    loop {
+        let rc = unsafe {
+            libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, -1)
+        };
+        if rc < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+            {
+                continue;
+            }
+            return;
+        }
+        if pfds[0].revents != 0 {
+            // child exited
+            return;
+        }
+        let triggered = pfds[1..].iter().zip(&fds)
+            .find(|(pfd, _)| pfd.revents != 0);
+        if let Some((pfd, &(_, signal))) = triggered {
+            if let Some(ref fired) = fired {
+                if pfd.fd == fds[fds.len() - 1].0.as_raw_fd() {
+                    fired.store(true, Ordering::SeqCst);
+                }
+            }
+            unsafe {
+                libc::syscall(libc::SYS_pidfd_send_signal, pidfd.as_raw_fd(),
+                              signal.as_raw(), 0, 0)
+            };
+            return;
+        }
+    }
+}
+
+/// Starts a thread watching the descriptors set by `kill_fd` (and the
+/// timer of `kill_namespace_on_timeout`) for `pid`
+pub fn start_watcher(pid: pid_t, fds: &[(Closing, Signal)],
+    timeout: Option<&(Duration, Arc<AtomicBool>)>)
+    -> io::Result<()>
+{
+    if fds.is_empty() && timeout.is_none() {
+        return Ok(());
+    }
+    let timer = match timeout {
+        Some((timeout, fired)) => Some(Timer::start(*timeout, fired)?),
+        None => None,
+    };
+    let pidfd = sys::pidfd_open(pid).map(Closing::new)?;
+    let fds = fds.iter()
+        .map(|(fd, sig)| {
+            sys::dup_cloexec(fd.as_raw_fd(), 3)
+                .map(|x| (Closing::new(x), *sig))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    thread::Builder::new()
+        .name("unshare-kill-fd".into())
+        .spawn(move || watch(pidfd, fds, timer))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use std::path::Path;
+
+    use crate::{Command, Error, ExitStatus, Namespace, Signal};
+    use crate::pipe::Pipe;
+
+    #[test]
+    fn test_kill_on_close() {
+        let (rd, wr) = Pipe::new().unwrap().split();
+        let mut child = Command::new("/bin/sleep").arg("10")
+            .kill_fd(&rd, Signal::SIGTERM).unwrap()
+            .spawn().unwrap();
+        drop(rd);
+        let start = Instant::now();
+        drop(wr);
+        assert_eq!(child.wait().unwrap(),
+                   ExitStatus::Signaled(Signal::SIGTERM, false));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_kill_namespace_on_timeout() {
+        let marker = std::env::temp_dir().join(
+            format!("unshare-test-ns-timeout-{}", std::process::id()));
+        let mut cmd = Command::new("/bin/sh");
+        // the orphan would outlive the shell unless the namespace is killed
+        cmd.arg("-c").arg(format!("(sleep 0.3; touch {}) & exec sleep 10",
+                                  marker.display()))
+            .kill_namespace_on_timeout(Duration::from_millis(100));
+        let err = cmd.spawn().unwrap_err();
+        assert!(matches!(err, Error::KillFd(libc::EINVAL)), "{:?}", err);
+        let mut child = match cmd.unshare(&[Namespace::Pid]).spawn() {
+            Ok(child) => child,
+            // unprivileged
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) ||
+                          e.raw_os_error() == Some(libc::EACCES) => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(child.wait().unwrap(),
+                   ExitStatus::Signaled(Signal::SIGKILL, false));
+        assert!(child.timed_out());
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(!Path::new(&marker).exists());
+
+        let mut child = Command::new("/bin/true")
+            .unshare(&[Namespace::Pid])
+            .kill_namespace_on_timeout(Duration::from_secs(10))
+            .spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+        assert!(!child.timed_out());
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use libc::pid_t;
+
+use crate::{Command, Child};
+
+
+/// Labels of the running children which have any, by pid
+static LABELS: Mutex<BTreeMap<pid_t, BTreeMap<String, String>>>
+    = Mutex::new(BTreeMap::new());
+
+/// Remembers labels of the spawned child
+pub(crate) fn register(pid: pid_t, labels: &BTreeMap<String, String>) {
+    let mut all = LABELS.lock().unwrap_or_else(|e| e.into_inner());
+    if labels.is_empty() {
+        // the pid may be reused after a labelled child
+        all.remove(&pid);
+    } else {
+        all.insert(pid, labels.clone());
+    }
+}
+
+/// Forgets labels of the child reaped by `Child::wait`
+pub(crate) fn forget(pid: pid_t) {
+    LABELS.lock().unwrap_or_else(|e| e.into_inner()).remove(&pid);
+}
+
+/// Removes and returns labels of the child spawned with `Command::label`
+///
+/// Use it for pids returned by `reap_zombies`, `reap_spawned_zombies`,
+/// `child_events` and `PidfdSet::wait_any`, which know nothing about
+/// labels. Labels are kept until taken, the child is reaped by
+/// `Child::wait`, or another child gets the same pid. Returns `None` if
+/// the child has no labels.
+pub fn take_labels(pid: pid_t) -> Option<BTreeMap<String, String>> {
+    LABELS.lock().unwrap_or_else(|e| e.into_inner()).remove(&pid)
+}
+
+impl Command {
+    /// Add a label to the child, e.g. a job id or a tenant
+    ///
+    /// Labels don't affect the child. They are copied to the `Child` (see
+    /// `Child::labels`), shown in debug output, included in audit records
+    /// and may be looked up by pid with `take_labels`. Setting the same key
+    /// again replaces the value.
+    pub fn label<K: Into<String>, V: Into<String>>(&mut self, key: K,
+        value: V)
+        -> &mut Command
+    {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+}
+
+impl Child {
+    /// Labels set by `Command::label`
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    /// Value of the label set by `Command::label`
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(|x| &x[..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Command;
+    use super::take_labels;
+
+    #[test]
+    fn test_labels() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.label("job", "1").label("tenant", "a").label("job", "2");
+        let debug = format!("{:?}", cmd);
+        assert!(debug.contains(r#"labels={job="2",tenant="a"}"#), "{}", debug);
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(child.label("job"), Some("2"));
+        assert_eq!(child.labels().len(), 2);
+        let pid = child.pid();
+        let labels = take_labels(pid).unwrap();
+        assert_eq!(&labels, child.labels());
+        assert_eq!(take_labels(pid), None);
+        child.wait().unwrap();
+    }
+}
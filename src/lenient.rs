@@ -0,0 +1,61 @@
+use crate::{Command, Child, ExitStatus};
+use crate::error::Error;
+
+
+/// The exit code a shell uses for the failure to execute a command
+fn exit_code(err: &Error) -> i32 {
+    match *err {
+        Error::Exec(libc::ENOENT) => 127,
+        Error::ExecInNamespace { errno: libc::ENOENT, .. } => 127,
+        Error::Exec(_) | Error::ExecInNamespace { .. } => 126,
+        _ => 127,
+    }
+}
+
+impl Command {
+    /// Spawn the command, turning the failure into an exited child
+    ///
+    /// This is for supervisors which treat every failure as the process
+    /// ended badly. If `spawn()` fails, the returned child has already
+    /// exited with code `127` (or `126` if the program is found but can't
+    /// be executed, like shells do), and the error is available from
+    /// `Child::spawn_error`. There is no process then, so `pid()` is
+    /// zero, `wait()` returns the status right away and signals fail.
+    pub fn spawn_lenient(&mut self) -> Child {
+        self.spawn().unwrap_or_else(|err| {
+            let mut child = Child::from_pid(0);
+            child.status = Some(ExitStatus::Exited(exit_code(&err) as i8));
+            child.labels = self.labels.clone();
+            child.spawn_error = Some(err);
+            child
+        })
+    }
+}
+
+impl Child {
+    /// Returns the error if the child is made by a failed `spawn_lenient`
+    pub fn spawn_error(&self) -> Option<&Error> {
+        self.spawn_error.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Error, ExitStatus};
+
+    #[test]
+    fn test_spawn_lenient() {
+        let mut child = Command::new("/nonexistent").spawn_lenient();
+        assert_eq!(child.pid(), 0);
+        assert!(matches!(child.spawn_error(),
+                         Some(Error::Exec(libc::ENOENT))));
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(127));
+        assert!(child.kill().is_err());
+        let mut child = Command::new("/dev/null").spawn_lenient();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(126));
+
+        let mut child = Command::new("/bin/true").spawn_lenient();
+        assert!(child.spawn_error().is_none());
+        assert!(child.wait().unwrap().success());
+    }
+}
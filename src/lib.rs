@@ -26,9 +26,22 @@
 //! Anyway this is low-level interface. You may want to use some higher level
 //! abstraction which mounts filesystems, sets network and monitors processes.
 //!
+//! # Features
+//!
+//! * `nix` (enabled by default) -- conversions between `Signal` and
+//!   `nix::sys::signal::Signal`. The crate itself uses only `libc`, so
+//!   you can build it with `default-features = false` to drop `nix` from
+//!   the dependency tree (useful for small static binaries, e.g. using musl).
+//! * `accounting` -- `Accounting`, which reports resource usage of exited
+//!   children (CPU, IO, delays) from the kernel's taskstats interface.
+//! * `systemd` -- `Command::register_systemd_scope`, which puts the child
+//!   into a new transient scope unit, using a built-in D-Bus client.
+//! * `capi` -- exports C functions (`include/unshare.h`) to embed the
+//!   crate into supervisors written in C or C++.
+//!
 #![warn(missing_docs)]
 extern crate libc;
-extern crate nix;
+#[cfg(feature="nix")] extern crate nix;
 #[cfg(test)] extern crate rand;
 
 mod caps;
@@ -50,29 +63,168 @@ mod wait;
 mod stdio;
 mod debug;
 mod zombies;
+mod privileged;
+mod spawner;
+mod signal;
+mod copy;
+mod env_template;
+mod environ;
+mod elf;
+mod preload;
+mod foreign;
+mod host_files;
+mod report;
+mod kill_fd;
+mod trace;
+mod syscalls;
+mod seccomp;
+mod terminal;
+mod socket;
+mod hardening;
+mod spawn_env;
+mod lines;
+mod mount_plan;
+mod probe;
+mod ports;
+mod audit;
+mod id_map_writer;
+mod shell;
+mod scratch;
+mod idmapped;
+mod fd_table;
+mod spans;
+mod timens;
+mod userns;
+mod exec_in;
+mod exec_fd;
+mod daemon;
+mod fd_plan;
+mod timings;
+mod logger;
+mod env_file;
+mod finalize;
+mod mlock;
+mod core_dump;
+mod shared_mem;
+mod parent_exit;
+mod labels;
+mod path_map;
+mod features;
+mod cgroup;
+mod cleanup;
+mod process_tree;
+mod limiter;
+mod spawn_record;
+mod donate;
+mod loginuid;
+mod error_report;
+mod spec_env;
+mod ready;
+mod shutdown;
+mod lenient;
+mod takeover;
+mod container;
+mod pool;
+mod netlink;
+#[cfg(feature="accounting")] mod accounting;
+#[cfg(feature="systemd")] mod systemd;
+#[cfg(feature="capi")] mod capi;
+mod forward;
+mod mount_provider;
+mod image;
+mod limits;
+mod freeze;
+mod sys;
+mod arch;
+pub mod no_alloc;
+pub mod preexec;
+pub mod mounts;
+pub mod network;
+#[cfg(feature="async-process")] pub mod async_process;
 
-pub use crate::error::Error;
-pub use crate::status::ExitStatus;
-pub use crate::stdio::{Stdio, Fd};
+pub use crate::error::{Error, ConfigError};
+pub use crate::status::{ExitStatus, WaitStatus, WaitOptions};
+pub use crate::stdio::{Stdio, Fd, FdConfig};
 pub use crate::pipe::{PipeReader, PipeWriter};
-pub use crate::namespace::{Namespace};
+pub use crate::namespace::{Namespace, ns_equal, user_namespace_owner};
 pub use crate::idmap::{UidMap, GidMap};
-pub use crate::zombies::{reap_zombies, child_events, ChildEvent};
-pub use nix::sys::signal::Signal;
-pub use crate::debug::{Style, Printer};
+pub use crate::zombies::{reap_zombies, reap_spawned_zombies};
+pub use crate::zombies::{child_events, ChildEvent, PidfdSet};
+pub use crate::signal::Signal;
+pub use crate::linux::reassert_parent_death_signal;
+pub use crate::debug::{Style, Printer, EnvRedaction};
 pub use crate::caps::{Capability};
+pub use crate::privileged::{PrivilegedOps};
+pub use crate::config::{DeathSigScope, OrphanedSetup, ResolvePaths};
+pub use crate::config::{CredentialStep, WaitBackend};
+pub use crate::fds::{FdMapping, FdMappingCollision};
+pub use crate::fd_table::{FdKind, FdEntry};
+pub use crate::spans::{SpanInjector, SpanEvent};
+pub use crate::spans::{set_span_injector, set_span_subscriber};
+pub use crate::limits::Resource;
+pub use crate::network::NetworkBackend;
+pub use crate::forward::PortForward;
+pub use crate::mount_provider::{MountProvider, ProvidedMount};
+pub use crate::image::ImageType;
+pub use crate::host_files::HostFile;
+pub use crate::report::{RunPolicy, RunReport, Output};
+pub use crate::seccomp::{SeccompSupervisor, SeccompRequest, SeccompResponse};
+pub use crate::hardening::{HardeningStep, HardeningReport};
+pub use crate::spawn_env::SpawnEnvironment;
+pub use crate::lines::Lines;
+pub use crate::mount_plan::MountOp;
+pub use crate::probe::ProbeReport;
+pub use crate::timings::SetupTimings;
+pub use crate::finalize::FinalSpec;
+pub use crate::mlock::MemoryLock;
+pub use crate::core_dump::CorePolicy;
+pub use crate::shared_mem::SharedMemory;
+pub use crate::parent_exit::StdioPolicy;
+pub use crate::labels::take_labels;
+pub use crate::path_map::PathPair;
+pub use crate::features::{kernel_features, Features};
+pub use crate::cgroup::{CgroupPolicy, CgroupMount};
+pub use crate::process_tree::ProcessNode;
+pub use crate::spawn_record::SpawnRecord;
+pub use crate::donate::DONATED_DIRS_VAR;
+pub use crate::error_report::ErrorReport;
+pub use crate::ready::Probe;
+pub use crate::shutdown::SHUTDOWN_FD_VAR;
+pub use crate::takeover::LISTENERS_FD_VAR;
+pub use crate::container::{Container, ContainerBuilder, RunningContainer};
+pub use crate::container::ContainerMetrics;
+pub use crate::pool::WarmPool;
+pub use crate::limiter::{SpawnLimiter, LimiterStats};
+pub use crate::audit::{AuditOp, AuditRecord};
+pub use crate::id_map_writer::{IdMapWriter, DirectWrite, NewXidmapCmd};
+#[cfg(feature="accounting")]
+pub use crate::accounting::{Accounting, AccountedEvents, TaskStats};
+#[cfg(feature="systemd")]
+pub use crate::systemd::UnitValue;
 
 use std::ffi::{CString, OsString};
+use std::fs::File;
 use std::path::PathBuf;
 use std::os::unix::io::RawFd;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 
 use crate::pipe::PipeHolder;
+use crate::stdio::Closing;
+use crate::limits::Limits;
+use crate::network::NetworkHelper;
+use crate::mount_provider::Teardown;
+use crate::audit::AuditCallback;
+use crate::finalize::FinalizeCallback;
 
 use libc::{pid_t};
 
 type BoxError = Box<dyn (::std::error::Error) + Send + Sync + 'static>;
+/// Callback run in the parent while the child is frozen, gets its pid
+type FrozenCallback = Box<dyn FnMut(u32) -> Result<(), BoxError>>;
 
 /// Main class for running processes. Works in the spirit of builder pattern.
 pub struct Command {
@@ -82,25 +234,86 @@ pub struct Command {
     config: config::Config,
     fds: HashMap<RawFd, Fd>,
     close_fds: Vec<(RawFd, RawFd)>,
+    keep_fds: Vec<RawFd>,
+    exec_notify: Option<Closing>,
+    exec_fd: Option<Closing>,
     chroot_dir: Option<PathBuf>,
     pivot_root: Option<(PathBuf, PathBuf, bool)>,
-    id_map_commands: Option<(PathBuf, PathBuf)>,
+    copy_files: Vec<(PathBuf, PathBuf, libc::mode_t, copy::Xattrs)>,
+    id_map_writer: Option<Box<dyn IdMapWriter>>,
     pid_env_vars: HashSet<OsString>,
+    env_templates: HashMap<OsString, OsString>,
+    default_path: Option<OsString>,
+    scrub_env: bool,
+    env_files: Vec<PathBuf>,
+    preload: Vec<PathBuf>,
+    foreign_interpreter: Option<PathBuf>,
+    host_files: Vec<HostFile>,
+    scratch: Option<(PathBuf, PathBuf)>,
+    idmapped_mounts: Vec<(PathBuf, PathBuf)>,
     keep_caps: Option<[u32; 2]>,
-    before_unfreeze: Option<Box<dyn FnMut(u32) -> Result<(), BoxError>>>,
+    after_idmap: Option<FrozenCallback>,
+    before_unfreeze: Option<FrozenCallback>,
+    finalize: Option<FinalizeCallback>,
     pre_exec: Option<Box<dyn Fn() -> Result<(), io::Error>>>,
+    privileged_ops: Option<Box<dyn PrivilegedOps>>,
+    inherited_limits: Option<Limits>,
+    userspace_network: Option<(NetworkBackend, Vec<OsString>)>,
+    mount_providers: Vec<(PathBuf, Box<dyn MountProvider>)>,
+    kill_fds: Vec<(Closing, Signal)>,
+    namespace_timeout: Option<(Duration, Arc<AtomicBool>)>,
+    limiter: Option<SpawnLimiter>,
+    donated_dirs: Vec<(String, RawFd)>,
+    shutdown_fd: Option<RawFd>,
+    listener_fd: Option<RawFd>,
+    debug_syscalls: Option<PathBuf>,
+    seccomp_notify: Option<Vec<libc::c_long>>,
+    audit: Option<AuditCallback>,
+    timer: Option<timings::Timer>,
+    logger: Option<Box<Command>>,
+    shared_memory: Option<usize>,
+    labels: BTreeMap<String, String>,
+    #[cfg(feature="systemd")]
+    systemd_scope: Option<systemd::Scope>,
 }
 
 /// The reference to the running child
 #[derive(Debug)]
 pub struct Child {
     pid: pid_t,
+    pidfd: Option<File>,
     status: Option<ExitStatus>,
     fds: HashMap<RawFd, PipeHolder>,
+    fd_kinds: Vec<(RawFd, FdKind)>,
+    closed_fds: Vec<(RawFd, RawFd)>,
     /// Stdin of a child if it is a pipe
+    #[deprecated(note="use `take_stdin()`, the field may change its type")]
     pub stdin: Option<PipeWriter>,
     /// Stdout of a child if it is a pipe
+    #[deprecated(note="use `take_stdout()`, the field may change its type")]
     pub stdout: Option<PipeReader>,
     /// Stderr of a child if it is a pipe
+    #[deprecated(note="use `take_stderr()`, the field may change its type")]
     pub stderr: Option<PipeReader>,
+    network_helper: Option<NetworkHelper>,
+    seccomp: Option<SeccompSupervisor>,
+    /// Terminal given to the child and the previous foreground group
+    terminal: Option<(File, pid_t)>,
+    hardening: HardeningReport,
+    mounts: Vec<MountOp>,
+    scratch: Option<(PathBuf, PathBuf)>,
+    spawned_at: Option<Instant>,
+    setup_timings: Option<SetupTimings>,
+    logger: Option<Box<Child>>,
+    shared_memory: Option<SharedMemory>,
+    labels: BTreeMap<String, String>,
+    timed_out: Option<Arc<AtomicBool>>,
+    spawn_record: Option<Arc<SpawnRecord>>,
+    wait_backend: WaitBackend,
+    shutdown_fd: Option<RawFd>,
+    listener_fd: Option<RawFd>,
+    proc_path: Option<PathBuf>,
+    spawn_error: Option<Error>,
+    start_time: Option<u64>,
+    teardown: Teardown,
 }
@@ -0,0 +1,278 @@
+use std::fmt;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::{Command, Child};
+use crate::error::Error;
+use crate::sys;
+
+
+/// How often a blocked `spawn()` rechecks the limits
+const RECHECK: Duration = Duration::from_millis(10);
+
+/// Limits the rate and the number of running children of many commands
+///
+/// Attach it by `Command::limiter`. Clones refer to the same limiter, so
+/// it may be shared by commands of all tenants of a service (or a limiter
+/// per tenant may be made). A token is taken by each `spawn()` (even if
+/// it fails), and a slot of concurrent children is taken by each child
+/// until it exits. Exit is noticed by a pidfd, so it doesn't matter
+/// whether the child is reaped by `Child::wait`, `reap_zombies` or
+/// whatever.
+///
+/// When the limit is reached, `spawn()` waits until tokens are refilled
+/// or other children exit, or fails with `Error::SpawnLimit(EAGAIN)`
+/// right away if `fail_fast` is set.
+#[derive(Clone)]
+pub struct SpawnLimiter(Arc<Mutex<State>>);
+
+/// Counters of a `SpawnLimiter`, see `SpawnLimiter::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LimiterStats {
+    /// Children spawned through the limiter which are still running
+    pub running: usize,
+    /// Number of `spawn()` calls let through
+    pub admitted: u64,
+    /// Number of `spawn()` calls failed by `fail_fast`
+    pub rejected: u64,
+    /// Number of `spawn()` calls which had to wait
+    pub delayed: u64,
+    /// Total time `spawn()` calls have waited
+    pub waited: Duration,
+}
+
+struct State {
+    rate: Option<(f64, f64)>,
+    tokens: f64,
+    refilled: Instant,
+    max_concurrent: Option<usize>,
+    fail_fast: bool,
+    /// Spawns admitted but not finished yet
+    pending: usize,
+    /// Pidfds of the running children
+    running: Vec<File>,
+    stats: LimiterStats,
+}
+
+impl State {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some((per_second, burst)) = self.rate {
+            let elapsed = (now - self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * per_second).min(burst);
+        }
+        self.refilled = now;
+    }
+    fn prune(&mut self) {
+        self.running.retain(|pidfd| {
+            let mut pfd = libc::pollfd {
+                fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0,
+            };
+            // pidfd is readable when the process exits
+            unsafe { libc::poll(&mut pfd, 1, 0) == 0 }
+        });
+    }
+    fn try_admit(&mut self) -> bool {
+        self.refill();
+        if self.rate.is_some() && self.tokens < 1.0 {
+            return false;
+        }
+        if let Some(max) = self.max_concurrent {
+            self.prune();
+            if self.running.len() + self.pending >= max {
+                return false;
+            }
+        }
+        if self.rate.is_some() {
+            self.tokens -= 1.0;
+        }
+        self.pending += 1;
+        true
+    }
+}
+
+impl SpawnLimiter {
+    /// Creates a limiter which doesn't limit anything yet
+    pub fn new() -> SpawnLimiter {
+        SpawnLimiter(Arc::new(Mutex::new(State {
+            rate: None,
+            tokens: 0.0,
+            refilled: Instant::now(),
+            max_concurrent: None,
+            fail_fast: false,
+            pending: 0,
+            running: Vec::new(),
+            stats: LimiterStats::default(),
+        })))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Allow `per_second` spawns on average, and bursts of up to `burst`
+    ///
+    /// This is a token bucket, which is full initially. Zero `burst` is
+    /// treated as one.
+    pub fn rate(&self, per_second: f64, burst: u32) -> &SpawnLimiter {
+        let burst = burst.max(1) as f64;
+        let mut state = self.state();
+        state.rate = Some((per_second, burst));
+        state.tokens = burst;
+        state.refilled = Instant::now();
+        self
+    }
+
+    /// Allow at most `max` children running at the same time
+    pub fn max_concurrent(&self, max: usize) -> &SpawnLimiter {
+        self.state().max_concurrent = Some(max);
+        self
+    }
+
+    /// Fail `spawn()` instead of waiting when the limit is reached
+    pub fn fail_fast(&self, fail_fast: bool) -> &SpawnLimiter {
+        self.state().fail_fast = fail_fast;
+        self
+    }
+
+    /// Returns the current counters
+    pub fn stats(&self) -> LimiterStats {
+        let mut state = self.state();
+        state.prune();
+        LimiterStats { running: state.running.len(), ..state.stats }
+    }
+
+    /// Waits until the spawn is allowed by the limits
+    pub(crate) fn admit(&self) -> Result<Admission, Error> {
+        let start = Instant::now();
+        let mut delayed = false;
+        loop {
+            {
+                let mut state = self.state();
+                if state.try_admit() {
+                    state.stats.admitted += 1;
+                    if delayed {
+                        state.stats.delayed += 1;
+                        state.stats.waited += start.elapsed();
+                    }
+                    return Ok(Admission(Some(self.clone())));
+                }
+                if state.fail_fast {
+                    state.stats.rejected += 1;
+                    return Err(Error::SpawnLimit(libc::EAGAIN));
+                }
+            }
+            delayed = true;
+            sleep(RECHECK);
+        }
+    }
+
+    fn release(&self, pidfd: Option<File>) {
+        let mut state = self.state();
+        state.pending -= 1;
+        if let Some(pidfd) = pidfd {
+            state.running.push(pidfd);
+        }
+    }
+}
+
+/// The slot of the spawn in progress, released if `spawn()` panics
+pub(crate) struct Admission(Option<SpawnLimiter>);
+
+impl Admission {
+    /// Releases the slot, accounting the child if it's spawned
+    pub(crate) fn finish(mut self, child: Option<&Child>) {
+        let pidfd = child.and_then(|child| match child.pidfd {
+            Some(ref pidfd) => pidfd.try_clone().ok(),
+            None => sys::pidfd_open(child.pid()).ok()
+                .map(|fd| unsafe { File::from_raw_fd(fd) }),
+        });
+        if let Some(limiter) = self.0.take() {
+            limiter.release(pidfd);
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.0.take() {
+            limiter.release(None);
+        }
+    }
+}
+
+impl Default for SpawnLimiter {
+    fn default() -> SpawnLimiter {
+        SpawnLimiter::new()
+    }
+}
+
+impl fmt::Debug for SpawnLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state();
+        f.debug_struct("SpawnLimiter")
+            .field("rate", &state.rate)
+            .field("max_concurrent", &state.max_concurrent)
+            .field("fail_fast", &state.fail_fast)
+            .field("stats", &state.stats)
+            .finish()
+    }
+}
+
+impl Command {
+    /// Limit spawning of the command by the `limiter`
+    ///
+    /// Many commands may share the same limiter. Each invocation
+    /// **replaces** the limiter.
+    pub fn limiter(&mut self, limiter: &SpawnLimiter) -> &mut Command {
+        self.limiter = Some(limiter.clone());
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::{Command, Error};
+    use super::SpawnLimiter;
+
+    #[test]
+    fn test_max_concurrent() {
+        let limiter = SpawnLimiter::new();
+        limiter.max_concurrent(1).fail_fast(true);
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10").limiter(&limiter);
+        let mut child = cmd.spawn().unwrap();
+        let err = cmd.spawn().unwrap_err();
+        assert!(matches!(err, Error::SpawnLimit(libc::EAGAIN)), "{:?}", err);
+        assert_eq!(limiter.stats().running, 1);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let mut child = cmd.spawn().unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let stats = limiter.stats();
+        assert_eq!((stats.running, stats.admitted, stats.rejected), (0, 2, 1));
+    }
+
+    #[test]
+    fn test_rate() {
+        let limiter = SpawnLimiter::new();
+        limiter.rate(20.0, 2);
+        let mut cmd = Command::new("/bin/true");
+        cmd.limiter(&limiter);
+        let start = Instant::now();
+        for _ in 0..4 {
+            cmd.status().unwrap();
+        }
+        // two spawns in a burst, the rest waits for 50ms each
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        let stats = limiter.stats();
+        assert_eq!((stats.admitted, stats.delayed), (4, 2));
+        assert!(stats.waited >= Duration::from_millis(80));
+    }
+}
@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::ptr;
+
+use libc::{c_int, pid_t, cpu_set_t};
+
+use crate::{Child, Command};
+use crate::config::CredentialStep;
+
+
+/// Resource limits of a process as used by `prlimit64` syscall
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Rlimit64 {
+    cur: u64,
+    max: u64,
+}
+
+/// Resource limited by `Child::set_rlimit`
+///
+/// See `man 2 getrlimit` for the details on each one
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Resource {
+    /// CPU time in seconds (`RLIMIT_CPU`)
+    Cpu,
+    /// Maximum size of a file created, in bytes (`RLIMIT_FSIZE`)
+    FileSize,
+    /// Maximum size of the data segment, in bytes (`RLIMIT_DATA`)
+    Data,
+    /// Maximum size of the stack, in bytes (`RLIMIT_STACK`)
+    Stack,
+    /// Maximum size of a core file, in bytes (`RLIMIT_CORE`)
+    Core,
+    /// Resident set size, ignored by modern kernels (`RLIMIT_RSS`)
+    Rss,
+    /// Number of processes of the real user id (`RLIMIT_NPROC`)
+    Processes,
+    /// Maximum file descriptor number plus one (`RLIMIT_NOFILE`)
+    OpenFiles,
+    /// Memory locked into RAM, in bytes (`RLIMIT_MEMLOCK`)
+    LockedMemory,
+    /// Size of the virtual memory, in bytes (`RLIMIT_AS`)
+    AddressSpace,
+    /// Number of file locks, ignored by modern kernels (`RLIMIT_LOCKS`)
+    FileLocks,
+    /// Number of queued signals of the real user id (`RLIMIT_SIGPENDING`)
+    PendingSignals,
+    /// Bytes allocated for POSIX message queues (`RLIMIT_MSGQUEUE`)
+    MessageQueues,
+    /// Ceiling of the nice value, as `20 - limit` (`RLIMIT_NICE`)
+    Nice,
+    /// Ceiling of the real-time priority (`RLIMIT_RTPRIO`)
+    RealtimePriority,
+    /// CPU time of a real-time process, in microseconds (`RLIMIT_RTTIME`)
+    RealtimeCpu,
+}
+
+const RESOURCES: &[Resource] = &[
+    Resource::Cpu,
+    Resource::FileSize,
+    Resource::Data,
+    Resource::Stack,
+    Resource::Core,
+    Resource::Rss,
+    Resource::Processes,
+    Resource::OpenFiles,
+    Resource::LockedMemory,
+    Resource::AddressSpace,
+    Resource::FileLocks,
+    Resource::PendingSignals,
+    Resource::MessageQueues,
+    Resource::Nice,
+    Resource::RealtimePriority,
+    Resource::RealtimeCpu,
+];
+
+impl Resource {
+    fn as_raw(self) -> c_int {
+        use self::Resource::*;
+        (match self {
+            Cpu => libc::RLIMIT_CPU,
+            FileSize => libc::RLIMIT_FSIZE,
+            Data => libc::RLIMIT_DATA,
+            Stack => libc::RLIMIT_STACK,
+            Core => libc::RLIMIT_CORE,
+            Rss => libc::RLIMIT_RSS,
+            Processes => libc::RLIMIT_NPROC,
+            OpenFiles => libc::RLIMIT_NOFILE,
+            LockedMemory => libc::RLIMIT_MEMLOCK,
+            AddressSpace => libc::RLIMIT_AS,
+            FileLocks => libc::RLIMIT_LOCKS,
+            PendingSignals => libc::RLIMIT_SIGPENDING,
+            MessageQueues => libc::RLIMIT_MSGQUEUE,
+            Nice => libc::RLIMIT_NICE,
+            RealtimePriority => libc::RLIMIT_RTPRIO,
+            RealtimeCpu => libc::RLIMIT_RTTIME,
+        }) as c_int
+    }
+}
+
+/// Snapshot of the limits of some process (see `inherit_limits_from`)
+#[derive(Clone)]
+pub struct Limits {
+    rlimits: Vec<(Resource, Rlimit64)>,
+    nice: c_int,
+    affinity: cpu_set_t,
+}
+
+fn prlimit(pid: pid_t, resource: Resource, new: Option<&Rlimit64>)
+    -> io::Result<Rlimit64>
+{
+    let mut old = Rlimit64 { cur: 0, max: 0 };
+    let new = new.map(|x| x as *const Rlimit64).unwrap_or(ptr::null());
+    let rc = unsafe {
+        libc::syscall(libc::SYS_prlimit64, pid, resource.as_raw(), new,
+                      &mut old as *mut Rlimit64)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(old)
+}
+
+/// Parses nice value out of the `/proc/<pid>/stat`
+fn parse_nice(stat: &str) -> Option<c_int> {
+    // process name may contain spaces and parenthesis
+    let fields = &stat[stat.rfind(')')?+1..];
+    // nice is 19th field, counting from pid
+    fields.split_whitespace().nth(16)?.parse().ok()
+}
+
+impl Limits {
+    pub fn read(pid: pid_t) -> io::Result<Limits> {
+        let mut rlimits = Vec::with_capacity(RESOURCES.len());
+        for &res in RESOURCES {
+            rlimits.push((res, prlimit(pid, res, None)?));
+        }
+        let mut stat = String::new();
+        File::open(format!("/proc/{}/stat", pid))?
+            .read_to_string(&mut stat)?;
+        let nice = parse_nice(&stat).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           "can't parse /proc/<pid>/stat")
+        })?;
+        let mut affinity: cpu_set_t = unsafe { mem::zeroed() };
+        let rc = unsafe {
+            libc::sched_getaffinity(pid, mem::size_of::<cpu_set_t>(),
+                                    &mut affinity)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Limits { rlimits, nice, affinity })
+    }
+
+    /// Applies limits to the (frozen) child process
+    pub fn apply(&self, pid: pid_t) -> io::Result<()> {
+        for &(res, ref limit) in &self.rlimits {
+            prlimit(pid, res, Some(limit))?;
+        }
+        let rc = unsafe {
+            libc::syscall(libc::SYS_setpriority,
+                          libc::PRIO_PROCESS as c_int, pid, self.nice)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let rc = unsafe {
+            libc::sched_setaffinity(pid, mem::size_of::<cpu_set_t>(),
+                                    &self.affinity)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Command {
+    /// Copy resource limits, nice value and CPU affinity from the process
+    ///
+    /// Values are read at the time of this call (using `prlimit64` and
+    /// `/proc/<pid>/stat`) and are applied to the child by the parent
+    /// process while the child is frozen (or by the child itself, see
+    /// `credential_order`). This is useful when respawning a crashed
+    /// service or cloning environment of a process.
+    ///
+    /// Note: raising hard limits or decreasing nice value requires
+    /// privileges (`CAP_SYS_RESOURCE` and `CAP_SYS_NICE` respectively),
+    /// without them spawn fails with `Error::SetLimits`. Reading limits of
+    /// another user's process requires them too.
+    pub fn inherit_limits_from(&mut self, pid: pid_t)
+        -> io::Result<&mut Command>
+    {
+        self.inherited_limits = Some(Limits::read(pid)?);
+        Ok(self)
+    }
+
+    /// Limits are applied by the child instead, when `credential_order`
+    /// puts them after other credential changes
+    pub(crate) fn parent_applies_limits(&self) -> bool {
+        self.config.credential_order[0] == CredentialStep::Limits
+    }
+}
+
+impl Child {
+    /// Change resource limit of the running process
+    ///
+    /// This uses `prlimit64` so works for any process we have permissions
+    /// for, without cooperation from the process itself. Use
+    /// `libc::RLIM64_INFINITY` for unlimited values. Note: raising the hard
+    /// limit requires `CAP_SYS_RESOURCE` in the initial user namespace.
+    pub fn set_rlimit(&self, resource: Resource, soft: u64, hard: u64)
+        -> Result<(), io::Error>
+    {
+        // Same protection against pid reuse as in `signal()`
+        if self.status.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid argument: can't limit an exited process",
+            ))
+        }
+        self.check_pid()?;
+        prlimit(self.pid, resource, Some(&Rlimit64 { cur: soft, max: hard }))
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{Command, CredentialStep};
+    use super::{Limits, Resource, parse_nice};
+
+    #[test]
+    fn test_parse_nice() {
+        assert_eq!(parse_nice("1234 (a) b) S 1 1234 1234 0 -1 4194560 \
+            100 0 0 0 1 2 0 0 20 -5 1 0 12345 10000000 100"), Some(-5));
+        assert_eq!(parse_nice("1234 (bash) S 1"), None);
+    }
+
+    #[test]
+    fn test_read_self() {
+        let limits = Limits::read(unsafe { libc::getpid() }).unwrap();
+        assert_eq!(limits.nice, unsafe { libc::nice(0) });
+    }
+
+    #[test]
+    fn test_set_rlimit() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        child.set_rlimit(Resource::OpenFiles, 123, 456).unwrap();
+        let limits = fs::read_to_string(
+            format!("/proc/{}/limits", child.pid())).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(limits.lines().any(|line| {
+            line.starts_with("Max open files") &&
+            line.split_whitespace().collect::<Vec<_>>()[3..5] == ["123", "456"]
+        }), "{}", limits);
+        assert!(child.set_rlimit(Resource::OpenFiles, 1, 1).is_err());
+    }
+
+    #[test]
+    fn test_limits_after_uid() {
+        let mut limits = Limits::read(unsafe { libc::getpid() }).unwrap();
+        for (res, limit) in &mut limits.rlimits {
+            if *res == Resource::OpenFiles {
+                limit.cur = 77;
+            }
+        }
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("test $(ulimit -n) = 77");
+        cmd.inherited_limits = Some(limits);
+        cmd.credential_order([CredentialStep::Gid, CredentialStep::Groups,
+            CredentialStep::Uid, CredentialStep::Limits,
+            CredentialStep::Caps]);
+        assert!(cmd.status().unwrap().success());
+    }
+}
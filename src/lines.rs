@@ -0,0 +1,125 @@
+use std::io::{self, Read};
+use std::os::unix::io::RawFd;
+
+use crate::{Child, PipeReader};
+
+
+/// Iterator over lines written by the child to a pipe
+///
+/// Created by `Child::lines`. Lines are returned without the trailing
+/// newline. Data is read only when the next line is requested and into
+/// a buffer of a fixed size, so the child blocks writing when the
+/// consumer is slow, instead of output piling up in memory. Lines longer
+/// than the buffer are returned in several pieces.
+#[derive(Debug)]
+pub struct Lines {
+    reader: PipeReader,
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl Lines {
+    /// Limit of the line length used by `Child::lines`
+    pub const DEFAULT_MAX_LINE: usize = 65536;
+
+    /// Reads lines from `reader`, splitting the ones longer than `max_line`
+    pub fn new(reader: PipeReader, max_line: usize) -> Lines {
+        Lines {
+            reader,
+            buf: vec![0; max_line.max(1)].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+    /// Returns the pipe, dropping data which is read but not returned yet
+    pub fn into_inner(self) -> PipeReader {
+        self.reader
+    }
+    fn take(&mut self, len: usize, skip: usize) -> Vec<u8> {
+        let line = self.buf[self.start..self.start+len].to_vec();
+        self.start += len + skip;
+        line
+    }
+}
+
+impl Iterator for Lines {
+    type Item = io::Result<Vec<u8>>;
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        loop {
+            let pending = &self.buf[self.start..self.end];
+            if let Some(pos) = pending.iter().position(|&c| c == b'\n') {
+                return Some(Ok(self.take(pos, 1)));
+            }
+            if self.eof {
+                if pending.is_empty() {
+                    return None;
+                }
+                return Some(Ok(self.take(pending.len(), 0)));
+            }
+            if pending.len() == self.buf.len() {
+                return Some(Ok(self.take(pending.len(), 0)));
+            }
+            if self.end == self.buf.len() {
+                self.buf.copy_within(self.start..self.end, 0);
+                self.end -= self.start;
+                self.start = 0;
+            }
+            match self.reader.read(&mut self.buf[self.end..]) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.end += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl Child {
+    /// Returns an iterator over lines written by the child to a pipe
+    ///
+    /// The `fd` is `1` for stdout, `2` for stderr, or any descriptor
+    /// configured as `Fd::piped_write()`. Returns None for other
+    /// configurations or when the pipe is already taken. Lines are limited
+    /// to `Lines::DEFAULT_MAX_LINE` bytes, use `Lines::new` for other
+    /// limits.
+    pub fn lines(&mut self, fd: RawFd) -> Option<Lines> {
+        let reader = match fd {
+            1 => self.take_stdout(),
+            2 => self.take_stderr(),
+            _ => self.take_pipe_reader(fd),
+        };
+        reader.map(|reader| Lines::new(reader, Lines::DEFAULT_MAX_LINE))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Stdio};
+    use crate::pipe::Pipe;
+    use super::Lines;
+
+    #[test]
+    fn test_lines() {
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("echo one; echo; printf 'two\\nlast'")
+            .stdout(Stdio::piped())
+            .spawn().unwrap();
+        let lines = child.lines(1).unwrap()
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(lines, vec![&b"one"[..], b"", b"two", b"last"]);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_long_lines() {
+        let (rd, mut wr) = Pipe::new().unwrap().split();
+        std::io::Write::write_all(&mut wr, b"abcdefg\nhi\n").unwrap();
+        drop(wr);
+        let lines = Lines::new(rd, 3)
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(lines, vec![&b"abc"[..], b"def", b"g", b"hi"]);
+    }
+}
@@ -1,16 +1,20 @@
 use std::ffi::OsStr;
+use std::fs::File;
 use std::io;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
 use std::path::Path;
+use std::time::Duration;
 
-use nix::sys::signal::{Signal};
+use libc::{c_ulong, pid_t};
 
 use crate::ffi_util::ToCString;
-use crate::{Command, Namespace};
+use crate::{Command, Namespace, Signal, DeathSigScope, OrphanedSetup};
+use crate::{ResolvePaths, CredentialStep, NewXidmapCmd};
 use crate::idmap::{UidMap, GidMap};
-use crate::stdio::dup_file_cloexec;
-use crate::namespace::to_clone_flag;
+use crate::stdio::{dup_file_cloexec, Closing};
+use crate::namespace::{self, to_clone_flag, check_namespace_fd};
 use crate::caps::Capability;
+use crate::error::ConfigError;
 
 
 impl Command {
@@ -44,17 +48,155 @@ impl Command {
     ///
     /// 2. The pid namespaces
     ///
-    /// The former is out of scope of this library. The latter works by
+    /// The former is enabled by `init_mode(true)`. The latter works by
     /// ``cmd.unshare(Namespace::Pid)``, but you may need to setup mount points
-    /// and other important things (which are out of scope too).
+    /// and other important things (which are out of scope of this library).
     ///
     /// To reset this behavior use ``allow_daemonize()``.
     ///
+    /// The kernel clears the signal when credentials of the process change,
+    /// so it's set again after switching `uid`/`gid` and capabilities. If
+    /// the `pre_exec` callback changes credentials, it should call
+    /// `reassert_parent_death_signal` afterwards.
     pub fn set_parent_death_signal(&mut self, sig: Signal) -> &mut Command {
         self.config.death_sig = Some(sig);
         self
     }
 
+    /// Returns the signal sent to the child when its parent dies
+    ///
+    /// `None` means the child may outlive the parent (see
+    /// `allow_daemonize`).
+    pub fn parent_death_signal(&self) -> Option<Signal> {
+        self.config.death_sig
+    }
+
+    /// Set which exit triggers the parent death signal
+    ///
+    /// The kernel sends parent death signal when the *thread* that spawned
+    /// the child exits, not when the whole process exits. This is
+    /// surprising when children are spawned from worker threads (or thread
+    /// pools) which may exit while process itself keeps running.
+    ///
+    /// With `DeathSigScope::Process` the child is cloned from a dedicated
+    /// internal thread which lives until the process exits (the calling
+    /// thread blocks until clone is done). Note that the child inherits
+    /// per-thread attributes, such as signal mask, scheduling priority and
+    /// CPU affinity, from this internal thread rather than the calling one.
+    ///
+    /// Default is `DeathSigScope::Thread`.
+    pub fn pdeathsig_scope(&mut self, scope: DeathSigScope) -> &mut Command {
+        self.config.death_sig_scope = scope;
+        self
+    }
+
+    /// Set what child does if parent dies before it's unfrozen
+    ///
+    /// After clone the child waits for the parent to finish setup (write
+    /// uid/gid maps, run `before_unfreeze` callback and so on). If parent
+    /// dies at that time, the setup may be incomplete, so by default the
+    /// child exits with code 127 if any parent-side setup was requested
+    /// (uid maps, privileged ops, `cgroup` placement or a systemd scope),
+    /// and continues otherwise.
+    ///
+    /// Note: if parent death signal is set (default, see
+    /// `set_parent_death_signal`) the child kills itself with this signal
+    /// regardless of this setting.
+    pub fn on_orphaned_setup(&mut self, policy: OrphanedSetup)
+        -> &mut Command
+    {
+        self.config.orphaned_setup = Some(policy);
+        self
+    }
+
+    /// Whether the child exits if the parent dies during setup
+    pub(crate) fn abort_orphaned(&self) -> bool {
+        #[cfg(feature="systemd")]
+        let scope = self.systemd_scope.is_some();
+        #[cfg(not(feature="systemd"))]
+        let scope = false;
+        match self.config.orphaned_setup {
+            Some(OrphanedSetup::Abort) => true,
+            Some(OrphanedSetup::Continue) => false,
+            None => {
+                self.config.id_maps.is_some() ||
+                self.privileged_ops.is_some() ||
+                self.config.cgroup.is_some() ||
+                scope
+            }
+        }
+    }
+
+    /// Configure command to be spawned by an init process
+    ///
+    /// When the calling process is pid 1 (of the host, e.g. in initramfs,
+    /// or of a pid namespace, e.g. container init) the defaults of this
+    /// crate don't make much sense: parent death signal never fires (and
+    /// must not, unless you want all services to die with init), and
+    /// orphaned descendants are reparented to the caller, so it has to reap
+    /// them. This toggle adjusts settings for that case:
+    ///
+    /// 1. Parent death signal is disabled (like `allow_daemonize()`), use
+    ///    `set_parent_death_signal()` after this call to override
+    ///
+    /// 2. If the caller is not pid 1, it's made a subreaper
+    ///    (`prctl(PR_SET_CHILD_SUBREAPER)`) at spawn, so orphaned
+    ///    descendants are reparented to it the same way as to pid 1. Note
+    ///    that this setting is process-wide and stays after `Child` is dead
+    ///
+    /// In both cases use `child_events()` or `reap_zombies()` to reap all
+    /// the children, as `Child::wait` only waits for the immediate child.
+    ///
+    /// Disabling the mode restores default parent death signal (`SIGKILL`)
+    /// if it was disabled by enabling the mode. The signal set by
+    /// `set_parent_death_signal()` is kept.
+    pub fn init_mode(&mut self, enable: bool) -> &mut Command {
+        if enable {
+            self.config.death_sig = None;
+        } else if self.config.init_mode && self.config.death_sig.is_none() {
+            self.config.death_sig = Some(Signal::SIGKILL);
+        }
+        self.config.init_mode = enable;
+        self
+    }
+
+    /// Set how symlinks are handled in paths inside the new root
+    ///
+    /// With `ResolvePaths::NoFollow` the paths that are resolved inside the
+    /// new root (`put_old` of `pivot_root`, chroot dir after pivot,
+    /// working directory) must not contain symlinks or `..` pointing outside
+    /// of the root, otherwise spawn fails with `Error::ChangeRoot` (usually
+    /// with `ELOOP` or `EXDEV`). This prevents symlinks planted in an
+    /// untrusted image to redirect setup outside of the intended tree.
+    ///
+    /// Paths are resolved using `openat2(RESOLVE_BENEATH|RESOLVE_NO_SYMLINKS)`
+    /// when supported by the kernel (linux 5.6+) and by walking path
+    /// component by component otherwise. Chroot and working directory are
+    /// then entered by the file descriptor, so they can't be swapped
+    /// in-between.
+    ///
+    /// The roots themselves (`new_root`, and chroot dir when there is no
+    /// pivot) are trusted and resolved as usual. Default is
+    /// `ResolvePaths::Follow`.
+    pub fn resolve_paths(&mut self, policy: ResolvePaths) -> &mut Command {
+        self.config.resolve_paths = policy;
+        self
+    }
+
+    /// Make all mounts private in the child's mount namespace
+    ///
+    /// This runs equivalent of ``mount --make-rprivate /`` in the child
+    /// before any other mount operation (i.e. before `pivot_root`). Without
+    /// that, mounts and unmounts done in the child propagate to the parent
+    /// mount namespace on systems where `/` is shared (e.g. with systemd).
+    ///
+    /// Default is on if mount namespace is unshared (see `unshare`), and
+    /// off otherwise.
+    pub fn make_private_rec(&mut self, enable: bool) -> &mut Command {
+        self.config.make_private_rec = Some(enable);
+        self
+    }
+
     /// Set chroot dir. Only absolute path is supported
     ///
     /// This method has a non-standard security feature: even if current_dir
@@ -68,16 +210,24 @@ impl Command {
     ///
     /// # Panics
     ///
-    /// If directory is not absolute
+    /// If directory is not absolute, see `try_chroot_dir`
     pub fn chroot_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command
+    {
+        self.try_chroot_dir(dir).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Set chroot dir, returning an error instead of panicking
+    ///
+    /// See `chroot_dir`.
+    pub fn try_chroot_dir<P: AsRef<Path>>(&mut self, dir: P)
+        -> Result<&mut Command, ConfigError>
     {
         let dir = dir.as_ref();
         if !dir.is_absolute() {
-            panic!("Chroot dir must be absolute");
+            return Err(ConfigError::RelativeChrootDir(dir.to_path_buf()));
         }
         self.chroot_dir = Some(dir.to_path_buf());
-
-        self
+        Ok(self)
     }
 
     /// Moves the root of the file system to the directory `put_old` and
@@ -91,10 +241,10 @@ impl Command {
     ///
     /// **Warning** if you don't unshare the mount namespace you will get
     /// moved filesystem root for *all processes running in that namespace*
-    /// including parent (currently running) process itself. If you don't
-    /// run equivalent to ``mount --make-private`` for the old root filesystem
-    /// and set ``unmount`` to true, you may get unmounted filesystem for
-    /// running processes too.
+    /// including parent (currently running) process itself. If mounts are
+    /// not private (see `make_private_rec`, which is on by default when
+    /// mount namespace is unshared) and ``unmount`` is true, you may get
+    /// unmounted filesystem for running processes too.
     ///
     /// See `man 2 pivot` for further details
     ///
@@ -104,28 +254,40 @@ impl Command {
     /// # Panics
     ///
     /// Panics if either path is not absolute or new_root is not a prefix of
-    /// put_old.
+    /// put_old, see `try_pivot_root`.
     pub fn pivot_root<A: AsRef<Path>, B:AsRef<Path>>(&mut self,
         new_root: A, put_old: B, unmount: bool)
         -> &mut Command
+    {
+        self.try_pivot_root(new_root, put_old, unmount)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Set `pivot_root`, returning an error instead of panicking
+    ///
+    /// See `pivot_root`.
+    pub fn try_pivot_root<A: AsRef<Path>, B:AsRef<Path>>(&mut self,
+        new_root: A, put_old: B, unmount: bool)
+        -> Result<&mut Command, ConfigError>
     {
         let new_root = new_root.as_ref();
         let put_old = put_old.as_ref();
         if !new_root.is_absolute() {
-            panic!("New root must be absolute");
+            return Err(ConfigError::RelativeNewRoot(new_root.to_path_buf()));
         };
         if !put_old.is_absolute() {
-            panic!("The `put_old` dir must be absolute");
+            return Err(ConfigError::RelativePutOld(put_old.to_path_buf()));
         }
         let mut old_cmp = put_old.components();
         for (n, o) in new_root.components().zip(old_cmp.by_ref()) {
             if n != o {
-                panic!("The new_root is not a prefix of put old");
+                return Err(ConfigError::PutOldOutsideNewRoot(
+                    new_root.to_path_buf(), put_old.to_path_buf()));
             }
         }
         self.pivot_root = Some((new_root.to_path_buf(), put_old.to_path_buf(),
                                 unmount));
-        self
+        Ok(self)
     }
 
     /// Unshare given namespaces
@@ -151,11 +313,75 @@ impl Command {
     /// See `man 2 setns` for further details
     ///
     /// Note: using `unshare` and `setns` for the same namespace is meaningless.
+    ///
+    /// The file is checked to be a namespace of the specified kind (using
+    /// `NS_GET_NSTYPE` ioctl, so linux 4.11 is required), otherwise an
+    /// error of `InvalidInput` kind is returned.
+    ///
+    /// The user namespace is joined before the others, regardless of the
+    /// order of calls, so namespaces owned by it can be joined with the
+    /// capabilities the child gets there (e.g. when unprivileged).
+    ///
+    /// Joining a pid namespace only affects children created afterwards,
+    /// so the pid namespace is joined by the thread which clones the child
+    /// (as in `Child::exec_in`), and the child itself is in it.
+    ///
+    /// After joining a mount namespace, the program path is resolved in
+    /// that namespace, and `spawn()` returns `Error::ExecInNamespace` if
+    /// it's not found there. Use `exec_fd` to run a file of the host.
     pub fn set_namespace<F: AsRawFd>(&mut self, file: &F, ns: Namespace)
         -> io::Result<&mut Command>
     {
+        check_namespace_fd(file.as_raw_fd(), ns)?;
         let fd = dup_file_cloexec(file)?;
-        self.config.setns_namespaces.insert(ns, fd);
+        if ns == Namespace::Pid {
+            self.config.clone_pid_ns = Some(fd);
+        } else {
+            self.config.setns_namespaces.insert(ns, fd);
+        }
+        Ok(self)
+    }
+
+    /// Join namespaces persisted as files in the directory
+    ///
+    /// The files are named like in `/proc/<pid>/ns`: `mnt`, `uts`, `ipc`,
+    /// `user`, `pid`, `net` and `cgroup` (usually bind mounts made by a
+    /// container manager). Each present file is opened and joined as by
+    /// `set_namespace` (the user namespace is always joined first), missing
+    /// ones are skipped. Nothing is configured if any file fails, and the
+    /// error message names the file. An error of `NotFound` kind is
+    /// returned if there are no namespace files at all.
+    pub fn join_ns_dir<P: AsRef<Path>>(&mut self, dir: P)
+        -> io::Result<&mut Command>
+    {
+        let dir = dir.as_ref();
+        let attribute = |path: &Path, e: io::Error| {
+            io::Error::new(e.kind(), format!("{:?}: {}", path, e))
+        };
+        let mut files = Vec::new();
+        for &ns in namespace::ALL {
+            let path = dir.join(namespace::proc_name(ns));
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(attribute(&path, e)),
+            };
+            check_namespace_fd(file.as_raw_fd(), ns)
+                .map_err(|e| attribute(&path, e))?;
+            files.push((ns, file));
+        }
+        if files.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("no namespace files in {:?}", dir)));
+        }
+        for (ns, file) in files {
+            let fd = Closing::new(file.into_raw_fd());
+            if ns == Namespace::Pid {
+                self.config.clone_pid_ns = Some(fd);
+            } else {
+                self.config.setns_namespaces.insert(ns, fd);
+            }
+        }
         Ok(self)
     }
 
@@ -174,7 +400,13 @@ impl Command {
     ///
     /// The library will not try to guess the behavior. By default it will
     /// write directly. You need to call the `set_id_map_commands` when you
-    /// want non-default behavior.
+    /// want non-default behavior, or `id_map_writer` for other ways (e.g.
+    /// a privileged broker).
+    ///
+    /// When the current process is in a user namespace itself (e.g. in a
+    /// rootless container), `outside` ids are the ids of that namespace,
+    /// and must be mapped there (see `UidMap::current`). This is checked on
+    /// `spawn()`, see `Error::UserNamespace`.
     ///
     /// See `man 7 user_namespaces` for more info
     pub fn set_id_maps(&mut self, uid_map: Vec<UidMap>, gid_map: Vec<GidMap>)
@@ -194,15 +426,13 @@ impl Command {
     ///
     /// See `man 1 newuidmap`, `man 1 newgidmap` for details
     ///
-    /// This method is no-op unless `set_id_maps` is called.
+    /// This method is no-op unless `set_id_maps` is called. It's a shortcut
+    /// for `id_map_writer(NewXidmapCmd::new(newuidmap, newgidmap))`.
     pub fn set_id_map_commands<A: AsRef<Path>, B: AsRef<Path>>(&mut self,
         newuidmap: A, newgidmap: B)
         -> &mut Command
     {
-        self.id_map_commands = Some((
-            newuidmap.as_ref().to_path_buf(),
-            newgidmap.as_ref().to_path_buf()));
-        self
+        self.id_map_writer(NewXidmapCmd::new(newuidmap, newgidmap))
     }
 
     /// Keep signal mask intact after executing child, keeps also ignored
@@ -220,6 +450,43 @@ impl Command {
         self
     }
 
+    /// Reset `SIGPIPE` to `SIG_DFL` before `execve()` (default `true`)
+    ///
+    /// Rust programs ignore `SIGPIPE`, and the ignored disposition is
+    /// inherited by the program, so shell-like programs writing into
+    /// a closed pipe get `EPIPE` instead of being killed. Unlike other
+    /// signals, `SIGPIPE` is reset even with `keep_sigmask`, unless this
+    /// is set to `false`. Without `keep_sigmask` it's reset anyway.
+    pub fn restore_sigpipe(&mut self, restore: bool) -> &mut Command {
+        self.config.restore_sigpipe = restore;
+        self
+    }
+
+    /// Start the program with the signals blocked
+    ///
+    /// The signals are added to the signal mask right before `execve()`,
+    /// after it's reset (see `keep_sigmask`) and after `pre_exec`, so
+    /// e.g. `SIGTERM` sent during startup stays pending until the program
+    /// installs its handlers and unblocks it. `SIGKILL` and `SIGSTOP`
+    /// can't be blocked and are silently ignored by the kernel. An invalid
+    /// signal number fails `spawn()` with `Error::BlockSignals`.
+    ///
+    /// Each invocation **replaces** the list of signals.
+    pub fn blocked_signals(&mut self, signals: &[Signal]) -> &mut Command {
+        self.config.blocked_signals = signals.to_vec();
+        self
+    }
+
+    /// Set the file mode creation mask of the child
+    ///
+    /// By default, the mask of the current process is inherited. It's set
+    /// right before executing the program, so doesn't affect files written
+    /// by `copy_into_root`.
+    pub fn umask(&mut self, mask: libc::mode_t) -> &mut Command {
+        self.config.umask = Some(mask & 0o777);
+        self
+    }
+
     /// Set the argument zero for the process
     ///
     /// By default argument zero is same as path to the program to run. You
@@ -235,9 +502,10 @@ impl Command {
     ///
     /// If child process is being launched as a foreground job,
     /// the child process group needs to be put into the foreground on
-    /// the controlling terminal using `tcsetpgrp`. To request status
-    /// information from stopped child process you should call `waitpid` with
-    /// `WUNTRACED` flag. And then check status with `WIFSTOPPED` macro.
+    /// the controlling terminal using `tcsetpgrp` (see
+    /// `Child::give_terminal` and `Child::take_terminal`). To find out
+    /// that the child process is stopped use `Child::wait_with_options`
+    /// with `WaitOptions::stopped()`.
     /// After giving child process group access to the controlling terminal
     /// you should send the SIGCONT signal to the child process group.
     pub fn make_group_leader(&mut self, make_group_leader: bool) -> &mut Command {
@@ -245,6 +513,21 @@ impl Command {
         self
     }
 
+    /// Use packet mode pipes for internal communication with the child
+    ///
+    /// The library uses two internal pipes to wake the child up and to
+    /// receive errors from it. In packet mode (`O_DIRECT` flag, see `man 2
+    /// pipe`) each error frame is read by exactly one `read` call, so frames
+    /// can never be read partially or merged. When kernel doesn't support
+    /// packet mode, normal pipes are used silently.
+    ///
+    /// This is enabled by default, and you should only disable it when
+    /// something (like seccomp filter) breaks the `O_DIRECT` flag.
+    pub fn packet_pipes(&mut self, enable: bool) -> &mut Command {
+        self.config.packet_pipes = enable;
+        self
+    }
+
     /// Inserts a magic environment variable that will contain pid of spawned
     /// process
     ///
@@ -266,6 +549,7 @@ impl Command {
     {
         self.init_env_map();
         self.environ.as_mut().unwrap().remove(key.as_ref());
+        self.env_templates.remove(key.as_ref());
         self.pid_env_vars.insert(key.as_ref().to_os_string());
         self
     }
@@ -280,7 +564,8 @@ impl Command {
     ///
     /// This works both when uid changes (from 0 to other) and when it
     /// isn't changed, but requires process to have all capabilities
-    /// granted by this method.
+    /// granted by this method. Failure to raise ambient capabilities on
+    /// kernels supporting them is reported as `Error::AmbientCaps`.
     ///
     /// This method replaces whole capability mask on each invocation
     pub fn keep_caps<'x>(&mut self,
@@ -293,4 +578,421 @@ impl Command {
         }
         self.keep_caps = Some(buf);
     }
+
+    /// Change the order in which credentials of the child are set
+    ///
+    /// Default order is `Limits`, `Gid`, `Groups`, `Uid`, `Caps`. Some
+    /// setups need another one, for example limiting capabilities before
+    /// changing groups, or applying `RLIMIT_NPROC` (which is checked
+    /// against the processes of the new user on `setuid`) after the user
+    /// is changed. Note that raising limits after changing user requires
+    /// keeping `CAP_SYS_RESOURCE`, and changing user after setting
+    /// capabilities clears effective and ambient capabilities.
+    ///
+    /// When `Limits` isn't the first step, limits are applied by the child
+    /// itself rather than by the parent while the child is frozen.
+    ///
+    /// # Panics
+    ///
+    /// If some step is listed twice.
+    pub fn credential_order(&mut self, order: [CredentialStep; 5])
+        -> &mut Command
+    {
+        for (idx, step) in order.iter().enumerate() {
+            if order[..idx].contains(step) {
+                panic!("Credential step {:?} is listed twice", step);
+            }
+        }
+        self.config.credential_order = order;
+        self
+    }
+
+    /// Retry executing the program if it fails with `ETXTBSY`
+    ///
+    /// The error means the file is open for writing, which often happens
+    /// for a freshly written binary: a process forked by another thread
+    /// holds a copy of the descriptor until it executes its own program.
+    /// The child retries up to `attempts` more times, sleeping `delay`
+    /// between attempts, and then fails with `Error::Exec(ETXTBSY)` as
+    /// usual. Other errors fail right away. Disabled by default.
+    pub fn retry_etxtbsy(&mut self, attempts: u32, delay: Duration)
+        -> &mut Command
+    {
+        self.config.retry_etxtbsy = Some((attempts, delay));
+        self
+    }
+}
+
+/// Set the parent death signal of the current process again
+///
+/// The kernel clears the signal on credential changes (`setuid`,
+/// `setgid`, `capset` and so on). This function is for the `pre_exec`
+/// callbacks changing credentials, it doesn't allocate. The `parent` is
+/// the pid of the parent as seen by the child (use `getppid()` before
+/// changing credentials): if it has died already, the signal is sent to
+/// the current process right away and an error is returned.
+pub fn reassert_parent_death_signal(sig: Signal, parent: pid_t)
+    -> io::Result<()>
+{
+    unsafe {
+        let raw = sig.as_raw() as c_ulong;
+        if libc::prctl(libc::PR_SET_PDEATHSIG, raw, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::getppid() != parent {
+            libc::kill(libc::getpid(), sig.as_raw());
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{self, BufRead, BufReader, Read};
+    use std::process;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use crate::{Command, Error, Stdio, ChildEvent, Signal, child_events};
+    use crate::{Namespace, CgroupPolicy, OrphanedSetup};
+    use crate::{Capability, CredentialStep};
+
+    /// Set for the test process started by `run_isolated`, to the file
+    /// the inner test writes its output to when it passes
+    const INNER_VAR: &str = "UNSHARE_TEST_INNER";
+
+    /// Runs `f` in a new process of the test `name` of this module (forking
+    /// the multithreaded test harness isn't safe), optionally as pid 1 of
+    /// a new pid namespace. Returns the output of `f`, or `None` if
+    /// namespace can't be created (i.e. when running unprivileged)
+    ///
+    /// The test must call this first: in the new process `f` is run and
+    /// the process exits.
+    fn run_isolated(name: &str, new_pid_ns: bool, f: fn() -> String)
+        -> Option<String>
+    {
+        if let Some(marker) = env::var_os(INNER_VAR) {
+            fs::write(marker, f()).unwrap();
+            process::exit(0);
+        }
+        let marker = env::temp_dir().join(
+            format!("unshare-test-{}-{}", name, std::process::id()));
+        fs::remove_file(&marker).ok();
+        let mut cmd = Command::new("/proc/self/exe");
+        cmd.arg("--exact").arg(format!("linux::test::{}", name))
+            .env(INNER_VAR, &marker)
+            .stdout(Stdio::null()).stderr(Stdio::null());
+        if new_pid_ns {
+            cmd.unshare(&[Namespace::Pid]);
+        }
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(ref e) if new_pid_ns &&
+                e.raw_os_error() == Some(libc::EPERM) => return None,
+            Err(e) => panic!("can't run {}: {}", name, e),
+        };
+        let status = child.wait().unwrap();
+        assert!(status.success(), "{} failed with {}", name, status);
+        let output = fs::read_to_string(&marker)
+            .expect("the inner test didn't run");
+        fs::remove_file(&marker).unwrap();
+        Some(output)
+    }
+
+    /// Spawns a shell which leaves an orphan behind and checks that we reap
+    /// the orphan
+    fn reap_orphan() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("/bin/sleep 0.1 & echo $!");
+        cmd.stdout(Stdio::piped());
+        cmd.init_mode(true);
+        assert!(cmd.config.death_sig.is_none());
+        let mut child = cmd.spawn().unwrap();
+        let mut line = String::new();
+        BufReader::new(child.take_stdout().unwrap())
+            .read_line(&mut line).unwrap();
+        let orphan: i32 = line.trim().parse().unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            for event in child_events() {
+                if let ChildEvent::Death(pid, status) = event {
+                    assert_eq!(pid, orphan);
+                    assert!(status.success());
+                    return;
+                }
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("orphan was not reaped");
+    }
+
+    #[test]
+    fn test_init_mode_death_signal() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.init_mode(true).init_mode(false);
+        assert_eq!(cmd.parent_death_signal(), Some(Signal::SIGKILL));
+        cmd.set_parent_death_signal(Signal::SIGTERM).init_mode(false);
+        assert_eq!(cmd.parent_death_signal(), Some(Signal::SIGTERM));
+        cmd.allow_daemonize().init_mode(false);
+        assert_eq!(cmd.parent_death_signal(), None);
+    }
+
+    #[test]
+    fn test_init_mode_subreaper() {
+        assert!(run_isolated("test_init_mode_subreaper", false, || {
+            assert!(unsafe { libc::getpid() } != 1);
+            reap_orphan();
+            let mut flag = 0;
+            unsafe {
+                libc::prctl(libc::PR_GET_CHILD_SUBREAPER, &mut flag);
+            }
+            assert_eq!(flag, 1);
+            String::new()
+        }).is_some());
+    }
+
+    #[test]
+    fn test_init_mode_pid1() {
+        let output = run_isolated("test_init_mode_pid1", true, || {
+            assert_eq!(unsafe { libc::getpid() }, 1);
+            reap_orphan();
+            let mut flag = 0;
+            unsafe {
+                libc::prctl(libc::PR_GET_CHILD_SUBREAPER, &mut flag);
+            }
+            assert_eq!(flag, 0);
+            String::new()
+        });
+        if output.is_none() {
+            eprintln!("skipping pid namespace test: not permitted");
+        }
+    }
+
+    fn is_dead(pid: i32) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            // zombie may be not reaped by init yet
+            Ok(stat) => stat[stat.rfind(')').unwrap()..].starts_with(") Z"),
+            Err(_) => true,
+        }
+    }
+
+    /// Spawns the command in the process started by `run_isolated`,
+    /// returns the pid of the child (empty if credentials can't be changed)
+    fn spawn_dying(cmd: &mut Command) -> String {
+        assert_eq!(cmd.parent_death_signal(), Some(Signal::SIGKILL));
+        match cmd.spawn() {
+            Ok(child) => child.pid().to_string(),
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => {
+                String::new()
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Checks that the child spawned by `f` in the test process which exits
+    /// right away is killed by parent death signal
+    fn assert_dies_with_parent(name: &str, f: fn() -> String) {
+        let output = run_isolated(name, false, f).unwrap();
+        if output.is_empty() {
+            eprintln!("skipping death signal test: can't change credentials");
+            return;
+        }
+        let child_pid: i32 = output.parse().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !is_dead(child_pid) {
+            assert!(Instant::now() < deadline, "child outlived its parent");
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_death_signal_after_setuid() {
+        assert_dies_with_parent("test_death_signal_after_setuid", || {
+            spawn_dying(Command::new("/bin/sleep").arg("10")
+                .uid(65534).gid(65534))
+        });
+    }
+
+    #[test]
+    fn test_death_signal_after_setuid_only() {
+        assert_dies_with_parent("test_death_signal_after_setuid_only", || {
+            spawn_dying(Command::new("/bin/sleep").arg("10")
+                .uid(65534))
+        });
+    }
+
+    #[test]
+    fn test_death_signal_after_setgroups() {
+        assert_dies_with_parent("test_death_signal_after_setgroups", || {
+            spawn_dying(Command::new("/bin/sleep").arg("10")
+                .gid(65534).groups(vec![65534]))
+        });
+    }
+
+    fn effective_caps(cmd: &mut Command) -> String {
+        cmd.arg("-c").arg("grep CapEff /proc/self/status")
+            .uid(65534).gid(65534)
+            .stdout(Stdio::piped());
+        cmd.keep_caps(&[Capability::CAP_NET_BIND_SERVICE,
+                        Capability::CAP_SETUID]);
+        let mut child = cmd.spawn().unwrap();
+        let mut output = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        output.split_whitespace().nth(1).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_credential_order() {
+        assert_eq!(effective_caps(&mut Command::new("/bin/sh")),
+                   "0000000000000480");
+        // setuid clears capabilities set before
+        assert_eq!(effective_caps(Command::new("/bin/sh")
+            .credential_order([CredentialStep::Limits, CredentialStep::Gid,
+                CredentialStep::Groups, CredentialStep::Caps,
+                CredentialStep::Uid])),
+            "0000000000000000");
+    }
+
+    #[test]
+    #[should_panic(expected="listed twice")]
+    fn test_credential_order_duplicate() {
+        Command::new("/bin/true")
+            .credential_order([CredentialStep::Uid, CredentialStep::Limits,
+                CredentialStep::Gid, CredentialStep::Groups,
+                CredentialStep::Uid]);
+    }
+
+    #[test]
+    fn test_try_roots() {
+        use std::path::PathBuf;
+        use crate::ConfigError;
+
+        let mut cmd = Command::new("/bin/true");
+        assert_eq!(cmd.try_chroot_dir("inner").unwrap_err(),
+                   ConfigError::RelativeChrootDir(PathBuf::from("inner")));
+        assert_eq!(cmd.try_pivot_root("/root", "/tmp/old", false)
+                   .unwrap_err(),
+                   ConfigError::PutOldOutsideNewRoot(PathBuf::from("/root"),
+                       PathBuf::from("/tmp/old")));
+        assert!(cmd.chroot_dir.is_none() && cmd.pivot_root.is_none());
+        cmd.try_pivot_root("/root", "/root/old", false).unwrap()
+            .try_chroot_dir("/inner").unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected="must be absolute")]
+    fn test_relative_chroot_dir() {
+        Command::new("/bin/true").chroot_dir("inner");
+    }
+
+    fn sigpipe_ignored(cmd: &mut Command) -> bool {
+        let mut child = cmd.stdout(Stdio::piped()).spawn().unwrap();
+        let mut out = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut out).unwrap();
+        assert!(child.wait().unwrap().success());
+        let mask = u64::from_str_radix(out.trim().rsplit('\t').next().unwrap(),
+                                       16).unwrap();
+        mask & (1 << (libc::SIGPIPE - 1)) != 0
+    }
+
+    #[test]
+    fn test_restore_sigpipe() {
+        // ignored by the test harness (as by any rust program)
+        let mut cmd = Command::new("/bin/grep");
+        cmd.arg("^SigIgn").arg("/proc/self/status").keep_sigmask();
+        assert!(!sigpipe_ignored(&mut cmd));
+        assert!(sigpipe_ignored(cmd.restore_sigpipe(false)));
+    }
+
+    #[test]
+    fn test_blocked_signals() {
+        let mut cmd = Command::new("/bin/grep");
+        cmd.arg("^SigBlk").arg("/proc/self/status")
+            .stdout(Stdio::piped())
+            .blocked_signals(&[Signal::SIGTERM, Signal::SIGUSR1]);
+        let mut child = cmd.spawn().unwrap();
+        let mut out = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut out).unwrap();
+        assert!(child.wait().unwrap().success());
+        let mask = out.trim().rsplit('\t').next().unwrap();
+        assert_eq!(u64::from_str_radix(mask, 16).unwrap(),
+                   (1 << (libc::SIGTERM - 1)) | (1 << (libc::SIGUSR1 - 1)));
+
+        let err = cmd.blocked_signals(&[Signal::from_raw(1000)])
+            .spawn().unwrap_err();
+        assert!(matches!(err, Error::BlockSignals(libc::EINVAL)), "{:?}", err);
+    }
+    #[test]
+    fn test_retry_etxtbsy() {
+        let path = std::env::temp_dir().join("unshare-test-etxtbsy");
+        fs::copy("/bin/true", &path).unwrap();
+        let writer = || Command::new("/bin/sh").arg("-c")
+            .arg("exec 3>>\"$0\"; sleep 0.2").arg(&path)
+            .spawn().unwrap();
+        let wait_open = || sleep(Duration::from_millis(50));
+        let mut holder = writer();
+        wait_open();
+        let err = Command::new(&path).spawn().unwrap_err();
+        assert!(matches!(err, Error::Exec(libc::ETXTBSY)), "{:?}", err);
+        holder.wait().unwrap();
+        let mut holder = writer();
+        wait_open();
+        let mut child = Command::new(&path)
+            .retry_etxtbsy(100, Duration::from_millis(10))
+            .spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+        holder.wait().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_join_ns_dir() {
+        let dir = std::env::temp_dir().join("unshare-test-ns-dir");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir(&dir).unwrap();
+        let mut cmd = Command::new("/bin/true");
+        let err = cmd.join_ns_dir(&dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let mut holder = match Command::new("/bin/sleep").arg("10")
+            .unshare(&[Namespace::Uts]).spawn()
+        {
+            Ok(child) => child,
+            // unprivileged
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) ||
+                          e.raw_os_error() == Some(libc::EACCES) => return,
+            Err(e) => panic!("{}", e),
+        };
+        let uts = format!("/proc/{}/ns/uts", holder.pid());
+        std::os::unix::fs::symlink(&uts, dir.join("uts")).unwrap();
+        std::os::unix::fs::symlink(&uts, dir.join("net")).unwrap();
+        let err = cmd.join_ns_dir(&dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("/net"), "{}", err);
+        assert!(cmd.config.setns_namespaces.is_empty());
+        fs::remove_file(dir.join("net")).unwrap();
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("[ \"$(readlink /proc/self/ns/uts)\" = \"$0\" ]")
+            .arg(fs::read_link(&uts).unwrap())
+            .join_ns_dir(&dir).unwrap()
+            .spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+        holder.kill().unwrap();
+        holder.wait().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_abort_orphaned_default() {
+        let mut cmd = Command::new("/bin/true");
+        assert!(!cmd.abort_orphaned());
+        cmd.cgroup(CgroupPolicy::MoveTo("/sys/fs/cgroup/x".into()));
+        assert!(cmd.abort_orphaned());
+        cmd.on_orphaned_setup(OrphanedSetup::Continue);
+        assert!(!cmd.abort_orphaned());
+    }
 }
@@ -0,0 +1,122 @@
+use std::os::unix::io::AsRawFd;
+
+use crate::{Command, Child};
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::pipe::Pipe;
+use crate::stdio::{Fd, Closing};
+use crate::sys;
+
+
+impl Command {
+    /// Send stdout and stderr of the child to the stdin of the `logger`
+    ///
+    /// On every `spawn()` the logger is spawned first (with its own
+    /// configuration, so it may be sandboxed differently or not at all),
+    /// then the child, both ends of the pipe are closed in the current
+    /// process. So when the child and its descendants exit, the logger gets
+    /// end of file. Like `svlogd` paired with a service by `runit`.
+    ///
+    /// Stdout and stderr configured for the child are replaced. The stdin
+    /// of the logger is replaced too (other descriptors of the logger,
+    /// e.g. stdout, are used as configured).
+    ///
+    /// The logger is owned by the returned `Child` (see `Child::logger`),
+    /// `wait()` returns after both the child and the logger exit. If
+    /// the logger can't be spawned, its error is returned by `spawn()`. If
+    /// the child can't be spawned, the logger is killed.
+    ///
+    /// Each invocation **replaces** previously set logger.
+    pub fn pipe_output_to(&mut self, logger: Command) -> &mut Command {
+        self.logger = Some(Box::new(logger));
+        self
+    }
+
+    /// Spawns the logger, and connects stdout and stderr to it
+    pub(crate) fn spawn_logger(&mut self) -> Result<Option<Child>, Error> {
+        let logger = match self.logger {
+            Some(ref mut logger) => logger,
+            None => return Ok(None),
+        };
+        let (rd, wr) = Pipe::new()?.split();
+        let wr = Closing::new(wr.into_fd());
+        let dup = Closing::new(
+            result(Err::CreatePipe, sys::dup_cloexec(wr.as_raw_fd(), 3))?);
+        logger.fds.insert(0, Fd::Fd(Closing::new(rd.into_fd())));
+        let spawned = logger.spawn();
+        // the read end is closed here
+        logger.fds.insert(0, Fd::Inherit);
+        let child = spawned?;
+        self.fds.insert(1, Fd::Fd(wr));
+        self.fds.insert(2, Fd::Fd(dup));
+        Ok(Some(child))
+    }
+
+    /// Closes the write end of the logger pipe after `spawn()`
+    pub(crate) fn release_logger_pipe(&mut self) {
+        if self.logger.is_some() {
+            self.fds.insert(1, Fd::Inherit);
+            self.fds.insert(2, Fd::Inherit);
+        }
+    }
+}
+
+/// Gives the logger to the child, or kills it if spawning child failed
+pub(crate) fn attach(result: Result<Child, Error>, logger: Option<Child>)
+    -> Result<Child, Error>
+{
+    match result {
+        Ok(mut child) => {
+            child.logger = logger.map(Box::new);
+            Ok(child)
+        }
+        Err(e) => {
+            if let Some(mut logger) = logger {
+                logger.kill().ok();
+                logger.wait().ok();
+            }
+            Err(e)
+        }
+    }
+}
+
+impl Child {
+    /// Returns the logger spawned for this child by
+    /// `Command::pipe_output_to`
+    pub fn logger(&mut self) -> Option<&mut Child> {
+        self.logger.as_deref_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use crate::{Command, Stdio, Error};
+
+    #[test]
+    fn test_pipe_output_to() {
+        let mut logger = Command::new("/bin/sed");
+        logger.arg("s/^/log: /").stdout(Stdio::piped());
+        let mut child = Command::new("/bin/sh")
+            .arg("-c").arg("echo out; echo err >&2")
+            .pipe_output_to(logger)
+            .spawn().unwrap();
+        let mut output = String::new();
+        child.logger().unwrap().take_stdout().unwrap()
+            .read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output, "log: out\nlog: err\n");
+        assert!(child.logger().unwrap().wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_spawn_error() {
+        let mut cmd = Command::new("/nonexistent");
+        cmd.pipe_output_to(Command::new("/bin/cat"));
+        match cmd.spawn() {
+            Err(Error::Exec(code)) => assert_eq!(code, libc::ENOENT),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
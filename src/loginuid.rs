@@ -0,0 +1,82 @@
+use std::fs;
+use std::io;
+
+use libc::uid_t;
+
+use crate::{Command, Child};
+
+
+impl Command {
+    /// Set the audit login uid of the child (`/proc/self/loginuid`)
+    ///
+    /// This is what `pam_loginuid` does for a login session: the uid is
+    /// recorded in every audit event of the child and its descendants, even
+    /// after `su` or `sudo`. The kernel starts a new audit session when the
+    /// login uid is written, its id can be read by `Child::audit_session`
+    /// (there is no way to choose it).
+    ///
+    /// The uid is written by the child itself (the kernel doesn't allow
+    /// writing it for other processes) before joining namespaces, changing
+    /// root and dropping privileges, so the current process needs
+    /// `CAP_AUDIT_CONTROL` in the initial user namespace (unless the
+    /// login uid is not set yet and `loginuid_immutable` isn't set).
+    /// Fails with `Error::LoginUid` if the kernel has no audit support.
+    pub fn loginuid(&mut self, uid: uid_t) -> &mut Command {
+        self.config.loginuid = Some(uid);
+        self
+    }
+}
+
+fn read_id(path: String) -> io::Result<u32> {
+    fs::read_to_string(path)?.trim().parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl Child {
+    /// Returns the audit session id of the child (`/proc/<pid>/sessionid`)
+    ///
+    /// A new session is started by `Command::loginuid`, otherwise the
+    /// session of the current process is inherited. `u32::MAX` means
+    /// there is no session. Fails with `ENOENT` if the kernel has no audit
+    /// support.
+    pub fn audit_session(&self) -> io::Result<u32> {
+        read_id(format!("/proc/{}/sessionid", self.pid()))
+    }
+
+    /// Returns the audit login uid of the child (`/proc/<pid>/loginuid`)
+    ///
+    /// `u32::MAX` means it's not set.
+    pub fn loginuid(&self) -> io::Result<uid_t> {
+        read_id(format!("/proc/{}/loginuid", self.pid()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{Command, Error};
+
+    #[test]
+    fn test_loginuid() {
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10").loginuid(12345);
+        if !Path::new("/proc/self/loginuid").exists() {
+            let err = cmd.spawn().unwrap_err();
+            assert!(matches!(err, Error::LoginUid(libc::ENOENT)), "{}", err);
+            return;
+        }
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            // unprivileged or loginuid is immutable
+            Err(Error::LoginUid(libc::EPERM)) => return,
+            Err(e) => panic!("unexpected error {}", e),
+        };
+        assert_eq!(child.loginuid().unwrap(), 12345);
+        let session = child.audit_session().unwrap();
+        let own = std::fs::read_to_string("/proc/self/sessionid").unwrap();
+        assert_ne!(session.to_string(), own.trim());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
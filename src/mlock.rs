@@ -0,0 +1,112 @@
+use libc::c_int;
+
+use crate::Command;
+
+
+/// How memory of the child is locked, see `Command::lock_memory`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MemoryLock {
+    /// Lock all current and future pages (`MCL_CURRENT|MCL_FUTURE`)
+    MlockAll,
+    /// Lock pages when they are faulted in, so mapped but untouched memory
+    /// isn't loaded (`MCL_ONFAULT`, linux 4.4)
+    OnFault,
+}
+
+impl MemoryLock {
+    /// Flags for `mlockall`
+    pub(crate) fn flags(self) -> c_int {
+        match self {
+            MemoryLock::MlockAll => libc::MCL_CURRENT|libc::MCL_FUTURE,
+            MemoryLock::OnFault => {
+                libc::MCL_CURRENT|libc::MCL_FUTURE|libc::MCL_ONFAULT
+            }
+        }
+    }
+}
+
+impl Command {
+    /// Lock memory of the child into RAM, so it's never swapped out
+    ///
+    /// The child calls `mlockall` before changing credentials, so locking
+    /// works with `CAP_IPC_LOCK` of the current process even if the child
+    /// runs as unprivileged user. Otherwise the amount of memory is limited
+    /// by `RLIMIT_MEMLOCK`, see `memlock_limit`. Spawn fails with
+    /// `Error::LockMemory` if memory can't be locked.
+    ///
+    /// Note: the kernel unlocks memory on `execve`, so this protects the
+    /// child until the exec (e.g. secrets handled by `pre_exec`). The
+    /// program must lock its own memory, which in turn usually requires
+    /// raising `memlock_limit`.
+    pub fn lock_memory(&mut self, lock: MemoryLock) -> &mut Command {
+        self.config.lock_memory = Some(lock);
+        self
+    }
+
+    /// Set both soft and hard `RLIMIT_MEMLOCK` of the child, in bytes
+    ///
+    /// The limit is set by the child before `lock_memory` and before
+    /// changing credentials, so raising the hard limit works with
+    /// `CAP_SYS_RESOURCE` of the current process. Unlike locks, the limit
+    /// is kept through the exec, so the program can lock its memory
+    /// itself. Use `libc::RLIM_INFINITY` for unlimited. Spawn fails with
+    /// `Error::SetLimits` if the limit can't be set.
+    pub fn memlock_limit(&mut self, bytes: u64) -> &mut Command {
+        self.config.memlock_limit = Some(bytes);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{Command, MemoryLock};
+
+    /// Reads the `VmLck` of `/proc/self/status` without allocating
+    fn locked_kb() -> io::Result<u64> {
+        let mut buf = [0u8; 4096];
+        let fd = unsafe {
+            libc::open(b"/proc/self/status\0".as_ptr() as *const _,
+                       libc::O_RDONLY|libc::O_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = unsafe {
+            libc::read(fd, buf.as_mut_ptr() as *mut _, buf.len())
+        };
+        unsafe { libc::close(fd) };
+        let data = &buf[..len.max(0) as usize];
+        let start = data.windows(6).position(|x| x == b"VmLck:")
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))?;
+        Ok(data[start+6..].iter()
+            .skip_while(|c| !c.is_ascii_digit())
+            .take_while(|c| c.is_ascii_digit())
+            .fold(0, |n, c| n * 10 + (c - b'0') as u64))
+    }
+
+    fn check_locked(lock: MemoryLock) {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("test $(ulimit -l) = 4096")
+            .lock_memory(lock)
+            .memlock_limit(4 << 20);
+        unsafe {
+            cmd.pre_exec(|| match locked_kb()? {
+                0 => Err(io::Error::from_raw_os_error(libc::ENOLCK)),
+                _ => Ok(()),
+            });
+        }
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_mlockall() {
+        check_locked(MemoryLock::MlockAll);
+    }
+
+    #[test]
+    fn test_on_fault() {
+        check_locked(MemoryLock::OnFault);
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Command, Child};
+use crate::cgroup::{CgroupMount, CGROUPFS_TARGET};
+use crate::chroot::in_root;
+
+
+/// A mount operation done when spawning the child
+///
+/// See `Command::mount_plan`. Paths are the ones in the mount namespace of
+/// the current process, i.e. paths in the new root are prefixed with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountOp {
+    /// Making all mounts private, so they don't propagate to the parent
+    /// namespace (see `make_private_rec`)
+    MakePrivate,
+    /// Attaching the mount of a `MountProvider` (or of `root_from_image`)
+    Provided {
+        /// Mount point
+        target: PathBuf,
+    },
+    /// Bind mounting a host path into the new root (`inject_host_file`,
+    /// `scratch_dir` and `foreign_arch_interpreter`)
+    Bind {
+        /// Path mounted
+        source: PathBuf,
+        /// Mount point
+        target: PathBuf,
+        /// The mount is read-only
+        read_only: bool,
+    },
+    /// Changing root by `pivot_root`
+    PivotRoot {
+        /// The new root
+        new_root: PathBuf,
+        /// Where the old root is put
+        put_old: PathBuf,
+        /// The old root is unmounted afterwards
+        unmount_old_root: bool,
+    },
+    /// Changing root by `chroot` (after `PivotRoot`, if both are used)
+    Chroot {
+        /// The new root
+        dir: PathBuf,
+    },
+    /// Mounting the cgroup of the child (`mount_cgroupfs`), after the root
+    /// is changed
+    Cgroupfs {
+        /// Mount point in the new root
+        target: PathBuf,
+        /// The mount is read-only
+        read_only: bool,
+    },
+}
+
+impl Command {
+    /// Returns the mount operations the child will do, in order
+    ///
+    /// This is for auditing and debugging complex layouts and is computed
+    /// from the current configuration. Some details can only be decided
+    /// on `spawn()`: host files are listed if present on the host now, and
+    /// the interpreter of `foreign_arch_interpreter` is listed whenever
+    /// the root is changed, while it's only mounted for programs of
+    /// the foreign architecture missing the interpreter in the new root
+    /// (check `Child::mounts_applied` for the actual list).
+    pub fn mount_plan(&self) -> Vec<MountOp> {
+        let root = self.host_root();
+        let interpreter = self.foreign_interpreter.as_ref()
+            .filter(|_| root != Path::new("/"))
+            .map(|path| (path.clone(), in_root(&root, path)));
+        self.mount_ops(interpreter)
+    }
+
+    pub(crate) fn make_private(&self) -> bool {
+        self.config.make_private_rec
+            .unwrap_or(self.config.namespaces & libc::CLONE_NEWNS != 0)
+    }
+
+    /// Mount operations in the order the child does them, given the
+    /// source and target of the interpreter mount
+    pub(crate) fn mount_ops(&self, interpreter: Option<(PathBuf, PathBuf)>)
+        -> Vec<MountOp>
+    {
+        let root = self.host_root();
+        let mut ops = Vec::new();
+        if self.make_private() {
+            ops.push(MountOp::MakePrivate);
+        }
+        for (target, _) in &self.mount_providers {
+            ops.push(MountOp::Provided { target: in_root(&root, target) });
+        }
+        if let Some((source, target)) = interpreter {
+            ops.push(MountOp::Bind { source, target, read_only: false });
+        }
+        if root != Path::new("/") {
+            for item in &self.host_files {
+                for path in item.paths() {
+                    let path = Path::new(path);
+                    if fs::metadata(path).is_err() {
+                        continue;
+                    }
+                    ops.push(MountOp::Bind {
+                        source: path.to_path_buf(),
+                        target: in_root(&root, path),
+                        read_only: true,
+                    });
+                }
+            }
+        }
+        if let Some((ref source, ref target)) = self.scratch {
+            ops.push(MountOp::Bind {
+                source: source.clone(),
+                target: in_root(&root, target),
+                read_only: false,
+            });
+        }
+        if let Ok(Some((source, target))) = self.core_dump_dir() {
+            ops.push(MountOp::Bind {
+                source: source.to_path_buf(),
+                target,
+                read_only: false,
+            });
+        }
+        for (source, target) in &self.idmapped_mounts {
+            ops.push(MountOp::Bind {
+                source: source.clone(),
+                target: in_root(&root, target),
+                read_only: false,
+            });
+        }
+        if let Some((ref new_root, ref put_old, unmount)) = self.pivot_root {
+            ops.push(MountOp::PivotRoot {
+                new_root: new_root.clone(),
+                put_old: put_old.clone(),
+                unmount_old_root: unmount,
+            });
+        }
+        if let Some(ref dir) = self.chroot_dir {
+            ops.push(MountOp::Chroot { dir: dir.clone() });
+        }
+        if let Some(mode) = self.config.cgroupfs {
+            ops.push(MountOp::Cgroupfs {
+                target: PathBuf::from(CGROUPFS_TARGET),
+                read_only: mode == CgroupMount::ReadOnly,
+            });
+        }
+        ops
+    }
+}
+
+impl Child {
+    /// Returns the mount operations done by the child, in order
+    ///
+    /// Unlike `Command::mount_plan`, this is exactly what was done. Empty
+    /// for children created by `Child::from_pid`.
+    pub fn mounts_applied(&self) -> &[MountOp] {
+        &self.mounts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::{Command, Namespace};
+    use super::MountOp;
+
+    #[test]
+    fn test_plan_order() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Mount]);
+        cmd.pivot_root("/tmp/root", "/tmp/root/old", true);
+        cmd.chroot_dir("/inner");
+        assert_eq!(cmd.mount_plan(), vec![
+            MountOp::MakePrivate,
+            MountOp::PivotRoot {
+                new_root: PathBuf::from("/tmp/root"),
+                put_old: PathBuf::from("/tmp/root/old"),
+                unmount_old_root: true,
+            },
+            MountOp::Chroot { dir: PathBuf::from("/inner") },
+        ]);
+        assert_eq!(Command::new("/bin/true").mount_plan(), vec![]);
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+
+use libc::{c_int, c_void};
+
+use crate::{Command, BoxError};
+use crate::ffi_util::ToCString;
+use crate::sys;
+
+
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+const MOUNT_ATTR_RDONLY: u64 = 1;
+
+/// Mount returned by `MountProvider::provide`
+pub struct ProvidedMount {
+    /// Detached mount, as returned by `fsmount` or
+    /// `open_tree(.., OPEN_TREE_CLONE)`
+    ///
+    /// `None` means the provider has mounted the file system at the target
+    /// in the child's mount namespace by itself (e.g. by running a FUSE
+    /// daemon which joined the namespace).
+    pub mount: Option<File>,
+    /// Called when the child is reaped (see `Command::mount_provider`)
+    pub teardown: Option<Box<dyn FnOnce() + Send>>,
+}
+
+/// Supplies a file system mounted into the sandbox
+///
+/// This is the place to start a FUSE daemon or to mount an image by
+/// `fsopen`/`fsmount`, both of which may need to know the child (e.g. to
+/// use its user namespace).
+pub trait MountProvider {
+    /// Called in the parent while the child (`pid`) is frozen, after uid
+    /// maps are written, and before `before_unfreeze` callback
+    fn provide(&mut self, pid: u32) -> Result<ProvidedMount, BoxError>;
+}
+
+/// Teardown callbacks of the mounts, called when dropped
+#[derive(Default)]
+pub struct Teardown(Vec<Box<dyn FnOnce() + Send>>);
+
+impl Teardown {
+    pub fn push(&mut self, callback: Box<dyn FnOnce() + Send>) {
+        self.0.push(callback);
+    }
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        for callback in self.0.drain(..) {
+            callback();
+        }
+    }
+}
+
+impl fmt::Debug for Teardown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Teardown({} callbacks)", self.0.len())
+    }
+}
+
+impl Command {
+    /// Mount a file system supplied by `provider` at `target`
+    ///
+    /// The `target` is a path inside the new root (i.e. after `pivot_root`
+    /// and `chroot_dir` are applied). The provider is called while the
+    /// child is frozen, and the mount it returns is passed to the child,
+    /// which attaches it by `move_mount` after making mounts private and
+    /// before changing root. So the mount namespace must be unshared, and
+    /// linux 5.2 is required. Mounts are attached in the order providers
+    /// are added, so they can be used as a root in `pivot_root`.
+    ///
+    /// The `teardown` of the provided mount is called when the child is
+    /// reaped by `Child::wait`, or the `Child` handle is dropped, or
+    /// spawning fails.
+    ///
+    /// Errors of the provider are reported as `Error::MountProvider`,
+    /// errors of attaching the mount as `Error::AttachMount`.
+    pub fn mount_provider<P, M>(&mut self, target: P, provider: M)
+        -> &mut Command
+        where P: AsRef<Path>, M: MountProvider + 'static
+    {
+        self.mount_providers.push(
+            (target.as_ref().to_path_buf(), Box::new(provider)));
+        self
+    }
+}
+
+/// Sends file descriptor through the unix socket
+///
+/// With `None` the message without descriptor is sent. Doesn't allocate,
+/// so can be used in the child
+pub fn send_fd(sock: RawFd, fd: Option<RawFd>) -> io::Result<()> {
+    unsafe {
+        let mut byte = 0u8;
+        let mut iov = libc::iovec {
+            iov_base: &mut byte as *mut u8 as *mut c_void,
+            iov_len: 1,
+        };
+        let space = libc::CMSG_SPACE(mem::size_of::<c_int>() as u32);
+        // enough for a `cmsghdr` with a single descriptor
+        let mut cmsg_buf = [0u64; 4];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if let Some(fd) = fd {
+            msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+            msg.msg_controllen = space as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len =
+                libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as _;
+            (libc::CMSG_DATA(cmsg) as *mut c_int).write_unaligned(fd);
+        }
+        if libc::sendmsg(sock, &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receives a file descriptor sent by `send_fd`
+///
+/// Doesn't allocate, so can be used in the child
+///
+/// Returns descriptor (`None` if the message has none) or errno
+pub unsafe fn recv_fd(sock: c_int) -> Result<Option<c_int>, c_int> {
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut c_void,
+        iov_len: 1,
+    };
+    // enough for a `cmsghdr` with a single descriptor
+    let mut cmsg_buf = [0u64; 4];
+    let mut msg: libc::msghdr = mem::zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+    loop {
+        if libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) >= 0 {
+            break;
+        }
+        if sys::errno() != libc::EINTR {
+            return Err(sys::errno());
+        }
+    }
+    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+    if cmsg.is_null() {
+        return Ok(None);
+    }
+    if (*cmsg).cmsg_level != libc::SOL_SOCKET ||
+        (*cmsg).cmsg_type != libc::SCM_RIGHTS
+    {
+        return Err(libc::EPROTO);
+    }
+    Ok(Some((libc::CMSG_DATA(cmsg) as *const c_int).read_unaligned()))
+}
+
+/// Returns a detached bind mount of `path`
+pub fn clone_tree(path: &Path) -> io::Result<File> {
+    let fd = unsafe {
+        libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD,
+            path.to_cstring().as_ptr(),
+            OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Makes the (detached) mount read-only, requires linux 5.12
+pub fn set_read_only(mount: &File) -> io::Result<()> {
+    // struct mount_attr
+    let attr: [u64; 4] = [MOUNT_ATTR_RDONLY, 0, 0, 0];
+    let rc = unsafe {
+        libc::syscall(libc::SYS_mount_setattr, mount.as_raw_fd(),
+            b"\0".as_ptr(), libc::AT_EMPTY_PATH, attr.as_ptr(),
+            mem::size_of_val(&attr))
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
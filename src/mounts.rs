@@ -0,0 +1,235 @@
+//! Helpers for unmounting file systems and temporary roots
+//!
+//! These are the same operations that are used internally for `pivot_root`,
+//! exposed for the code that orchestrates sandbox roots.
+use std::env;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Read};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc::{c_char, c_int, MNT_DETACH};
+
+use crate::ffi_util::ToCString;
+
+
+static ROOTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A private temporary directory, removed on drop
+///
+/// See `make_ephemeral_root`. Use it as a path (it's `AsRef<Path>`).
+#[derive(Debug)]
+pub struct EphemeralRoot {
+    path: PathBuf,
+    tmpfs: bool,
+}
+
+/// Create a temporary directory to use as a new root of the child
+///
+/// The directory is created with mode `0700` in `/dev/shm` (or in
+/// `env::temp_dir()` if there is no `/dev/shm`) and, if the current
+/// process is allowed to mount, a new `tmpfs` is mounted over it, so the
+/// files put there are private to the current mount namespace and never
+/// hit the disk. Populate it and pass to `Command::pivot_root` or
+/// `Command::chroot_dir`.
+///
+/// On drop, all file systems mounted at or below the directory (in the
+/// mount namespace of the current process) are detached by
+/// `lazy_umount`, so the child may still use them, then the directory is
+/// removed with its contents. If some mount can't be detached, the
+/// directory is left intact, rather than removing files through the mount.
+pub fn make_ephemeral_root() -> io::Result<EphemeralRoot> {
+    let shm = Path::new("/dev/shm");
+    let base = if shm.is_dir() { shm.to_path_buf() } else { env::temp_dir() };
+    let path = base.join(format!("unshare-root-{}-{}",
+        unsafe { libc::getpid() },
+        ROOTS.fetch_add(1, Ordering::SeqCst)));
+    DirBuilder::new().mode(0o700).create(&path)?;
+    let rc = unsafe {
+        libc::mount(b"tmpfs\0".as_ptr() as *const c_char,
+            path.to_cstring().as_ptr(),
+            b"tmpfs\0".as_ptr() as *const c_char,
+            libc::MS_NOSUID | libc::MS_NODEV,
+            b"mode=0700\0".as_ptr() as *const libc::c_void)
+    };
+    Ok(EphemeralRoot { path, tmpfs: rc == 0 })
+}
+
+impl EphemeralRoot {
+    /// The path of the directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Whether a `tmpfs` is mounted over the directory
+    pub fn is_tmpfs(&self) -> bool {
+        self.tmpfs
+    }
+}
+
+impl AsRef<Path> for EphemeralRoot {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for EphemeralRoot {
+    fn drop(&mut self) {
+        let mut buf = Vec::new();
+        let mounts = File::open("/proc/self/mountinfo")
+            .and_then(|mut f| f.read_to_end(&mut buf))
+            .map(|_| mounts_under(&buf, &self.path));
+        let mounts = match mounts {
+            Ok(mounts) => mounts,
+            Err(_) => return,
+        };
+        for mount_point in &mounts {
+            // the mounts left are checked below
+            lazy_umount(mount_point).ok();
+        }
+        if !mounts.is_empty() {
+            buf.clear();
+            let left = File::open("/proc/self/mountinfo")
+                .and_then(|mut f| f.read_to_end(&mut buf))
+                .map(|_| mounts_under(&buf, &self.path));
+            if !matches!(left, Ok(ref left) if left.is_empty()) {
+                return;
+            }
+        }
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+/// Lazily unmount file system at `path`
+///
+/// This is `umount2(path, MNT_DETACH)`: the mount point and all mounts
+/// underneath it are detached from the file system hierarchy immediately,
+/// and cleaned up when they are not busy any more.
+pub fn lazy_umount<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref().to_cstring();
+    if unsafe { umount_raw(path.as_ptr(), MNT_DETACH) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Unmount file system at `path` and all file systems mounted underneath it
+///
+/// Mount points are found in `/proc/self/mountinfo` and unmounted deepest
+/// first. Unlike `lazy_umount` this fails (with `EBUSY`) if any of the
+/// file systems is in use, in which case some of the file systems may
+/// have been unmounted already.
+pub fn recursive_umount<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let mut buf = Vec::new();
+    File::open("/proc/self/mountinfo")?.read_to_end(&mut buf)?;
+    for mount_point in mounts_under(&buf, path.as_ref()) {
+        let cpath = mount_point.to_cstring();
+        if unsafe { umount_raw(cpath.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Raw `umount2` which is safe to call in the child after clone
+pub(crate) unsafe fn umount_raw(path: *const c_char, flags: c_int) -> c_int {
+    libc::umount2(path, flags)
+}
+
+/// Returns mount points from the mountinfo which are at or below `prefix`
+///
+/// Result is sorted in the order appropriate for unmounting (deepest first)
+fn mounts_under(mountinfo: &[u8], prefix: &Path) -> Vec<PathBuf> {
+    let mut result = mountinfo.split(|&c| c == b'\n')
+        .filter_map(|line| line.split(|&c| c == b' ').nth(4))
+        .map(|field| PathBuf::from(OsString::from_vec(unescape(field))))
+        .filter(|path| path.starts_with(prefix))
+        .collect::<Vec<_>>();
+    // Stable sort keeps mount points of the same length in mountinfo order,
+    // so after reversing the latter mounts (which are on top) go first
+    result.sort_by_key(|p| p.as_os_str().as_bytes().len());
+    result.reverse();
+    result
+}
+
+/// Decodes octal escapes (`\040` for space and alike) used in mountinfo
+fn unescape(field: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(field.len());
+    let mut idx = 0;
+    while idx < field.len() {
+        if field[idx] == b'\\' && idx + 4 <= field.len() &&
+            field[idx+1..idx+4].iter().all(|c| (b'0'..=b'7').contains(c))
+        {
+            result.push(field[idx+1..idx+4].iter()
+                .fold(0u8, |acc, c| (acc << 3) | (c - b'0')));
+            idx += 4;
+        } else {
+            result.push(field[idx]);
+            idx += 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::ptr;
+
+    use crate::ffi_util::ToCString;
+    use super::{mounts_under, unescape, make_ephemeral_root};
+
+    const MOUNTINFO: &[u8] = b"\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 0:5 / /proc rw,nosuid shared:2 - proc proc rw
+30 22 0:40 / /tmp/root rw shared:10 - tmpfs tmpfs rw
+31 30 0:41 / /tmp/root/proc rw shared:11 - proc proc rw
+32 30 0:42 / /tmp/root/my\\040dir rw shared:12 - tmpfs tmpfs rw
+33 22 0:43 / /tmp/rootfs rw shared:13 - tmpfs tmpfs rw
+";
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(b"/a\\040b"), b"/a b");
+        assert_eq!(unescape(b"/a\\134"), b"/a\\");
+        assert_eq!(unescape(b"/a\\04"), b"/a\\04");
+    }
+
+    #[test]
+    fn test_mounts_under() {
+        assert_eq!(mounts_under(MOUNTINFO, Path::new("/tmp/root")), vec![
+            PathBuf::from("/tmp/root/my dir"),
+            PathBuf::from("/tmp/root/proc"),
+            PathBuf::from("/tmp/root"),
+        ]);
+    }
+
+    #[test]
+    fn test_ephemeral_root() {
+        let root = make_ephemeral_root().unwrap();
+        let path = root.path().to_path_buf();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o700);
+        let inner = path.join("inner");
+        fs::create_dir(&inner).unwrap();
+        let mut busy = None;
+        if root.is_tmpfs() {
+            let target = inner.to_cstring();
+            let rc = unsafe {
+                libc::mount(b"tmpfs\0".as_ptr() as *const libc::c_char,
+                    target.as_ptr(),
+                    b"tmpfs\0".as_ptr() as *const libc::c_char, 0,
+                    ptr::null())
+            };
+            assert_eq!(rc, 0);
+            busy = Some(fs::File::create(inner.join("file")).unwrap());
+        }
+        drop(root);
+        assert!(!path.exists());
+        drop(busy);
+    }
+}
@@ -1,10 +1,20 @@
-use nix::sched::CloneFlags;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::ptr;
+
+use libc::{c_int, pid_t, uid_t};
+
+use crate::Child;
 
 
 /// Namespace name to unshare
 ///
 /// See `man 7 namespaces` for more information
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum Namespace {
     /// Unshare the mount namespace. It basically means that you can now mount
     /// and unmount folders without touching parent mount points.
@@ -70,14 +80,296 @@ pub enum Namespace {
 
 /// Convert namespace to a clone flag passed to syscalls
 // TODO(tailhook) should this method be private?
-pub fn to_clone_flag(ns: Namespace) -> CloneFlags {
+pub fn to_clone_flag(ns: Namespace) -> c_int {
+    match ns {
+        Namespace::Mount => libc::CLONE_NEWNS,
+        Namespace::Uts => libc::CLONE_NEWUTS,
+        Namespace::Ipc => libc::CLONE_NEWIPC,
+        Namespace::User => libc::CLONE_NEWUSER,
+        Namespace::Pid => libc::CLONE_NEWPID,
+        Namespace::Net => libc::CLONE_NEWNET,
+        Namespace::Cgroup => libc::CLONE_NEWCGROUP,
+    }
+}
+
+/// Name of the namespace file in `/proc/<pid>/ns`
+pub(crate) fn proc_name(ns: Namespace) -> &'static str {
     match ns {
-        Namespace::Mount => CloneFlags::CLONE_NEWNS,
-        Namespace::Uts => CloneFlags::CLONE_NEWUTS,
-        Namespace::Ipc => CloneFlags::CLONE_NEWIPC,
-        Namespace::User => CloneFlags::CLONE_NEWUSER,
-        Namespace::Pid => CloneFlags::CLONE_NEWPID,
-        Namespace::Net => CloneFlags::CLONE_NEWNET,
-        Namespace::Cgroup => CloneFlags::CLONE_NEWCGROUP,
+        Namespace::Mount => "mnt",
+        Namespace::Uts => "uts",
+        Namespace::Ipc => "ipc",
+        Namespace::User => "user",
+        Namespace::Pid => "pid",
+        Namespace::Net => "net",
+        Namespace::Cgroup => "cgroup",
+    }
+}
+
+pub(crate) const ALL: &[Namespace] = &[
+    Namespace::Mount, Namespace::Uts, Namespace::Ipc, Namespace::User,
+    Namespace::Pid, Namespace::Net, Namespace::Cgroup,
+];
+
+pub(crate) fn from_clone_flag(flag: c_int) -> Option<Namespace> {
+    ALL.iter().cloned().find(|&ns| to_clone_flag(ns) == flag)
+}
+
+/// Namespaces having their flags set in the clone `flags`
+pub fn namespaces_of(flags: c_int) -> Vec<Namespace> {
+    ALL.iter().cloned().filter(|&ns| flags & to_clone_flag(ns) != 0)
+        .collect()
+}
+
+/// Checks that file descriptor refers to the namespace of the kind `ns`
+///
+/// An open namespace file keeps the namespace alive, so there are no
+/// stale descriptors to detect: the namespace may have no processes left,
+/// which isn't checked. Requires linux 4.11 for `NS_GET_NSTYPE`.
+pub fn check_namespace_fd(fd: RawFd, ns: Namespace) -> io::Result<()> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let kind = unsafe { libc::ioctl(fd, libc::NS_GET_NSTYPE) };
+    if kind < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOTTY) | Some(libc::EINVAL) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("file descriptor {} (inode {}) is not a namespace, \
+                         expected {:?} namespace", fd, stat.st_ino, ns))),
+            _ => Err(err),
+        };
+    }
+    if kind != to_clone_flag(ns) {
+        let actual = from_clone_flag(kind)
+            .map(|x| format!("{:?}", x))
+            .unwrap_or_else(|| format!("unknown (0x{:x})", kind));
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("file descriptor {} (inode {}) refers to {} namespace, \
+                     expected {:?} namespace", fd, stat.st_ino, actual, ns)));
+    }
+    Ok(())
+}
+
+/// Returns the uid of the creator of the user namespace referred by `fd`
+///
+/// The uid is as seen in the user namespace of the current process
+/// (`NS_GET_OWNER_UID`, requires linux 4.11 too). Fails with
+/// `InvalidInput` if the file isn't a user namespace.
+pub fn user_namespace_owner(fd: RawFd) -> io::Result<uid_t> {
+    check_namespace_fd(fd, Namespace::User)?;
+    let mut uid: uid_t = 0;
+    if unsafe { libc::ioctl(fd, libc::NS_GET_OWNER_UID, &mut uid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Returns true if both processes are in the same namespace of kind `ns`
+///
+/// Compares device and inode numbers of `/proc/<pid>/ns/<name>` files,
+/// which identify the namespace. Handy for asserting isolation in tests,
+/// e.g. `ns_equal(child.pid(), process::id() as pid_t, Namespace::Net)`.
+/// Fails with `ENOENT` if any of the processes has exited (even if it
+/// isn't reaped yet).
+pub fn ns_equal(a: pid_t, b: pid_t, ns: Namespace) -> io::Result<bool> {
+    ns_equal_in(Path::new("/proc"), a, b, ns)
+}
+
+/// Like `ns_equal`, with the proc file system mounted at `proc`
+pub(crate) fn ns_equal_in(proc: &Path, a: pid_t, b: pid_t, ns: Namespace)
+    -> io::Result<bool>
+{
+    let a = fs::metadata(proc.join(format!("{}/ns/{}", a, proc_name(ns))))?;
+    let b = fs::metadata(proc.join(format!("{}/ns/{}", b, proc_name(ns))))?;
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+impl Child {
+    /// Open the namespace `ns` of the child
+    ///
+    /// The returned descriptor keeps the namespace alive even after the
+    /// child exits, and can be passed to `Command::set_namespace` to run
+    /// other processes there. In the `before_unfreeze` callback, use
+    /// `Child::from_pid(pid).ns_fd(..)` to open namespaces of the frozen
+    /// child.
+    ///
+    /// Fails with `ESRCH` if the child has already been reaped. If the
+    /// handle has a pidfd, it's also checked that the pid hasn't been
+    /// reused while the file was opened. The file is verified to be the
+    /// namespace of the requested kind (requires linux 4.11).
+    pub fn ns_fd(&self, ns: Namespace) -> io::Result<OwnedFd> {
+        if self.status.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        let file = File::open(self.proc_dir()
+            .join(format!("{}/ns/{}", self.pid, proc_name(ns))))?;
+        check_namespace_fd(file.as_raw_fd(), ns)?;
+        if let Some(ref pidfd) = self.pidfd {
+            let rc = unsafe {
+                libc::syscall(libc::SYS_pidfd_send_signal,
+                    pidfd.as_raw_fd(), 0,
+                    ptr::null::<libc::siginfo_t>(), 0)
+            };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(file.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::rc::Rc;
+
+    use crate::{Command, UidMap, GidMap};
+    use super::{Namespace, check_namespace_fd, ns_equal, user_namespace_owner};
+
+    #[test]
+    fn test_check_namespace_fd() {
+        let net = File::open("/proc/self/ns/net").unwrap();
+        check_namespace_fd(net.as_raw_fd(), Namespace::Net).unwrap();
+        let err = check_namespace_fd(net.as_raw_fd(), Namespace::Pid)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("refers to Net namespace"),
+                "{}", err);
+        let file = File::open("/proc/self/stat").unwrap();
+        let err = check_namespace_fd(file.as_raw_fd(), Namespace::Net)
+            .unwrap_err();
+        assert!(err.to_string().contains("is not a namespace"), "{}", err);
+    }
+
+    #[test]
+    fn test_join_user_namespace_first() {
+        // the uts namespace is owned by the user namespace of the holder
+        let mut holder = Command::new("/bin/sleep");
+        holder.arg("10")
+            .unshare(&[Namespace::User, Namespace::Uts])
+            .set_id_maps(
+                vec![UidMap { inside_uid: 0,
+                    outside_uid: unsafe { libc::geteuid() }, count: 1 }],
+                vec![GidMap { inside_gid: 0,
+                    outside_gid: unsafe { libc::getegid() }, count: 1 }]);
+        unsafe {
+            holder.pre_exec(|| {
+                let name = b"holder";
+                if libc::sethostname(name.as_ptr() as *const libc::c_char,
+                                     name.len()) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut holder = match holder.spawn() {
+            Ok(child) => child,
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => return,
+            Err(e) => panic!("{}", e),
+        };
+        let ns = |name| File::open(format!("/proc/{}/ns/{}",
+                                            holder.pid(), name)).unwrap();
+        let (uts, user) = (ns("uts"), ns("user"));
+        // uts is set first, the child joins user namespace first anyway
+        let status = Command::new("/bin/sh")
+            .arg("-c").arg("test $(cat /proc/sys/kernel/hostname) = holder")
+            .set_namespace(&uts, Namespace::Uts).unwrap()
+            .set_namespace(&user, Namespace::User).unwrap()
+            .status().unwrap();
+        holder.kill().ok();
+        holder.wait().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_user_namespace_owner() {
+        let net = File::open("/proc/self/ns/net").unwrap();
+        let err = user_namespace_owner(net.as_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut child = Command::new("/bin/sleep").arg("10")
+            .set_id_maps(
+                vec![UidMap { inside_uid: 0, outside_uid: 65534, count: 1 }],
+                vec![GidMap { inside_gid: 0, outside_gid: 65534, count: 1 }])
+            .spawn().unwrap();
+        let own = std::process::id() as libc::pid_t;
+        assert!(!ns_equal(own, child.pid(), Namespace::User).unwrap());
+        let user = File::from(child.ns_fd(Namespace::User).unwrap());
+        assert_eq!(user_namespace_owner(user.as_raw_fd()).unwrap(),
+                   unsafe { libc::geteuid() });
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_ns_fd() {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10").unshare(&[Namespace::Uts]);
+        let mut child = cmd.spawn().unwrap();
+        let uts = child.ns_fd(Namespace::Uts).unwrap();
+        check_namespace_fd(uts.as_raw_fd(), Namespace::Uts).unwrap();
+        let own = std::fs::metadata("/proc/self/ns/uts").unwrap();
+        let fd_meta = File::from(uts).metadata().unwrap();
+        assert_ne!(own.ino(), fd_meta.ino());
+        let child_meta = std::fs::metadata(
+            format!("/proc/{}/ns/uts", child.pid())).unwrap();
+        assert_eq!(child_meta.ino(), fd_meta.ino());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let err = child.ns_fd(Namespace::Uts).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+    }
+
+    #[test]
+    fn test_ns_equal() {
+        let mut child = Command::new("/bin/sleep").arg("10")
+            .unshare(&[Namespace::Uts])
+            .spawn().unwrap();
+        let own = std::process::id() as libc::pid_t;
+        let equal = (ns_equal(own, child.pid(), Namespace::Uts).unwrap(),
+                     ns_equal(own, child.pid(), Namespace::Net).unwrap());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(equal, (false, true));
+        let err = ns_equal(own, child.pid(), Namespace::Net).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_join_pid_namespace() {
+        let mut target = Command::new("/bin/sleep").arg("10")
+            .unshare(&[Namespace::Pid])
+            .spawn().unwrap();
+        let pidns = target.ns_fd(Namespace::Pid).unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        // pid 1 is the target
+        cmd.arg("-c").arg("read x; test $$ -ne 1")
+            .stdin(crate::Stdio::piped())
+            .set_namespace(&pidns, Namespace::Pid).unwrap();
+        let frozen = Rc::new(Cell::new(0));
+        let seen = frozen.clone();
+        cmd.before_unfreeze(move |pid| { seen.set(pid); Ok(()) });
+        let mut child = cmd.spawn().unwrap();
+        let joined = ns_equal(child.pid(), target.pid(), Namespace::Pid)
+            .unwrap();
+        // callbacks get the process running the program
+        assert_eq!(frozen.get(), child.pid() as u32);
+        drop(child.take_stdin());
+        let status = child.wait().unwrap();
+        let err = Command::new("/nonexistent")
+            .set_namespace(&pidns, Namespace::Pid).unwrap()
+            .spawn().unwrap_err();
+        target.kill().unwrap();
+        target.wait().unwrap();
+        assert!(joined);
+        assert!(status.success(), "{}", status);
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
     }
 }
@@ -0,0 +1,287 @@
+//! Minimal netlink client used by `network::create_veth` and accounting
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+use libc::{c_int, c_void};
+
+use crate::stdio::Closing;
+
+
+const HEADER_LEN: usize = 16;
+
+/// Message being built, with the `nlmsghdr` in front
+pub struct Message {
+    buf: Vec<u8>,
+    nested: Vec<usize>,
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+impl Message {
+    pub fn new(kind: u16, flags: c_int) -> Message {
+        let mut buf = vec![0u8; HEADER_LEN];
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags as u16).to_ne_bytes());
+        Message { buf, nested: Vec::new() }
+    }
+    /// Appends a fixed header of the message (`ifinfomsg`, `ifaddrmsg`)
+    pub fn push(&mut self, data: &[u8]) -> &mut Message {
+        self.buf.extend(data);
+        pad(&mut self.buf);
+        self
+    }
+    pub fn attr(&mut self, kind: u16, data: &[u8]) -> &mut Message {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend(&len.to_ne_bytes());
+        self.buf.extend(&kind.to_ne_bytes());
+        self.push(data)
+    }
+    /// Appends attribute with a string value, which must be nul-terminated
+    pub fn attr_str(&mut self, kind: u16, value: &str) -> &mut Message {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(kind, &data)
+    }
+    pub fn begin_nested(&mut self, kind: u16) -> &mut Message {
+        self.nested.push(self.buf.len());
+        self.attr(kind, &[])
+    }
+    pub fn end_nested(&mut self) -> &mut Message {
+        let start = self.nested.pop().expect("nested attribute started");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start+2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+}
+
+/// Returns `ifinfomsg` structure
+pub fn ifinfomsg(index: c_int, flags: u32, change: u32) -> [u8; 16] {
+    let mut data = [0u8; 16];
+    data[0] = libc::AF_UNSPEC as u8;
+    data[4..8].copy_from_slice(&index.to_ne_bytes());
+    data[8..12].copy_from_slice(&flags.to_ne_bytes());
+    data[12..16].copy_from_slice(&change.to_ne_bytes());
+    data
+}
+
+/// Returns `ifaddrmsg` structure
+pub fn ifaddrmsg(family: c_int, prefix_len: u8, index: u32) -> [u8; 8] {
+    let mut data = [0u8; 8];
+    data[0] = family as u8;
+    data[1] = prefix_len;
+    data[4..8].copy_from_slice(&index.to_ne_bytes());
+    data
+}
+
+/// Returns `rtmsg` structure of a unicast route in the main table
+pub fn rtmsg(family: c_int, dst_len: u8, scope: u8) -> [u8; 12] {
+    let mut data = [0u8; 12];
+    data[0] = family as u8;
+    data[1] = dst_len;
+    data[4] = libc::RT_TABLE_MAIN;
+    data[5] = libc::RTPROT_BOOT;
+    data[6] = scope;
+    data[7] = libc::RTN_UNICAST;
+    data
+}
+
+/// Netlink socket, in the network namespace of the current thread
+pub struct Socket {
+    fd: Closing,
+    seq: u32,
+}
+
+impl Socket {
+    /// Opens route netlink socket
+    pub fn new() -> io::Result<Socket> {
+        Socket::open(libc::NETLINK_ROUTE)
+    }
+
+    pub fn open(protocol: c_int) -> io::Result<Socket> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW|libc::SOCK_CLOEXEC,
+                         protocol)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Socket { fd: Closing::new(fd), seq: 0 })
+    }
+
+    /// Sends the message, returns its sequence number
+    pub fn send(&mut self, msg: &mut Message) -> io::Result<u32> {
+        self.seq += 1;
+        let len = msg.buf.len() as u32;
+        msg.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        msg.buf[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        let rc = unsafe {
+            libc::sendto(self.fd.as_raw_fd(),
+                msg.buf.as_ptr() as *const c_void, msg.buf.len(), 0,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32)
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(self.seq)
+    }
+
+    /// Receives a datagram, returns `None` if `nonblocking` and there is
+    /// nothing to receive
+    pub fn recv(&self, buf: &mut [u8], nonblocking: bool)
+        -> io::Result<Option<usize>>
+    {
+        let flags = if nonblocking { libc::MSG_DONTWAIT } else { 0 };
+        loop {
+            let n = unsafe {
+                libc::recv(self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut c_void, buf.len(), flags)
+            };
+            if n >= 0 {
+                return Ok(Some(n as usize));
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock if nonblocking => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Sends request and waits for acknowledgement
+    ///
+    /// Message must have `NLM_F_ACK` flag
+    pub fn request(&mut self, msg: &mut Message) -> io::Result<()> {
+        let seq = self.send(msg)?;
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = self.recv(&mut buf, false)?.unwrap_or(0);
+            if let Some(errno) = find_ack(&buf[..n], seq) {
+                return if errno == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::from_raw_os_error(-errno))
+                };
+            }
+        }
+    }
+
+    /// Sends request and returns payload of the reply
+    #[cfg_attr(not(feature="accounting"), allow(dead_code))]
+    pub fn request_reply(&mut self, msg: &mut Message) -> io::Result<Vec<u8>>
+    {
+        let seq = self.send(msg)?;
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = self.recv(&mut buf, false)?.unwrap_or(0);
+            if let Some(errno) = find_ack(&buf[..n], seq) {
+                if errno != 0 {
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+            }
+            let reply = messages(&buf[..n])
+                .find(|&(kind, rseq, _)| {
+                    rseq == seq && kind != libc::NLMSG_ERROR as u16
+                });
+            if let Some((_, _, payload)) = reply {
+                return Ok(payload.to_vec());
+            }
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset+4]);
+    u32::from_ne_bytes(bytes)
+}
+
+/// Iterates over messages in the datagram, yields type, sequence number
+/// and payload
+pub fn messages(mut buf: &[u8]) -> impl Iterator<Item=(u16, u32, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        let len = read_u32(buf, 0) as usize;
+        if len < HEADER_LEN || len > buf.len() {
+            return None;
+        }
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        let seq = read_u32(buf, 8);
+        let payload = &buf[HEADER_LEN..len];
+        buf = &buf[((len + 3) & !3).min(buf.len())..];
+        Some((kind, seq, payload))
+    })
+}
+
+/// Iterates over attributes, yields type (without flags) and value
+#[cfg_attr(not(feature="accounting"), allow(dead_code))]
+pub fn attrs(mut buf: &[u8]) -> impl Iterator<Item=(u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        // strip NLA_F_NESTED and NLA_F_NET_BYTEORDER
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & 0x3FFF;
+        let value = &buf[4..len];
+        buf = &buf[((len + 3) & !3).min(buf.len())..];
+        Some((kind, value))
+    })
+}
+
+/// Returns error code of the acknowledgement for `seq` if it's in `buf`
+fn find_ack(buf: &[u8], seq: u32) -> Option<i32> {
+    messages(buf)
+        .find(|&(kind, rseq, payload)| {
+            kind == libc::NLMSG_ERROR as u16 && rseq == seq &&
+                payload.len() >= 4
+        })
+        .map(|(_, _, payload)| read_u32(payload, 0) as i32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Message, find_ack, attrs};
+
+    #[test]
+    fn test_message() {
+        let mut msg = Message::new(16, 5);
+        msg.push(&[1, 2]);
+        msg.begin_nested(18).attr_str(3, "abc").end_nested();
+        assert_eq!(msg.buf.len(), 16 + 4 + 4 + 8);
+        assert_eq!(&msg.buf[20..24], &[12, 0, 18, 0]);
+        assert_eq!(&msg.buf[24..32], &[8, 0, 3, 0, b'a', b'b', b'c', 0]);
+    }
+
+    #[test]
+    fn test_ack() {
+        let mut buf = vec![0u8; 36];
+        buf[0..4].copy_from_slice(&36u32.to_ne_bytes());
+        buf[4..6].copy_from_slice(&2u16.to_ne_bytes());
+        buf[8..12].copy_from_slice(&7u32.to_ne_bytes());
+        buf[16..20].copy_from_slice(&(-17i32).to_ne_bytes());
+        assert_eq!(find_ack(&buf, 7), Some(-17));
+        assert_eq!(find_ack(&buf, 8), None);
+        assert_eq!(find_ack(&buf[..20], 7), None);
+    }
+
+    #[test]
+    fn test_attrs() {
+        let mut msg = Message::new(16, 5);
+        msg.attr(1, &[1, 2, 3]).attr_str(0x8002, "ab");
+        let parsed = attrs(&msg.buf[16..]).collect::<Vec<_>>();
+        assert_eq!(parsed, vec![(1, &[1, 2, 3][..]), (2, &b"ab\0"[..])]);
+        assert_eq!(attrs(&msg.buf[16..22]).count(), 0);
+    }
+}
@@ -0,0 +1,436 @@
+//! Helpers for setting up network of the child
+//!
+//! Network namespace is empty when created, these are the basic building
+//! blocks to connect it to the host. They are meant to be called in the
+//! `before_unfreeze` callback (or `PrivilegedOps::setup`), which receives
+//! pid of the frozen child.
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::thread;
+
+use libc::pid_t;
+
+use crate::{Command, Child, Namespace, Fd};
+use crate::error::{Error, result, cmd_result};
+use crate::error::ErrorCode as Err;
+use crate::ffi_util::ToCString;
+use crate::netlink::{Message, Socket, ifinfomsg, ifaddrmsg, rtmsg};
+
+
+/// Helper providing network connectivity in a new network namespace
+/// without privileges (see `Command::userspace_network`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkBackend {
+    /// Run `slirp4netns --configure <pid> tap0`
+    ///
+    /// The helper is waited to report it's ready, and then runs until the
+    /// child is waited for (it's killed afterwards)
+    Slirp4netns,
+    /// Run `pasta --config-net <pid>`
+    ///
+    /// The helper is waited to configure the network and detach. It then
+    /// runs in background and exits by itself when the namespace is gone.
+    Pasta,
+}
+
+/// Running helper, killed when dropped
+#[derive(Debug)]
+pub(crate) struct NetworkHelper(Box<Child>);
+
+impl Drop for NetworkHelper {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+/// Finds executable in the `PATH` of the current process
+pub(crate) fn find_in_path(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+impl Command {
+    /// Provide network in the new network namespace using a userspace
+    /// helper
+    ///
+    /// This unshares `Net` namespace. The helper binary is found in the
+    /// `PATH` of the current process and gets `options` before the pid
+    /// argument. It's started in the parent after uid maps are written and
+    /// before `before_unfreeze` callback, so the network is configured by
+    /// the time the child is started.
+    ///
+    /// Failure to run the helper is `Error::UserspaceNetwork`, if it exits
+    /// early `Error::AuxCommandExited` or `Error::AuxCommandKilled` is
+    /// returned.
+    pub fn userspace_network<S: AsRef<OsStr>>(&mut self,
+        backend: NetworkBackend, options: &[S])
+        -> &mut Command
+    {
+        self.unshare(&[Namespace::Net]);
+        self.userspace_network = Some((backend,
+            options.iter().map(|x| x.as_ref().to_os_string()).collect()));
+        self
+    }
+}
+
+/// Starts the helper for the frozen child
+pub(crate) fn start_helper(backend: NetworkBackend, options: &[OsString],
+    pid: pid_t)
+    -> Result<Option<NetworkHelper>, Error>
+{
+    let binary = match backend {
+        NetworkBackend::Slirp4netns => "slirp4netns",
+        NetworkBackend::Pasta => "pasta",
+    };
+    let path = find_in_path(binary)
+        .ok_or(Error::UserspaceNetwork(libc::ENOENT))?;
+    let mut cmd = Command::new(path);
+    match backend {
+        NetworkBackend::Slirp4netns => {
+            cmd.arg("--configure").arg("--ready-fd=3");
+            cmd.args(options);
+            cmd.arg(pid.to_string()).arg("tap0");
+            cmd.file_descriptor(3, Fd::piped_write());
+            let mut helper = NetworkHelper(Box::new(
+                result(Err::UserspaceNetwork, cmd.spawn())?));
+            let mut ready = [0u8; 1];
+            let mut pipe = helper.0.take_pipe_reader(3).unwrap();
+            match pipe.read(&mut ready) {
+                Ok(1) if ready[0] == b'1' => Ok(Some(helper)),
+                _ => {
+                    cmd_result(Err::UserspaceNetwork, helper.0.wait())?;
+                    // Exited successfully but not ready
+                    Err(Error::UserspaceNetwork(libc::EPIPE))
+                }
+            }
+        }
+        NetworkBackend::Pasta => {
+            cmd.arg("--config-net");
+            cmd.args(options);
+            cmd.arg(pid.to_string());
+            // pasta daemonizes after setup, don't kill it
+            cmd.allow_daemonize();
+            cmd_result(Err::UserspaceNetwork, cmd.status())?;
+            Ok(None)
+        }
+    }
+}
+
+const VETH_INFO_PEER: u16 = 1;
+
+/// Addresses assigned by `create_veth`, as address and prefix length
+#[derive(Debug, Clone, Default)]
+pub struct VethAddrs {
+    /// Addresses of the host end of the pair
+    pub host: Vec<(IpAddr, u8)>,
+    /// Addresses of the end of the pair moved into the child
+    pub child: Vec<(IpAddr, u8)>,
+}
+
+/// A route added by `configure`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// Destination network and prefix length, `None` for the default route
+    pub destination: Option<(IpAddr, u8)>,
+    /// Gateway, the destination is reachable directly if it's `None`
+    pub gateway: Option<IpAddr>,
+    /// Outgoing interface, required if there is no gateway
+    pub interface: Option<String>,
+}
+
+/// Static network configuration of the child, see `configure`
+#[derive(Debug, Clone, Default)]
+pub struct NetConfig {
+    /// Addresses to assign: interface name, address and prefix length
+    pub addrs: Vec<(String, IpAddr, u8)>,
+    /// Routes added after the addresses, in order
+    pub routes: Vec<Route>,
+    /// Name servers, see `NetConfig::resolv_conf`
+    pub dns: Vec<IpAddr>,
+}
+
+impl NetConfig {
+    /// Returns `/etc/resolv.conf` with the name servers of `dns`
+    ///
+    /// Name servers are a file of the child's root rather than a property
+    /// of the network namespace, so `configure` doesn't set them. Put the
+    /// file into the root of the child (e.g. by `Command::copy_into_root`
+    /// of a temporary file or by a `MountProvider`).
+    pub fn resolv_conf(&self) -> String {
+        self.dns.iter().map(|x| format!("nameserver {}\n", x)).collect()
+    }
+}
+
+fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name.len() >= libc::IFNAMSIZ ||
+        name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace())
+    {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("invalid interface name {:?}", name)));
+    }
+    Ok(())
+}
+
+fn interface_index(name: &str) -> io::Result<u32> {
+    match unsafe { libc::if_nametoindex(name.to_cstring().as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        x => Ok(x),
+    }
+}
+
+/// Assigns addresses and brings the link up, in the current netns
+fn configure_link(name: &str, addrs: &[(IpAddr, u8)]) -> io::Result<()> {
+    let index = interface_index(name)?;
+    let mut sock = Socket::new()?;
+    for &(addr, prefix_len) in addrs {
+        let (family, bytes) = match addr {
+            IpAddr::V4(x) => (libc::AF_INET, x.octets().to_vec()),
+            IpAddr::V6(x) => (libc::AF_INET6, x.octets().to_vec()),
+        };
+        sock.request(Message::new(libc::RTM_NEWADDR,
+                libc::NLM_F_REQUEST|libc::NLM_F_ACK|
+                libc::NLM_F_CREATE|libc::NLM_F_EXCL)
+            .push(&ifaddrmsg(family, prefix_len, index))
+            .attr(libc::IFA_LOCAL, &bytes)
+            .attr(libc::IFA_ADDRESS, &bytes))?;
+    }
+    let up = libc::IFF_UP as u32;
+    sock.request(Message::new(libc::RTM_NEWLINK,
+            libc::NLM_F_REQUEST|libc::NLM_F_ACK)
+        .push(&ifinfomsg(index as i32, up, up)))
+}
+
+/// Adds the route, in the current netns
+fn add_route(sock: &mut Socket, route: &Route) -> io::Result<()> {
+    let family = match route.destination.map(|x| x.0).or(route.gateway) {
+        Some(IpAddr::V4(_)) => libc::AF_INET,
+        Some(IpAddr::V6(_)) => libc::AF_INET6,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "route has neither destination nor gateway"));
+        }
+    };
+    let octets = |addr: IpAddr| match addr {
+        IpAddr::V4(x) => x.octets().to_vec(),
+        IpAddr::V6(x) => x.octets().to_vec(),
+    };
+    let scope = if route.gateway.is_some() {
+        libc::RT_SCOPE_UNIVERSE
+    } else {
+        libc::RT_SCOPE_LINK
+    };
+    let dst_len = route.destination.map_or(0, |x| x.1);
+    let mut msg = Message::new(libc::RTM_NEWROUTE,
+        libc::NLM_F_REQUEST|libc::NLM_F_ACK|
+        libc::NLM_F_CREATE|libc::NLM_F_EXCL);
+    msg.push(&rtmsg(family, dst_len, scope));
+    if let Some((addr, _)) = route.destination {
+        msg.attr(libc::RTA_DST, &octets(addr));
+    }
+    if let Some(addr) = route.gateway {
+        msg.attr(libc::RTA_GATEWAY, &octets(addr));
+    }
+    if let Some(ref name) = route.interface {
+        msg.attr(libc::RTA_OIF, &interface_index(name)?.to_ne_bytes());
+    }
+    sock.request(&mut msg)
+}
+
+/// Runs function in a thread which has joined network namespace of `pid`
+fn in_netns<T, F>(pid: u32, f: F) -> io::Result<T>
+    where F: FnOnce() -> io::Result<T> + Send, T: Send
+{
+    let netns = File::open(format!("/proc/{}/ns/net", pid))?;
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let rc = unsafe {
+                libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET)
+            };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            f()
+        }).join().expect("network setup thread panicked")
+    })
+}
+
+/// Creates a veth pair with one end in the network namespace of the child
+///
+/// The `host_name` end stays in the current network namespace and the
+/// `child_name` end is moved into the namespace of the process `pid`
+/// (usually, the frozen child). Then the addresses are assigned and both
+/// ends are brought up. If any step fails the pair is removed.
+///
+/// This uses rtnetlink directly, so requires `CAP_NET_ADMIN` but no
+/// external tools. Setting addresses in the child's namespace is done by
+/// a short-lived thread which joins the namespace.
+pub fn create_veth(pid: u32, host_name: &str, child_name: &str,
+    addrs: &VethAddrs)
+    -> io::Result<()>
+{
+    check_name(host_name)?;
+    check_name(child_name)?;
+    let mut sock = Socket::new()?;
+    sock.request(Message::new(libc::RTM_NEWLINK,
+            libc::NLM_F_REQUEST|libc::NLM_F_ACK|
+            libc::NLM_F_CREATE|libc::NLM_F_EXCL)
+        .push(&ifinfomsg(0, 0, 0))
+        .attr_str(libc::IFLA_IFNAME, host_name)
+        .begin_nested(libc::IFLA_LINKINFO)
+            .attr_str(libc::IFLA_INFO_KIND, "veth")
+            .begin_nested(libc::IFLA_INFO_DATA)
+                .begin_nested(VETH_INFO_PEER)
+                    .push(&ifinfomsg(0, 0, 0))
+                    .attr_str(libc::IFLA_IFNAME, child_name)
+                    .attr(libc::IFLA_NET_NS_PID, &pid.to_ne_bytes())
+                .end_nested()
+            .end_nested()
+        .end_nested())?;
+    let result = configure_link(host_name, &addrs.host)
+        .and_then(|()| in_netns(pid, || {
+            configure_link(child_name, &addrs.child)
+        }));
+    if result.is_err() {
+        // removing one end removes both
+        if let Ok(index) = interface_index(host_name) {
+            sock.request(Message::new(libc::RTM_DELLINK,
+                    libc::NLM_F_REQUEST|libc::NLM_F_ACK)
+                .push(&ifinfomsg(index as i32, 0, 0))).ok();
+        }
+    }
+    result
+}
+
+/// Moves the interface `name` into the network namespace of the process
+///
+/// The interface (e.g. a physical one, or `macvlan`) is moved from the
+/// current network namespace into the one of the process `pid` (usually,
+/// the frozen child), keeping its name. Requires `CAP_NET_ADMIN` in both
+/// namespaces. Configure it afterwards by `configure`.
+pub fn move_interface(pid: u32, name: &str) -> io::Result<()> {
+    check_name(name)?;
+    let index = interface_index(name)?;
+    Socket::new()?.request(Message::new(libc::RTM_NEWLINK,
+            libc::NLM_F_REQUEST|libc::NLM_F_ACK)
+        .push(&ifinfomsg(index as i32, 0, 0))
+        .attr(libc::IFLA_NET_NS_PID, &pid.to_ne_bytes()))
+}
+
+/// Applies the static network configuration in the namespace of `pid`
+///
+/// Brings the loopback interface up, assigns addresses and brings up the
+/// interfaces having them, then adds the routes. Like `create_veth`, this
+/// uses rtnetlink from a short-lived thread which joins the namespace.
+/// Fails on the first error, leaving the steps done so far in place (the
+/// namespace is usually thrown away along with the failed child).
+pub fn configure(pid: u32, config: &NetConfig) -> io::Result<()> {
+    for (name, _, _) in &config.addrs {
+        check_name(name)?;
+    }
+    in_netns(pid, || {
+        configure_link("lo", &[])?;
+        let mut names = Vec::new();
+        for (name, _, _) in &config.addrs {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        for name in names {
+            let addrs = config.addrs.iter()
+                .filter(|&(x, _, _)| x == name)
+                .map(|&(_, addr, prefix_len)| (addr, prefix_len))
+                .collect::<Vec<_>>();
+            configure_link(name, &addrs)?;
+        }
+        let mut sock = Socket::new()?;
+        for route in &config.routes {
+            add_route(&mut sock, route)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::process;
+    use std::thread;
+
+    use crate::{Command, Namespace};
+    use super::{NetConfig, Route, VethAddrs, move_interface, configure};
+    use super::{create_veth, interface_index};
+
+    #[test]
+    fn test_create_veth() {
+        // a network namespace of the thread, so the host isn't touched
+        thread::spawn(|| {
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                let err = std::io::Error::last_os_error();
+                assert_eq!(err.raw_os_error(), Some(libc::EPERM), "{}", err);
+                return;  // not privileged
+            }
+            let addrs = VethAddrs {
+                host: vec![(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1)), 24)],
+                child: vec![(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)), 24)],
+            };
+            let mut child = Command::new("/bin/sh").arg("-c")
+                .arg("grep -q '^ *unshv1:' /proc/net/dev && \
+                      grep -q ' 10.8.0.2$' /proc/net/fib_trie")
+                .unshare(&[Namespace::Net])
+                .before_unfreeze(move |pid| {
+                    create_veth(pid, "unshv0", "unshv1", &addrs)?;
+                    // the host end stays in the namespace of this thread,
+                    // until the namespace of the child is destroyed
+                    interface_index("unshv0")?;
+                    Ok(())
+                })
+                .spawn().unwrap();
+            assert!(child.wait().unwrap().success());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn test_move_and_configure() {
+        // a network namespace of the thread, so the host isn't touched
+        thread::spawn(|| {
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                return;  // not privileged
+            }
+            let created = process::Command::new("ip")
+                .args(["link", "add", "unsh0", "type", "veth",
+                       "peer", "name", "unsh1"])
+                .status();
+            if !created.is_ok_and(|x| x.success()) {
+                return;  // no `ip` or no veth support
+            }
+            let config = NetConfig {
+                addrs: vec![("unsh0".into(),
+                             IpAddr::V4(Ipv4Addr::new(10, 7, 0, 2)), 24)],
+                routes: vec![Route {
+                    destination: None,
+                    gateway: Some(IpAddr::V4(Ipv4Addr::new(10, 7, 0, 1))),
+                    interface: None,
+                }],
+                dns: vec![IpAddr::V4(Ipv4Addr::new(10, 7, 0, 1))],
+            };
+            assert_eq!(config.resolv_conf(), "nameserver 10.7.0.1\n");
+            let mut child = Command::new("/bin/sh").arg("-c")
+                .arg("grep -q '^unsh0\t00000000\t0100070A' /proc/net/route")
+                .unshare(&[Namespace::Net])
+                .before_unfreeze(move |pid| {
+                    move_interface(pid, "unsh0")?;
+                    configure(pid, &config)?;
+                    Ok(())
+                })
+                .spawn().unwrap();
+            assert!(child.wait().unwrap().success());
+        }).join().unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+//! Helpers that never allocate memory
+//!
+//! These are fine to use in `pre_exec` callbacks, where no memory
+//! allocation may happen (see `Command::pre_exec` for more info). All
+//! functions work on a user-supplied buffer, which is usually just an array
+//! on the stack.
+//!
+//! # Example
+//!
+//! Building a `/proc/<pid>/..` path without allocation:
+//!
+//! ```rust
+//! use unshare::no_alloc::{MAX_PID_LEN, format_pid, join_path};
+//!
+//! let mut pidbuf = [0u8; MAX_PID_LEN+1];
+//! let mut pathbuf = [0u8; 64];
+//! let pid = format_pid(&mut pidbuf, 1234);
+//! let path = join_path(&mut pathbuf, &[b"/proc", pid, b"ns/net"]).unwrap();
+//! assert_eq!(path.to_bytes(), b"/proc/1234/ns/net");
+//! ```
+use std::ffi::CStr;
+
+use libc::pid_t;
+
+
+/// Maximum number of bytes that pid may take when formatted as a decimal
+/// number (without trailing nul byte)
+pub const MAX_PID_LEN: usize = 12;
+
+/// Maximum number of bytes that `u64` may take when formatted as a decimal
+/// number (without trailing nul byte)
+pub const MAX_U64_LEN: usize = 20;
+
+/// Format a number at the end of the buffer
+///
+/// Returns a slice of the buffer containing the decimal number followed
+/// by a nul byte. Returns `None` if the buffer is too small.
+pub fn format_u64(buf: &mut [u8], value: u64) -> Option<&[u8]> {
+    let len = buf.len();
+    if len < 2 {
+        return None;
+    }
+    buf[len-1] = 0;
+    let mut tmp = value;
+    for n in (0..len-1).rev() {
+        buf[n] = (tmp % 10) as u8 + b'0';
+        tmp /= 10;
+        if tmp == 0 {
+            return Some(&buf[n..]);
+        }
+    }
+    None
+}
+
+/// Format a pid at the end of the buffer
+///
+/// Returns a slice of the buffer containing the decimal number followed
+/// by a nul byte. The size of the buffer guarantees that any pid fits.
+pub fn format_pid(buf: &mut [u8; MAX_PID_LEN+1], pid: pid_t) -> &[u8] {
+    match format_u64(&mut buf[..], pid as u32 as u64) {
+        Some(data) => data,
+        None => unreachable!("can't format pid"),
+    }
+}
+
+/// Join path components with a slash into the buffer
+///
+/// A single trailing nul byte of each part is ignored, so the output of
+/// `format_pid` or `format_u64` may be passed directly. Slashes are not
+/// deduplicated, so parts should not end with a slash (except the root `/`
+/// as the first part).
+///
+/// Returns `None` if result doesn't fit the buffer or if any part has
+/// an embedded nul byte.
+pub fn join_path<'a>(buf: &'a mut [u8], parts: &[&[u8]]) -> Option<&'a CStr> {
+    let mut pos = 0;
+    for (idx, part) in parts.iter().enumerate() {
+        let part = match part.split_last() {
+            Some((&0, rest)) => rest,
+            _ => part,
+        };
+        if part.contains(&0) {
+            return None;
+        }
+        if idx > 0 && !(idx == 1 && pos == 1 && buf[0] == b'/') {
+            *buf.get_mut(pos)? = b'/';
+            pos += 1;
+        }
+        buf.get_mut(pos..pos+part.len())?.copy_from_slice(part);
+        pos += part.len();
+    }
+    *buf.get_mut(pos)? = 0;
+    // we have checked that there are no nul bytes inside
+    Some(unsafe { CStr::from_bytes_with_nul_unchecked(&buf[..pos+1]) })
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{thread_rng, Rng};
+    use std::ffi::CStr;
+    use super::{MAX_PID_LEN, MAX_U64_LEN, format_pid, format_u64, join_path};
+
+    fn fmt_normal(val: i32) -> String {
+        let mut buf = [0u8; MAX_PID_LEN+1];
+        let slice = format_pid(&mut buf, val);
+        return CStr::from_bytes_with_nul(slice).unwrap()
+            .to_string_lossy().to_string();
+    }
+    #[test]
+    fn test_format() {
+        assert_eq!(fmt_normal(0), "0");
+        assert_eq!(fmt_normal(1), "1");
+        assert_eq!(fmt_normal(7), "7");
+        assert_eq!(fmt_normal(79), "79");
+        assert_eq!(fmt_normal(254), "254");
+        assert_eq!(fmt_normal(1158), "1158");
+        assert_eq!(fmt_normal(77839), "77839");
+    }
+    #[test]
+    fn test_random() {
+        for _ in 0..100000 {
+            let x = thread_rng().gen();
+            if x < 0 { continue; }
+            assert_eq!(fmt_normal(x), format!("{}", x));
+        }
+    }
+    #[test]
+    fn test_u64() {
+        let mut buf = [0u8; MAX_U64_LEN+1];
+        assert_eq!(format_u64(&mut buf, u64::MAX).unwrap(),
+                   &b"18446744073709551615\0"[..]);
+        assert_eq!(format_u64(&mut buf[..3], 123), None);
+        assert_eq!(format_u64(&mut buf[..4], 123).unwrap(), &b"123\0"[..]);
+    }
+    #[test]
+    fn test_join() {
+        let mut buf = [0u8; 32];
+        assert_eq!(join_path(&mut buf, &[b"/", b"proc"]).unwrap().to_bytes(),
+                   b"/proc");
+        assert_eq!(join_path(&mut buf, &[b"/proc", b"12\0", b"uid_map"])
+                   .unwrap().to_bytes(),
+                   b"/proc/12/uid_map");
+        assert_eq!(join_path(&mut buf, &[b"rel", b"path"]).unwrap().to_bytes(),
+                   b"rel/path");
+        assert!(join_path(&mut buf, &[b"/a\0b"]).is_none());
+        assert!(join_path(&mut buf[..5], &[b"/proc"]).is_none());
+        assert!(join_path(&mut buf[..6], &[b"/proc"]).is_some());
+    }
+}
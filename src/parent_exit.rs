@@ -0,0 +1,91 @@
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use crate::Command;
+
+
+/// What to do with stdio which may break when the parent exits
+///
+/// See `Command::on_parent_exit`
+pub enum StdioPolicy {
+    /// Keep the stdio as configured (the default)
+    Keep,
+    /// Replace inherited stdio which is a pipe or a socket by `/dev/null`
+    Null,
+    /// Send stdout and stderr to the logger, like `pipe_output_to`
+    Logger(Box<Command>),
+}
+
+/// Returns true if the descriptor is a pipe or a socket, so writing to it
+/// fails with `EPIPE` once the other end is closed
+pub(crate) fn is_pipe_or_socket(fd: RawFd) -> bool {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return false;
+    }
+    let kind = stat.st_mode & libc::S_IFMT;
+    kind == libc::S_IFIFO || kind == libc::S_IFSOCK
+}
+
+impl Command {
+    /// Set up stdio so the child is unaffected when the current process,
+    /// or whoever reads its output, exits
+    ///
+    /// A child surviving the parent (e.g. with `death_sig` disabled) gets
+    /// `SIGPIPE` or `EPIPE` writing to the pipes inherited from the parent
+    /// once their readers are gone. The layout is decided on `spawn()`:
+    ///
+    /// * `StdioPolicy::Null` replaces inherited stdin, stdout and stderr
+    ///   which are pipes or sockets by `/dev/null` (so their output is
+    ///   discarded even while the parent is alive)
+    /// * `StdioPolicy::Logger` keeps the output, by passing it to the
+    ///   logger, see `pipe_output_to`
+    ///
+    /// Pipes created by `Stdio::piped` are left as is, as the current
+    /// process reads them. See also `Stdio::inherit_or_null` for stdio which
+    /// may be closed in the current process.
+    ///
+    /// Each invocation **replaces** previously set policy (and the logger).
+    pub fn on_parent_exit(&mut self, policy: StdioPolicy) -> &mut Command {
+        self.config.null_inherited_pipes = false;
+        self.logger = None;
+        match policy {
+            StdioPolicy::Keep => {}
+            StdioPolicy::Null => self.config.null_inherited_pipes = true,
+            StdioPolicy::Logger(logger) => {
+                self.pipe_output_to(*logger);
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    use crate::{Command, Fd};
+    use crate::pipe::Pipe;
+    use crate::stdio::is_open;
+    use super::is_pipe_or_socket;
+
+    #[test]
+    fn test_is_pipe_or_socket() {
+        let (rd, _wr) = Pipe::new().unwrap().split();
+        assert!(is_pipe_or_socket(rd.as_raw_fd()));
+        let file = File::open("/dev/null").unwrap();
+        assert!(!is_pipe_or_socket(file.as_raw_fd()));
+        assert!(is_open(file.as_raw_fd()));
+        assert!(!is_open(1000));
+    }
+
+    #[test]
+    fn test_inherit_or_null() {
+        let status = Command::new("/bin/sh").arg("-c")
+            .arg("test $(readlink /proc/self/fd/1000) = /dev/null")
+            .file_descriptor(1000, Fd::inherit_or_null())
+            .status().unwrap();
+        assert!(status.success());
+    }
+}
@@ -0,0 +1,153 @@
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+use crate::Command;
+use crate::mount_plan::MountOp;
+use crate::chroot::in_root;
+
+
+/// A path on the host and the path of the same file in the child
+///
+/// See `Command::map_path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPair {
+    /// Absolute path in the mount namespace of the current process
+    pub host: PathBuf,
+    /// Path in the child, `None` if the file is not visible there
+    pub sandbox: Option<PathBuf>,
+}
+
+/// Makes the path absolute and removes `.` and `..` components
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = if path.is_absolute() {
+        PathBuf::from("/")
+    } else {
+        env::current_dir().unwrap_or_else(|_| PathBuf::from("/"))
+    };
+    for item in path.components() {
+        match item {
+            Component::ParentDir => { result.pop(); }
+            Component::Normal(name) => result.push(name),
+            Component::RootDir | Component::CurDir | Component::Prefix(_)
+                => {}
+        }
+    }
+    result
+}
+
+/// Returns the `path` as seen from `dir` as a root, if it's beneath it
+fn beneath(dir: &Path, path: &Path) -> Option<PathBuf> {
+    path.strip_prefix(dir).ok().map(|rest| Path::new("/").join(rest))
+}
+
+/// Whether the mount hides the path
+fn hides(op: &MountOp, path: &Path) -> bool {
+    match *op {
+        MountOp::Bind { ref target, .. } | MountOp::Provided { ref target }
+            => path.starts_with(target),
+        _ => false,
+    }
+}
+
+impl Command {
+    /// Returns the path under which the host file is visible in the child
+    ///
+    /// This is computed from the `mount_plan`: bind mounts (`scratch_dir`,
+    /// `idmapped_mount`, `inject_host_file`), `pivot_root` and
+    /// `chroot_dir`. Paths covered by a mount done later are not visible
+    /// (contents of `MountProvider` mounts are unknown, so they only hide
+    /// paths). The path in the new root is preferred to bind mounts, and
+    /// both are preferred to the old root kept by `pivot_root`.
+    ///
+    /// Relative paths are relative to the current directory. The path is
+    /// normalized lexically: symlinks aren't resolved either on the host
+    /// or in the child, so canonicalize the path first if needed.
+    pub fn map_path<P: AsRef<Path>>(&self, host_path: P) -> PathPair {
+        let host = normalize(host_path.as_ref());
+        let ops = self.mount_plan();
+        // paths of the file in the new mount namespace with the index of
+        // the first mount that may hide them
+        let mut aliases = vec![(0, host.clone())];
+        for (idx, op) in ops.iter().enumerate() {
+            if let MountOp::Bind { ref source, ref target, .. } = *op {
+                if let Ok(rest) = host.strip_prefix(source) {
+                    aliases.push((idx + 1, target.join(rest)));
+                }
+            }
+        }
+        aliases.retain(|(idx, path)| {
+            !ops[*idx..].iter().any(|op| hides(op, path))
+        });
+        let mut pivot = None;
+        let mut chroot = None;
+        for op in &ops {
+            match *op {
+                MountOp::PivotRoot {
+                    ref new_root, ref put_old, unmount_old_root,
+                } => pivot = Some((new_root, put_old, unmount_old_root)),
+                MountOp::Chroot { ref dir } => chroot = Some(dir),
+                _ => {}
+            }
+        }
+        let visible = |path: &Path, old_root: bool| {
+            let path = match pivot {
+                Some((new_root, put_old, unmount)) => {
+                    match beneath(new_root, path) {
+                        Some(path) => path,
+                        None if old_root && !unmount => {
+                            in_root(&beneath(new_root, put_old)?, path)
+                        }
+                        None => return None,
+                    }
+                }
+                None => path.to_path_buf(),
+            };
+            match chroot {
+                Some(dir) => beneath(dir, &path),
+                None => Some(path),
+            }
+        };
+        let sandbox = aliases.iter()
+            .find_map(|(_, path)| visible(path, false))
+            .or_else(|| {
+                aliases.iter().find_map(|(_, path)| visible(path, true))
+            });
+        PathPair { host, sandbox }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::{Command, Namespace};
+
+    fn sandbox(cmd: &Command, path: &str) -> Option<PathBuf> {
+        cmd.map_path(path).sandbox
+    }
+
+    #[test]
+    fn test_map_path() {
+        let mut cmd = Command::new("/bin/true");
+        assert_eq!(sandbox(&cmd, "/etc/../etc/./hosts"),
+                   Some(PathBuf::from("/etc/hosts")));
+        cmd.unshare(&[Namespace::Mount]);
+        cmd.pivot_root("/tmp/root", "/tmp/root/old", false);
+        cmd.scratch_dir("/var/scratch", "/work");
+        assert_eq!(sandbox(&cmd, "/tmp/root/etc/hosts"),
+                   Some(PathBuf::from("/etc/hosts")));
+        assert_eq!(sandbox(&cmd, "/var/scratch/a/../b"),
+                   Some(PathBuf::from("/work/b")));
+        assert_eq!(sandbox(&cmd, "/home/user"),
+                   Some(PathBuf::from("/old/home/user")));
+        // hidden by the scratch directory
+        assert_eq!(sandbox(&cmd, "/tmp/root/work/b"), None);
+        cmd.pivot_root("/tmp/root", "/tmp/root/old", true);
+        assert_eq!(sandbox(&cmd, "/home/user"), None);
+        cmd.chroot_dir("/inner");
+        assert_eq!(sandbox(&cmd, "/tmp/root/inner/x"),
+                   Some(PathBuf::from("/x")));
+        assert_eq!(sandbox(&cmd, "/tmp/root/etc/hosts"), None);
+        assert!(cmd.map_path("relative").host.is_absolute());
+    }
+}
@@ -1,14 +1,14 @@
 use std::io;
 use std::mem;
-use std::os::unix::io::{RawFd};
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
 
-use nix::unistd::pipe2;
-use nix::fcntl::OFlag;
 use libc;
-use libc::{c_void, size_t};
+use libc::{c_int, c_void, size_t};
 
-use crate::error::{result, Error};
+use crate::error::{result, Error, IntoError};
 use crate::error::ErrorCode::CreatePipe;
+use crate::sys::pipe2;
 
 
 /// A pipe used to communicate with subprocess
@@ -27,14 +27,26 @@ pub struct PipeWriter(RawFd);
 pub enum PipeHolder {
     Reader(PipeReader),
     Writer(PipeWriter),
+    Socket(OwnedFd),
 }
 
 
 impl Pipe {
     pub fn new() -> Result<Pipe, Error> {
-        let (rd, wr) = result(CreatePipe, pipe2(OFlag::O_CLOEXEC))?;
+        let (rd, wr) = result(CreatePipe, pipe2(libc::O_CLOEXEC))?;
         Ok(Pipe(rd, wr))
     }
+    /// Creates pipe in a packet mode (`O_DIRECT`)
+    ///
+    /// In packet mode each write is a separate packet and each read reads
+    /// at most one packet. Falls back to normal pipe on old kernels.
+    pub fn new_packet() -> Result<Pipe, Error> {
+        match pipe2(libc::O_CLOEXEC | libc::O_DIRECT) {
+            Ok((rd, wr)) => Ok(Pipe(rd, wr)),
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => Pipe::new(),
+            Err(e) => Err(e.into_error(CreatePipe)),
+        }
+    }
     pub fn split(self) -> (PipeReader, PipeWriter) {
         let Pipe(rd, wr) = self;
         mem::forget(self);
@@ -60,6 +72,42 @@ impl PipeReader {
         mem::forget(self);
         return fd;
     }
+    /// Read data, waiting at most `timeout` for it to become available
+    ///
+    /// Works like `Read::read` except an error of `TimedOut` kind is
+    /// returned if there is nothing to read (and no end of file) within
+    /// `timeout`. Waiting is done by `poll`, so the descriptor stays in
+    /// blocking mode.
+    pub fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration)
+        -> io::Result<usize>
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            // round up, so we don't spin for less than a millisecond
+            let millis = left.as_nanos().div_ceil(1_000_000)
+                .min(c_int::MAX as u128) as c_int;
+            let mut pfd = libc::pollfd {
+                fd: self.0,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut pfd, 1, millis) } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                0 => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut,
+                        "timed out reading from pipe"));
+                }
+                // also POLLHUP and POLLERR, read reports them
+                _ => return io::Read::read(self, buf),
+            }
+        }
+    }
 }
 
 impl PipeWriter {
@@ -72,6 +120,18 @@ impl PipeWriter {
     }
 }
 
+impl AsRawFd for PipeReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl AsRawFd for PipeWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
 impl Drop for PipeReader {
     fn drop(&mut self) {
         unsafe { libc::close(self.0) };
@@ -112,3 +172,29 @@ impl io::Write for PipeWriter {
     }
     fn flush(&mut self) -> io::Result<()> { Ok(()) }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Write};
+    use std::time::{Duration, Instant};
+
+    use super::Pipe;
+
+    #[test]
+    fn test_read_timeout() {
+        let (mut rd, mut wr) = Pipe::new().unwrap().split();
+        let mut buf = [0u8; 16];
+        let start = Instant::now();
+        let err = rd.read_timeout(&mut buf, Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        wr.write_all(b"hello").unwrap();
+        assert_eq!(rd.read_timeout(&mut buf, Duration::from_secs(1)).unwrap(),
+                   5);
+        assert_eq!(&buf[..5], b"hello");
+        drop(wr);
+        assert_eq!(rd.read_timeout(&mut buf, Duration::from_secs(1)).unwrap(),
+                   0);
+    }
+}
@@ -0,0 +1,209 @@
+use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::{Command, Child};
+use crate::error::Error;
+
+
+type Reply = SyncSender<Result<Child, Error>>;
+
+/// A request for a child: extra arguments, environment and the reply
+struct Job {
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    reply: Reply,
+}
+
+/// Unwinds the spawn of a frozen child when the pool is dropped
+struct Closed;
+
+/// Children spawned in advance, which execute the program on demand
+///
+/// Each worker thread of the pool spawns a child and keeps it frozen (as
+/// in `before_unfreeze`) with namespaces created, id maps written and
+/// callbacks run, until it's requested by `exec`. Then arguments and
+/// environment are finalized (see `Command::finalize_with`) and the child
+/// is unfrozen, so only the setup done by the child itself (mounts,
+/// changing root, descriptors) and `execve` are left. The worker starts
+/// the next child right away.
+///
+/// Dropping the pool kills and reaps the frozen children (the ones already
+/// returned by `exec` are not affected).
+pub struct WarmPool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    ready: Arc<AtomicUsize>,
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+fn worker(command: &(dyn Fn() -> Command + Send + Sync),
+    jobs: &Arc<Mutex<Receiver<Job>>>, ready: &Arc<AtomicUsize>,
+    error: &Mutex<Option<Error>>)
+{
+    loop {
+        let taken: Rc<RefCell<Option<Reply>>> = Rc::new(RefCell::new(None));
+        let mut cmd = command();
+        let (slot, jobs, ready) = (taken.clone(), jobs.clone(), ready.clone());
+        cmd.finalize_with(move |spec| {
+            ready.fetch_add(1, Ordering::SeqCst);
+            let job = jobs.lock().unwrap().recv();
+            ready.fetch_sub(1, Ordering::SeqCst);
+            let job = match job {
+                Ok(job) => job,
+                // the frozen child is killed on unwinding
+                Err(_) => panic::resume_unwind(Box::new(Closed)),
+            };
+            spec.args_mut().extend(job.args);
+            for (key, value) in job.env {
+                spec.set_env(key, value);
+            }
+            *slot.borrow_mut() = Some(job.reply);
+        });
+        let result = match panic::catch_unwind(AssertUnwindSafe(
+            || cmd.spawn()))
+        {
+            Ok(result) => result,
+            Err(payload) if payload.is::<Closed>() => return,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        let reply = taken.borrow_mut().take();
+        match reply {
+            Some(reply) => { reply.send(result).ok(); }
+            None => {
+                // failed before the child is requested, so it would fail
+                // again likely
+                *error.lock().unwrap() = result.err();
+                return;
+            }
+        }
+    }
+}
+
+impl WarmPool {
+    /// Start `size` worker threads, each keeping a child spawned
+    ///
+    /// The `command` is called to create the command for every child.
+    /// Its `finalize_with` callback is replaced by the pool. If spawning
+    /// fails before the child is requested (e.g. `before_unfreeze` fails),
+    /// the worker exits, so a broken command doesn't spin, and `exec`
+    /// returns the error when no workers are left.
+    pub fn new<F>(size: usize, command: F) -> WarmPool
+        where F: Fn() -> Command + Send + Sync + 'static
+    {
+        let (tx, rx) = channel();
+        let jobs = Arc::new(Mutex::new(rx));
+        let command = Arc::new(command);
+        let ready = Arc::new(AtomicUsize::new(0));
+        let error = Arc::new(Mutex::new(None));
+        let workers = (0..size).map(|_| {
+            let (command, jobs) = (command.clone(), jobs.clone());
+            let (ready, error) = (ready.clone(), error.clone());
+            thread::Builder::new().name("unshare-pool".into())
+                .spawn(move || worker(&*command, &jobs, &ready, &error))
+                .expect("can't start pool thread")
+        }).collect();
+        WarmPool { jobs: Some(tx), workers, ready, error }
+    }
+
+    /// Run the program in one of the frozen children
+    ///
+    /// The `args` are appended to the arguments of the command, and the
+    /// variables of `env` are set replacing ones of the command. Waits for
+    /// a child if none is frozen at the moment. Returns the error of the
+    /// spawn, or of the last failed worker if all have exited.
+    pub fn exec<A, S, E, K, V>(&self, args: A, env: E) -> Result<Child, Error>
+        where A: IntoIterator<Item=S>, S: AsRef<OsStr>,
+              E: IntoIterator<Item=(K, V)>, K: AsRef<OsStr>, V: AsRef<OsStr>,
+    {
+        let (reply, result) = sync_channel(1);
+        let job = Job {
+            args: args.into_iter().map(|x| x.as_ref().to_os_string())
+                .collect(),
+            env: env.into_iter()
+                .map(|(k, v)| (k.as_ref().to_os_string(),
+                               v.as_ref().to_os_string()))
+                .collect(),
+            reply,
+        };
+        let sent = self.jobs.as_ref().map(|jobs| jobs.send(job));
+        match sent {
+            Some(Ok(())) => {}
+            _ => return Err(self.take_error()),
+        }
+        // the reply is dropped if the worker exits instead
+        result.recv().unwrap_or_else(|_| Err(self.take_error()))
+    }
+
+    /// Returns the number of children frozen and ready for `exec`
+    pub fn ready(&self) -> usize {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    fn take_error(&self) -> Error {
+        self.error.lock().unwrap().take().unwrap_or(Error::UnknownError)
+    }
+}
+
+impl Drop for WarmPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use crate::{Command, Error, ExitStatus, CgroupPolicy};
+    use super::WarmPool;
+
+    fn wait_ready(pool: &WarmPool, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.ready() < count {
+            assert!(Instant::now() < deadline);
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let pool = WarmPool::new(2, || {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.arg("-c").arg("[ \"$X\" = \"$1\" ] && exit \"$2\"").arg("sh");
+            cmd
+        });
+        wait_ready(&pool, 2);
+        let mut child = pool.exec(["a", "3"], [("X", "a")]).unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(3));
+        let mut child = pool.exec(["a", "3"], [("X", "b")]).unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(1));
+        // replenished
+        wait_ready(&pool, 2);
+    }
+
+    #[test]
+    fn test_broken_command() {
+        let pool = WarmPool::new(1, || {
+            let mut cmd = Command::new("/bin/true");
+            cmd.cgroup(CgroupPolicy::MoveTo("/nonexistent/cgroup".into()));
+            cmd
+        });
+        let none: [(&str, &str); 0] = [];
+        match pool.exec(["x"], none) {
+            Err(Error::Cgroup(_)) => {}
+            other => panic!("{:?}", other.map(|c| c.pid())),
+        }
+        // the worker has exited
+        assert!(matches!(pool.exec(["x"], none), Err(Error::UnknownError)));
+    }
+}
@@ -0,0 +1,108 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::{Command, Capability};
+use crate::error::Error;
+use crate::ffi_util::ToCString;
+use crate::chroot::in_root;
+
+
+/// Returns true if executing the file clears ambient capabilities
+fn drops_ambient(path: &Path, no_new_privs: bool) -> bool {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return false,  // exec reports the error
+    };
+    // with no_new_privs set-user-ID bits are ignored
+    let setid = meta.permissions().mode() & (libc::S_ISUID|libc::S_ISGID);
+    if setid != 0 && !no_new_privs {
+        return true;
+    }
+    let rc = unsafe {
+        libc::getxattr(path.to_cstring().as_ptr(),
+            b"security.capability\0".as_ptr() as *const libc::c_char,
+            std::ptr::null_mut(), 0)
+    };
+    rc >= 0
+}
+
+impl Command {
+    /// Allow the child to bind ports below 1024 when running as non-root
+    ///
+    /// This keeps `CAP_NET_BIND_SERVICE` through the `uid` change and
+    /// the exec (by means of `keep_caps` and the ambient set) in addition
+    /// to capabilities already listed in `keep_caps` (so call it after
+    /// `keep_caps`, which replaces the whole mask). Like `keep_caps`,
+    /// this drops all other capabilities and requires the current process
+    /// to have the capability. Ambient capabilities need linux 4.3 (see
+    /// `HardeningStep::AmbientCapabilities` for older kernels).
+    ///
+    /// The kernel clears ambient capabilities when executing set-user-ID
+    /// and set-group-ID programs (unless `no_new_privs` is set, e.g. by
+    /// `seccomp_notify`) and programs with file capabilities. So `spawn()`
+    /// fails with `Error::AmbientCaps(EPERM)` for such programs, instead of
+    /// running them unable to bind the port.
+    pub fn allow_privileged_ports(&mut self) -> &mut Command {
+        let cap = Capability::CAP_NET_BIND_SERVICE as u32;
+        let caps = self.keep_caps.get_or_insert([0, 0]);
+        caps[(cap >> 5) as usize] |= 1 << (cap & 31);
+        self.config.privileged_ports = true;
+        self
+    }
+
+    /// Checks ambient capabilities survive executing the program
+    pub(crate) fn check_privileged_ports(&self) -> Result<(), Error> {
+        if !self.config.privileged_ports {
+            return Ok(());
+        }
+        let root = self.host_root();
+        let program = Path::new(OsStr::from_bytes(self.filename.as_bytes()));
+        let host_program = if program.is_absolute() {
+            in_root(&root, program)
+        } else if root == Path::new("/") {
+            program.to_path_buf()
+        } else {
+            return Ok(());
+        };
+        if drops_ambient(&host_program, self.seccomp_notify.is_some()) {
+            return Err(Error::AmbientCaps(libc::EPERM));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::{Command, Error};
+
+    #[test]
+    fn test_bind_privileged_port() {
+        let status = Command::new("/bin/sh").arg("-c")
+            .arg("grep -q 'CapAmb:.*0400$' /proc/self/status")
+            .uid(65534).gid(65534)
+            .allow_privileged_ports()
+            .status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_setuid_program() {
+        let path = std::env::temp_dir().join("unshare-test-setuid-true");
+        fs::copy("/bin/true", &path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o4755))
+            .unwrap();
+        let result = Command::new(&path).uid(65534)
+            .allow_privileged_ports().spawn();
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::AmbientCaps(code)) => assert_eq!(code, libc::EPERM),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
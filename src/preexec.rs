@@ -0,0 +1,167 @@
+//! Helpers for `pre_exec` callbacks
+//!
+//! The callback runs in the child between `clone` and `execve`, where
+//! memory must not be allocated (see `Command::pre_exec`). Functions in
+//! `ops` are thin wrappers of system calls which don't allocate. Errors
+//! are `io::Error::from_raw_os_error`, which doesn't allocate either, so
+//! they may be returned from the callback with `?`, and the errno is
+//! reported as `Error::PreExec` by `spawn()`.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use unshare::{Command, Namespace};
+//! use unshare::preexec::ops;
+//!
+//! let mut cmd = Command::new("/bin/hostname");
+//! cmd.unshare(&[Namespace::Uts]);
+//! unsafe {
+//!     cmd.pre_exec(|| {
+//!         ops::sethostname(b"sandbox")?;
+//!         let mut buf = [0u8; 64];
+//!         let path = ops::join_path(&mut buf, &[b"/tmp", b"log"])
+//!             .ok_or_else(|| std::io::Error::from_raw_os_error(
+//!                 libc::ENAMETOOLONG))?;
+//!         let fd = ops::open(path, libc::O_WRONLY|libc::O_CREAT, 0o644)?;
+//!         ops::dup2(fd, 2)?;
+//!         ops::close(fd)
+//!     });
+//! }
+//! ```
+//!
+//! The callback must not panic (so avoid `unwrap()` and indexing): the
+//! panic hook runs in the child, and it allocates and takes locks, which
+//! may deadlock if another thread held them at the time of the spawn.
+
+/// Non-allocating system call wrappers
+pub mod ops {
+    use std::ffi::CStr;
+    use std::io;
+    use std::os::unix::io::RawFd;
+    use std::ptr;
+
+    use libc::{c_int, c_ulong, mode_t};
+
+    use crate::sys::errno;
+
+    pub use crate::no_alloc::join_path;
+
+    fn check(rc: c_int) -> io::Result<c_int> {
+        if rc < 0 {
+            Err(io::Error::from_raw_os_error(errno()))
+        } else {
+            Ok(rc)
+        }
+    }
+
+    fn opt_ptr(value: Option<&CStr>) -> *const libc::c_char {
+        value.map_or(ptr::null(), |x| x.as_ptr())
+    }
+
+    /// Open the file, the descriptor has `O_CLOEXEC` set
+    pub fn open(path: &CStr, flags: c_int, mode: mode_t)
+        -> io::Result<RawFd>
+    {
+        check(unsafe {
+            libc::open(path.as_ptr(), flags | libc::O_CLOEXEC, mode)
+        })
+    }
+
+    /// Close the descriptor
+    pub fn close(fd: RawFd) -> io::Result<()> {
+        check(unsafe { libc::close(fd) }).map(|_| ())
+    }
+
+    /// Duplicate `old` to `new`, the `new` doesn't have `O_CLOEXEC` set
+    ///
+    /// If `new` is open, it's closed first.
+    pub fn dup2(old: RawFd, new: RawFd) -> io::Result<()> {
+        check(unsafe { libc::dup2(old, new) }).map(|_| ())
+    }
+
+    /// Write the whole buffer, retrying on partial writes and `EINTR`
+    pub fn write_all(fd: RawFd, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let rc = unsafe {
+                libc::write(fd, data.as_ptr() as *const libc::c_void,
+                            data.len())
+            };
+            if rc < 0 {
+                if errno() == libc::EINTR {
+                    continue;
+                }
+                return Err(io::Error::from_raw_os_error(errno()));
+            }
+            data = &data[rc as usize..];
+        }
+        Ok(())
+    }
+
+    /// Mount the file system, see `man 2 mount`
+    pub fn mount(source: Option<&CStr>, target: &CStr,
+        fstype: Option<&CStr>, flags: c_ulong, data: Option<&CStr>)
+        -> io::Result<()>
+    {
+        check(unsafe {
+            libc::mount(opt_ptr(source), target.as_ptr(), opt_ptr(fstype),
+                flags, opt_ptr(data) as *const libc::c_void)
+        }).map(|_| ())
+    }
+
+    /// Unmount the file system, see `man 2 umount2`
+    pub fn umount(target: &CStr, flags: c_int) -> io::Result<()> {
+        check(unsafe { libc::umount2(target.as_ptr(), flags) }).map(|_| ())
+    }
+
+    /// Process control operation, see `man 2 prctl`
+    ///
+    /// Returns the (non-negative) result of the operation.
+    pub fn prctl(option: c_int, arg2: c_ulong, arg3: c_ulong,
+        arg4: c_ulong, arg5: c_ulong)
+        -> io::Result<c_int>
+    {
+        check(unsafe { libc::prctl(option, arg2, arg3, arg4, arg5) })
+    }
+
+    /// Set the host name, requires `Namespace::Uts` to be unshared to not
+    /// change the host name of the host
+    pub fn sethostname(name: &[u8]) -> io::Result<()> {
+        check(unsafe {
+            libc::sethostname(name.as_ptr() as *const libc::c_char,
+                              name.len())
+        }).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Error, Namespace};
+    use super::ops;
+
+    #[test]
+    fn test_ops() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("test $(hostname) = sandbox");
+        cmd.unshare(&[Namespace::Uts]);
+        unsafe {
+            cmd.pre_exec(|| {
+                ops::sethostname(b"sandbox")?;
+                ops::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0)?;
+                Ok(())
+            });
+        }
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_errno() {
+        let mut cmd = Command::new("/bin/true");
+        unsafe {
+            cmd.pre_exec(|| ops::close(12345));
+        }
+        match cmd.spawn() {
+            Err(Error::PreExec(code)) => assert_eq!(code, libc::EBADF),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use crate::Command;
+use crate::chroot::in_root;
+use crate::elf::{elf_has_interp, read_header};
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+
+
+fn is_static_binary(path: &Path) -> bool {
+    read_header(path)
+        .is_some_and(|data| elf_has_interp(&data) == Some(false))
+}
+
+/// Joins `LD_PRELOAD` set in the environment with the `libs`
+fn join_preload(current: Option<&OsStr>, libs: &[PathBuf]) -> OsString {
+    let mut value = Vec::new();
+    if let Some(cur) = current {
+        // both colons and spaces are separators for the dynamic loader
+        let cur = cur.as_bytes();
+        let end = cur.iter().rposition(|&c| c != b':' && c != b' ')
+            .map(|x| x+1).unwrap_or(0);
+        value.extend(&cur[..end]);
+    }
+    for lib in libs {
+        if !value.is_empty() {
+            value.push(b':');
+        }
+        value.extend(lib.as_os_str().as_bytes());
+    }
+    OsString::from_vec(value)
+}
+
+impl Command {
+    /// Add a library to `LD_PRELOAD` of the child
+    ///
+    /// The `path_in_root` is a path inside the new root (i.e. after
+    /// `pivot_root` and `chroot_dir` are applied). Libraries are appended
+    /// to the `LD_PRELOAD` in the final environment of the child, so
+    /// `env("LD_PRELOAD", ..)` may be used to put something in front. The
+    /// variable is kept even if `scrub_dangerous_env` is enabled.
+    ///
+    /// On `spawn()` the library is checked to exist, otherwise
+    /// `Error::Preload` is returned. Note that this check is done in the
+    /// parent's mount namespace, so files mounted by the child are not
+    /// visible. If the program is a statically linked binary, which
+    /// ignores `LD_PRELOAD`, `HardeningStep::Preload` is reported as
+    /// skipped by `Child::hardening_report` (or `spawn()` fails in
+    /// `strict` mode).
+    ///
+    /// # Panics
+    ///
+    /// If path is not absolute or contains a colon or a space, which can't
+    /// be represented in `LD_PRELOAD`.
+    pub fn preload<P: AsRef<Path>>(&mut self, path_in_root: P)
+        -> &mut Command
+    {
+        let path = path_in_root.as_ref();
+        if !path.is_absolute() {
+            panic!("Preload path must be absolute");
+        }
+        if path.as_os_str().as_bytes().iter().any(|&c| c == b':' || c == b' ')
+        {
+            panic!("Preload path must not contain colons or spaces");
+        }
+        self.preload.push(path.to_path_buf());
+        self
+    }
+
+    /// Checks preloaded libraries and returns the new value of `LD_PRELOAD`
+    pub(crate) fn preload_env(&self, current: Option<&OsStr>)
+        -> Result<Option<OsString>, Error>
+    {
+        if self.preload.is_empty() {
+            return Ok(None);
+        }
+        let root = self.host_root();
+        for lib in &self.preload {
+            result(Err::Preload, fs::metadata(in_root(&root, lib)))?;
+        }
+        Ok(Some(join_preload(current, &self.preload)))
+    }
+
+    /// Returns true if the program ignores `LD_PRELOAD` of `preload`
+    ///
+    /// Fails with `Error::Preload(ENOEXEC)` in strict mode instead.
+    pub(crate) fn preload_ignored(&self) -> Result<bool, Error> {
+        if self.preload.is_empty() {
+            return Ok(false);
+        }
+        let program = in_root(&self.host_root(),
+            Path::new(OsStr::from_bytes(self.filename.as_bytes())));
+        if !is_static_binary(&program) {
+            return Ok(false);
+        }
+        if self.config.strict {
+            return Err(Error::Preload(libc::ENOEXEC));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::{Command, Error, HardeningStep, Stdio};
+    use super::join_preload;
+
+    #[test]
+    fn test_join() {
+        let libs = [PathBuf::from("/a.so"), PathBuf::from("/b.so")];
+        assert_eq!(join_preload(None, &libs), OsStr::new("/a.so:/b.so"));
+        assert_eq!(join_preload(Some(OsStr::new("")), &libs[..1]),
+                   OsStr::new("/a.so"));
+        assert_eq!(join_preload(Some(OsStr::new("/x.so: ")), &libs[..1]),
+                   OsStr::new("/x.so:/a.so"));
+        assert_eq!(join_preload(Some(OsStr::new("/x.so /y.so")), &libs[1..]),
+                   OsStr::new("/x.so /y.so:/b.so"));
+    }
+
+    #[test]
+    #[cfg_attr(not(target_env="gnu"), ignore="needs static glibc ldconfig")]
+    fn test_static_program() {
+        // statically linked on glibc systems
+        let ldconfig = ["/sbin/ldconfig", "/usr/sbin/ldconfig"].iter()
+            .map(Path::new).find(|p| p.exists()).expect("no ldconfig");
+        assert!(super::is_static_binary(ldconfig));
+        // the libc of this process, wherever the distribution puts it
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        let lib = maps.lines()
+            .filter_map(|line| line.split_whitespace().nth(5))
+            .find(|path| path.contains("/libc.so"))
+            .expect("no libc mapped");
+        let mut cmd = Command::new(ldconfig);
+        cmd.arg("--version").stdout(Stdio::null()).preload(lib);
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(child.hardening_report().skipped(),
+                   &[HardeningStep::Preload]);
+        child.wait().unwrap();
+        assert!(matches!(cmd.strict(true).spawn(),
+                         Err(Error::Preload(libc::ENOEXEC))));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::{Command, BoxError};
+use crate::idmap::{UidMap, GidMap};
+
+
+/// Privileged steps of the child setup that may be delegated to a helper
+///
+/// When a running process is unprivileged, it may still be able to perform
+/// clone itself, but the steps that require privileges (writing uid/gid
+/// maps, moving process into a cgroup, moving network interfaces) must be
+/// done by somebody else, for example by a setuid helper or by a
+/// privileged daemon talking over a socket.
+///
+/// All methods are called in the **parent** process while the child is
+/// frozen (i.e. after `clone` but before the child continues to `execve`).
+/// Methods are called in the order they are declared here, and all of them
+/// run before the ``before_unfreeze`` callback.
+///
+/// Any error returned from the methods aborts the spawn and kills the child.
+pub trait PrivilegedOps {
+    /// Write uid and gid mappings for the child process
+    ///
+    /// Only called when ``set_id_maps`` is configured. When ops are set,
+    /// neither direct writing of `/proc/<pid>/uid_map`, nor the writer set
+    /// by ``id_map_writer`` (or ``set_id_map_commands``) are used.
+    fn write_id_maps(&mut self, pid: u32, uid_map: &[UidMap],
+        gid_map: &[GidMap])
+        -> Result<(), BoxError>;
+
+    /// Perform any other privileged setup of the frozen child
+    ///
+    /// This is the place to move the process into a cgroup, or move
+    /// network interfaces into its namespace. Default implementation
+    /// does nothing.
+    fn setup(&mut self, _pid: u32) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+
+impl Command {
+    /// Delegate privileged steps of the child setup to the `ops` object
+    ///
+    /// See `PrivilegedOps` for the description of the steps and when they
+    /// are run.
+    ///
+    /// Each invocation **replaces** previously set object.
+    pub fn privileged_ops(&mut self, ops: impl PrivilegedOps + 'static)
+        -> &mut Command
+    {
+        self.privileged_ops = Some(Box::new(ops));
+        self
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+use crate::{Command, ExitStatus, HardeningReport, MountOp};
+use crate::error::Error;
+
+
+/// Result of `Command::probe`
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    /// Time it took to set up the sandbox and exit the probe
+    pub duration: Duration,
+    /// Isolation steps applied and skipped
+    pub hardening: HardeningReport,
+    /// Mount operations done by the probe
+    pub mounts: Vec<MountOp>,
+}
+
+impl Command {
+    /// Check that the sandbox is viable without running the program
+    ///
+    /// The child is spawned the usual way: namespaces, id maps, mounts,
+    /// user, capabilities and descriptors are set up and callbacks
+    /// (including `before_unfreeze` and `pre_exec`) are run. But instead
+    /// of executing the program, the child checks the program is
+    /// executable there and exits. So services can validate their
+    /// configuration at startup rather than on the first real job.
+    ///
+    /// Returns the error `spawn()` would return (`Error::Exec` if
+    /// the program isn't found or isn't executable), or
+    /// `Error::ChildDiedDuringSetup` if the probe didn't exit cleanly, for
+    /// example because it was killed by a limit.
+    pub fn probe(&mut self) -> Result<ProbeReport, Error> {
+        let start = Instant::now();
+        self.config.probe = true;
+        let result = self.spawn();
+        self.config.probe = false;
+        let mut child = result?;
+        let status = child.wait()
+            .map_err(|e| Error::WaitError(e.raw_os_error().unwrap_or(-1)))?;
+        if status != ExitStatus::Exited(0) {
+            return Err(Error::ChildDiedDuringSetup(status));
+        }
+        Ok(ProbeReport {
+            duration: start.elapsed(),
+            hardening: child.hardening_report().clone(),
+            mounts: child.mounts_applied().to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Error, HardeningStep};
+
+    #[test]
+    fn test_probe() {
+        let file = std::env::temp_dir().join("unshare-probe-test");
+        let _ = std::fs::remove_file(&file);
+        let report = Command::new("/bin/sh").arg("-c")
+            .arg(format!("touch {}", file.display()))
+            .close_fds(..)
+            .probe().unwrap();
+        assert_eq!(report.hardening.applied(), &[HardeningStep::CloseFds]);
+        // the program isn't run
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn test_probe_not_executable() {
+        match Command::new("/etc/passwd").probe() {
+            Err(Error::Exec(code)) => assert_eq!(code, libc::EACCES),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use libc::pid_t;
+
+use crate::Child;
+
+
+/// A process and its descendants, see `Child::process_tree`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessNode {
+    /// Pid in the pid namespace of the current process
+    pub pid: pid_t,
+    /// Pid in the innermost pid namespace of the process, if it differs
+    /// from `pid` (e.g. `1` for the child in a new pid namespace)
+    pub ns_pid: Option<pid_t>,
+    /// Name of the executable (`comm`, truncated to 15 bytes by the kernel)
+    pub name: String,
+    /// State from `/proc/<pid>/stat`: `R` running, `S` sleeping, `D`
+    /// uninterruptible sleep, `Z` zombie, `T` stopped and so on
+    pub state: char,
+    /// Children of the process ordered by pid
+    pub children: Vec<ProcessNode>,
+}
+
+struct Entry {
+    ppid: pid_t,
+    name: String,
+    state: char,
+}
+
+/// Parses `/proc/<pid>/stat`, the name may contain spaces and parenthesis
+fn read_stat(pid: pid_t) -> Option<Entry> {
+    let data = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let start = data.find('(')?;
+    let end = data.rfind(')')?;
+    let mut fields = data.get(end + 1..)?.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let ppid = fields.next()?.parse().ok()?;
+    let name = data.get(start + 1..end)?.to_string();
+    Some(Entry { ppid, name, state })
+}
+
+/// Start time of the process since boot, in clock ticks
+///
+/// Together with the pid it identifies the process, as a new process with
+/// the same pid can't start at the same tick.
+pub(crate) fn start_time(pid: pid_t) -> Option<u64> {
+    start_time_in(Path::new("/proc"), pid)
+}
+
+/// Like `start_time`, with the proc file system mounted at `proc`
+pub(crate) fn start_time_in(proc: &Path, pid: pid_t) -> Option<u64> {
+    let data = fs::read_to_string(proc.join(pid.to_string()).join("stat"))
+        .ok()?;
+    let end = data.rfind(')')?;
+    // field 22, the name is the field 2
+    data.get(end + 1..)?.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The last pid of the `NSpid` line of `/proc/<pid>/status`
+fn read_ns_pid(pid: pid_t) -> Option<pid_t> {
+    let data = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = data.lines().find(|x| x.starts_with("NSpid:"))?;
+    let ns_pid = line.split_whitespace().last()?.parse().ok()?;
+    Some(ns_pid).filter(|&x| x != pid)
+}
+
+fn build(pid: pid_t, entry: Entry, entries: &mut HashMap<pid_t, Entry>)
+    -> ProcessNode
+{
+    let mut child_pids = entries.iter()
+        .filter(|(_, e)| e.ppid == pid)
+        .map(|(&pid, _)| pid)
+        .collect::<Vec<_>>();
+    child_pids.sort();
+    let children = child_pids.into_iter().filter_map(|child| {
+        // removed if it's already built, i.e. on pid reuse loops
+        let entry = entries.remove(&child)?;
+        Some(build(child, entry, entries))
+    }).collect();
+    ProcessNode {
+        pid,
+        ns_pid: read_ns_pid(pid),
+        name: entry.name,
+        state: entry.state,
+        children,
+    }
+}
+
+impl ProcessNode {
+    /// Pids of the process and all its descendants, parents go first
+    pub fn pids(&self) -> Vec<pid_t> {
+        let mut result = vec![self.pid];
+        for child in &self.children {
+            result.extend(child.pids());
+        }
+        result
+    }
+}
+
+impl Child {
+    /// Returns a snapshot of the child and all its descendants
+    ///
+    /// The tree is built from the parent pids of all processes in `/proc`,
+    /// which is read while processes may fork and exit, so it's not
+    /// atomic. Orphans are reparented to the child only if it's a
+    /// subreaper or the init of a new pid namespace (then the tree
+    /// includes all processes of the namespace created by the child,
+    /// `ns_pid` shows pids inside it), otherwise they are not in the tree.
+    ///
+    /// To kill everything under the child, freeze it first (see
+    /// `Child::freeze`) and signal the `pids()` of the snapshot. Returns
+    /// error if the child is already waited for (`ESRCH`).
+    pub fn process_tree(&self) -> io::Result<ProcessNode> {
+        let mut root = None;
+        let mut entries = HashMap::new();
+        for item in fs::read_dir("/proc")? {
+            let pid = match item?.file_name().to_str()
+                .and_then(|x| x.parse::<pid_t>().ok())
+            {
+                Some(pid) => pid,
+                None => continue,
+            };
+            // processes may exit while the directory is read
+            if let Some(entry) = read_stat(pid) {
+                if pid == self.pid() {
+                    root = Some(entry);
+                } else {
+                    entries.insert(pid, entry);
+                }
+            }
+        }
+        match root {
+            Some(root) if self.status.is_none() => {
+                Ok(build(self.pid(), root, &mut entries))
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::ESRCH)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::{Command, Namespace, Signal};
+
+    #[test]
+    fn test_process_tree() {
+        let mut child = Command::new("/bin/sh")
+            .arg("-c").arg("sleep 10 & sleep 10 & wait")
+            .spawn().unwrap();
+        let mut tree = child.process_tree().unwrap();
+        for _ in 0..1000 {
+            if tree.children.len() == 2 &&
+                tree.children.iter().all(|x| x.name == "sleep")
+            {
+                break;
+            }
+            sleep(Duration::from_millis(5));
+            tree = child.process_tree().unwrap();
+        }
+        assert_eq!(tree.pid, child.pid());
+        assert_eq!(tree.name, "sh");
+        assert_eq!(tree.ns_pid, None);
+        assert_eq!(tree.children.len(), 2);
+        assert!(tree.children.iter().all(|x| x.name == "sleep"));
+        let pids = tree.pids();
+        assert_eq!(pids.len(), 3);
+        for &pid in pids.iter().rev() {
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+        child.wait().unwrap();
+        assert_eq!(child.process_tree().unwrap_err().raw_os_error(),
+                   Some(libc::ESRCH));
+    }
+
+    #[test]
+    fn test_pid_namespace() {
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10").unshare(&[Namespace::Pid]);
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            // unprivileged
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) ||
+                          e.raw_os_error() == Some(libc::EACCES) => return,
+            Err(e) => panic!("{}", e),
+        };
+        let tree = child.process_tree().unwrap();
+        assert_eq!(tree.ns_pid, Some(1));
+        child.signal(Signal::SIGKILL).unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use std::ffi::CString;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use crate::{Child, Namespace, ns_equal};
+
+
+/// How often `Child::wait_ready` rechecks the probe
+const RECHECK: Duration = Duration::from_millis(10);
+
+/// A readiness check of `Child::wait_ready`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    /// The file (or directory, socket, etc.) exists
+    PathExists(PathBuf),
+    /// The unix socket accepts connections
+    UnixConnect(PathBuf),
+    /// The TCP port accepts connections
+    TcpConnect(SocketAddr),
+}
+
+/// Path inside the root of the child, absolute paths are expected
+fn in_child_root(pid: i32, path: &Path) -> PathBuf {
+    let path = path.strip_prefix("/").unwrap_or(path);
+    Path::new(&format!("/proc/{}/root", pid)).join(path)
+}
+
+fn unix_connect(path: &Path) -> io::Result<UnixStream> {
+    // the full path may exceed the limit of `sun_path`
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let fd = unsafe {
+        libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = UnixStream::connect(format!("/proc/self/fd/{}", fd));
+    unsafe { libc::close(fd) };
+    result
+}
+
+impl Child {
+    /// Wait until the probe succeeds, e.g. the service in the child listens
+    ///
+    /// Paths are resolved in the root of the child, by
+    /// `/proc/<pid>/root/..` (so symlinks are resolved relative to the
+    /// current root and there is some, not very useful, ambiguity for
+    /// absolute symlinks). TCP connections are made in the network
+    /// namespace of the child (this needs `CAP_SYS_ADMIN` if it differs
+    /// from the one of the current process). Connections are closed right
+    /// away.
+    ///
+    /// The probe is rechecked every 10 ms. Returns `TimedOut` error if the
+    /// probe didn't succeed in the `timeout`, and `ECHILD` if the child
+    /// has exited (it isn't reaped here).
+    pub fn wait_ready(&self, probe: &Probe, timeout: Duration)
+        -> io::Result<()>
+    {
+        let deadline = Instant::now() + timeout;
+        if self.peek_status()?.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::ECHILD));
+        }
+        let netns = match *probe {
+            Probe::TcpConnect(_) => {
+                let own = unsafe { libc::getpid() };
+                if ns_equal(self.pid(), own, Namespace::Net)? {
+                    None
+                } else {
+                    Some(self.ns_fd(Namespace::Net)?)
+                }
+            }
+            _ => None,
+        };
+        loop {
+            if self.peek_status()?.is_some() {
+                return Err(io::Error::from_raw_os_error(libc::ECHILD));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            let ready = match *probe {
+                Probe::PathExists(ref path) => {
+                    in_child_root(self.pid(), path).symlink_metadata().is_ok()
+                }
+                Probe::UnixConnect(ref path) => {
+                    unix_connect(&in_child_root(self.pid(), path)).is_ok()
+                }
+                Probe::TcpConnect(addr) => {
+                    // at least a millisecond, zero timeout is an error
+                    let limit = left.clamp(Duration::from_millis(1), RECHECK);
+                    let connect = || TcpStream::connect_timeout(&addr, limit);
+                    match netns {
+                        // the thread is thrown away after `setns`
+                        Some(ref fd) => thread::scope(|s| s.spawn(|| {
+                            let rc = unsafe {
+                                libc::setns(fd.as_raw_fd(), libc::CLONE_NEWNET)
+                            };
+                            if rc != 0 {
+                                return Err(io::Error::last_os_error());
+                            }
+                            Ok(connect().is_ok())
+                        }).join().unwrap())?,
+                        None => connect().is_ok(),
+                    }
+                }
+            };
+            if ready {
+                return Ok(());
+            }
+            if left.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                    "the child isn't ready in time"));
+            }
+            sleep(left.min(RECHECK));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::time::Duration;
+
+    use crate::{Command, Namespace};
+    use super::Probe;
+
+    #[test]
+    fn test_path_exists() {
+        let name = format!("unshare-test-ready-{}", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("sleep 0.1; touch \"$0\"; sleep 10").arg(&path)
+            .spawn().unwrap();
+        let probe = Probe::PathExists(path.clone());
+        assert_eq!(child.wait_ready(&probe, Duration::from_millis(20))
+                   .unwrap_err().kind(), ErrorKind::TimedOut);
+        child.wait_ready(&probe, Duration::from_secs(5)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let probe = Probe::UnixConnect(path.clone());
+        assert!(child.wait_ready(&probe, Duration::ZERO).is_err());
+        let _listener = UnixListener::bind(&path).unwrap();
+        child.wait_ready(&probe, Duration::ZERO).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let probe = Probe::TcpConnect(listener.local_addr().unwrap());
+        let mut child = Command::new("/bin/sleep").arg("10")
+            .spawn().unwrap();
+        child.wait_ready(&probe, Duration::from_secs(5)).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let err = child.wait_ready(&probe, Duration::from_secs(5))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ECHILD));
+
+        // nothing listens in the new network namespace
+        let mut child = match Command::new("/bin/sleep").arg("10")
+            .unshare(&[Namespace::Net]).spawn()
+        {
+            Ok(child) => child,
+            // unprivileged
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) ||
+                          e.raw_os_error() == Some(libc::EACCES) => return,
+            Err(e) => panic!("{}", e),
+        };
+        let err = child.wait_ready(&probe, Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Command, Child, ExitStatus, Stdio, PipeReader};
+use crate::error::Error;
+
+
+type SuccessFn = Box<dyn Fn(&ExitStatus, &Output) -> bool>;
+
+/// How `Command::run` runs the command and decides whether it succeeded
+pub struct RunPolicy {
+    max_duration: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
+    success: SuccessFn,
+}
+
+/// Output captured by `Command::run`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Output {
+    /// Data written by the process to stdout
+    pub stdout: Vec<u8>,
+    /// Data written by the process to stderr
+    pub stderr: Vec<u8>,
+}
+
+/// Result of `Command::run`
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    /// Number of times the command was run
+    pub attempts: u32,
+    /// How long each attempt took
+    pub durations: Vec<Duration>,
+    /// Exit status of the last attempt
+    pub final_status: ExitStatus,
+    /// Output of the last attempt
+    pub output: Output,
+    /// Whether the last attempt was killed for exceeding `max_duration`
+    pub timed_out: bool,
+    /// Whether the last attempt is successful according to the policy
+    pub success: bool,
+}
+
+impl RunPolicy {
+    /// Run once, without time limit, succeed if exit code is zero
+    pub fn new() -> RunPolicy {
+        RunPolicy {
+            max_duration: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            success: Box::new(|status, _| status.success()),
+        }
+    }
+    /// Kill each attempt with `SIGKILL` if it runs longer than `duration`
+    ///
+    /// Attempt that is killed is always a failure.
+    pub fn max_duration(&mut self, duration: Duration) -> &mut RunPolicy {
+        self.max_duration = Some(duration);
+        self
+    }
+    /// Run the command up to `retries` more times if it fails
+    pub fn retries(&mut self, retries: u32) -> &mut RunPolicy {
+        self.retries = retries;
+        self
+    }
+    /// Sleep between attempts
+    pub fn retry_delay(&mut self, delay: Duration) -> &mut RunPolicy {
+        self.retry_delay = delay;
+        self
+    }
+    /// Decide whether the attempt is successful
+    ///
+    /// By default, it's successful if the process exits with code zero.
+    pub fn success<F>(&mut self, predicate: F) -> &mut RunPolicy
+        where F: Fn(&ExitStatus, &Output) -> bool + 'static
+    {
+        self.success = Box::new(predicate);
+        self
+    }
+}
+
+impl Default for RunPolicy {
+    fn default() -> RunPolicy {
+        RunPolicy::new()
+    }
+}
+
+impl fmt::Debug for RunPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RunPolicy")
+            .field("max_duration", &self.max_duration)
+            .field("retries", &self.retries)
+            .field("retry_delay", &self.retry_delay)
+            .finish()
+    }
+}
+
+/// Reads both pipes until closed or `deadline`
+///
+/// Data is appended to `output` if `keep` is set, and discarded otherwise.
+/// Returns false if deadline is reached
+fn collect(stdout: Option<PipeReader>, stderr: Option<PipeReader>,
+    deadline: Option<Instant>, output: &mut Output, keep: bool)
+    -> io::Result<bool>
+{
+    let mut pipes = [(stdout, &mut output.stdout),
+                     (stderr, &mut output.stderr)];
+    let mut buf = [0u8; 8192];
+    while pipes.iter().any(|(pipe, _)| pipe.is_some()) {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(false);
+                }
+                // round up, so we don't spin when less than a millisecond
+                // is left
+                let left = (deadline - now).as_millis() + 1;
+                left.min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+        let mut fds = pipes.iter().map(|(pipe, _)| libc::pollfd {
+            fd: pipe.as_ref().map_or(-1, |p| p.as_raw_fd()),
+            events: libc::POLLIN,
+            revents: 0,
+        }).collect::<Vec<_>>();
+        let rc = unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout)
+        };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        for (pfd, (pipe, data)) in fds.iter().zip(pipes.iter_mut()) {
+            if pfd.revents == 0 {
+                continue;
+            }
+            match pipe.as_mut().unwrap().read(&mut buf) {
+                Ok(0) => *pipe = None,
+                Ok(n) if keep => data.extend(&buf[..n]),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn wait_error(e: io::Error) -> Error {
+    Error::WaitError(e.raw_os_error().unwrap_or(-1))
+}
+
+/// Checks whether the process has exited, without reaping it
+fn has_exited(pid: libc::pid_t) -> io::Result<bool> {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    let rc = unsafe {
+        libc::waitid(libc::P_PID, pid as libc::id_t, &mut info,
+                     libc::WEXITED | libc::WNOHANG | libc::WNOWAIT)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { info.si_pid() } != 0)
+}
+
+/// Runs a single attempt, returns status, output and whether it timed out
+fn attempt(mut child: Child, deadline: Option<Instant>)
+    -> Result<(ExitStatus, Output, bool), Error>
+{
+    let mut output = Output::default();
+    let stdout = child.take_stdout();
+    let stderr = child.take_stderr();
+    let finished = match collect(stdout, stderr, deadline, &mut output, true)
+    {
+        Ok(finished) => finished,
+        Err(e) => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(Error::PipeError(e.raw_os_error().unwrap_or(-1)));
+        }
+    };
+    if !finished {
+        // pipes may also be closed by the process, without exiting
+        child.kill().ok();
+        return Ok((child.wait().map_err(wait_error)?, output, true));
+    }
+    if let Some(deadline) = deadline {
+        // the process may still run after closing the pipes
+        loop {
+            if has_exited(child.pid()).map_err(wait_error)? {
+                return Ok((child.wait().map_err(wait_error)?, output, false));
+            }
+            if Instant::now() >= deadline {
+                child.kill().ok();
+                return Ok((child.wait().map_err(wait_error)?, output, true));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    Ok((child.wait().map_err(wait_error)?, output, false))
+}
+
+/// Waits for the child reading its piped stdout and stderr meanwhile
+///
+/// So the child can't block on a full pipe while the parent is waiting.
+/// Piped stdin is closed first, as nobody is going to write it.
+pub(crate) fn finish(mut child: Child, keep: bool)
+    -> Result<(ExitStatus, Output), Error>
+{
+    drop(child.take_stdin());
+    let mut output = Output::default();
+    let stdout = child.take_stdout();
+    let stderr = child.take_stderr();
+    if let Err(e) = collect(stdout, stderr, None, &mut output, keep) {
+        child.kill().ok();
+        child.wait().ok();
+        return Err(Error::PipeError(e.raw_os_error().unwrap_or(-1)));
+    }
+    Ok((child.wait().map_err(wait_error)?, output))
+}
+
+impl Command {
+    /// Run the command and return exit status and captured output
+    ///
+    /// Stdout and stderr are captured (this replaces their previous
+    /// configuration). Both pipes are read while waiting, so any amount of
+    /// output doesn't deadlock. Stdin is closed if it's piped.
+    pub fn output(&mut self) -> Result<(ExitStatus, Output), Error> {
+        self.stdout(Stdio::piped());
+        self.stderr(Stdio::piped());
+        finish(self.spawn()?, true)
+    }
+
+    /// Run the command according to the `policy` and report the results
+    ///
+    /// This is an entry point for CI-like use: stdout and stderr are
+    /// captured (this replaces their previous configuration), and the
+    /// command is re-run on failure up to the number of retries. Each
+    /// attempt is limited by `max_duration`, if set.
+    ///
+    /// Errors of spawning the command are returned immediately, without
+    /// retrying, as they're likely caused by the configuration.
+    pub fn run(&mut self, policy: &RunPolicy) -> Result<RunReport, Error> {
+        self.stdout(Stdio::piped());
+        self.stderr(Stdio::piped());
+        let mut durations = Vec::new();
+        loop {
+            let start = Instant::now();
+            let deadline = policy.max_duration.map(|d| start + d);
+            let child = self.spawn()?;
+            let (status, output, timed_out) = attempt(child, deadline)?;
+            durations.push(start.elapsed());
+            let success = !timed_out && (policy.success)(&status, &output);
+            let attempts = durations.len() as u32;
+            if success || attempts > policy.retries {
+                return Ok(RunReport {
+                    attempts,
+                    durations,
+                    final_status: status,
+                    output,
+                    timed_out,
+                    success,
+                });
+            }
+            thread::sleep(policy.retry_delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{Command, ExitStatus, Stdio};
+    use super::RunPolicy;
+
+    #[test]
+    fn test_retries() {
+        let report = Command::new("/bin/sh")
+            .arg("-c").arg("echo out; echo err >&2; exit 3")
+            .run(RunPolicy::new().retries(2))
+            .unwrap();
+        assert_eq!(report.attempts, 3);
+        assert_eq!(report.durations.len(), 3);
+        assert_eq!(report.final_status, ExitStatus::Exited(3));
+        assert_eq!(report.output.stdout, b"out\n");
+        assert_eq!(report.output.stderr, b"err\n");
+        assert!(!report.success && !report.timed_out);
+
+        let report = Command::new("/bin/sh")
+            .arg("-c").arg("echo ok; exit 1")
+            .run(RunPolicy::new().retries(5)
+                .success(|_, output| output.stdout == b"ok\n"))
+            .unwrap();
+        assert_eq!(report.attempts, 1);
+        assert!(report.success);
+    }
+
+    #[test]
+    fn test_large_output() {
+        // much bigger than a pipe buffer, and written to both pipes
+        let script = "head -c 4000000 /dev/zero; \
+                      head -c 3000000 /dev/zero >&2";
+        let (status, output) = Command::new("/bin/sh")
+            .arg("-c").arg(script)
+            .output().unwrap();
+        assert!(status.success());
+        assert_eq!(output.stdout.len(), 4000000);
+        assert_eq!(output.stderr.len(), 3000000);
+        let status = Command::new("/bin/sh")
+            .arg("-c").arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_max_duration() {
+        let report = Command::new("/bin/sh")
+            .arg("-c").arg("exec sleep 10")
+            .run(RunPolicy::new().max_duration(Duration::from_millis(100)))
+            .unwrap();
+        assert!(report.timed_out && !report.success);
+        assert_eq!(report.final_status.signal(), Some(libc::SIGKILL));
+        assert!(report.durations[0] < Duration::from_secs(5));
+        // pipes are closed, but the process still runs
+        let report = Command::new("/bin/sh")
+            .arg("-c").arg("exec >&- 2>&-; sleep 10")
+            .run(RunPolicy::new().max_duration(Duration::from_millis(100)))
+            .unwrap();
+        assert!(report.timed_out);
+    }
+}
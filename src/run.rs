@@ -1,39 +1,63 @@
 use std::collections::HashMap;
 use std::env::current_dir;
-use std::ffi::CString;
-use std::fs::File;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::iter::repeat;
 use std::os::unix::ffi::{OsStrExt};
-use std::os::unix::io::{RawFd, AsRawFd};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, OwnedFd};
 use std::path::{Path, PathBuf};
 use std::ptr;
+use std::sync::{Arc, Mutex};
 
 use libc::{c_char, close};
-use nix;
-use nix::errno::Errno::EINTR;
-use nix::fcntl::{fcntl, FcntlArg, open};
-use nix::fcntl::OFlag;
-use nix::sched::{clone, CloneFlags};
-use nix::sys::signal::{SIGKILL, SIGCHLD, kill};
-use nix::sys::stat::Mode;
-use nix::sys::wait::waitpid;
-use nix::unistd::{setpgid, Pid};
+use libc::{c_int, pid_t};
+use libc::{O_CLOEXEC, O_RDONLY, O_WRONLY, WNOHANG, SIGCHLD, EINTR};
 
 use crate::child;
-use crate::config::Config;
-use crate::{Command, Child, ExitStatus};
-use crate::error::{Error, result, cmd_result};
+use crate::spawner;
+use crate::config::{Config, DeathSigScope, ResolvePaths};
+use crate::config::WaitBackend;
+use crate::{Command, Child, ExitStatus, Signal, FdKind};
+use crate::error::{Error, IntoError, result, decode_error};
+use crate::error::{ERROR_FRAME_LEN, EXEC_FRAME};
 use crate::error::ErrorCode as Err;
 use crate::pipe::{Pipe, PipeReader, PipeWriter, PipeHolder};
-use crate::stdio::{Fd, Closing};
-use crate::chroot::{Pivot, Chroot};
+use crate::stdio::{Fd, Closing, StdioReserve};
+use crate::sys;
+use crate::zombies;
+use crate::process_tree::start_time_in;
+use crate::chroot::{Pivot, Chroot, Beneath};
+use crate::copy::CopyFile;
+use crate::env_template::expand_templates;
+use crate::network::{self, NetworkHelper};
+use crate::mount_provider::{Teardown, send_fd};
+use crate::idmapped::set_idmap;
+use crate::exec_in::PidNsScope;
+use crate::daemon::{self, DAEMON_PID_FRAME};
+use crate::fd_plan;
+use crate::timings::Step;
+use crate::finalize::{FinalExec, SharedExec};
+use crate::logger;
+use crate::labels;
+use crate::parent_exit::is_pipe_or_socket;
+use crate::shared_mem::SharedMemory;
+use crate::kill_fd;
+use crate::cgroup;
+use crate::cleanup::SpawnCleanup;
+use crate::report;
+#[cfg(feature="systemd")] use crate::systemd;
+use crate::hardening::{HardeningReport, HardeningStep, SkippedSteps};
+use crate::limits::Limits;
+use crate::id_map_writer;
+use crate::trace;
+use crate::seccomp::{self, SeccompSupervisor};
+use crate::chroot::in_root;
 use crate::ffi_util::ToCString;
 use crate::namespace::to_clone_flag;
+use crate::no_alloc::MAX_PID_LEN;
 
 
-pub const MAX_PID_LEN: usize = 12;
-
 pub struct ChildInfo<'a> {
     pub filename: *const c_char,
     pub args: &'a [*const c_char],
@@ -42,15 +66,36 @@ pub struct ChildInfo<'a> {
     pub cfg: &'a Config,
     pub chroot: &'a Option<Chroot>,
     pub pivot: &'a Option<Pivot>,
+    pub work_dir_beneath: &'a Option<Beneath>,
+    pub copy_files: &'a [CopyFile],
+    pub make_private: bool,
+    /// Socket receiving mounts from `MountProvider`s, or `-1`
+    pub mount_socket: RawFd,
+    pub mount_targets: &'a [CString],
     pub wakeup_pipe: RawFd,
+    pub abort_orphaned: bool,
+    pub allow_ptrace: bool,
     pub error_pipe: RawFd,
     pub fds: &'a [(RawFd, RawFd)],
     /// This map may only be used for lookup but not for iteration!
     pub fd_lookup: &'a HashMap<RawFd, RawFd>,
     pub close_fds: &'a [(RawFd, RawFd)],
-    pub setns_namespaces: &'a [(CloneFlags, RawFd)],
+    /// Descriptor of the program set by `exec_fd`
+    pub exec_fd: Option<RawFd>,
+    pub seccomp_filter: Option<&'a [libc::sock_filter]>,
+    /// Socket passing the seccomp listener to the parent, or `-1`
+    pub seccomp_socket: RawFd,
+    pub setns_namespaces: &'a [(c_int, RawFd)],
+    pub deferred_namespaces: c_int,
+    /// Contents of `timens_offsets` for `virtual_clock`
+    pub time_offsets: Option<&'a CStr>,
     pub pid_env_vars: &'a [(usize, usize)],
+    pub final_exec: Option<&'a SharedExec>,
     pub keep_caps: &'a Option<[u32; 2]>,
+    /// Limits applied by the child (when not the first credential step)
+    pub limits: Option<&'a Limits>,
+    /// Best effort steps skipped by the child are marked here
+    pub skipped: &'a SkippedSteps,
     pub pre_exec: &'a Option<Box<dyn Fn() -> Result<(), io::Error>>>,
 }
 
@@ -90,15 +135,17 @@ fn relative_to<A:AsRef<Path>, B:AsRef<Path>>(dir: A, rel: B, absolute: bool)
     }
 }
 
-fn prepare_descriptors(fds: &HashMap<RawFd, Fd>)
+fn prepare_descriptors(fds: &HashMap<RawFd, Fd>, null_pipes: bool,
+    reserve: &StdioReserve)
     -> Result<(HashMap<RawFd, RawFd>, HashMap<RawFd, PipeHolder>,
                Vec<Closing>), Error>
 {
     let mut inner = HashMap::new();
     let mut outer = HashMap::new();
     let mut guards = Vec::new();
+    let dev_null = CStr::from_bytes_with_nul(b"/dev/null\0").unwrap();
     for (&dest_fd, fdkind) in fds.iter() {
-        let mut fd = match fdkind {
+        let fd = match fdkind {
             &Fd::ReadPipe => {
                 let (rd, wr) = Pipe::new()?.split();
                 let fd = rd.into_fd();
@@ -113,26 +160,47 @@ fn prepare_descriptors(fds: &HashMap<RawFd, Fd>)
                 outer.insert(dest_fd, PipeHolder::Reader(rd));
                 fd
             }
+            &Fd::SeqPacket => {
+                let (ours, theirs) = result(Err::CreatePipe,
+                    sys::socketpair(libc::AF_UNIX,
+                        libc::SOCK_SEQPACKET|libc::SOCK_CLOEXEC))?;
+                guards.push(Closing::new(theirs));
+                let ours = unsafe { OwnedFd::from_raw_fd(ours) };
+                outer.insert(dest_fd, PipeHolder::Socket(ours));
+                theirs
+            }
             &Fd::ReadNull => {
                 // Need to keep fd with cloexec, until we are in child
                 let fd = result(Err::CreatePipe,
-                    open(Path::new("/dev/null"),
-                         OFlag::O_CLOEXEC|OFlag::O_RDONLY,
-                         Mode::empty()))?;
+                    sys::open(dev_null, O_CLOEXEC|O_RDONLY))?;
                 guards.push(Closing::new(fd));
                 fd
             }
             &Fd::WriteNull => {
                 // Need to keep fd with cloexec, until we are in child
                 let fd = result(Err::CreatePipe,
-                    open(Path::new("/dev/null"),
-                         OFlag::O_CLOEXEC|OFlag::O_WRONLY,
-                         Mode::empty()))?;
+                    sys::open(dev_null, O_CLOEXEC|O_WRONLY))?;
                 guards.push(Closing::new(fd));
                 fd
             }
-            &Fd::Inherit => {
-                dest_fd
+            &Fd::Inherit if reserve.is_closed(dest_fd) => {
+                // the placeholder is closed on exec
+                continue;
+            }
+            &Fd::Inherit | &Fd::InheritOrNull => {
+                let closed = reserve.is_closed(dest_fd);
+                // the pipe breaks when its reader exits
+                let breaks = null_pipes && dest_fd <= 2 &&
+                    is_pipe_or_socket(dest_fd);
+                if closed || breaks {
+                    let mode = if dest_fd == 0 { O_RDONLY } else { O_WRONLY };
+                    let fd = result(Err::CreatePipe,
+                        sys::open(dev_null, O_CLOEXEC|mode))?;
+                    guards.push(Closing::new(fd));
+                    fd
+                } else {
+                    dest_fd
+                }
             }
             &Fd::Fd(ref x) => {
                 x.as_raw_fd()
@@ -140,23 +208,43 @@ fn prepare_descriptors(fds: &HashMap<RawFd, Fd>)
         };
         // The descriptor must not clobber the descriptors that are passed to
         // a child
-        while fd != dest_fd && fds.contains_key(&fd) {
-            fd = result(Err::CreatePipe,
-                fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(3)))?;
-            guards.push(Closing::new(fd));
-        }
+        let fd = result(Err::CreatePipe,
+            fd_plan::out_of_the_way(fd, dest_fd, fds, |fd, min| {
+                let fd = sys::dup_cloexec(fd, min)?;
+                guards.push(Closing::new(fd));
+                Ok(fd)
+            }))?;
         inner.insert(dest_fd, fd);
     }
     Ok((inner, outer, guards))
 }
 
+/// Moves the descriptor used internally by the child above the `floor`
+/// and out of the way of the descriptors configured for the child
+///
+/// The `floor` is ignored if it's above the limit of open files.
+fn move_internal(fd: RawFd, floor: RawFd, fds: &HashMap<RawFd, Fd>)
+    -> Result<Closing, Error>
+{
+    let mut fd = Closing::new(fd);
+    result(Err::CreatePipe,
+        fd_plan::move_above(fd.as_raw_fd(), floor, fds, |old, min| {
+            let new = sys::dup_cloexec(old, min)?;
+            // the old descriptor is closed here
+            fd = Closing::new(new);
+            Ok(new)
+        }))?;
+    Ok(fd)
+}
+
 impl Command {
     /// Run the command and return exit status
+    ///
+    /// Piped stdout and stderr are read and discarded while waiting, so
+    /// the child doesn't block on a full pipe (use `output()` to keep the
+    /// data). Piped stdin is closed.
     pub fn status(&mut self) -> Result<ExitStatus, Error> {
-        // TODO(tailhook) stdin/stdout/stderr
-        self.spawn()?
-        .wait()
-        .map_err(|e| Error::WaitError(e.raw_os_error().unwrap_or(-1)))
+        report::finish(self.spawn()?, false).map(|(status, _)| status)
     }
     /// Spawn the command and return a handle that can be waited for
     pub fn spawn(&mut self) -> Result<Child, Error> {
@@ -165,19 +253,105 @@ impl Command {
         // be more clear and also allow to print Display command easily in
         // error handler
         self.init_env_map();
-        unsafe { self.spawn_inner() }
+        let admission = match self.limiter {
+            Some(ref limiter) => Some(limiter.admit()?),
+            None => None,
+        };
+        let start = self.span_start();
+        let stdio = result(Err::CreatePipe, StdioReserve::new());
+        let mut result = stdio.and_then(|stdio| {
+            let logger = self.spawn_logger()?;
+            let result = self.map_shared_memory().and_then(|shm| {
+                let result = unsafe {
+                    self.spawn_inner(shm.as_ref(), &stdio)
+                };
+                self.release_shared_memory(shm.as_ref());
+                result.map(|mut child| {
+                    child.shared_memory = shm;
+                    child
+                })
+            });
+            self.release_logger_pipe();
+            logger::attach(result, logger)
+        });
+        if let Some(admission) = admission {
+            admission.finish(result.as_ref().ok());
+        }
+        Command::span_spawned(start, &mut result);
+        self.audit_spawn(&result);
+        result
     }
 
-    unsafe fn spawn_inner(&mut self) -> Result<Child, Error> {
+    unsafe fn spawn_inner(&mut self, shm: Option<&SharedMemory>,
+        stdio: &StdioReserve)
+        -> Result<Child, Error>
+    {
+        self.start_timer();
+        if self.config.init_mode && libc::getpid() != 1 {
+            result(Err::SetSubreaper, sys::set_child_subreaper())?;
+        }
         // TODO(tailhook) add RAII for pipes
-        let (wakeup_rd, wakeup) = Pipe::new()?.split();
-        let (errpipe, errpipe_wr) = Pipe::new()?.split();
+        let new_pipe = if self.config.packet_pipes {
+            Pipe::new_packet
+        } else {
+            Pipe::new
+        };
+        // until the child ends of the pipes are closed below, so children
+        // spawned by other threads don't inherit them (which matters when
+        // the spawn blocks while the child is frozen, e.g. in `WarmPool`)
+        let clone_lock = CLONE_LOCK.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let (wakeup_rd, wakeup) = new_pipe()?.split();
+        let (errpipe, errpipe_wr) = new_pipe()?.split();
 
-        let c_args = raw_with_null(&self.args);
+        self.check_privileged_ports()?;
+        self.check_shell()?;
+        self.check_user_namespace()?;
+        self.check_proc_path()?;
+        self.check_daemonize()?;
+        self.check_namespace_timeout()?;
+        let time_offsets = self.time_offsets()?;
+        // undoes the changes of the new root if the spawn fails, dropped
+        // after the child is killed
+        let mut cleanup = SpawnCleanup::new();
+        let mut foreign = self.foreign_exec(&mut cleanup)?;
+        let c_args = raw_with_null(
+            foreign.as_ref().map_or(&self.args, |f| &f.args));
 
-        let mut environ: Vec<_> = self.environ.as_ref().unwrap()
-            .iter().map(|(k, v)| {
-                let mut pair = k[..].as_bytes().to_vec();
+        let environ = self.environ_with_files()?;
+        let templates = expand_templates(&environ, &self.env_templates)?;
+        let mut vars: Vec<_> = environ
+            .iter()
+            .filter(|(k, _)| !self.env_templates.contains_key(*k))
+            .chain(templates.iter().map(|(k, v)| (k, v)))
+            .map(|(k, v)| (&k[..], &v[..]))
+            .collect();
+        self.apply_env_policy(&mut vars);
+        let mut injected = self.injected_env();
+        injected.extend(shm.map_or_else(Vec::new, SharedMemory::env));
+        vars.retain(|&(k, _)| injected.iter().all(|(x, _)| x != k));
+        vars.extend(injected.iter().map(|(k, v)| (&k[..], &v[..])));
+        let preload_var = OsStr::new("LD_PRELOAD");
+        let preload = self.preload_env(vars.iter()
+            .find(|&&(k, _)| k == preload_var).map(|&(_, v)| v))?;
+        if let Some(ref value) = preload {
+            vars.retain(|&(k, _)| k != preload_var);
+            vars.push((preload_var, value));
+        }
+        let mut final_exec = match self.finalize {
+            Some(_) => Some(result(Err::CreatePipe, FinalExec::new(
+                foreign.as_ref().map_or(&self.args, |f| &f.args).iter()
+                    .map(|x| OsStr::from_bytes(x.as_bytes()).to_os_string())
+                    .collect(),
+                vars.iter()
+                    .map(|&(k, v)| (k.to_os_string(), v.to_os_string()))
+                    .collect(),
+                self.pid_env_vars.iter().cloned().collect()))?),
+            None => None,
+        };
+        let mut environ: Vec<_> = vars.into_iter()
+            .map(|(k, v)| {
+                let mut pair = k.as_bytes().to_vec();
                 pair.push(b'=');
                 pair.extend(v.as_bytes());
                 pair.push(0);
@@ -194,172 +368,609 @@ impl Command {
         }
         let c_environ: Vec<_> = raw_with_null_mut(&mut environ);
 
-        let (int_fds, ext_fds, _guards) = prepare_descriptors(&self.fds)?;
+        let (int_fds, ext_fds, guards) = prepare_descriptors(&self.fds,
+            self.config.null_inherited_pipes, stdio)?;
+        let floor = self.config.internal_fd_floor;
+        let wakeup_rd = move_internal(wakeup_rd.into_fd(), floor, &self.fds)?;
+        let errpipe_wr = move_internal(errpipe_wr.into_fd(), floor,
+                                       &self.fds)?;
+        // The descriptor must not be clobbered by the ones passed to
+        // a child, or it will be closed too early
+        let exec_notify = match self.exec_notify.take() {
+            Some(fd) => {
+                Some(move_internal(fd.into_fd(), floor, &self.fds)?)
+            }
+            None => None,
+        };
+        let exec_notify_fd = exec_notify.as_ref().map(|x| x.as_raw_fd());
+        let exec_fd = match self.exec_fd_raw() {
+            Some(fd) => {
+                let copy = result(Err::CreatePipe, sys::dup_cloexec(fd, 3))?;
+                Some(move_internal(copy, floor, &self.fds)?)
+            }
+            None => None,
+        };
+        let exec_fd_raw = exec_fd.as_ref().map(|x| x.as_raw_fd());
 
-        let pivot = self.pivot_root.as_ref().map(|&(ref new, ref old, unmnt)| {
-            Pivot {
-                new_root: new.to_cstring(),
-                put_old: old.to_cstring(),
-                old_inside: relative_to(old, new, true).unwrap().to_cstring(),
-                workdir: current_dir().ok()
+        let nofollow = self.config.resolve_paths == ResolvePaths::NoFollow;
+        let beneath = |path: &Path| {
+            if nofollow { Beneath::new(path).map(Some) } else { Ok(None) }
+        };
+
+        let pivot = match self.pivot_root {
+            Some((ref new, ref old, unmnt)) => {
+                let old_inside = relative_to(old, new, true).unwrap();
+                let workdir = current_dir().ok()
                     .and_then(|cur| relative_to(cur, new, true))
-                    .unwrap_or(PathBuf::from("/"))
-                    .to_cstring(),
-                unmount_old_root: unmnt,
+                    .unwrap_or(PathBuf::from("/"));
+                Some(Pivot {
+                    new_root: new.to_cstring(),
+                    put_old: old.to_cstring(),
+                    old_inside: old_inside.to_cstring(),
+                    workdir: workdir.to_cstring(),
+                    unmount_old_root: unmnt,
+                    put_old_beneath: beneath(&old_inside)?,
+                    workdir_beneath: beneath(&workdir)?,
+                })
             }
-        });
+            None => None,
+        };
 
-        let chroot = self.chroot_dir.as_ref().map(|dir| {
-            let wrk_rel = if let Some((ref piv, _, _)) = self.pivot_root {
-                piv.join(relative_to(dir, "/", false).unwrap())
-            } else {
-                dir.to_path_buf()
-            };
-            Chroot {
-                root: dir.to_cstring(),
-                workdir: current_dir().ok()
+        let chroot = match self.chroot_dir {
+            Some(ref dir) => {
+                let wrk_rel = if let Some((ref piv, _, _)) = self.pivot_root {
+                    piv.join(relative_to(dir, "/", false).unwrap())
+                } else {
+                    dir.to_path_buf()
+                };
+                let workdir = current_dir().ok()
                     .and_then(|cur| relative_to(cur, wrk_rel, true))
-                    .unwrap_or(PathBuf::from("/"))
-                    .to_cstring()
-,
+                    .unwrap_or(PathBuf::from("/"));
+                Some(Chroot {
+                    root: dir.to_cstring(),
+                    workdir: workdir.to_cstring(),
+                    // chroot dir is inside the new root only after pivot
+                    root_beneath: if pivot.is_some() {
+                        beneath(dir)?
+                    } else {
+                        None
+                    },
+                    workdir_beneath: beneath(&workdir)?,
+                })
             }
-        });
+            None => None,
+        };
+        let copy_files = self.copy_files.iter()
+            .map(|&(ref host, ref dest, mode, ref xattrs)| {
+                CopyFile::read(host, dest, mode, xattrs, nofollow)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // working directory is resolved inside the new root if there is one
+        let work_dir_beneath = match self.config.work_dir {
+            Some(ref dir) if pivot.is_some() || chroot.is_some() => {
+                beneath(Path::new(OsStr::from_bytes(dir.as_bytes())))?
+            }
+            _ => None,
+        };
+
+        let abort_orphaned = self.abort_orphaned();
+        let make_private = self.make_private();
+
+        let root = self.host_root();
+        let interpreter = self.foreign_interpreter.as_ref()
+            .filter(|_| foreign.as_ref().is_some_and(|f| f.mount.is_some()))
+            .map(|path| (path.clone(), in_root(&root, path)));
+        let mounts = self.mount_ops(interpreter);
+        let mut mount_targets = self.mount_providers.iter()
+            .map(|(target, _)| in_root(&root, target).to_cstring())
+            .collect::<Vec<_>>();
+        // attached after the providers, which may supply the root
+        let mut extra_mounts = Vec::new();
+        extra_mounts.extend(foreign.as_mut().and_then(|f| f.mount.take()));
+        extra_mounts.extend(self.host_file_mounts(&mut cleanup)?);
+        extra_mounts.extend(self.scratch_mount(&mut cleanup)?);
+        extra_mounts.extend(self.core_dump_mount(&mut cleanup)?);
+        // the last ones, made idmapped in `setup_frozen`
+        extra_mounts.extend(self.idmapped_mount_points(&mut cleanup)?);
+        mount_targets.extend(extra_mounts.iter().map(|(t, _)| t.clone()));
+        let (mount_sock, mount_sock_child) = if mount_targets.is_empty() {
+            (None, None)
+        } else {
+            let (a, b) = result(Err::CreatePipe, sys::socketpair(
+                libc::AF_UNIX, libc::SOCK_SEQPACKET|libc::SOCK_CLOEXEC))?;
+            (Some(Closing::new(a)), Some(move_internal(b, floor, &self.fds)?))
+        };
+        let mount_socket = mount_sock_child.as_ref()
+            .map_or(-1, |x| x.as_raw_fd());
+        let seccomp_filter = self.seccomp_filter()?;
+        let (seccomp_sock, seccomp_sock_child) = if seccomp_filter.is_some() {
+            let (a, b) = result(Err::CreatePipe, sys::socketpair(
+                libc::AF_UNIX, libc::SOCK_SEQPACKET|libc::SOCK_CLOEXEC))?;
+            (Some(Closing::new(a)), Some(move_internal(b, floor, &self.fds)?))
+        } else {
+            (None, None)
+        };
+        let seccomp_socket = seccomp_sock_child.as_ref()
+            .map_or(-1, |x| x.as_raw_fd());
+
+        let resolve_beneath = work_dir_beneath.is_some() ||
+            pivot.as_ref().is_some_and(|p| {
+                p.put_old_beneath.is_some() || p.workdir_beneath.is_some()
+            }) ||
+            chroot.as_ref().is_some_and(|c| {
+                c.root_beneath.is_some() || c.workdir_beneath.is_some()
+            }) ||
+            copy_files.iter().any(|f| f.dir_beneath.is_some());
+        let skipped = result(Err::CreatePipe, SkippedSteps::new())?;
+        if self.preload_ignored()? {
+            skipped.mark(HardeningStep::Preload);
+        }
 
         let mut nstack = [0u8; 4096];
         let mut wakeup = Some(wakeup);
-        let mut wakeup_rd = Some(wakeup_rd);
-        let mut errpipe_wr = Some(errpipe_wr);
         let args_slice = &c_args[..];
+        let final_shared = final_exec.as_ref().map(|x| &x.shared);
         let environ_slice = &c_environ[..];
         // We transform all hashmaps into vectors, because iterating over
         // hash map involves closure which crashes in the child in unoptimized
         // build
         let fds = int_fds.iter().map(|(&x, &y)| (x, y)).collect::<Vec<_>>();
-        let close_fds = self.close_fds.iter().cloned().collect::<Vec<_>>();
-        let setns_ns = self.config.setns_namespaces.iter()
+        let close_fds = self.close_set(&int_fds, errpipe_wr.as_raw_fd(),
+            &[exec_notify_fd, exec_fd_raw, Some(seccomp_socket)])?;
+        let mut setns_ns = self.config.setns_namespaces.iter()
             .map(|(ns, fd)| (to_clone_flag(*ns), fd.as_raw_fd()))
             .collect::<Vec<_>>();
-        let pid = result(Err::Fork, clone(Box::new(|| -> isize {
+        // joining user namespace first gives capabilities to join others
+        setns_ns.sort_by_key(|&(ns, _)| ns != libc::CLONE_NEWUSER);
+        let child_fn = Box::new(|| -> isize {
             // Note: mo memory allocations/deallocations here
             close(wakeup.take().unwrap().into_fd());
             let child_info = ChildInfo {
-                filename: self.filename.as_ptr(),
+                filename: foreign.as_ref()
+                    .map_or(self.filename.as_ptr(), |f| f.filename.as_ptr()),
                 args: args_slice,
                 environ: environ_slice,
                 cfg: &self.config,
                 chroot: &chroot,
                 pivot: &pivot,
-                wakeup_pipe: wakeup_rd.take().unwrap().into_fd(),
-                error_pipe: errpipe_wr.take().unwrap().into_fd(),
+                work_dir_beneath: &work_dir_beneath,
+                copy_files: &copy_files,
+                make_private,
+                mount_socket,
+                mount_targets: &mount_targets,
+                wakeup_pipe: wakeup_rd.as_raw_fd(),
+                abort_orphaned,
+                allow_ptrace: self.debug_syscalls.is_some(),
+                error_pipe: errpipe_wr.as_raw_fd(),
                 fds: &fds,
                 fd_lookup: &int_fds,
                 close_fds: &close_fds,
+                exec_fd: exec_fd_raw,
+                seccomp_filter: seccomp_filter.as_deref(),
+                seccomp_socket,
                 setns_namespaces: &setns_ns,
+                deferred_namespaces: self.deferred_namespaces(),
+                time_offsets: time_offsets.as_deref(),
                 pid_env_vars: &pid_env_vars,
+                final_exec: final_shared,
                 keep_caps: &self.keep_caps,
+                limits: self.inherited_limits.as_ref()
+                    .filter(|_| !self.parent_applies_limits()),
+                skipped: &skipped,
                 pre_exec: &self.pre_exec,
             };
             child::child_after_clone(&child_info);
-        }), &mut nstack[..], self.config.namespaces, Some(SIGCHLD as i32)))?;
+        });
+        let namespaces = self.config.namespaces & !self.deferred_namespaces();
+        let pid_ns = self.config.clone_pid_ns.as_ref().map(|x| x.as_raw_fd());
+        let do_clone = move || {
+            let _pid_ns = match pid_ns {
+                Some(fd) => Some(result(Err::SetNs, PidNsScope::enter(fd))?),
+                None => None,
+            };
+            result(Err::Fork,
+                sys::clone(child_fn, &mut nstack[..], namespaces | SIGCHLD))
+        };
+        let pid = match self.config.death_sig_scope {
+            DeathSigScope::Thread => do_clone(),
+            DeathSigScope::Process => spawner::in_spawner_thread(do_clone),
+        }.map_err(|e| self.clone_error(e))?;
+        // declared after `wakeup`, so on unwinding the child is killed
+        // before it sees the pipe closed and proceeds on its own
+        let mut guard = KillOnDrop(Some(pid));
+        self.mark(Step::Cloned);
+        // the child is frozen, so the pid can't be reused yet, the pidfd
+        // is also used to notice death of the child before exec
+        let pidfd = match sys::pidfd_open(pid) {
+            Ok(fd) => Some(File::from_raw_fd(fd)),
+            Err(_) if !self.config.pidfd => None,
+            Err(e) => return Err(e.into_error(Err::Fork)),
+        };
         drop(wakeup_rd);
         drop(errpipe_wr); // close pipe so we don't wait for ourself
+        drop(exec_notify); // only the child holds it now
+        drop(exec_fd);
+        drop(mount_sock_child);
+        drop(seccomp_sock_child);
+        drop(guards);
+        drop(clone_lock);
 
-        if let Err(e) = self.after_start(pid, wakeup.unwrap(), errpipe) {
-            kill(pid, SIGKILL).ok();
-            loop {
-                match waitpid(pid, None) {
-                    Err(nix::Error::Sys(EINTR)) => continue,
-                    _ => break,
+        let frozen = Frozen {
+            pid,
+            pidfd: pidfd.as_ref(),
+            wakeup: wakeup.as_mut().unwrap(),
+            errpipe,
+            mount_sock,
+            seccomp_sock,
+            cleanup: &mut cleanup,
+        };
+        let (network_helper, mut teardown, seccomp, daemon) = match
+            self.after_start(frozen, &extra_mounts, final_exec.as_mut())
+        {
+            Ok(x) => x,
+            Err(e) => {
+                if let Error::ChildDiedDuringSetup(..) = e {
+                    // already reaped, so pid may belong to some other process
+                    guard.0 = None;
                 }
+                return Err(self.exec_error(e));
             }
-            return Err(e);
+        };
+        let (pid, pidfd) = match daemon {
+            Some(daemon) => {
+                // the intermediate process exits right after the fork
+                result(Err::Daemonize, reap(pid))?;
+                (daemon, None)
+            }
+            None => (pid, pidfd.filter(|_| self.config.pidfd)),
+        };
+        guard.0 = None;
+        cleanup.commit();
+        if let Some(path) = foreign.and_then(|f| f.created) {
+            teardown.push(Box::new(move || { fs::remove_file(path).ok(); }));
+        }
+        if !self.config.daemonize &&
+            self.config.wait_backend == WaitBackend::Sigchld
+        {
+            zombies::track(pid);
         }
+        labels::register(pid, &self.labels);
 
+        // pipes in other direction (configured by `Command::fd`) are left
+        // for `take_pipe_reader`/`take_pipe_writer`
+        let mut fd_kinds = self.fds.iter()
+            .map(|(&fd, kind)| (fd, FdKind::of(kind)))
+            .collect::<Vec<_>>();
+        fd_kinds.sort_by_key(|&(fd, _)| fd);
         let mut outer_fds = ext_fds;
+        let stdin = match outer_fds.remove(&0) {
+            Some(PipeHolder::Writer(x)) => Some(x),
+            Some(other) => { outer_fds.insert(0, other); None }
+            None => None,
+        };
+        let mut take_reader = |fd| match outer_fds.remove(&fd) {
+            Some(PipeHolder::Reader(x)) => Some(x),
+            Some(other) => { outer_fds.insert(fd, other); None }
+            None => None,
+        };
+        let stdout = take_reader(1);
+        let stderr = take_reader(2);
+        // signals are checked against pid reuse if there is no pidfd
+        let start_time = match pidfd {
+            Some(_) => None,
+            None => start_time_in(self.proc_dir(), pid),
+        };
+        #[allow(deprecated)]
         Ok(Child {
-            pid: pid.into(),
+            pid,
+            pidfd,
             status: None,
-            stdin: outer_fds.remove(&0).map(|x| {
-                match x {
-                    PipeHolder::Writer(x) => x,
-                    _ => unreachable!(),
-                }}),
-            stdout: outer_fds.remove(&1).map(|x| {
-                match x {
-                    PipeHolder::Reader(x) => x,
-                    _ => unreachable!(),
-                }}),
-            stderr: outer_fds.remove(&2).map(|x| {
-                match x {
-                    PipeHolder::Reader(x) => x,
-                    _ => unreachable!(),
-                }}),
+            stdin,
+            stdout,
+            stderr,
             fds: outer_fds,
+            fd_kinds,
+            closed_fds: close_fds,
+            network_helper,
+            seccomp,
+            terminal: None,
+            hardening: HardeningReport::new(
+                self.requested_hardening(resolve_beneath), skipped.get()),
+            spawn_record: Some(Arc::new(self.spawn_record(&mounts))),
+            wait_backend: self.config.wait_backend,
+            shutdown_fd: self.shutdown_fd,
+            listener_fd: self.listener_fd,
+            proc_path: self.config.proc_path.clone(),
+            spawn_error: None,
+            start_time,
+            mounts,
+            scratch: self.scratch.clone(),
+            spawned_at: None,
+            setup_timings: self.setup_timings(),
+            logger: None,
+            shared_memory: None,
+            labels: self.labels.clone(),
+            timed_out: self.namespace_timeout_flag(),
+            teardown,
         })
     }
 
-    fn after_start(&mut self, pid: Pid,
-        mut wakeup: PipeWriter, mut errpipe: PipeReader)
-        -> Result<(), Error>
+    /// Computes descriptors closed by the child, checks for conflicts
+    fn close_set(&self, fds: &HashMap<RawFd, RawFd>, errpipe: RawFd,
+        internal: &[Option<RawFd>]) -> Result<Vec<(RawFd, RawFd)>, Error>
     {
-        if self.config.make_group_leader {
-            result(Err::SetPGid, setpgid(pid, pid))?;
+        let mut used = fds.keys().cloned().collect::<Vec<_>>();
+        used.sort();
+        used.push(errpipe);
+        used.extend(internal.iter().flatten().filter(|&&fd| fd >= 0));
+        if self.config.strict_close_fds {
+            if let Some(fd) = fd_plan::first_covered(&self.close_fds, &used) {
+                return Err(Error::FdPlanConflict(fd));
+            }
         }
+        used.extend(&self.keep_fds);
+        Ok(fd_plan::close_set(&self.close_fds, &used))
+    }
 
-        if let Some(&(ref uids, ref gids)) = self.config.id_maps.as_ref() {
-            if let Some(&(ref ucmd, ref gcmd)) = self.id_map_commands.as_ref()
-            {
-                let mut cmd = Command::new(ucmd);
-                cmd.arg(format!("{}", pid));
-                for map in uids {
-                    cmd.arg(format!("{}", map.inside_uid));
-                    cmd.arg(format!("{}", map.outside_uid));
-                    cmd.arg(format!("{}", map.count));
+    fn after_start(&mut self, child: Frozen,
+        extra_mounts: &[(CString, File)], final_exec: Option<&mut FinalExec>)
+        -> Result<Started, Error>
+    {
+        let Frozen { pid, pidfd, wakeup, mut errpipe, mount_sock,
+                     seccomp_sock, cleanup } = child;
+        // If child is killed while frozen (e.g. by OOM killer), the setup
+        // steps fail with obscure errors, or even succeed. So we check
+        // whether the child is still alive before unfreezing it. Death
+        // after unfreezing is noticed by `wait_exec`.
+        let (helper, teardown) = match
+            self.setup_frozen(pid, mount_sock, extra_mounts, final_exec)
+        {
+            Ok(extra) => extra,
+            Err(e) => return Err(reap_dead_child(pid, WNOHANG).unwrap_or(e)),
+        };
+        if let Some(e) = reap_dead_child(pid, WNOHANG) {
+            return Err(e);
+        }
+
+        if let Err(e) = wakeup.write_all(b"x") {
+            return Err(reap_dead_child(pid, WNOHANG)
+                .unwrap_or_else(|| e.into_error(Err::PipeError)));
+        }
+        self.mark(Step::Unfrozen);
+        let seccomp = match seccomp_sock {
+            Some(sock) => seccomp::receive_supervisor(sock)?,
+            None => None,
+        };
+        let daemon = self.wait_exec(pid, pidfd, &mut errpipe,
+                                    seccomp.as_ref(), cleanup)?;
+        Ok((helper, teardown, seccomp, daemon))
+    }
+
+    /// Waits until the child executes the program, returns the daemon pid
+    ///
+    /// Meanwhile errors and the pid of the daemon are read from the error
+    /// pipe, and system calls of the child under the seccomp supervisor
+    /// are continued. Death of the child before exec is noticed either as
+    /// the end of file without `EXEC_FRAME`, or through the pidfd if the
+    /// pipe is kept open by some other process. The child is reaped then.
+    fn wait_exec(&self, pid: pid_t, pidfd: Option<&File>,
+        errpipe: &mut PipeReader, mut seccomp: Option<&SeccompSupervisor>,
+        cleanup: &mut SpawnCleanup)
+        -> Result<Option<pid_t>, Error>
+    {
+        let mut daemon = None;
+        let mut exec_started = false;
+        let mut exited = false;
+        let mut frame = [0u8; ERROR_FRAME_LEN];
+        let mut len = 0;
+        loop {
+            // the intermediate process of the daemon exits after the fork,
+            // negative descriptors are ignored by poll
+            let pidfd = match pidfd {
+                Some(fd) if daemon.is_none() && !exited => fd.as_raw_fd(),
+                _ => -1,
+            };
+            let mut fds = [
+                libc::pollfd {
+                    fd: errpipe.as_raw_fd(), events: libc::POLLIN, revents: 0,
+                },
+                libc::pollfd { fd: pidfd, events: libc::POLLIN, revents: 0 },
+                libc::pollfd {
+                    fd: seccomp.map_or(-1, |s| s.as_raw_fd()),
+                    events: libc::POLLIN, revents: 0,
+                },
+            ];
+            let timeout = if exited { 0 } else { -1 };
+            let rc = unsafe { libc::poll(fds.as_mut_ptr(), 3, timeout) };
+            if rc < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
                 }
-                cmd_result(Err::SetIdMap, cmd.status())?;
-                let mut cmd = Command::new(gcmd);
-                cmd.arg(format!("{}", pid));
-                for map in gids {
-                    cmd.arg(format!("{}", map.inside_gid));
-                    cmd.arg(format!("{}", map.outside_gid));
-                    cmd.arg(format!("{}", map.count));
+                return result(Err::PipeError, Err(err));
+            }
+            if rc == 0 || fds[0].revents != 0 {
+                let n = match rc {
+                    // the child exited, the pipe is kept open by others
+                    0 => 0,
+                    _ => result(Err::PipeError,
+                                errpipe.read(&mut frame[len..]))?,
+                };
+                match n {
+                    0 if len > 0 => return Err(Error::UnknownError),
+                    // the daemon isn't our child to reap
+                    0 if exec_started || daemon.is_some() => return Ok(daemon),
+                    0 => return Err(reap_dead_child(pid, 0)
+                                    .unwrap_or(Error::UnknownError)),
+                    n => len += n,
                 }
-                cmd_result(Err::SetIdMap, cmd.status())?;
-            } else {
-                let mut buf = Vec::new();
-                for map in uids {
-                    writeln!(&mut buf, "{} {} {}",
-                        map.inside_uid, map.outside_uid, map.count).unwrap();
+                if len < frame.len() {
+                    continue;
                 }
-                result(Err::SetIdMap,
-                    File::create(format!("/proc/{}/uid_map", pid))
-                    .and_then(|mut f| f.write_all(&buf[..])))?;
-                let mut buf = Vec::new();
-                for map in gids {
-                    writeln!(&mut buf, "{} {} {}",
-                        map.inside_gid, map.outside_gid, map.count).unwrap();
+                len = 0;
+                match frame[0] {
+                    EXEC_FRAME => exec_started = true,
+                    DAEMON_PID_FRAME
+                        if self.config.daemonize && daemon.is_none() =>
+                    {
+                        let pid = daemon::decode_pid(&frame);
+                        cleanup.kill_on_failure(pid);
+                        daemon = Some(pid);
+                    }
+                    _ => return Err(decode_error(&frame)),
                 }
-                result(Err::SetIdMap,
-                    File::create(format!("/proc/{}/gid_map", pid))
-                    .and_then(|mut f| f.write_all(&buf[..])))?;
+            } else if fds[1].revents != 0 {
+                // recheck the pipe, the error may be written right before
+                // the exit, but after the pipe was polled
+                exited = true;
+            } else if fds[2].revents & libc::POLLIN != 0 {
+                seccomp.unwrap().continue_setup()?;
+            } else if fds[2].revents != 0 {
+                // no processes left using the filter
+                seccomp = None;
             }
         }
-        if let Some(ref mut callback) = self.before_unfreeze {
-            callback(i32::from(pid) as u32).map_err(Error::BeforeUnfreeze)?;
+    }
+
+    fn setup_frozen(&mut self, pid: pid_t, mount_sock: Option<Closing>,
+        extra_mounts: &[(CString, File)], final_exec: Option<&mut FinalExec>)
+        -> Result<(Option<NetworkHelper>, Teardown), Error>
+    {
+        if self.config.make_group_leader {
+            result(Err::SetPGid, sys::setpgid(pid, pid))?;
+        }
+        if let Some(ref limits) = self.inherited_limits {
+            if self.parent_applies_limits() {
+                result(Err::SetLimits, limits.apply(pid))?;
+            }
+        }
+        if let Some(ref path) = self.config.cgroup {
+            result(Err::Cgroup, cgroup::move_to_cgroup(pid, path))?;
+        }
+        #[cfg(feature="systemd")]
+        if let Some(ref scope) = self.systemd_scope {
+            systemd::start_scope(pid, scope)
+                .map_err(|e| Error::SystemdScope(e.into()))?;
         }
 
-        result(Err::PipeError, wakeup.write_all(b"x"))?;
-        let mut err = [0u8; 6];
-        match result(Err::PipeError, errpipe.read(&mut err))? {
-            0 => {}  // Process successfully execve'd or dead
-            5 => {
-                let code = err[0];
-                let errno = ((err[1] as i32) << 24) | ((err[2] as i32) << 16) |
-                    ((err[3] as i32) << 8) | (err[4] as i32);
-                return Err(Err::from_i32(code as i32, errno))
+        if let Some(&(ref uids, ref gids)) = self.config.id_maps.as_ref() {
+            if let Some(ref mut ops) = self.privileged_ops {
+                ops.write_id_maps(pid as u32, uids, gids)
+                    .map_err(Error::PrivilegedOps)?;
+            } else if let Some(ref mut writer) = self.id_map_writer {
+                writer.write_id_maps(pid as u32, uids, gids)?;
+            } else {
+                id_map_writer::write_maps(self.proc_dir(), pid as u32,
+                                          uids, gids)?;
+            }
+            self.mark(Step::MapsWritten);
+        }
+        if let Some(ref mut callback) = self.after_idmap {
+            callback(pid as u32).map_err(Error::AfterIdmap)?;
+        }
+        if let Some(ref mut ops) = self.privileged_ops {
+            ops.setup(pid as u32).map_err(Error::PrivilegedOps)?;
+        }
+        let network_helper = match self.userspace_network {
+            Some((backend, ref options)) => {
+                network::start_helper(backend, options, pid)?
             }
-            _ => { return Err(Error::UnknownError); }
+            None => None,
+        };
+        let mut teardown = Teardown::default();
+        for (_, provider) in &mut self.mount_providers {
+            let mount = provider.provide(pid as u32)
+                .map_err(Error::MountProvider)?;
+            if let Some(callback) = mount.teardown {
+                teardown.push(callback);
+            }
+            let sock = mount_sock.as_ref().unwrap().as_raw_fd();
+            let fd = mount.mount.as_ref().map(|x| x.as_raw_fd());
+            result(Err::AttachMount, send_fd(sock, fd))?;
+        }
+        let idmapped = extra_mounts.len() - self.idmapped_mounts.len();
+        for (_, mount) in &extra_mounts[idmapped..] {
+            result(Err::IdmappedMount,
+                   set_idmap(mount, self.proc_dir(), pid))?;
+        }
+        for (_, mount) in extra_mounts {
+            let sock = mount_sock.as_ref().unwrap().as_raw_fd();
+            result(Err::AttachMount, send_fd(sock, Some(mount.as_raw_fd())))?;
         }
-        Ok(())
+        result(Err::KillFd, kill_fd::start_watcher(pid,
+            &self.kill_fds, self.namespace_timeout.as_ref()))?;
+        if let Some(ref path) = self.debug_syscalls {
+            result(Err::DebugSyscalls, trace::start_tracer(pid, path))?;
+        }
+        if let Some(ref mut callback) = self.before_unfreeze {
+            callback(pid as u32).map_err(Error::BeforeUnfreeze)?;
+        }
+        if let (Some(callback), Some(exec)) = (&mut self.finalize, final_exec)
+        {
+            exec.spec.pid = pid as u32;
+            callback(&mut exec.spec);
+            exec.write()?;
+        }
+        Ok((network_helper, teardown))
     }
 }
+
+/// Held while the current process has descriptors meant for the child only
+static CLONE_LOCK: Mutex<()> = Mutex::new(());
+
+/// The child waiting for the wakeup, and our ends of the channels to it
+struct Frozen<'a> {
+    pid: pid_t,
+    pidfd: Option<&'a File>,
+    wakeup: &'a mut PipeWriter,
+    errpipe: PipeReader,
+    mount_sock: Option<Closing>,
+    seccomp_sock: Option<Closing>,
+    cleanup: &'a mut SpawnCleanup,
+}
+
+/// Returned by `after_start`, the last item is the pid of the daemon
+type Started = (Option<NetworkHelper>, Teardown, Option<SeccompSupervisor>,
+                Option<pid_t>);
+
+/// Kills and reaps the child unless disarmed by setting `None`
+///
+/// Guards the child between clone and a successful exec, so neither an
+/// error nor a panic in callbacks (`before_unfreeze`, mount providers,
+/// privileged ops) leaves a frozen child behind.
+struct KillOnDrop(Option<pid_t>);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            sys::kill(pid, Signal::SIGKILL).ok();
+            reap(pid).ok();
+        }
+    }
+}
+
+/// Waits for the process to exit, retrying on `EINTR`
+fn reap(pid: pid_t) -> io::Result<()> {
+    loop {
+        match sys::waitpid(pid, 0) {
+            Err(ref e) if e.raw_os_error() == Some(EINTR) => continue,
+            res => return res.map(|_| ()),
+        }
+    }
+}
+
+/// Reaps the child if it's already dead and returns appropriate error
+///
+/// With zero `flags` waits for the child, which is known to be exiting.
+fn reap_dead_child(pid: pid_t, flags: c_int) -> Option<Error> {
+    use crate::sys::WaitStatus::*;
+    let status = loop {
+        match sys::waitpid(pid, flags) {
+            Ok(Exited(_, code)) => break ExitStatus::Exited(code as i8),
+            Ok(Signaled(_, sig, core)) => break ExitStatus::Signaled(sig, core),
+            Err(ref e) if e.raw_os_error() == Some(EINTR) => continue,
+            _ => return None,
+        }
+    };
+    Some(Error::ChildDiedDuringSetup(status))
+}
@@ -0,0 +1,140 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::path::Path;
+
+use crate::{Command, Child};
+use crate::cleanup::SpawnCleanup;
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::ffi_util::ToCString;
+use crate::host_files::mount_point;
+use crate::mount_provider::clone_tree;
+use crate::chroot::in_root;
+
+
+impl Command {
+    /// Bind-mount the host directory read-write at `container_path`
+    ///
+    /// This is the directory to exchange inputs and outputs with the
+    /// sandbox: the host sees what the child writes at `host_dir`. The
+    /// `container_path` is in the new root (`pivot_root` or `chroot_dir`),
+    /// if there is no directory there, it's created (with parents) and
+    /// left in place. Both paths are returned by `Child::scratch`.
+    ///
+    /// Like `inject_host_file`, the mount is made in the parent on
+    /// `spawn()`, which requires `CAP_SYS_ADMIN` and linux 5.12, and
+    /// attached by the child after the host files. Mount namespace must be
+    /// unshared. Errors are reported as `Error::ScratchDir`.
+    ///
+    /// Each invocation **replaces** previously set directory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `container_path` is not absolute
+    pub fn scratch_dir<A: AsRef<Path>, B: AsRef<Path>>(&mut self,
+        host_dir: A, container_path: B)
+        -> &mut Command
+    {
+        let container_path = container_path.as_ref();
+        assert!(container_path.is_absolute(),
+            "scratch directory path must be absolute");
+        self.scratch = Some((host_dir.as_ref().to_path_buf(),
+                             container_path.to_path_buf()));
+        self
+    }
+
+    /// Returns the mount point (host path) and the bind mount
+    pub(crate) fn scratch_mount(&self, cleanup: &mut SpawnCleanup)
+        -> Result<Option<(CString, File)>, Error>
+    {
+        let (host_dir, container_path) = match self.scratch {
+            Some((ref host, ref container)) => (host, container),
+            None => return Ok(None),
+        };
+        if self.config.namespaces & libc::CLONE_NEWNS == 0 {
+            // the mount would be visible by all processes
+            return Err(Error::ScratchDir(libc::EINVAL));
+        }
+        let target = in_root(&self.host_root(), container_path);
+        let mount = result(Err::ScratchDir, clone_tree(host_dir))?;
+        result(Err::ScratchDir, mount_point(&target, true, cleanup))?;
+        Ok(Some((target.to_cstring(), mount)))
+    }
+}
+
+impl Child {
+    /// Returns the host directory and its path in the sandbox
+    ///
+    /// See `Command::scratch_dir`. `None` if not configured or for
+    /// children created by `Child::from_pid`.
+    pub fn scratch(&self) -> Option<(&Path, &Path)> {
+        self.scratch.as_ref().map(|(host, container)| {
+            (host.as_path(), container.as_path())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{Command, Namespace, Error, CorePolicy};
+
+    #[test]
+    fn test_scratch_dir() {
+        let base = std::env::temp_dir().join("unshare-test-scratch");
+        let _ = fs::remove_dir_all(&base);
+        let host = base.join("host");
+        fs::create_dir_all(&host).unwrap();
+        fs::write(host.join("input"), "hello").unwrap();
+        let work = base.join("work");
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg(format!("cat {0}/input > {0}/output", work.display()))
+            .unshare(&[Namespace::Mount])
+            .scratch_dir(&host, &work)
+            .spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(child.scratch(), Some((host.as_path(), work.as_path())));
+        assert_eq!(fs::read_to_string(host.join("output")).unwrap(),
+                   "hello");
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_mount_point_removed_on_failure() {
+        let base = std::env::temp_dir().join("unshare-test-scratch-undo");
+        let _ = fs::remove_dir_all(&base);
+        let host = base.join("host");
+        fs::create_dir_all(&host).unwrap();
+        let work = base.join("a/b/work");
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Mount]).scratch_dir(&host, &work);
+        cmd.before_unfreeze(|_| Err("injected".into()));
+        assert!(matches!(cmd.spawn(), Err(Error::BeforeUnfreeze(_))));
+        assert!(!base.join("a").exists());
+        // core dumps are set up after the scratch mount point is created
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Mount]).scratch_dir(&host, &work)
+            .core_dumps(CorePolicy::ToDirectory(base.join("nonexistent")));
+        assert!(matches!(cmd.spawn(), Err(Error::CoreDumps(_))));
+        assert!(!base.join("a").exists());
+
+        let mut child = Command::new("/bin/true")
+            .unshare(&[Namespace::Mount])
+            .scratch_dir(&host, &work)
+            .spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+        assert!(work.is_dir());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_scratch_needs_mount_ns() {
+        match Command::new("/bin/true")
+            .scratch_dir("/tmp", "/tmp/scratch").spawn()
+        {
+            Err(Error::ScratchDir(code)) => assert_eq!(code, libc::EINVAL),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use libc::{c_long, pid_t, sock_filter};
+
+use crate::{Command, Child};
+use crate::arch::AUDIT_ARCH;
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::mount_provider::recv_fd;
+use crate::stdio::Closing;
+
+
+/// Jump offsets in BPF are 8 bit
+const MAX_SYSCALLS: usize = 254;
+
+/// System call made by the child, received by `SeccompSupervisor`
+#[derive(Debug, Clone)]
+pub struct SeccompRequest {
+    /// Cookie identifying the request
+    pub id: u64,
+    /// Thread that made the system call (in the pid namespace of the
+    /// supervisor)
+    pub pid: pid_t,
+    /// System call number, like `libc::SYS_connect`
+    pub syscall: c_long,
+    /// `AUDIT_ARCH_*` value of the system call convention
+    pub arch: u32,
+    /// Instruction pointer of the system call
+    pub instruction_pointer: u64,
+    /// Arguments of the system call, pointers refer to the memory of the
+    /// process (see `SeccompSupervisor::is_valid`)
+    pub args: [u64; 6],
+}
+
+/// How the system call of the `SeccompRequest` is completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompResponse {
+    /// Return the value, without executing the system call
+    Return(i64),
+    /// Fail with the error code (like `libc::EPERM`), without executing
+    /// the system call
+    Error(i32),
+    /// Execute the system call as usual (linux 5.5)
+    ///
+    /// Note the arguments are read by the kernel again, so this must not
+    /// be used to make security decisions on memory of the process.
+    Continue,
+}
+
+/// Receives system calls selected by `Command::seccomp_notify`
+///
+/// Each request must be answered by `respond`, the thread of the child
+/// that made the system call is blocked until then. If the supervisor is
+/// dropped, pending and further system calls fail with `ENOSYS`.
+#[derive(Debug)]
+pub struct SeccompSupervisor {
+    listener: File,
+}
+
+impl Command {
+    /// Let the parent handle some system calls made by the child
+    ///
+    /// Each of the `syscalls` (numbers like `libc::SYS_mount`), made by
+    /// the executed program or any process or thread it starts, is
+    /// suspended and reported to the `SeccompSupervisor` returned by
+    /// `Child::take_seccomp_supervisor`. The supervisor may emulate the
+    /// system call, for example, do `mount` or `connect` on behalf of the
+    /// child, which it isn't allowed to do itself. Other system calls are
+    /// allowed.
+    ///
+    /// The filter is installed right before `execve`, after `pre_exec`
+    /// callback, and `PR_SET_NO_NEW_PRIVS` is set for that. The calls
+    /// made by the child before the program is executed are continued by
+    /// `spawn()` automatically. This requires linux 5.5, and `sendmsg` is
+    /// not allowed in the list, as it's used by the child to pass the
+    /// listener to the parent.
+    ///
+    /// Errors are reported as `Error::SeccompNotify`.
+    pub fn seccomp_notify<I>(&mut self, syscalls: I) -> &mut Command
+        where I: IntoIterator<Item=c_long>
+    {
+        self.seccomp_notify = Some(syscalls.into_iter().collect());
+        self
+    }
+
+    /// Returns the BPF program for `seccomp_notify`
+    pub(crate) fn seccomp_filter(&self)
+        -> Result<Option<Vec<sock_filter>>, Error>
+    {
+        match self.seccomp_notify {
+            Some(ref syscalls) => notify_filter(syscalls).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Child {
+    /// Returns supervisor of the system calls set by
+    /// `Command::seccomp_notify`
+    ///
+    /// Returns None for other configurations or when called twice
+    pub fn take_seccomp_supervisor(&mut self) -> Option<SeccompSupervisor> {
+        self.seccomp.take()
+    }
+}
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code: code as u16, jt, jf, k }
+}
+
+fn notify_filter(syscalls: &[c_long]) -> Result<Vec<sock_filter>, Error> {
+    use libc::{BPF_LD, BPF_W, BPF_ABS, BPF_JMP, BPF_JEQ, BPF_K, BPF_RET};
+
+    let arch = AUDIT_ARCH.ok_or(Error::SeccompNotify(libc::ENOSYS))?;
+    if syscalls.len() > MAX_SYSCALLS || syscalls.contains(&libc::SYS_sendmsg)
+    {
+        return Err(Error::SeccompNotify(libc::EINVAL));
+    }
+    let n = syscalls.len();
+    let mut prog = vec![
+        // other conventions (like i386 on x86_64) have different numbers
+        stmt(BPF_LD | BPF_W | BPF_ABS, 4),  // seccomp_data.arch
+        jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 0, (n + 1) as u8),
+        stmt(BPF_LD | BPF_W | BPF_ABS, 0),  // seccomp_data.nr
+    ];
+    for (idx, &nr) in syscalls.iter().enumerate() {
+        prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32,
+                       (n - idx) as u8, 0));
+    }
+    prog.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    prog.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_USER_NOTIF));
+    Ok(prog)
+}
+
+impl SeccompSupervisor {
+    /// Waits for the next system call
+    ///
+    /// Fails with `ENOENT` if the process was killed while the request was
+    /// being received (just try again).
+    pub fn next_request(&self) -> io::Result<SeccompRequest> {
+        let mut req: libc::seccomp_notif = unsafe { mem::zeroed() };
+        loop {
+            let rc = unsafe {
+                libc::ioctl(self.listener.as_raw_fd(),
+                            libc::SECCOMP_IOCTL_NOTIF_RECV, &mut req)
+            };
+            if rc == 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        Ok(SeccompRequest {
+            id: req.id,
+            pid: req.pid as pid_t,
+            syscall: req.data.nr as c_long,
+            arch: req.data.arch,
+            instruction_pointer: req.data.instruction_pointer,
+            args: req.data.args,
+        })
+    }
+
+    /// Completes the system call
+    ///
+    /// Fails with `ENOENT` if the process is not waiting for the response
+    /// anymore (e.g. was killed).
+    pub fn respond(&self, request: &SeccompRequest, response: SeccompResponse)
+        -> io::Result<()>
+    {
+        let mut resp: libc::seccomp_notif_resp = unsafe { mem::zeroed() };
+        resp.id = request.id;
+        match response {
+            SeccompResponse::Return(val) => resp.val = val,
+            SeccompResponse::Error(errno) => resp.error = -errno,
+            SeccompResponse::Continue => {
+                resp.flags = libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32;
+            }
+        }
+        let rc = unsafe {
+            libc::ioctl(self.listener.as_raw_fd(),
+                        libc::SECCOMP_IOCTL_NOTIF_SEND, &mut resp)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Checks that the process is still waiting for the response
+    ///
+    /// Memory of the process (e.g. `/proc/<pid>/mem`) may be read by the
+    /// supervisor to get values pointed by the arguments. Checking the
+    /// request after opening or reading it ensures that the pid wasn't
+    /// reused by another process meanwhile.
+    pub fn is_valid(&self, request: &SeccompRequest) -> bool {
+        let mut id = request.id;
+        unsafe {
+            libc::ioctl(self.listener.as_raw_fd(),
+                        libc::SECCOMP_IOCTL_NOTIF_ID_VALID, &mut id) == 0
+        }
+    }
+
+    /// Installs a copy of `fd` into the process that made the request
+    ///
+    /// Returns the descriptor number in that process, which may be used
+    /// as the result of the system call (e.g. emulated `open`). Requires
+    /// linux 5.9.
+    pub fn addfd<F: AsRawFd>(&self, request: &SeccompRequest, fd: &F,
+        cloexec: bool)
+        -> io::Result<RawFd>
+    {
+        let mut addfd: libc::seccomp_notif_addfd = unsafe { mem::zeroed() };
+        addfd.id = request.id;
+        addfd.srcfd = fd.as_raw_fd() as u32;
+        if cloexec {
+            addfd.newfd_flags = libc::O_CLOEXEC as u32;
+        }
+        let rc = unsafe {
+            libc::ioctl(self.listener.as_raw_fd(),
+                        libc::SECCOMP_IOCTL_NOTIF_ADDFD, &mut addfd)
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(rc)
+    }
+}
+
+impl AsRawFd for SeccompSupervisor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// Receives the listener from the child
+///
+/// Until the child executes the program (or fails), system calls of the
+/// child itself are continued with `continue_setup`.
+pub(crate) fn receive_supervisor(sock: Closing)
+    -> Result<Option<SeccompSupervisor>, Error>
+{
+    match unsafe { recv_fd(sock.as_raw_fd()) } {
+        Ok(Some(fd)) => Ok(Some(SeccompSupervisor {
+            listener: unsafe { File::from_raw_fd(fd) },
+        })),
+        // the child failed before installing the filter
+        Ok(None) => Ok(None),
+        Err(errno) => Err(Error::SeccompNotify(errno)),
+    }
+}
+
+impl SeccompSupervisor {
+    /// Continues the pending system call made by the child during setup
+    pub(crate) fn continue_setup(&self) -> Result<(), Error> {
+        match self.next_request() {
+            Ok(req) => result(Err::SeccompNotify,
+                self.respond(&req, SeccompResponse::Continue)),
+            // the child was killed
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => result(Err::SeccompNotify, Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::thread;
+
+    use crate::{Command, ExitStatus, Stdio};
+    use super::SeccompResponse;
+
+    #[test]
+    fn test_emulate_uname() {
+        let mut child = Command::new("/bin/uname").arg("-n")
+            .seccomp_notify(vec![libc::SYS_uname])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn().unwrap();
+        let supervisor = child.take_seccomp_supervisor().unwrap();
+        let handler = thread::spawn(move || {
+            let req = supervisor.next_request().unwrap();
+            assert_eq!(req.syscall, libc::SYS_uname);
+            assert!(supervisor.is_valid(&req));
+            supervisor.respond(&req, SeccompResponse::Error(libc::EPERM))
+                .unwrap();
+        });
+        let mut output = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut output).unwrap();
+        let status = child.wait().unwrap();
+        handler.join().unwrap();
+        assert_eq!(output, "");
+        assert_eq!(status, ExitStatus::Exited(1));
+    }
+}
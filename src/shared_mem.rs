@@ -0,0 +1,192 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+use crate::{Command, Child};
+use crate::error::{Error, result};
+use crate::error::ErrorCode as Err;
+use crate::stdio::{Fd, dup_file_cloexec};
+
+
+/// Memory region shared with the child, see `Command::shared_memory`
+///
+/// The child may change the memory at any time, so there are no slices
+/// referring to it, only methods copying the data in and out.
+#[derive(Debug)]
+pub struct SharedMemory {
+    ptr: *mut u8,
+    len: usize,
+    file: File,
+    child_fd: RawFd,
+}
+
+// the memory is only accessed by copying, like the child does anyway
+unsafe impl Send for SharedMemory {}
+unsafe impl Sync for SharedMemory {}
+
+impl SharedMemory {
+    fn new(len: usize, child_fd: RawFd) -> io::Result<SharedMemory> {
+        let fd = unsafe {
+            libc::memfd_create(b"unshare-shared\0".as_ptr() as *const _,
+                               libc::MFD_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(len as u64)?;
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len.max(1),
+                libc::PROT_READ|libc::PROT_WRITE, libc::MAP_SHARED,
+                file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SharedMemory { ptr: ptr as *mut u8, len, file, child_fd })
+    }
+
+    /// Size of the region in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the size is zero
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The memfd backing the memory, e.g. to seal it
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Copies bytes at `offset` into the buffer
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of the region
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) {
+        assert!(offset.checked_add(buf.len()).is_some_and(|e| e <= self.len),
+            "range is out of shared memory");
+        unsafe {
+            ptr::copy(self.ptr.add(offset), buf.as_mut_ptr(), buf.len());
+        }
+    }
+
+    /// Copies the data into the memory at `offset`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of the region
+    pub fn write_at(&self, offset: usize, data: &[u8]) {
+        assert!(offset.checked_add(data.len()).is_some_and(|e| e <= self.len),
+            "range is out of shared memory");
+        unsafe {
+            ptr::copy(data.as_ptr(), self.ptr.add(offset), data.len());
+        }
+    }
+
+    /// Variables telling the child where the memory is
+    pub(crate) fn env(&self) -> Vec<(OsString, OsString)> {
+        vec![
+            ("UNSHARE_SHM_FD".into(), self.child_fd.to_string().into()),
+            ("UNSHARE_SHM_SIZE".into(), self.len.to_string().into()),
+        ]
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len.max(1));
+        }
+    }
+}
+
+impl Command {
+    /// Share a memory region of `size` bytes with the child
+    ///
+    /// On every `spawn()` a new zero-filled region is created (backed by
+    /// `memfd_create`), mapped in the current process, see
+    /// `Child::shared_mem`, and given to the child as a file descriptor.
+    /// The descriptor is the lowest one (starting from 3) not configured by
+    /// `file_descriptor` or `keep_fds`. Its number and the
+    /// size are passed in `UNSHARE_SHM_FD` and `UNSHARE_SHM_SIZE`
+    /// environment variables, so the program can `mmap` it.
+    ///
+    /// Note: the program runs concurrently with the parent, so it must
+    /// wait for the data to be written (e.g. for a byte in stdin).
+    pub fn shared_memory(&mut self, size: usize) -> &mut Command {
+        self.shared_memory = Some(size);
+        self
+    }
+
+    /// Creates the region and configures the descriptor for the child
+    pub(crate) fn map_shared_memory(&mut self)
+        -> Result<Option<SharedMemory>, Error>
+    {
+        let size = match self.shared_memory {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        // configured descriptors are never closed by `close_fds`
+        let child_fd = (3..).find(|fd| {
+            !self.fds.contains_key(fd) && !self.keep_fds.contains(fd)
+        }).unwrap();
+        let shm = result(Err::SharedMemory,
+            SharedMemory::new(size, child_fd))?;
+        let fd = result(Err::SharedMemory, dup_file_cloexec(&shm.file))?;
+        self.fds.insert(child_fd, Fd::Fd(fd));
+        Ok(Some(shm))
+    }
+
+    /// Closes the descriptor configured by `map_shared_memory`
+    pub(crate) fn release_shared_memory(&mut self,
+        shm: Option<&SharedMemory>)
+    {
+        if let Some(shm) = shm {
+            self.fds.remove(&shm.child_fd);
+        }
+    }
+}
+
+impl Child {
+    /// Returns the memory shared with the child, see
+    /// `Command::shared_memory`
+    pub fn shared_mem(&self) -> Option<&SharedMemory> {
+        self.shared_memory.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use crate::{Command, Stdio};
+
+    #[test]
+    fn test_shared_memory() {
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("read x; \
+                  head -c $UNSHARE_SHM_SIZE <&$UNSHARE_SHM_FD; \
+                  printf world | dd of=/proc/self/fd/$UNSHARE_SHM_FD \
+                     bs=1 seek=6 conv=notrunc status=none")
+            .shared_memory(11)
+            .stdin(Stdio::piped()).stdout(Stdio::piped())
+            .spawn().unwrap();
+        let shm = child.shared_mem().unwrap();
+        assert_eq!(shm.len(), 11);
+        shm.write_at(0, b"hello ");
+        child.take_stdin().unwrap().write_all(b"\n").unwrap();
+        let mut output = Vec::new();
+        child.take_stdout().unwrap().read_to_end(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output, b"hello \0\0\0\0\0");
+        let mut buf = [0; 11];
+        child.shared_mem().unwrap().read_at(0, &mut buf);
+        assert_eq!(&buf, b"hello world");
+    }
+}
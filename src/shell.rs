@@ -0,0 +1,97 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::{Command, MountOp};
+use crate::error::Error;
+use crate::ffi_util::ToCString;
+use crate::chroot::in_root;
+
+
+impl Command {
+    /// Constructs a `Command` running `command` with `/bin/sh -c`
+    ///
+    /// The shell is the one in the new root (if `chroot_dir` or
+    /// `pivot_root` is set), use `shell_path` to change it. On `spawn()` the
+    /// shell is checked to be executable in the new root, so a missing
+    /// shell is reported as `Error::Exec` before the child is started
+    /// (the check is skipped if the path is covered by a mount done by the
+    /// child, the child reports the error then).
+    pub fn shell<S: AsRef<OsStr>>(command: S) -> Command {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(command);
+        cmd.config.shell = true;
+        cmd
+    }
+
+    /// Set the path of the shell for `Command::shell`
+    ///
+    /// The path is inside the new root.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the command isn't constructed by `Command::shell` or the
+    /// path is not absolute
+    pub fn shell_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Command {
+        let path = path.as_ref();
+        assert!(self.config.shell,
+            "shell_path() is only for commands created by Command::shell");
+        assert!(path.is_absolute(), "shell path must be absolute");
+        self.filename = path.to_cstring();
+        self.args[0] = path.to_cstring();
+        self
+    }
+
+    /// Checks that the shell of `Command::shell` is executable
+    pub(crate) fn check_shell(&self) -> Result<(), Error> {
+        if !self.config.shell {
+            return Ok(());
+        }
+        let root = self.host_root();
+        let shell = in_root(&root,
+            Path::new(OsStr::from_bytes(self.filename.as_bytes())));
+        let mounted = self.mount_plan().iter().any(|op| match *op {
+            MountOp::Provided { ref target } => shell.starts_with(target),
+            MountOp::Bind { ref target, .. } => shell.starts_with(target),
+            _ => false,
+        });
+        if mounted {
+            return Ok(());
+        }
+        let rc = unsafe {
+            libc::access(shell.to_cstring().as_ptr(), libc::X_OK)
+        };
+        if rc != 0 {
+            let err = io::Error::last_os_error();
+            return Err(Error::Exec(err.raw_os_error().unwrap_or(-1)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Error};
+
+    #[test]
+    fn test_shell() {
+        let status = Command::shell("test $((1 + 2)) = 3")
+            .status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_shell_in_root() {
+        let root = std::env::temp_dir().join("unshare-test-shell-root");
+        std::fs::create_dir_all(&root).unwrap();
+        match Command::shell("true").chroot_dir(&root).spawn() {
+            Err(Error::Exec(code)) => assert_eq!(code, libc::ENOENT),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match Command::shell("true").shell_path("/bin/sh/x").spawn() {
+            Err(Error::Exec(code)) => assert_eq!(code, libc::ENOTDIR),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::io;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::{Command, Child, Signal};
+use crate::pipe::PipeHolder;
+use crate::stdio::Fd;
+
+
+/// The environment variable with the descriptor of `shutdown_channel`
+pub const SHUTDOWN_FD_VAR: &str = "UNSHARE_SHUTDOWN_FD";
+
+/// How often `Child::request_shutdown` checks whether the child exited
+const RECHECK: Duration = Duration::from_millis(10);
+
+impl Command {
+    /// Give the child a descriptor which becomes readable on shutdown
+    ///
+    /// The child gets the reading end of a pipe at the lowest descriptor
+    /// number (starting from 3) which is not configured yet, the number is
+    /// put into the `UNSHARE_SHUTDOWN_FD` environment variable (so call
+    /// this after `env_clear`). The pipe is closed by
+    /// `Child::request_shutdown` (or when the `Child` is dropped), then
+    /// the child reads end of file from it. So the child may watch the
+    /// descriptor by its event loop (or a thread blocked in `read`) and
+    /// shut down gracefully, which doesn't need signal handlers. Calling
+    /// this again does nothing.
+    pub fn shutdown_channel(&mut self) -> &mut Command {
+        if self.shutdown_fd.is_some() {
+            return self;
+        }
+        let target = (3..).find(|x| !self.fds.contains_key(x)).unwrap();
+        self.fds.insert(target, Fd::ReadPipe);
+        self.shutdown_fd = Some(target);
+        self.env(SHUTDOWN_FD_VAR, target.to_string())
+    }
+}
+
+impl Child {
+    /// Ask the child to shut down through the `shutdown_channel`
+    ///
+    /// If `escalate` is set, waits up to the duration for the child to
+    /// exit and sends the signal if it hasn't. The child isn't reaped, use
+    /// `wait()` afterwards. Calling this again only escalates. Fails with
+    /// `EINVAL` if the command has no `shutdown_channel`.
+    pub fn request_shutdown(&mut self, escalate: Option<(Duration, Signal)>)
+        -> io::Result<()>
+    {
+        let fd = self.shutdown_fd
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        if let Some(PipeHolder::Writer(pipe)) = self.fds.remove(&fd) {
+            // unlike writing, closing can't raise `SIGPIPE` if the child exited
+            drop(pipe);
+        }
+        let (grace, signal) = match escalate {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + grace;
+        while self.peek_status()?.is_none() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return self.signal(signal);
+            }
+            sleep(left.min(RECHECK));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{Command, ExitStatus, Signal};
+
+    #[test]
+    fn test_shutdown() {
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("read x <&$UNSHARE_SHUTDOWN_FD; exit 5")
+            .shutdown_channel()
+            .spawn().unwrap();
+        child.request_shutdown(Some((Duration::from_secs(5), Signal::SIGKILL)))
+            .unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(5));
+        assert!(child.request_shutdown(None).is_ok());
+    }
+
+    #[test]
+    fn test_escalate() {
+        let mut child = Command::new("/bin/sleep").arg("10")
+            .shutdown_channel()
+            .spawn().unwrap();
+        child.request_shutdown(Some((Duration::from_millis(50),
+                                     Signal::SIGKILL)))
+            .unwrap();
+        assert_eq!(child.wait().unwrap(),
+                   ExitStatus::Signaled(Signal::SIGKILL, false));
+        let mut child = Command::new("/bin/true").spawn().unwrap();
+        assert!(child.request_shutdown(None).is_err());
+        child.wait().unwrap();
+    }
+}
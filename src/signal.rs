@@ -0,0 +1,138 @@
+#[cfg(feature="nix")]
+use std::convert::TryFrom;
+use std::fmt;
+
+use libc::c_int;
+#[cfg(feature="nix")]
+use nix::sys::signal::Signal as NixSignal;
+
+
+/// Unix signal number
+///
+/// This is a thin wrapper around the signal number, so unlike
+/// `nix::sys::signal::Signal` it can represent any signal including
+/// real-time ones. And it doesn't tie public API of this crate to the
+/// specific version of `nix`.
+///
+/// Constants for standard signals are provided, so `Signal::SIGKILL` works
+/// just like it works for the `nix` enum. Use `Signal::from_raw`/`as_raw`
+/// or `From`/`TryFrom` traits to convert from and to numbers and `nix`
+/// signals.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Signal(c_int);
+
+macro_rules! signals {
+    ($($name:ident),* $(,)*) => {
+        #[allow(missing_docs)]
+        impl Signal {
+            $(pub const $name: Signal = Signal(libc::$name);)*
+        }
+        const NAMES: &[(Signal, &str)] = &[
+            $((Signal::$name, stringify!($name)),)*
+        ];
+    }
+}
+
+signals!(
+    SIGHUP, SIGINT, SIGQUIT, SIGILL, SIGTRAP, SIGABRT, SIGBUS, SIGFPE,
+    SIGKILL, SIGUSR1, SIGSEGV, SIGUSR2, SIGPIPE, SIGALRM, SIGTERM,
+    SIGCHLD, SIGCONT, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU, SIGURG,
+    SIGXCPU, SIGXFSZ, SIGVTALRM, SIGPROF, SIGWINCH, SIGIO, SIGPWR, SIGSYS,
+);
+
+// there is no SIGSTKFLT on mips and sparc
+#[cfg(not(any(target_arch="mips", target_arch="mips64",
+              target_arch="sparc", target_arch="sparc64")))]
+impl Signal {
+    #[allow(missing_docs)]
+    pub const SIGSTKFLT: Signal = Signal(libc::SIGSTKFLT);
+}
+#[cfg(not(any(target_arch="mips", target_arch="mips64",
+              target_arch="sparc", target_arch="sparc64")))]
+const ARCH_NAMES: &[(Signal, &str)] = &[(Signal::SIGSTKFLT, "SIGSTKFLT")];
+#[cfg(any(target_arch="mips", target_arch="mips64",
+          target_arch="sparc", target_arch="sparc64"))]
+const ARCH_NAMES: &[(Signal, &str)] = &[];
+
+impl Signal {
+    /// Create signal from raw signal number
+    ///
+    /// No validation is done, invalid signal numbers are rejected by the
+    /// kernel at the time signal is used.
+    pub const fn from_raw(signo: c_int) -> Signal {
+        Signal(signo)
+    }
+    /// Returns raw signal number
+    pub const fn as_raw(self) -> c_int {
+        self.0
+    }
+    /// Returns name of the signal like `SIGKILL`
+    ///
+    /// Returns `None` for real-time and unknown signals.
+    pub fn name(self) -> Option<&'static str> {
+        NAMES.iter().chain(ARCH_NAMES)
+            .find(|&&(sig, _)| sig == self).map(|&(_, name)| name)
+    }
+}
+
+impl fmt::Debug for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "Signal({})", self.0),
+        }
+    }
+}
+
+impl From<c_int> for Signal {
+    fn from(signo: c_int) -> Signal {
+        Signal(signo)
+    }
+}
+
+impl From<Signal> for c_int {
+    fn from(sig: Signal) -> c_int {
+        sig.0
+    }
+}
+
+#[cfg(feature="nix")]
+impl From<NixSignal> for Signal {
+    fn from(sig: NixSignal) -> Signal {
+        Signal(sig as c_int)
+    }
+}
+
+#[cfg(feature="nix")]
+impl TryFrom<Signal> for NixSignal {
+    type Error = nix::Error;
+    fn try_from(sig: Signal) -> Result<NixSignal, nix::Error> {
+        NixSignal::try_from(sig.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Signal;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(format!("{:?}", Signal::SIGKILL), "SIGKILL");
+        assert_eq!(format!("{:?}", Signal::from_raw(libc::SIGRTMIN()+1)),
+                   format!("Signal({})", libc::SIGRTMIN()+1));
+    }
+
+    #[test]
+    #[cfg(feature="nix")]
+    fn test_nix() {
+        use std::convert::TryFrom;
+        use nix::sys::signal::Signal as NixSignal;
+        for &sig in NixSignal::iterator().collect::<Vec<_>>().iter() {
+            let our = Signal::from(sig);
+            assert_eq!(format!("{:?}", our), format!("{:?}", sig));
+            assert_eq!(NixSignal::try_from(our).unwrap(), sig);
+        }
+        assert!(NixSignal::try_from(Signal::from_raw(libc::SIGRTMIN()))
+                .is_err());
+    }
+}
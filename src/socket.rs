@@ -0,0 +1,106 @@
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+
+use crate::{Command, Child};
+use crate::pipe::PipeHolder;
+use crate::stdio::{Fd, dup_file_cloexec};
+
+
+pub(crate) fn check_socket(fd: RawFd) -> io::Result<()> {
+    let mut kind: libc::c_int = 0;
+    let mut len = mem::size_of_val(&kind) as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE,
+            &mut kind as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Command {
+    /// Pass a socket to the child and put its number into the environment
+    ///
+    /// The socket is duplicated to the lowest descriptor number (starting
+    /// from 3) which is not configured yet, and the number is stored in
+    /// the environment variable `var`. So the descriptor stays the same as
+    /// long as descriptors are configured in the same order.
+    ///
+    /// This is a way to give the child living in its own network namespace
+    /// (`Namespace::Net`) exactly one channel to the host: a connected
+    /// socket, usually one end of a `socketpair`, or a connection to some
+    /// service accepted by the current process. Use `Fd::seqpacket()` to
+    /// let the library create the pair and `Child::take_socket` to get
+    /// the other end.
+    ///
+    /// Returns an error if `socket` isn't a socket.
+    pub fn pass_socket_env<F, K>(&mut self, socket: &F, var: K)
+        -> io::Result<&mut Command>
+        where F: AsRawFd, K: AsRef<OsStr>
+    {
+        check_socket(socket.as_raw_fd())?;
+        let fd = dup_file_cloexec(socket)?;
+        let target = (3..).find(|x| !self.fds.contains_key(x)).unwrap();
+        self.fds.insert(target, Fd::Fd(fd));
+        Ok(self.env(var, target.to_string()))
+    }
+}
+
+impl Child {
+    /// Returns the socket declared as `Fd::seqpacket()`
+    ///
+    /// Returns None for wrong configuration or when called twice for same
+    /// descriptor. The socket may be converted to `UnixStream` for reading
+    /// and writing, message boundaries are preserved anyway.
+    pub fn take_socket(&mut self, fd: RawFd) -> Option<OwnedFd> {
+        match self.fds.remove(&fd) {
+            Some(PipeHolder::Socket(x)) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    use crate::{Command, Fd, ExitStatus};
+
+    #[test]
+    fn test_seqpacket() {
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("exec cat <&3 >&3")
+            .file_descriptor(3, Fd::seqpacket())
+            .spawn().unwrap();
+        let mut sock = UnixStream::from(child.take_socket(3).unwrap());
+        sock.write_all(b"hello").unwrap();
+        sock.write_all(b"world").unwrap();
+        let mut buf = [0u8; 64];
+        // cat reads a packet at a time
+        let n = sock.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        let n = sock.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"world");
+        sock.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(0));
+    }
+
+    #[test]
+    fn test_pass_socket_env() {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("echo $SOCK_FD >&$SOCK_FD")
+            .pass_socket_env(&theirs, "SOCK_FD").unwrap()
+            .spawn().unwrap();
+        drop(theirs);
+        let mut result = String::new();
+        ours.read_to_string(&mut result).unwrap();
+        assert_eq!(result, "3\n");
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(0));
+    }
+}
@@ -0,0 +1,189 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use libc::pid_t;
+
+use crate::{Command, Child, ExitStatus};
+use crate::error::Error;
+
+
+/// Adds tracing context to every spawned command
+///
+/// Registered by `set_span_injector`. It's called in the thread calling
+/// `spawn()`, so the current span can be found in thread-local storage
+/// of the tracing library.
+pub trait SpanInjector: Send + Sync {
+    /// Returns environment variables to set for the `program` (for
+    /// example `TRACEPARENT`)
+    ///
+    /// They override the variables set on the `Command` and are not
+    /// removed by `scrub_env`.
+    fn inject(&self, program: &OsStr) -> Vec<(OsString, OsString)>;
+}
+
+/// Event emitted to the subscriber set by `set_span_subscriber`
+#[derive(Debug)]
+pub enum SpanEvent<'a> {
+    /// `spawn()` is called
+    Spawn {
+        /// Path of the program
+        program: &'a OsStr,
+        /// When the call started
+        timestamp: SystemTime,
+    },
+    /// The program is executed successfully by the child
+    Exec {
+        /// Pid of the child
+        pid: pid_t,
+        /// Time since `Spawn`
+        duration: Duration,
+    },
+    /// `spawn()` has failed
+    SpawnFailed {
+        /// The error returned
+        error: &'a Error,
+        /// Time since `Spawn`
+        duration: Duration,
+    },
+    /// The child is reaped by its `Child` handle (e.g. `Child::wait`)
+    Exit {
+        /// Pid of the child
+        pid: pid_t,
+        /// Exit status of the child
+        status: ExitStatus,
+        /// Time since `Spawn`
+        duration: Duration,
+    },
+}
+
+type Subscriber = Box<dyn Fn(&SpanEvent) + Send + Sync>;
+
+static INJECTOR: RwLock<Option<Box<dyn SpanInjector>>> = RwLock::new(None);
+static SUBSCRIBER: RwLock<Option<Subscriber>> = RwLock::new(None);
+
+/// Set the injector called for every `Command` spawned by the process
+///
+/// Each invocation **replaces** previously set injector.
+pub fn set_span_injector(injector: impl SpanInjector + 'static) {
+    *INJECTOR.write().unwrap_or_else(|e| e.into_inner()) =
+        Some(Box::new(injector));
+}
+
+/// Set the subscriber receiving spawn, exec and exit events of every
+/// `Command` spawned by the process
+///
+/// The subscriber is called synchronously, so it should be fast. Each
+/// invocation **replaces** previously set subscriber.
+pub fn set_span_subscriber(f: impl Fn(&SpanEvent) + Send + Sync + 'static)
+{
+    *SUBSCRIBER.write().unwrap_or_else(|e| e.into_inner()) =
+        Some(Box::new(f));
+}
+
+pub(crate) fn emit(event: &SpanEvent) {
+    let subscriber = SUBSCRIBER.read().unwrap_or_else(|e| e.into_inner());
+    if let Some(ref f) = *subscriber {
+        f(event);
+    }
+}
+
+impl Command {
+    /// Returns variables of the injector, if there is one
+    pub(crate) fn injected_env(&self) -> Vec<(OsString, OsString)> {
+        let injector = INJECTOR.read().unwrap_or_else(|e| e.into_inner());
+        match *injector {
+            Some(ref injector) => {
+                injector.inject(OsStr::from_bytes(self.filename.as_bytes()))
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Emits `Spawn` event and returns the start time
+    pub(crate) fn span_start(&self) -> Instant {
+        emit(&SpanEvent::Spawn {
+            program: OsStr::from_bytes(self.filename.as_bytes()),
+            timestamp: SystemTime::now(),
+        });
+        Instant::now()
+    }
+
+    /// Emits `Exec` or `SpawnFailed` event
+    pub(crate) fn span_spawned(start: Instant,
+        result: &mut Result<Child, Error>)
+    {
+        let duration = start.elapsed();
+        match *result {
+            Ok(ref mut child) => {
+                child.spawned_at = Some(start);
+                emit(&SpanEvent::Exec { pid: child.pid(), duration });
+            }
+            Err(ref error) => {
+                emit(&SpanEvent::SpawnFailed { error, duration });
+            }
+        }
+    }
+}
+
+impl Child {
+    /// Emits `Exit` event for children returned by `spawn()`
+    pub(crate) fn span_exit(&self, status: ExitStatus) {
+        if let Some(start) = self.spawned_at {
+            emit(&SpanEvent::Exit {
+                pid: self.pid,
+                status,
+                duration: start.elapsed(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::{OsStr, OsString};
+    use std::sync::{Arc, Mutex};
+
+    use crate::{Command, ExitStatus};
+    use super::{SpanInjector, SpanEvent};
+    use super::{set_span_injector, set_span_subscriber};
+
+    struct Traceparent;
+
+    impl SpanInjector for Traceparent {
+        fn inject(&self, _program: &OsStr) -> Vec<(OsString, OsString)> {
+            vec![("UNSHARE_TEST_TRACEPARENT".into(), "00-abc-01".into())]
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        // other tests spawn children concurrently, so only the events of
+        // this one are checked
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = events.clone();
+        set_span_injector(Traceparent);
+        set_span_subscriber(move |event| {
+            let mut log = log.lock().unwrap();
+            match *event {
+                SpanEvent::Exec { pid, .. } => log.push(("exec", pid, None)),
+                SpanEvent::Exit { pid, status, .. } => {
+                    log.push(("exit", pid, Some(status)))
+                }
+                _ => {}
+            }
+        });
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg(r#"test "$UNSHARE_TEST_TRACEPARENT" = 00-abc-01"#)
+            .env("UNSHARE_TEST_TRACEPARENT", "overridden")
+            .spawn().unwrap();
+        let pid = child.pid();
+        let status = child.wait().unwrap();
+        assert_eq!(status, ExitStatus::Exited(0));
+        let events = events.lock().unwrap().iter()
+            .filter(|&&(_, x, _)| x == pid).cloned().collect::<Vec<_>>();
+        assert_eq!(events, vec![("exec", pid, None),
+                                ("exit", pid, Some(status))]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libc::mode_t;
+
+use crate::Command;
+use crate::limits::Limits;
+
+
+/// Snapshot of the state of the current process inherited by children
+///
+/// This includes environment (so locale settings too), umask, working
+/// directory, resource limits, nice value and CPU affinity. Applying the
+/// snapshot with `Command::spawn_environment` makes children independent
+/// of changes made to the current process after the capture, and of the
+/// thread that spawns them. This is useful for daemons which must spawn
+/// processes the same way as they would be spawned at the daemon start.
+#[derive(Clone)]
+pub struct SpawnEnvironment {
+    vars: Vec<(OsString, OsString)>,
+    umask: mode_t,
+    current_dir: PathBuf,
+    limits: Limits,
+}
+
+/// Reads umask from `/proc/self/status` (linux 4.7+)
+///
+/// Unlike `umask()` it doesn't change the mask even temporarily, so does
+/// not race with threads creating files.
+fn read_umask() -> Option<mode_t> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("Umask:"))?;
+    mode_t::from_str_radix(line["Umask:".len()..].trim(), 8).ok()
+}
+
+impl SpawnEnvironment {
+    /// Captures the state of the current process
+    pub fn capture() -> io::Result<SpawnEnvironment> {
+        let umask = read_umask().unwrap_or_else(|| unsafe {
+            let mask = libc::umask(0o022);
+            libc::umask(mask);
+            mask
+        });
+        Ok(SpawnEnvironment {
+            vars: env::vars_os().collect(),
+            umask,
+            current_dir: env::current_dir()?,
+            limits: Limits::read(unsafe { libc::getpid() })?,
+        })
+    }
+    /// Environment variables captured
+    pub fn vars(&self) -> impl Iterator<Item=(&OsStr, &OsStr)> {
+        self.vars.iter().map(|(k, v)| (&k[..], &v[..]))
+    }
+    /// File mode creation mask captured
+    pub fn umask(&self) -> mode_t {
+        self.umask
+    }
+    /// Working directory captured
+    pub fn current_dir(&self) -> &Path {
+        &self.current_dir
+    }
+}
+
+impl Command {
+    /// Spawn the child with environment, umask, working directory and
+    /// limits of the snapshot
+    ///
+    /// This replaces the environment set so far (like `env_clear`) and
+    /// the values set by `current_dir`, `umask` and `inherit_limits_from`.
+    /// The methods may be called afterwards to change the values.
+    pub fn spawn_environment(&mut self, snapshot: &SpawnEnvironment)
+        -> &mut Command
+    {
+        self.env_clear();
+        self.envs(snapshot.vars());
+        self.current_dir(&snapshot.current_dir);
+        self.umask(snapshot.umask);
+        self.inherited_limits = Some(snapshot.limits.clone());
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io::Read;
+
+    use crate::{Command, Stdio};
+    use super::SpawnEnvironment;
+
+    #[test]
+    fn test_snapshot() {
+        let mut snapshot = SpawnEnvironment::capture().unwrap();
+        snapshot.umask = 0o027;
+        snapshot.vars.push(("SNAPSHOT_VAR".into(), "yes".into()));
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("umask; echo $SNAPSHOT_VAR $EARLIER_VAR; pwd")
+            .env("EARLIER_VAR", "set")
+            .spawn_environment(&snapshot)
+            .stdout(Stdio::piped())
+            .spawn().unwrap();
+        let mut output = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut output).unwrap();
+        child.wait().unwrap();
+        let expected = format!("0027\nyes\n{}\n",
+            env::current_dir().unwrap().display());
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsStr;
+use std::hash::{Hash, Hasher};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use libc::{c_int, uid_t, gid_t};
+
+use crate::{Command, Child, Namespace, MountOp};
+use crate::namespace::{namespaces_of, to_clone_flag};
+
+
+/// What the child was spawned with, see `Child::spawn_record`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpawnRecord {
+    /// Path of the program as passed to `Command::new`
+    pub program: PathBuf,
+    /// Hash of the arguments (not including `argv[0]`)
+    ///
+    /// Only meant to compare the records made by the same process, the
+    /// hash function may differ between versions of the library.
+    pub args_hash: u64,
+    /// Raw `CLONE_NEW*` flags the child is cloned with
+    pub clone_flags: c_int,
+    /// Namespaces created for the child
+    pub namespaces: Vec<Namespace>,
+    /// Namespaces joined by `Command::set_namespace`
+    pub joined_namespaces: Vec<Namespace>,
+    /// Mount operations done by the child, see `Child::mounts_applied`
+    pub mounts: Vec<MountOp>,
+    /// User id set by `Command::uid`
+    pub uid: Option<uid_t>,
+    /// Group id set by `Command::gid`
+    pub gid: Option<gid_t>,
+}
+
+impl Command {
+    pub(crate) fn spawn_record(&self, mounts: &[MountOp]) -> SpawnRecord {
+        let mut hasher = DefaultHasher::new();
+        self.args[1..].hash(&mut hasher);
+        let joined = self.config.joined_namespaces()
+            .fold(0, |flags, ns| flags | to_clone_flag(ns));
+        SpawnRecord {
+            program: OsStr::from_bytes(self.filename.as_bytes()).into(),
+            args_hash: hasher.finish(),
+            clone_flags: self.config.namespaces,
+            namespaces: namespaces_of(self.config.namespaces),
+            joined_namespaces: namespaces_of(joined),
+            mounts: mounts.to_vec(),
+            uid: self.config.uid,
+            gid: self.config.gid,
+        }
+    }
+}
+
+impl Child {
+    /// Returns the record of the configuration the child was spawned with
+    ///
+    /// The record is shared, so it's cheap to keep it in event handlers
+    /// after the `Child` is gone. `None` for children created by
+    /// `Child::from_pid`.
+    pub fn spawn_record(&self) -> Option<&Arc<SpawnRecord>> {
+        self.spawn_record.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{Command, Child};
+
+    #[test]
+    fn test_spawn_record() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("exit 0");
+        let mut child = cmd.spawn().unwrap();
+        let record = child.spawn_record().unwrap().clone();
+        child.wait().unwrap();
+        assert_eq!(record.program, Path::new("/bin/sh"));
+        assert_eq!(record.clone_flags, 0);
+        assert!(record.namespaces.is_empty());
+        assert!(record.joined_namespaces.is_empty());
+        assert_eq!(record.uid, None);
+        let mut other = cmd.spawn().unwrap();
+        assert_eq!(other.spawn_record().unwrap().args_hash,
+                   record.args_hash);
+        other.wait().unwrap();
+        cmd.arg("extra");
+        let mut other = cmd.spawn().unwrap();
+        assert_ne!(other.spawn_record().unwrap().args_hash,
+                   record.args_hash);
+        other.wait().unwrap();
+        assert!(Child::from_pid(1).spawn_record().is_none());
+    }
+}
@@ -0,0 +1,96 @@
+//! The long-lived thread that clones children for `DeathSigScope::Process`
+//!
+//! Linux sends `PDEATHSIG` when the *thread* which created the child exits,
+//! not when the whole process exits. So to tie child lifetime to the process
+//! we clone children from the thread that lives until the process exits.
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct AssertSend<T>(T);
+unsafe impl<T> Send for AssertSend<T> {}
+
+static SPAWNER: Mutex<Option<Sender<Job>>> = Mutex::new(None);
+
+
+fn start_thread() -> Option<Sender<Job>> {
+    let (tx, rx) = channel::<Job>();
+    thread::Builder::new()
+        .name("unshare-spawner".into())
+        .spawn(move || {
+            for job in rx {
+                job();
+            }
+        })
+        .ok()?;
+    Some(tx)
+}
+
+/// Runs function in the spawner thread and waits for it to complete
+///
+/// If spawner thread can't be started function is run in current thread.
+///
+/// The function may borrow data from the caller, since the caller is blocked
+/// until the function is complete. A panic of the function is resumed in the
+/// caller, so it doesn't take the spawner thread down.
+pub fn in_spawner_thread<R, F: FnOnce() -> R>(f: F) -> R {
+    let (tx, rx) = channel();
+    let f = AssertSend(f);
+    let job: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+        let AssertSend(f) = f;
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        tx.send(AssertSend(result)).ok();
+    });
+    // We block on the result below, so that borrows of the function
+    // outlive the time it's run in the other thread
+    let job: Job = unsafe { mem::transmute(job) };
+    let job = {
+        let mut spawner = SPAWNER.lock().unwrap_or_else(|e| e.into_inner());
+        if spawner.is_none() {
+            *spawner = start_thread();
+        }
+        match spawner.as_ref() {
+            Some(sender) => match sender.send(job) {
+                Ok(()) => None,
+                Err(e) => {
+                    *spawner = None;
+                    Some(e.0)
+                }
+            },
+            None => Some(job),
+        }
+    };
+    if let Some(job) = job {
+        job();
+    }
+    match rx.recv() {
+        Ok(AssertSend(Ok(result))) => result,
+        Ok(AssertSend(Err(payload))) => panic::resume_unwind(payload),
+        Err(_) => unreachable!("spawner job is lost"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic;
+    use std::thread;
+
+    use super::in_spawner_thread;
+
+    #[test]
+    fn test_panic_in_job() {
+        let spawner = in_spawner_thread(|| thread::current().id());
+        let result = panic::catch_unwind(|| {
+            in_spawner_thread(|| panic!("job failed"))
+        });
+        assert_eq!(*result.unwrap_err().downcast::<&str>().unwrap(),
+                   "job failed");
+        // the same thread is still running jobs
+        assert_eq!(in_spawner_thread(|| thread::current().id()), spawner);
+    }
+}
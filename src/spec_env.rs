@@ -0,0 +1,335 @@
+use std::collections::{HashMap, BTreeMap};
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+
+use crate::{Command, UidMap, GidMap};
+
+
+/// Version of the encoding, the first byte of the spec
+const VERSION: u8 = 1;
+
+const ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0),
+                 *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6*i) & 63) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+fn base64_decode(data: &[u8]) -> io::Result<Vec<u8>> {
+    let chunks = data.chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        return Err(invalid("bad base64 length"));
+    }
+    let mut result = Vec::with_capacity(data.len() / 4 * 3);
+    for chunk in chunks {
+        let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 {
+            return Err(invalid("bad base64 padding"));
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4-pad] {
+            let value = ALPHABET.iter().position(|&x| x == c)
+                .ok_or_else(|| invalid("bad base64 character"))?;
+            n = n << 6 | value as u32;
+        }
+        n <<= 6 * pad;
+        result.extend_from_slice(&n.to_be_bytes()[1..4-pad]);
+    }
+    Ok(result)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+    fn bool(&mut self, value: bool) {
+        self.0.push(value as u8);
+    }
+    fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+    fn opt<T>(&mut self, value: Option<T>, f: impl FnOnce(&mut Writer, T)) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            f(self, value);
+        }
+    }
+    fn list<T>(&mut self, items: &[T], mut f: impl FnMut(&mut Writer, &T)) {
+        self.u32(items.len() as u32);
+        for item in items {
+            f(self, item);
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("spec is truncated"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("bad boolean in spec")),
+        }
+    }
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    fn os_string(&mut self) -> io::Result<OsString> {
+        Ok(OsString::from_vec(self.bytes()?.to_vec()))
+    }
+    fn c_string(&mut self) -> io::Result<CString> {
+        CString::new(self.bytes()?)
+            .map_err(|_| invalid("zero byte in spec string"))
+    }
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| invalid("bad utf-8 in spec"))
+    }
+    fn opt<T>(&mut self, f: impl FnOnce(&mut Reader<'a>) -> io::Result<T>)
+        -> io::Result<Option<T>>
+    {
+        if self.bool()? { f(self).map(Some) } else { Ok(None) }
+    }
+    fn list<T>(&mut self, mut f: impl FnMut(&mut Reader<'a>) -> io::Result<T>)
+        -> io::Result<Vec<T>>
+    {
+        // don't trust the length for preallocation
+        (0..self.u32()?).map(|_| f(self)).collect()
+    }
+}
+
+impl Command {
+    /// Encodes the spec of the command, see `export_spec_env`
+    fn encode_spec(&self) -> String {
+        let mut w = Writer(vec![VERSION]);
+        w.bytes(self.filename.as_bytes());
+        w.list(&self.args, |w, arg| w.bytes(arg.as_bytes()));
+        let environ = self.environ.as_ref().map(|env| {
+            let mut vars = env.iter().collect::<Vec<_>>();
+            vars.sort();
+            vars
+        });
+        w.opt(environ, |w, vars| w.list(&vars, |w, (k, v)| {
+            w.bytes(k.as_bytes());
+            w.bytes(v.as_bytes());
+        }));
+        w.opt(self.config.work_dir.as_ref(), |w, x| w.bytes(x.as_bytes()));
+        w.opt(self.config.uid, Writer::u32);
+        w.opt(self.config.gid, Writer::u32);
+        w.opt(self.config.supplementary_gids.as_ref(),
+            |w, x| w.list(x, |w, &g| w.u32(g)));
+        w.u32(self.config.namespaces as u32);
+        w.opt(self.config.id_maps.as_ref(), |w, (uids, gids)| {
+            w.list(uids, |w, m| {
+                w.u32(m.inside_uid);
+                w.u32(m.outside_uid);
+                w.u32(m.count);
+            });
+            w.list(gids, |w, m| {
+                w.u32(m.inside_gid);
+                w.u32(m.outside_gid);
+                w.u32(m.count);
+            });
+        });
+        w.opt(self.chroot_dir.as_ref(),
+            |w, x| w.bytes(x.as_os_str().as_bytes()));
+        w.opt(self.pivot_root.as_ref(), |w, (new_root, put_old, unmount)| {
+            w.bytes(new_root.as_os_str().as_bytes());
+            w.bytes(put_old.as_os_str().as_bytes());
+            w.bool(*unmount);
+        });
+        w.opt(self.config.umask, Writer::u32);
+        w.bool(self.config.make_group_leader);
+        w.list(&self.labels.iter().collect::<Vec<_>>(), |w, (k, v)| {
+            w.bytes(k.as_bytes());
+            w.bytes(v.as_bytes());
+        });
+        base64_encode(&w.0)
+    }
+
+    fn decode_spec(data: &[u8]) -> io::Result<Command> {
+        let data = base64_decode(data)?;
+        let mut r = Reader(&data);
+        if r.take(1)?[0] != VERSION {
+            return Err(invalid("unsupported spec version"));
+        }
+        let to_path = |x: &[u8]| PathBuf::from(OsStr::from_bytes(x));
+        let mut cmd = Command::new(OsStr::from_bytes(r.c_string()?.as_bytes()));
+        cmd.args = r.list(Reader::c_string)?;
+        if cmd.args.is_empty() {
+            return Err(invalid("no argv[0] in spec"));
+        }
+        let environ = r.opt(|r| {
+            r.list(|r| Ok((r.os_string()?, r.os_string()?)))
+        })?;
+        cmd.environ = environ.map(|x| x.into_iter().collect::<HashMap<_, _>>());
+        cmd.config.work_dir = r.opt(Reader::c_string)?;
+        cmd.config.uid = r.opt(Reader::u32)?;
+        cmd.config.gid = r.opt(Reader::u32)?;
+        cmd.config.supplementary_gids = r.opt(|r| r.list(Reader::u32))?;
+        cmd.config.namespaces = r.u32()? as i32;
+        cmd.config.id_maps = r.opt(|r| {
+            let uids = r.list(|r| Ok(UidMap {
+                inside_uid: r.u32()?,
+                outside_uid: r.u32()?,
+                count: r.u32()?,
+            }))?;
+            let gids = r.list(|r| Ok(GidMap {
+                inside_gid: r.u32()?,
+                outside_gid: r.u32()?,
+                count: r.u32()?,
+            }))?;
+            Ok((uids, gids))
+        })?;
+        if let Some(dir) = r.opt(Reader::bytes)? {
+            cmd.try_chroot_dir(to_path(dir))
+                .map_err(|e| invalid(&e.to_string()))?;
+        }
+        if let Some((new_root, put_old, unmount)) = r.opt(|r| {
+            Ok((r.bytes()?, r.bytes()?, r.bool()?))
+        })? {
+            cmd.try_pivot_root(to_path(new_root), to_path(put_old), unmount)
+                .map_err(|e| invalid(&e.to_string()))?;
+        }
+        if let Some(mask) = r.opt(Reader::u32)? {
+            cmd.umask(mask);
+        }
+        cmd.config.make_group_leader = r.bool()?;
+        cmd.labels = r.list(|r| Ok((r.string()?, r.string()?)))?
+            .into_iter().collect::<BTreeMap<_, _>>();
+        if !r.0.is_empty() {
+            return Err(invalid("trailing data in spec"));
+        }
+        Ok(cmd)
+    }
+
+    /// Pass the spec of the `stage` command to the child in an environment
+    /// variable
+    ///
+    /// This is for multi-stage setups, where the child re-executes
+    /// `/proc/self/exe` and the second stage calls `from_spec_env` to
+    /// build the command from the spec. For example, the outer stage
+    /// creates a user namespace and the inner one, running there as root,
+    /// does mounts and spawns the program.
+    ///
+    /// The spec is versioned binary encoded with base64. It includes the
+    /// program, arguments, environment (if it's not inherited), working
+    /// directory, user, group and supplementary groups, unshared
+    /// namespaces, id maps, `chroot_dir`, `pivot_root`, `umask`,
+    /// `make_group_leader` and labels. Other settings, descriptors and
+    /// callbacks in particular, can't be passed this way and must be
+    /// configured by the second stage.
+    pub fn export_spec_env<K: AsRef<OsStr>>(&mut self, var: K,
+        stage: &Command)
+        -> &mut Command
+    {
+        let spec = stage.encode_spec();
+        self.env(var, spec)
+    }
+
+    /// Build the command from the spec passed by `export_spec_env`
+    ///
+    /// Returns `NotFound` if the variable is not set, and `InvalidData` if
+    /// it can't be decoded (e.g. it's written by an incompatible version of
+    /// the library).
+    pub fn from_spec_env<K: AsRef<OsStr>>(var: K) -> io::Result<Command> {
+        let value = env::var_os(var.as_ref()).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound,
+                           "spec environment variable is not set")
+        })?;
+        Command::decode_spec(value.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::io::{ErrorKind, Read};
+    use std::os::unix::ffi::OsStrExt;
+
+    use crate::{Command, Namespace, UidMap, GidMap};
+    use super::{base64_encode, base64_decode};
+
+    #[test]
+    fn test_base64() {
+        for (data, encoded) in [(&b""[..], ""), (b"f", "Zg=="),
+            (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"foob", "Zm9vYg==")]
+        {
+            assert_eq!(base64_encode(data), encoded);
+            assert_eq!(base64_decode(encoded.as_bytes()).unwrap(), data);
+        }
+        assert!(base64_decode(b"Zm9").is_err());
+        assert!(base64_decode(b"Z!9v").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut stage = Command::new("/bin/echo");
+        stage.arg("hello").arg0("echo").env_clear().env("A", "1")
+            .current_dir("/tmp").uid(1000).gid(1000).groups(vec![1, 2])
+            .unshare(&[Namespace::Mount, Namespace::Pid])
+            .set_id_maps(vec![UidMap { inside_uid: 0, outside_uid: 1000,
+                                       count: 1 }],
+                         vec![GidMap { inside_gid: 0, outside_gid: 1000,
+                                       count: 1 }])
+            .chroot_dir("/srv/root").pivot_root("/srv", "/srv/old", true)
+            .umask(0o027).make_group_leader(true).label("job", "1");
+        let spec = stage.encode_spec();
+        let cmd = Command::decode_spec(spec.as_bytes()).unwrap();
+        assert_eq!(cmd.encode_spec(), spec);
+        assert_eq!(format!("{:?}", cmd), format!("{:?}", stage));
+
+        let mut outer = Command::new("/bin/sh");
+        outer.arg("-c").arg("printf %s \"$SPEC\"")
+            .stdout(crate::Stdio::piped())
+            .export_spec_env("SPEC", &stage);
+        let mut child = outer.spawn().unwrap();
+        let mut output = Vec::new();
+        child.take_stdout().unwrap().read_to_end(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(OsStr::from_bytes(&output), OsStr::new(&spec));
+
+        assert_eq!(Command::decode_spec(b"AA==").unwrap_err().kind(),
+                   ErrorKind::InvalidData);
+        assert_eq!(Command::from_spec_env("UNSHARE_TEST_NO_SUCH_SPEC")
+                   .unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
@@ -1,4 +1,7 @@
 use std::fmt;
+
+use libc::c_int;
+
 use crate::{Signal};
 
 
@@ -6,6 +9,10 @@ use crate::{Signal};
 ///
 /// Returned either by `reap_zombies()` or by `child_events()`
 /// or by `Child::wait()`
+///
+/// The enumeration is exhaustive, as a dead process has either exited or
+/// was killed, so it's fine to match on it. Use the constructors (or the
+/// variants) to make values in tests without running real processes.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExitStatus {
     /// Process exited normally with some exit code
@@ -15,6 +22,43 @@ pub enum ExitStatus {
 }
 
 impl ExitStatus {
+    /// Status of a process exited with `code`
+    ///
+    /// Only the lowest 8 bits of the code are kept, just like the kernel
+    /// does for the code passed to `exit()`.
+    pub fn from_exit_code(code: i32) -> ExitStatus {
+        ExitStatus::Exited(code as i8)
+    }
+    /// Status of a process killed by the signal
+    pub fn from_signal(sig: Signal, core_dumped: bool) -> ExitStatus {
+        ExitStatus::Signaled(sig, core_dumped)
+    }
+    /// Decodes the status returned by `waitpid()` and alike
+    ///
+    /// Returns `None` if the status doesn't describe a dead process (i.e.
+    /// it's a stop or continue).
+    pub fn from_raw(status: c_int) -> Option<ExitStatus> {
+        if libc::WIFEXITED(status) {
+            Some(ExitStatus::from_exit_code(libc::WEXITSTATUS(status)))
+        } else if libc::WIFSIGNALED(status) {
+            Some(ExitStatus::Signaled(
+                Signal::from_raw(libc::WTERMSIG(status)),
+                libc::WCOREDUMP(status)))
+        } else {
+            None
+        }
+    }
+    /// Decodes the `siginfo_t` filled by `waitid()` for a dead process
+    pub(crate) fn from_siginfo(info: &libc::siginfo_t) -> ExitStatus {
+        let status = unsafe { info.si_status() };
+        match info.si_code {
+            libc::CLD_EXITED => ExitStatus::Exited(status as i8),
+            libc::CLD_DUMPED => {
+                ExitStatus::Signaled(Signal::from_raw(status), true)
+            }
+            _ => ExitStatus::Signaled(Signal::from_raw(status), false),
+        }
+    }
     /// Returns `true` if this exit status means successful exit
     pub fn success(&self) -> bool {
         self == &ExitStatus::Exited(0)
@@ -30,7 +74,7 @@ impl ExitStatus {
     pub fn signal(&self) -> Option<i32> {
         match self {
             &ExitStatus::Exited(_) => None,
-            &ExitStatus::Signaled(sig, _) => Some(sig as i32),
+            &ExitStatus::Signaled(sig, _) => Some(sig.as_raw()),
         }
     }
 }
@@ -42,12 +86,109 @@ impl fmt::Display for ExitStatus {
             &Exited(c) => write!(fmt, "exited with code {}", c),
             &Signaled(sig, false) => {
                 write!(fmt, "killed by signal {:?}[{}]",
-                    sig, sig as i32)
+                    sig, sig.as_raw())
             }
             &Signaled(sig, true) => {
                 write!(fmt, "killed by signal {:?}[{}] (core dumped)",
-                    sig, sig as i32)
+                    sig, sig.as_raw())
             }
         }
     }
 }
+
+/// Change of the process state returned by `Child::wait_with_options`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStatus {
+    /// Process exited normally with some exit code
+    Exited(i8),
+    /// Process was killed by a signal (bool flag is true when core is dumped)
+    Signaled(Signal, /* core dumped */bool),
+    /// Process was stopped by a signal (needs `WaitOptions::stopped`)
+    Stopped(Signal),
+    /// Stopped process was resumed by `SIGCONT` (needs
+    /// `WaitOptions::continued`)
+    Continued,
+}
+
+impl WaitStatus {
+    /// Returns exit status if the process is dead
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        match *self {
+            WaitStatus::Exited(c) => Some(ExitStatus::Exited(c)),
+            WaitStatus::Signaled(sig, core) => {
+                Some(ExitStatus::Signaled(sig, core))
+            }
+            WaitStatus::Stopped(_) | WaitStatus::Continued => None,
+        }
+    }
+}
+
+impl From<ExitStatus> for WaitStatus {
+    fn from(status: ExitStatus) -> WaitStatus {
+        match status {
+            ExitStatus::Exited(c) => WaitStatus::Exited(c),
+            ExitStatus::Signaled(sig, core) => WaitStatus::Signaled(sig, core),
+        }
+    }
+}
+
+impl fmt::Display for WaitStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WaitStatus::Stopped(sig) => {
+                write!(fmt, "stopped by signal {:?}[{}]", sig, sig.as_raw())
+            }
+            WaitStatus::Continued => write!(fmt, "continued"),
+            _ => self.exit_status().unwrap().fmt(fmt),
+        }
+    }
+}
+
+/// Which state changes `Child::wait_with_options` reports
+///
+/// By default, like `Child::wait`, blocks until the process exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WaitOptions {
+    flags: c_int,
+}
+
+impl WaitOptions {
+    /// Wait until the process exits
+    pub fn new() -> WaitOptions {
+        WaitOptions { flags: 0 }
+    }
+    /// Report stops (`WUNTRACED`)
+    pub fn stopped(self) -> WaitOptions {
+        WaitOptions { flags: self.flags | libc::WUNTRACED }
+    }
+    /// Report resuming of the stopped process (`WCONTINUED`)
+    pub fn continued(self) -> WaitOptions {
+        WaitOptions { flags: self.flags | libc::WCONTINUED }
+    }
+    /// Return `None` instead of blocking if there is nothing to report
+    /// (`WNOHANG`)
+    pub fn no_hang(self) -> WaitOptions {
+        WaitOptions { flags: self.flags | libc::WNOHANG }
+    }
+    pub(crate) fn flags(&self) -> c_int {
+        self.flags
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ExitStatus, Signal};
+
+    #[test]
+    fn test_constructors() {
+        assert!(ExitStatus::from_exit_code(0).success());
+        assert_eq!(ExitStatus::from_exit_code(256), ExitStatus::Exited(0));
+        assert_eq!(ExitStatus::from_signal(Signal::SIGTERM, false).signal(),
+                   Some(libc::SIGTERM));
+        assert_eq!(ExitStatus::from_raw(0x0300), Some(ExitStatus::Exited(3)));
+        assert_eq!(ExitStatus::from_raw(0x0089),
+                   Some(ExitStatus::Signaled(Signal::SIGKILL, true)));
+        // stopped by SIGSTOP
+        assert_eq!(ExitStatus::from_raw(0x137f), None);
+    }
+}
@@ -7,7 +7,7 @@
 //
 use std::ffi::OsStr;
 use std::default::Default;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::collections::HashSet;
 use std::env;
 use std::path::Path;
@@ -36,17 +36,51 @@ impl Command {
             config: Default::default(),
             chroot_dir: None,
             pivot_root: None,
+            copy_files: Vec::new(),
             fds: vec![
                 (0, Fd::inherit()),
                 (1, Fd::inherit()),
                 (2, Fd::inherit()),
                 ].into_iter().collect(),
             close_fds: Vec::new(),
-            id_map_commands: None,
+            keep_fds: Vec::new(),
+            exec_notify: None,
+            exec_fd: None,
+            id_map_writer: None,
             pid_env_vars: HashSet::new(),
+            env_templates: HashMap::new(),
+            default_path: None,
+            scrub_env: false,
+            env_files: Vec::new(),
+            preload: Vec::new(),
+            foreign_interpreter: None,
+            host_files: Vec::new(),
+            scratch: None,
+            idmapped_mounts: Vec::new(),
             keep_caps: None,
+            after_idmap: None,
             before_unfreeze: None,
+            finalize: None,
             pre_exec: None,
+            privileged_ops: None,
+            inherited_limits: None,
+            userspace_network: None,
+            mount_providers: Vec::new(),
+            kill_fds: Vec::new(),
+            namespace_timeout: None,
+            limiter: None,
+            donated_dirs: Vec::new(),
+            shutdown_fd: None,
+            listener_fd: None,
+            debug_syscalls: None,
+            seccomp_notify: None,
+            audit: None,
+            timer: None,
+            logger: None,
+            shared_memory: None,
+            labels: BTreeMap::new(),
+            #[cfg(feature="systemd")]
+            systemd_scope: None,
         }
     }
 
@@ -80,6 +114,7 @@ impl Command {
             key.as_ref().to_os_string(),
             val.as_ref().to_os_string());
         self.pid_env_vars.remove(key.as_ref());
+        self.env_templates.remove(key.as_ref());
         self
     }
 
@@ -93,6 +128,7 @@ impl Command {
                 key.as_ref().to_os_string(),
                 val.as_ref().to_os_string());
             self.pid_env_vars.remove(key.as_ref());
+            self.env_templates.remove(key.as_ref());
         }
         self
     }
@@ -102,13 +138,21 @@ impl Command {
         self.init_env_map();
         self.environ.as_mut().unwrap().remove(key.as_ref());
         self.pid_env_vars.remove(key.as_ref());
+        self.env_templates.remove(key.as_ref());
         self
     }
 
     /// Clears the entire environment map for the child process.
+    ///
+    /// The child then gets exactly the variables set afterwards: neither
+    /// `PATH` nor `HOME` nor anything else is added by the library (see
+    /// `env_default_path`). This also drops inherited variables of the
+    /// dynamic loader (`LD_*`), use `scrub_dangerous_env` to drop the ones
+    /// set explicitly.
     pub fn env_clear(&mut self) -> &mut Command {
         self.environ = Some(HashMap::new());
         self.pid_env_vars = HashSet::new();
+        self.env_templates = HashMap::new();
         self
     }
 
@@ -139,20 +183,17 @@ impl Command {
 
     /// Configuration for the child process's stdin handle (file descriptor 0).
     pub fn stdin(&mut self, cfg: Stdio) -> &mut Command {
-        self.fds.insert(0, cfg.to_fd(false));
-        self
+        self.fd(0, cfg)
     }
 
     /// Configuration for the child process's stdout handle (file descriptor 1).
     pub fn stdout(&mut self, cfg: Stdio) -> &mut Command {
-        self.fds.insert(1, cfg.to_fd(true));
-        self
+        self.fd(1, cfg)
     }
 
     /// Configuration for the child process's stderr handle (file descriptor 2).
     pub fn stderr(&mut self, cfg: Stdio) -> &mut Command {
-        self.fds.insert(2, cfg.to_fd(true));
-        self
+        self.fd(2, cfg)
     }
 
     /// Set user id of the new process. Note that it works only for root
@@ -1,10 +1,12 @@
+use std::ffi::CStr;
 use std::io;
+use std::mem;
 use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd};
 
-use nix;
-use nix::fcntl::{fcntl, FcntlArg};
 use libc;
 
+use crate::sys;
+
 
 /// An enumeration that is used to configure stdio file descritors
 ///
@@ -15,6 +17,8 @@ pub enum Stdio {
     Pipe,
     /// This fd will be inherited from the parent application
     Inherit,
+    /// Inherited if open in the parent, otherwise /dev/null is opened
+    InheritOrNull,
     /// This fd will open /dev/null in read or write mode
     Null,
     /// This is fd passed by application (and closed by `unshare`)
@@ -35,27 +39,73 @@ pub enum Fd {
     WritePipe,
     /// This fd is inherited from parent (current) process
     Inherit,
+    /// This fd is inherited if it's open, otherwise `/dev/null` is opened
+    InheritOrNull,
     /// This fd is redirected from `/dev/null`
     ReadNull,
     /// This fd is redirected to `/dev/null`
     WriteNull,
+    /// This fd is one end of a `SOCK_SEQPACKET` socket pair
+    SeqPacket,
     /// This is fd passed by application (and closed by `unshare`)
     Fd(Closing),
 }
 
+/// Configuration of any file descriptor, see `Command::fd`
+///
+/// Usually created from either `Stdio` or `Fd` by `into()`.
+pub enum FdConfig {
+    /// Direction of the pipe is deduced from the descriptor number
+    Stdio(Stdio),
+    /// The descriptor is configured exactly as specified
+    Fd(Fd),
+}
+
 pub struct Closing(RawFd);
 
 pub fn dup_file_cloexec<F: AsRawFd>(file: &F) -> io::Result<Closing> {
-    match fcntl(file.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(3)) {
-        Ok(fd) => Ok(Closing::new(fd)),
-        Err(nix::Error::Sys(errno)) => {
-            return Err(io::Error::from_raw_os_error(errno as i32));
+    sys::dup_cloexec(file.as_raw_fd(), 3).map(Closing::new)
+}
+
+/// Returns true if the descriptor is open
+pub fn is_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+/// Placeholders for stdio closed in the current process
+///
+/// Otherwise pipes opened by `spawn()` get numbers 0-2 and are clobbered
+/// by the `dup2` in the child. Placeholders are `/dev/null` with `CLOEXEC`
+/// flag, they are closed when the reserve is dropped.
+pub struct StdioReserve {
+    closed: [bool; 3],
+    _placeholders: Vec<Closing>,
+}
+
+impl StdioReserve {
+    pub fn new() -> io::Result<StdioReserve> {
+        let dev_null = CStr::from_bytes_with_nul(b"/dev/null\0").unwrap();
+        let mut closed = [false; 3];
+        let mut placeholders = Vec::new();
+        for fd in 0..3 {
+            if is_open(fd) {
+                continue;
+            }
+            // lower numbers are open, so the lowest free one is `fd`
+            // (unless it's just taken by another thread)
+            let new = Closing::new(
+                sys::open(dev_null, libc::O_RDWR|libc::O_CLOEXEC)?);
+            closed[fd as usize] = new.as_raw_fd() == fd;
+            placeholders.push(new);
         }
-        Err(nix::Error::InvalidPath) => unreachable!(),
-        Err(nix::Error::InvalidUtf8) => unreachable!(),
-        Err(nix::Error::UnsupportedOperation) => {
-            return Err(io::Error::new(io::ErrorKind::Other,
-                "nix error: unsupported operation"));
+        Ok(StdioReserve { closed, _placeholders: placeholders })
+    }
+
+    /// Returns true if the descriptor is closed in the current process
+    pub fn is_closed(&self, fd: RawFd) -> bool {
+        match fd {
+            0..=2 => self.closed[fd as usize],
+            _ => !is_open(fd),
         }
     }
 }
@@ -64,7 +114,15 @@ impl Stdio {
     /// Pipe is created for child process
     pub fn piped() -> Stdio { Stdio::Pipe }
     /// The child inherits file descriptor from the parent process
+    ///
+    /// If it's closed in the parent process, it's closed in the child too.
     pub fn inherit() -> Stdio { Stdio::Inherit }
+    /// The child inherits file descriptor, if it's open in the parent
+    /// process, otherwise the child gets `/dev/null`
+    ///
+    /// Unlike `inherit()`, this never leaves stdio of the child closed, so
+    /// the next file opened by the program doesn't become its stdout.
+    pub fn inherit_or_null() -> Stdio { Stdio::InheritOrNull }
     /// Stream is attached to `/dev/null`
     pub fn null() -> Stdio { Stdio::Null }
     /// Converts stdio definition to file descriptor definition
@@ -75,6 +133,7 @@ impl Stdio {
             (Stdio::Pipe, false) => Fd::ReadPipe,
             (Stdio::Pipe, true) => Fd::WritePipe,
             (Stdio::Inherit, _) => Fd::Inherit,
+            (Stdio::InheritOrNull, _) => Fd::InheritOrNull,
             (Stdio::Null, false) => Fd::ReadNull,
             (Stdio::Null, true) => Fd::WriteNull,
         }
@@ -102,10 +161,18 @@ impl Fd {
     ///
     /// Not very useful for custom file descriptors better use `from_file()`
     pub fn inherit() -> Fd { Fd::Inherit }
+    /// Inherit the descriptor if it's open, otherwise open `/dev/null`
+    pub fn inherit_or_null() -> Fd { Fd::InheritOrNull }
     /// Create a readable pipe that always has end of file condition
     pub fn read_null() -> Fd { Fd::ReadNull }
     /// Create a writable pipe that ignores all the input
     pub fn write_null() -> Fd { Fd::WriteNull }
+    /// Create a unix socket pair of `SOCK_SEQPACKET` type
+    ///
+    /// The other end is returned by `Child::take_socket`. Unlike pipes,
+    /// the socket is bidirectional, keeps message boundaries and can be
+    /// used to pass file descriptors.
+    pub fn seqpacket() -> Fd { Fd::SeqPacket }
     /// A simpler helper method for `from_raw_fd`, that does dup of file
     /// descriptor, so is actually safe to use (but can fail)
     pub fn dup_file<F: AsRawFd>(file: &F) -> io::Result<Fd> {
@@ -117,10 +184,27 @@ impl Fd {
     }
 }
 
+impl From<Stdio> for FdConfig {
+    fn from(cfg: Stdio) -> FdConfig {
+        FdConfig::Stdio(cfg)
+    }
+}
+
+impl From<Fd> for FdConfig {
+    fn from(cfg: Fd) -> FdConfig {
+        FdConfig::Fd(cfg)
+    }
+}
+
 impl Closing {
     pub fn new(fd: RawFd) -> Closing {
         Closing(fd)
     }
+    pub fn into_fd(self) -> RawFd {
+        let fd = self.0;
+        mem::forget(self);
+        fd
+    }
 }
 
 impl AsRawFd for Closing {
@@ -136,3 +220,60 @@ impl Drop for Closing {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+
+    use crate::{Command, Stdio};
+
+    /// Runs in the process started by `test_closed_stdio`, and does
+    /// nothing otherwise
+    #[test]
+    fn closed_stdio_inner() {
+        let marker = match env::var_os("UNSHARE_TEST_CLOSED_STDIO") {
+            Some(marker) => marker,
+            None => return,
+        };
+        // the runtime opens /dev/null for stdio that is closed on startup
+        for fd in 0..3 {
+            unsafe { libc::close(fd) };
+        }
+        let mut child = Command::new("/bin/sh")
+            .arg("-c").arg("read x; echo $x; echo err >&2")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn().unwrap();
+        child.take_stdin().unwrap().write_all(b"hello\n").unwrap();
+        let mut output = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut output).unwrap();
+        let mut errors = String::new();
+        child.take_stderr().unwrap().read_to_string(&mut errors).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!((&output[..], &errors[..]), ("hello\n", "err\n"));
+
+        let status = Command::new("/bin/sh")
+            .arg("-c").arg("test ! -e /proc/$$/fd/0 && \
+                            test \"$(readlink /proc/$$/fd/1)\" = /dev/null")
+            .stdout(Stdio::inherit_or_null())
+            .status().unwrap();
+        assert!(status.success());
+        fs::write(marker, b"").unwrap();
+    }
+
+    #[test]
+    fn test_closed_stdio() {
+        let marker = env::temp_dir().join(
+            format!("unshare-test-closed-stdio-{}", std::process::id()));
+        let status = Command::new("/proc/self/exe")
+            .arg("--exact").arg("stdio::test::closed_stdio_inner")
+            .env("UNSHARE_TEST_CLOSED_STDIO", &marker)
+            .stdout(Stdio::null()).stderr(Stdio::null())
+            .status().unwrap();
+        assert!(status.success());
+        fs::remove_file(&marker).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! Thin wrappers around system calls used in the parent process
+//!
+//! We only depend on `libc` for system calls, so that crate can be built
+//! without `nix` (see `nix` feature). Child-side code doesn't use these
+//! wrappers, as it must never allocate.
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use libc::{c_int, c_void, pid_t};
+
+use crate::Signal;
+
+
+/// Returns `errno` of the last failed system call
+///
+/// Doesn't allocate, so it's safe to use in the child
+pub fn errno() -> c_int {
+    unsafe { *libc::__errno_location() }
+}
+
+fn check(rc: c_int) -> io::Result<c_int> {
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(rc)
+    }
+}
+
+pub fn pipe2(flags: c_int) -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0 as c_int; 2];
+    check(unsafe { libc::pipe2(fds.as_mut_ptr(), flags) })?;
+    Ok((fds[0], fds[1]))
+}
+
+pub fn socketpair(domain: c_int, kind: c_int) -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0 as c_int; 2];
+    check(unsafe { libc::socketpair(domain, kind, 0, fds.as_mut_ptr()) })?;
+    Ok((fds[0], fds[1]))
+}
+
+pub fn open(path: &CStr, flags: c_int) -> io::Result<RawFd> {
+    check(unsafe { libc::open(path.as_ptr(), flags) })
+}
+
+/// Duplicate file descriptor to the lowest number larger than `floor`
+/// with `CLOEXEC` flag
+pub fn dup_cloexec(fd: RawFd, floor: RawFd) -> io::Result<RawFd> {
+    check(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, floor) })
+}
+
+pub fn setpgid(pid: pid_t, pgid: pid_t) -> io::Result<()> {
+    check(unsafe { libc::setpgid(pid, pgid) }).map(|_| ())
+}
+
+pub fn kill(pid: pid_t, sig: Signal) -> io::Result<()> {
+    check(unsafe { libc::kill(pid, sig.as_raw()) }).map(|_| ())
+}
+
+/// Open a pidfd of the process, with `CLOEXEC` flag (linux 5.3)
+pub fn pidfd_open(pid: pid_t) -> io::Result<RawFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd as RawFd)
+}
+
+/// Make current process a subreaper for its orphaned descendants
+pub fn set_child_subreaper() -> io::Result<()> {
+    check(unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) })
+        .map(|_| ())
+}
+
+pub type CloneCb<'a> = Box<dyn FnMut() -> isize + 'a>;
+
+/// Clones process with the callback run on the provided stack
+///
+/// Note: unlike `fork` the callback will never return in the parent process
+pub fn clone(mut cb: CloneCb, stack: &mut [u8], flags: c_int)
+    -> io::Result<pid_t>
+{
+    extern "C" fn callback(data: *mut c_void) -> c_int {
+        let cb: &mut CloneCb = unsafe { &mut *(data as *mut CloneCb) };
+        (*cb)() as c_int
+    }
+    unsafe {
+        let top = stack.as_mut_ptr().add(stack.len());
+        // stack must be aligned on most architectures
+        let top = top.sub(top as usize % 16);
+        check(libc::clone(callback, top as *mut c_void, flags,
+                          &mut cb as *mut CloneCb as *mut c_void))
+    }
+}
+
+/// Decoded status returned by `waitpid`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStatus {
+    Exited(pid_t, i32),
+    Signaled(pid_t, Signal, bool),
+    Stopped(pid_t, Signal),
+    PtraceEvent(pid_t, Signal, c_int),
+    PtraceSyscall(pid_t),
+    Continued(pid_t),
+    StillAlive,
+}
+
+impl WaitStatus {
+    fn from_raw(pid: pid_t, status: c_int) -> WaitStatus {
+        use self::WaitStatus::*;
+        if libc::WIFEXITED(status) {
+            Exited(pid, libc::WEXITSTATUS(status))
+        } else if libc::WIFSIGNALED(status) {
+            Signaled(pid, Signal::from_raw(libc::WTERMSIG(status)),
+                     libc::WCOREDUMP(status))
+        } else if libc::WIFSTOPPED(status) {
+            let sig = libc::WSTOPSIG(status);
+            if status >> 16 != 0 {
+                PtraceEvent(pid, Signal::from_raw(sig), status >> 16)
+            } else if sig == libc::SIGTRAP | 0x80 {
+                PtraceSyscall(pid)
+            } else {
+                Stopped(pid, Signal::from_raw(sig))
+            }
+        } else if libc::WIFCONTINUED(status) {
+            Continued(pid)
+        } else {
+            unreachable!("unknown wait status {}", status);
+        }
+    }
+}
+
+/// Waits for process `pid` (or any child if pid is `-1`)
+pub fn waitpid(pid: pid_t, flags: c_int) -> io::Result<WaitStatus> {
+    let mut status = 0;
+    let res = check(unsafe { libc::waitpid(pid, &mut status, flags) })?;
+    if res == 0 {
+        Ok(WaitStatus::StillAlive)
+    } else {
+        Ok(WaitStatus::from_raw(res, status))
+    }
+}
@@ -0,0 +1,192 @@
+//! Names of system calls and error codes for `Command::debug_syscalls`
+use libc::{c_int, c_long};
+
+
+/// Description of a system call
+#[derive(Debug, Clone, Copy)]
+pub struct Syscall {
+    pub name: &'static str,
+    pub nargs: usize,
+    /// Indexes of the arguments that are nul-terminated strings
+    pub strings: &'static [usize],
+}
+
+macro_rules! syscalls {
+    ($fn:ident: $($name:ident $nargs:literal $([$($s:literal),*])*),* $(,)*)
+    => {
+        fn $fn(nr: c_long) -> Option<Syscall> {
+            match nr {
+                $(libc::$name => Some(Syscall {
+                    // strip the `SYS_` prefix
+                    name: stringify!($name).split_at(4).1,
+                    nargs: $nargs,
+                    strings: &[$($($s),*)*],
+                }),)*
+                _ => None,
+            }
+        }
+    }
+}
+
+macro_rules! errnos {
+    ($($name:ident),* $(,)*) => {
+        /// Returns name of the error code like `ENOENT`
+        pub fn errno_name(errno: c_int) -> Option<&'static str> {
+            match errno {
+                $(libc::$name => Some(stringify!($name)),)*
+                _ => None,
+            }
+        }
+    }
+}
+
+errnos!(
+    EPERM, ENOENT, ESRCH, EINTR, EIO, ENXIO, E2BIG, ENOEXEC, EBADF, ECHILD,
+    EAGAIN, ENOMEM, EACCES, EFAULT, ENOTBLK, EBUSY, EEXIST, EXDEV, ENODEV,
+    ENOTDIR, EISDIR, EINVAL, ENFILE, EMFILE, ENOTTY, ETXTBSY, EFBIG, ENOSPC,
+    ESPIPE, EROFS, EMLINK, EPIPE, EDOM, ERANGE, EDEADLK, ENAMETOOLONG,
+    ENOLCK, ENOSYS, ENOTEMPTY, ELOOP, ENOMSG, ENODATA, ETIME, ENOLINK,
+    EPROTO, EOVERFLOW, EILSEQ, ENOTSOCK, EDESTADDRREQ, EMSGSIZE,
+    EPROTOTYPE, ENOPROTOOPT, EPROTONOSUPPORT, EOPNOTSUPP, EAFNOSUPPORT,
+    EADDRINUSE, EADDRNOTAVAIL, ENETDOWN, ENETUNREACH, ECONNABORTED,
+    ECONNRESET, ENOBUFS, EISCONN, ENOTCONN, ETIMEDOUT, ECONNREFUSED,
+    EHOSTUNREACH, EALREADY, EINPROGRESS, ESTALE, EDQUOT, ECANCELED,
+    ENOKEY, EKEYEXPIRED, EKEYREVOKED, EKEYREJECTED,
+);
+
+/// Returns description of the system call, if it's known on this platform
+pub fn lookup(nr: c_long) -> Option<Syscall> {
+    #[cfg(target_arch="x86_64")]
+    { common(nr).or_else(|| legacy(nr)) }
+    #[cfg(target_arch="aarch64")]
+    { common(nr) }
+    #[cfg(not(any(target_arch="x86_64", target_arch="aarch64")))]
+    { let _ = nr; None }
+}
+
+// System calls that are present both on x86_64 and on architectures using
+// generic system call table (which lacks the legacy ones like `open`)
+#[cfg(any(target_arch="x86_64", target_arch="aarch64"))]
+syscalls!(common:
+    SYS_accept 3, SYS_accept4 4, SYS_acct 1 [0], SYS_add_key 5,
+    SYS_adjtimex 1, SYS_bind 3, SYS_bpf 3, SYS_brk 1, SYS_capget 2,
+    SYS_capset 2, SYS_chdir 1 [0], SYS_chroot 1 [0], SYS_clock_adjtime 2,
+    SYS_clock_getres 2, SYS_clock_gettime 2, SYS_clock_nanosleep 4,
+    SYS_clock_settime 2, SYS_clone 5, SYS_clone3 2, SYS_close 1,
+    SYS_close_range 3, SYS_connect 3, SYS_copy_file_range 6,
+    SYS_delete_module 2 [0], SYS_dup 1, SYS_dup3 3, SYS_epoll_create1 1,
+    SYS_epoll_ctl 4, SYS_epoll_pwait 6, SYS_epoll_pwait2 6, SYS_eventfd2 2,
+    SYS_execve 3 [0], SYS_execveat 5 [1], SYS_exit 1, SYS_exit_group 1,
+    SYS_faccessat 3 [1], SYS_faccessat2 4 [1], SYS_fadvise64 4,
+    SYS_fallocate 4, SYS_fanotify_init 2, SYS_fanotify_mark 5 [4],
+    SYS_fchdir 1, SYS_fchmod 2, SYS_fchmodat 3 [1], SYS_fchown 3,
+    SYS_fchownat 5 [1], SYS_fcntl 3, SYS_fdatasync 1, SYS_fgetxattr 4 [1],
+    SYS_finit_module 3, SYS_flistxattr 3, SYS_flock 2,
+    SYS_fremovexattr 2 [1], SYS_fsconfig 5 [2], SYS_fsetxattr 5 [1],
+    SYS_fsmount 3, SYS_fsopen 2 [0], SYS_fspick 3 [1], SYS_fstat 2,
+    SYS_fstatfs 2, SYS_fsync 1, SYS_ftruncate 2, SYS_futex 6,
+    SYS_futex_waitv 5, SYS_get_mempolicy 5, SYS_get_robust_list 3,
+    SYS_getcpu 3, SYS_getcwd 2, SYS_getdents64 3, SYS_getegid 0,
+    SYS_geteuid 0, SYS_getgid 0, SYS_getgroups 2, SYS_getitimer 2,
+    SYS_getpeername 3, SYS_getpgid 1, SYS_getpid 0, SYS_getppid 0,
+    SYS_getpriority 2, SYS_getrandom 3, SYS_getresgid 3, SYS_getresuid 3,
+    SYS_getrusage 2, SYS_getsid 1, SYS_getsockname 3, SYS_getsockopt 5,
+    SYS_gettid 0, SYS_gettimeofday 2, SYS_getuid 0, SYS_getxattr 4 [0, 1],
+    SYS_init_module 3 [2], SYS_inotify_add_watch 3 [1],
+    SYS_inotify_init1 1, SYS_inotify_rm_watch 2, SYS_io_cancel 3,
+    SYS_io_destroy 1, SYS_io_getevents 5, SYS_io_setup 2, SYS_io_submit 3,
+    SYS_io_uring_enter 6, SYS_io_uring_register 4, SYS_io_uring_setup 2,
+    SYS_ioctl 3, SYS_ioprio_get 2, SYS_ioprio_set 3, SYS_kcmp 5,
+    SYS_kexec_load 4, SYS_keyctl 5, SYS_kill 2, SYS_landlock_add_rule 4,
+    SYS_landlock_create_ruleset 3, SYS_landlock_restrict_self 2,
+    SYS_lgetxattr 4 [0, 1], SYS_linkat 5 [1, 3], SYS_listen 2,
+    SYS_listxattr 3 [0], SYS_llistxattr 3 [0], SYS_lookup_dcookie 3,
+    SYS_lremovexattr 2 [0, 1], SYS_lseek 3, SYS_lsetxattr 5 [0, 1],
+    SYS_madvise 3, SYS_mbind 6, SYS_membarrier 3, SYS_memfd_create 2 [0],
+    SYS_memfd_secret 1, SYS_migrate_pages 4, SYS_mincore 3,
+    SYS_mkdirat 3 [1], SYS_mknodat 4 [1], SYS_mlock 2, SYS_mlock2 3,
+    SYS_mlockall 1, SYS_mmap 6, SYS_mount 5 [0, 1, 2],
+    SYS_mount_setattr 5 [1], SYS_move_mount 5 [1, 3], SYS_move_pages 6,
+    SYS_mprotect 3, SYS_mq_getsetattr 3, SYS_mq_notify 2,
+    SYS_mq_open 4 [0], SYS_mq_timedreceive 5, SYS_mq_timedsend 5,
+    SYS_mq_unlink 1 [0], SYS_mremap 5, SYS_mseal 3, SYS_msgctl 3,
+    SYS_msgget 2, SYS_msgrcv 5, SYS_msgsnd 4, SYS_msync 3, SYS_munlock 2,
+    SYS_munlockall 0, SYS_munmap 2, SYS_name_to_handle_at 5 [1],
+    SYS_nanosleep 2, SYS_newfstatat 4 [1], SYS_nfsservctl 3,
+    SYS_open_by_handle_at 3, SYS_open_tree 3 [1], SYS_openat 4 [1],
+    SYS_openat2 4 [1], SYS_perf_event_open 5, SYS_personality 1,
+    SYS_pidfd_getfd 3, SYS_pidfd_open 2, SYS_pidfd_send_signal 4,
+    SYS_pipe2 2, SYS_pivot_root 2 [0, 1], SYS_pkey_alloc 2, SYS_pkey_free 1,
+    SYS_pkey_mprotect 4, SYS_ppoll 5, SYS_prctl 5, SYS_pread64 4,
+    SYS_preadv 5, SYS_preadv2 6, SYS_prlimit64 4, SYS_process_madvise 5,
+    SYS_process_mrelease 2, SYS_process_vm_readv 6, SYS_process_vm_writev 6,
+    SYS_pselect6 6, SYS_ptrace 4, SYS_pwrite64 4, SYS_pwritev 5,
+    SYS_pwritev2 6, SYS_quotactl 4 [1], SYS_quotactl_fd 4, SYS_read 3,
+    SYS_readahead 3, SYS_readlinkat 4 [1], SYS_readv 3, SYS_reboot 4,
+    SYS_recvfrom 6, SYS_recvmmsg 5, SYS_recvmsg 3, SYS_remap_file_pages 5,
+    SYS_removexattr 2 [0, 1], SYS_renameat2 5 [1, 3], SYS_request_key 4,
+    SYS_restart_syscall 0, SYS_rseq 4, SYS_rt_sigaction 4,
+    SYS_rt_sigpending 2, SYS_rt_sigprocmask 4, SYS_rt_sigqueueinfo 3,
+    SYS_rt_sigreturn 0, SYS_rt_sigsuspend 2, SYS_rt_sigtimedwait 4,
+    SYS_rt_tgsigqueueinfo 4, SYS_sched_get_priority_max 1,
+    SYS_sched_get_priority_min 1, SYS_sched_getaffinity 3,
+    SYS_sched_getattr 4, SYS_sched_getparam 2, SYS_sched_getscheduler 1,
+    SYS_sched_rr_get_interval 2, SYS_sched_setaffinity 3,
+    SYS_sched_setattr 3, SYS_sched_setparam 2, SYS_sched_setscheduler 3,
+    SYS_sched_yield 0, SYS_seccomp 3, SYS_semctl 4, SYS_semget 3,
+    SYS_semop 3, SYS_semtimedop 4, SYS_sendfile 4, SYS_sendmmsg 4,
+    SYS_sendmsg 3, SYS_sendto 6, SYS_set_mempolicy 3,
+    SYS_set_mempolicy_home_node 4, SYS_set_robust_list 2,
+    SYS_set_tid_address 1, SYS_setdomainname 2, SYS_setfsgid 1,
+    SYS_setfsuid 1, SYS_setgid 1, SYS_setgroups 2, SYS_sethostname 2,
+    SYS_setitimer 3, SYS_setns 2, SYS_setpgid 2, SYS_setpriority 3,
+    SYS_setregid 2, SYS_setresgid 3, SYS_setresuid 3, SYS_setreuid 2,
+    SYS_setsid 0, SYS_setsockopt 5, SYS_settimeofday 2, SYS_setuid 1,
+    SYS_setxattr 5 [0, 1], SYS_shmat 3, SYS_shmctl 3, SYS_shmdt 1,
+    SYS_shmget 3, SYS_shutdown 2, SYS_sigaltstack 2, SYS_signalfd4 4,
+    SYS_socket 3, SYS_socketpair 4, SYS_splice 6, SYS_statfs 2 [0],
+    SYS_statx 5 [1], SYS_swapoff 1 [0], SYS_swapon 2 [0],
+    SYS_symlinkat 3 [0, 2], SYS_sync 0, SYS_syncfs 1, SYS_sysinfo 1,
+    SYS_syslog 3, SYS_tee 4, SYS_tgkill 3, SYS_timer_create 3,
+    SYS_timer_delete 1, SYS_timer_getoverrun 1, SYS_timer_gettime 2,
+    SYS_timer_settime 4, SYS_timerfd_create 2, SYS_timerfd_gettime 2,
+    SYS_timerfd_settime 4, SYS_times 1, SYS_tkill 2, SYS_truncate 2 [0],
+    SYS_umask 1, SYS_umount2 2 [0], SYS_uname 1, SYS_unlinkat 3 [1],
+    SYS_unshare 1, SYS_userfaultfd 1, SYS_utimensat 4 [1], SYS_vhangup 0,
+    SYS_vmsplice 4, SYS_wait4 4, SYS_waitid 5, SYS_write 3, SYS_writev 3,
+);
+
+#[cfg(target_arch="x86_64")]
+syscalls!(legacy:
+    SYS_access 2 [0], SYS_alarm 1, SYS_arch_prctl 2, SYS_chmod 2 [0],
+    SYS_chown 3 [0], SYS_creat 2 [0], SYS_dup2 2, SYS_epoll_create 1,
+    SYS_epoll_wait 4, SYS_eventfd 1, SYS_fchmodat2 4 [1], SYS_fork 0,
+    SYS_futimesat 3 [1], SYS_get_thread_area 1, SYS_getdents 3,
+    SYS_getpgrp 0, SYS_getrlimit 2, SYS_inotify_init 0, SYS_ioperm 3,
+    SYS_iopl 1, SYS_kexec_file_load 5, SYS_lchown 3 [0], SYS_link 2 [0, 1],
+    SYS_lstat 2 [0], SYS_mkdir 2 [0], SYS_mknod 3 [0], SYS_modify_ldt 3,
+    SYS_open 3 [0], SYS_pause 0, SYS_pipe 1, SYS_poll 3,
+    SYS_readlink 3 [0], SYS_rename 2 [0, 1], SYS_renameat 4 [1, 3],
+    SYS_rmdir 1 [0], SYS_select 5, SYS_set_thread_area 1,
+    SYS_setrlimit 2, SYS_signalfd 3, SYS_stat 2 [0], SYS_symlink 2 [0, 1],
+    SYS_sync_file_range 4, SYS_sysfs 3, SYS_time 1, SYS_unlink 1 [0],
+    SYS_uselib 1 [0], SYS_ustat 2, SYS_utime 2 [0], SYS_utimes 2 [0],
+    SYS_vfork 0,
+);
+
+#[cfg(test)]
+mod test {
+    use super::{lookup, errno_name};
+
+    #[test]
+    #[cfg(any(target_arch="x86_64", target_arch="aarch64"))]
+    fn test_names() {
+        let openat = lookup(libc::SYS_openat).unwrap();
+        assert_eq!(openat.name, "openat");
+        assert_eq!(openat.nargs, 4);
+        assert_eq!(openat.strings, &[1]);
+        assert_eq!(lookup(100000).map(|s| s.name), None);
+        assert_eq!(errno_name(libc::ENOENT), Some("ENOENT"));
+        assert_eq!(errno_name(0), None);
+    }
+}
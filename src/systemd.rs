@@ -0,0 +1,571 @@
+//! Registering children as systemd scope units (the `systemd` feature)
+//!
+//! This is a minimal D-Bus client: it only does the EXTERNAL
+//! authentication over a unix socket, and method calls with the arguments
+//! needed for `StartTransientUnit`.
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use libc::pid_t;
+
+use crate::Command;
+
+
+const SYSTEMD_PRIVATE: &str = "/run/systemd/private";
+const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Limit of the message size, from the D-Bus specification
+const MAX_MESSAGE: usize = 1 << 27;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Value of a unit property, see `Command::register_systemd_scope`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnitValue {
+    /// Boolean (`b`), e.g. `Delegate`
+    Bool(bool),
+    /// 32-bit unsigned integer (`u`)
+    U32(u32),
+    /// 64-bit unsigned integer (`t`), e.g. `MemoryMax` or `TasksMax`
+    U64(u64),
+    /// String (`s`), e.g. `Description`
+    Str(String),
+}
+
+/// Scope unit configured by `register_systemd_scope`
+#[derive(Debug, Clone)]
+pub(crate) struct Scope {
+    name: String,
+    slice: Option<String>,
+    props: Vec<(String, UnitValue)>,
+}
+
+impl Command {
+    /// Put the child into a new transient systemd scope unit
+    ///
+    /// While the child is frozen (right after the `cgroup` placement), the
+    /// scope is started by the `StartTransientUnit` call to the systemd
+    /// manager over D-Bus, with the child as its only process, so the
+    /// program and all its descendants run in the cgroup of the scope,
+    /// with the resource control `props` applied (e.g.
+    /// `("MemoryMax", UnitValue::U64(1 << 30))`). The `.scope` suffix is
+    /// added to the `name` unless present, `slice` is the `Slice` property
+    /// (e.g. `"sandbox.slice"`), the default slice of systemd is used if
+    /// `None`.
+    ///
+    /// The scope is garbage collected by systemd when all its processes
+    /// exit, even if it fails (`CollectMode=inactive-or-failed` is set
+    /// unless in `props`). The `spawn()` fails with `Error::SystemdScope`
+    /// if systemd rejects the unit (e.g. the name is already used), or the
+    /// child isn't moved to the scope in 5 seconds.
+    ///
+    /// The manager is reached through `/run/systemd/private` when running
+    /// as root, or through the system bus (`DBUS_SYSTEM_BUS_ADDRESS`, if
+    /// set). Unprivileged processes need a polkit rule allowing them to
+    /// manage units.
+    ///
+    /// Each invocation **replaces** previously set scope.
+    pub fn register_systemd_scope(&mut self, name: &str, slice: Option<&str>,
+        props: Vec<(String, UnitValue)>)
+        -> &mut Command
+    {
+        let name = if name.ends_with(".scope") {
+            name.to_string()
+        } else {
+            format!("{}.scope", name)
+        };
+        self.systemd_scope = Some(Scope {
+            name,
+            slice: slice.map(|x| x.to_string()),
+            props,
+        });
+        self
+    }
+}
+
+/// Message data in the D-Bus wire format (in native byte order)
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn align(&mut self, n: usize) {
+        self.0.resize(self.0.len().next_multiple_of(n), 0);
+    }
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.0.extend_from_slice(&value.to_ne_bytes());
+    }
+    fn u64(&mut self, value: u64) {
+        self.align(8);
+        self.0.extend_from_slice(&value.to_ne_bytes());
+    }
+    fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+    fn sig(&mut self, value: &str) {
+        self.u8(value.len() as u8);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+    /// Writes an array which elements (aligned to `align`) are written by
+    /// the function
+    fn array(&mut self, align: usize, elements: impl FnOnce(&mut Writer)) {
+        self.u32(0);
+        let len_pos = self.0.len() - 4;
+        self.align(align);
+        let start = self.0.len();
+        elements(self);
+        let len = (self.0.len() - start) as u32;
+        self.0[len_pos..len_pos+4].copy_from_slice(&len.to_ne_bytes());
+    }
+    fn variant(&mut self, value: &UnitValue) {
+        match *value {
+            UnitValue::Bool(x) => { self.sig("b"); self.u32(x as u32) }
+            UnitValue::U32(x) => { self.sig("u"); self.u32(x) }
+            UnitValue::U64(x) => { self.sig("t"); self.u64(x) }
+            UnitValue::Str(ref x) => { self.sig("s"); self.str(x) }
+        }
+    }
+    /// Writes the header field with the string value
+    fn field(&mut self, code: u8, sig: &str, value: &str) {
+        self.align(8);
+        self.u8(code);
+        self.sig(sig);
+        if sig == "g" {
+            self.sig(value);
+        } else {
+            self.str(value);
+        }
+    }
+}
+
+/// Parses message data written in the byte order of the sender
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("invalid D-Bus message: {}", message))
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.next_multiple_of(n);
+    }
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let data = self.data.get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("truncated"))?;
+        self.pos += len;
+        Ok(data)
+    }
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        self.align(4);
+        let mut value = [0u8; 4];
+        value.copy_from_slice(self.bytes(4)?);
+        Ok(if self.big_endian {
+            u32::from_be_bytes(value)
+        } else {
+            u32::from_le_bytes(value)
+        })
+    }
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let value = self.bytes(len + 1)?;
+        Ok(String::from_utf8_lossy(&value[..len]).into_owned())
+    }
+    fn sig(&mut self) -> io::Result<String> {
+        let len = self.u8()? as usize;
+        let value = self.bytes(len + 1)?;
+        Ok(String::from_utf8_lossy(&value[..len]).into_owned())
+    }
+}
+
+/// Header fields and the body of a received message
+#[derive(Debug, Default)]
+struct Message {
+    kind: u8,
+    reply_serial: Option<u32>,
+    error_name: Option<String>,
+    signature: String,
+    body: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Message {
+    /// The first argument, if it's a string
+    fn first_str(&self) -> Option<String> {
+        if !self.signature.starts_with(['s', 'o']) {
+            return None;
+        }
+        Reader { data: &self.body, pos: 0, big_endian: self.big_endian }
+            .str().ok()
+    }
+}
+
+fn read_message(stream: &mut UnixStream) -> io::Result<Message> {
+    let mut fixed = [0u8; 16];
+    stream.read_exact(&mut fixed)?;
+    let big_endian = match fixed[0] {
+        b'l' => false,
+        b'B' => true,
+        _ => return Err(invalid("wrong byte order")),
+    };
+    let mut reader = Reader { data: &fixed, pos: 4, big_endian };
+    let body_len = reader.u32()? as usize;
+    reader.u32()?;  // serial
+    let fields_len = reader.u32()? as usize;
+    let header_len = (16 + fields_len).next_multiple_of(8);
+    if header_len + body_len > MAX_MESSAGE {
+        return Err(invalid("too long"));
+    }
+    let mut data = fixed.to_vec();
+    data.resize(header_len + body_len, 0);
+    stream.read_exact(&mut data[16..])?;
+    let mut message = Message {
+        kind: fixed[1],
+        big_endian,
+        ..Message::default()
+    };
+    let mut reader = Reader { data: &data[..16 + fields_len], pos: 16,
+                              big_endian };
+    while reader.pos < 16 + fields_len {
+        reader.align(8);
+        let code = reader.u8()?;
+        match &reader.sig()?[..] {
+            "s" | "o" => {
+                let value = reader.str()?;
+                if code == FIELD_ERROR_NAME {
+                    message.error_name = Some(value);
+                }
+            }
+            "g" => {
+                let value = reader.sig()?;
+                if code == FIELD_SIGNATURE {
+                    message.signature = value;
+                }
+            }
+            "u" => {
+                let value = reader.u32()?;
+                if code == FIELD_REPLY_SERIAL {
+                    message.reply_serial = Some(value);
+                }
+            }
+            _ => return Err(invalid("unknown header field type")),
+        }
+    }
+    message.body = data.split_off(header_len);
+    Ok(message)
+}
+
+struct Connection {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl Connection {
+    /// Connects to systemd like `systemctl` does
+    fn open() -> io::Result<Connection> {
+        if let Some(address) = env::var_os("DBUS_SYSTEM_BUS_ADDRESS") {
+            let address = address.to_string_lossy().into_owned();
+            let path = address.split(',')
+                .find_map(|x| x.strip_prefix("unix:path="))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("unsupported D-Bus address {:?}", address)))?;
+            return Connection::connect(Path::new(path), true);
+        }
+        match Connection::connect(Path::new(SYSTEMD_PRIVATE), false) {
+            Ok(conn) => Ok(conn),
+            Err(_) => Connection::connect(Path::new(SYSTEM_BUS), true),
+        }
+    }
+
+    /// Authenticates and, if it's a bus (not a direct connection to
+    /// systemd), registers on the bus
+    fn connect(path: &Path, bus: bool) -> io::Result<Connection> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut conn = Connection { stream, serial: 0 };
+        let uid = unsafe { libc::geteuid() }.to_string();
+        let hex = uid.bytes().map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        conn.stream.write_all(
+            format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())?;
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while !line.ends_with(b"\r\n") && line.len() < 512 {
+            conn.stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        if !line.starts_with(b"OK ") {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                format!("D-Bus authentication failed: {}",
+                        String::from_utf8_lossy(&line).trim_end())));
+        }
+        conn.stream.write_all(b"BEGIN\r\n")?;
+        if bus {
+            conn.call(Some("org.freedesktop.DBus"), "/org/freedesktop/DBus",
+                      "org.freedesktop.DBus", "Hello", "", &[])?;
+        }
+        Ok(conn)
+    }
+
+    /// Calls the method and waits for the reply, skipping other messages
+    fn call(&mut self, destination: Option<&str>, path: &str, interface: &str,
+        member: &str, signature: &str, body: &[u8])
+        -> io::Result<Message>
+    {
+        self.serial += 1;
+        let byte_order = if cfg!(target_endian="big") { b'B' } else { b'l' };
+        let mut msg = Writer(Vec::new());
+        msg.u8(byte_order);
+        msg.u8(METHOD_CALL);
+        msg.u8(0);  // flags
+        msg.u8(1);  // protocol version
+        msg.u32(body.len() as u32);
+        msg.u32(self.serial);
+        msg.array(8, |msg| {
+            msg.field(FIELD_PATH, "o", path);
+            msg.field(FIELD_INTERFACE, "s", interface);
+            msg.field(FIELD_MEMBER, "s", member);
+            if let Some(destination) = destination {
+                msg.field(FIELD_DESTINATION, "s", destination);
+            }
+            if !signature.is_empty() {
+                msg.field(FIELD_SIGNATURE, "g", signature);
+            }
+        });
+        msg.align(8);
+        msg.0.extend_from_slice(body);
+        self.stream.write_all(&msg.0)?;
+        loop {
+            let reply = read_message(&mut self.stream)?;
+            if reply.reply_serial != Some(self.serial) {
+                continue;
+            }
+            match reply.kind {
+                METHOD_RETURN => return Ok(reply),
+                ERROR => {
+                    return Err(io::Error::other(format!("{}: {}",
+                        reply.error_name.as_deref().unwrap_or("error"),
+                        reply.first_str().unwrap_or_default())));
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// Body of `StartTransientUnit` call creating the scope with the process
+fn start_unit_body(pid: pid_t, scope: &Scope) -> Vec<u8> {
+    let mut body = Writer(Vec::new());
+    body.str(&scope.name);
+    body.str("fail");
+    body.array(8, |body| {
+        let mut prop = |name: &str, value: &UnitValue| {
+            body.align(8);
+            body.str(name);
+            body.variant(value);
+        };
+        if let Some(ref slice) = scope.slice {
+            prop("Slice", &UnitValue::Str(slice.clone()));
+        }
+        if !scope.props.iter().any(|(name, _)| name == "CollectMode") {
+            prop("CollectMode", &UnitValue::Str("inactive-or-failed".into()));
+        }
+        for (name, value) in &scope.props {
+            prop(name, value);
+        }
+        body.align(8);
+        body.str("PIDs");
+        body.sig("au");
+        body.array(4, |body| body.u32(pid as u32));
+    });
+    // auxiliary units
+    body.array(8, |_| {});
+    body.0
+}
+
+/// Starts the scope and waits until the process is moved there
+pub(crate) fn start_scope(pid: pid_t, scope: &Scope) -> io::Result<()> {
+    let mut conn = Connection::open()?;
+    conn.call(Some("org.freedesktop.systemd1"), "/org/freedesktop/systemd1",
+              "org.freedesktop.systemd1.Manager", "StartTransientUnit",
+              "ssa(sv)a(sa(sv))", &start_unit_body(pid, scope))?;
+    // the job moving the process is asynchronous
+    let suffix = format!("/{}", scope.name);
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+        if cgroups.lines().any(|line| line.ends_with(&suffix)) {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                format!("process is not moved to {} in time", scope.name)));
+        }
+        sleep(Duration::from_millis(5));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::thread;
+
+    use crate::{Command, Error, Namespace, Stdio};
+    use crate::freeze::cgroup2_mount;
+    use super::{Writer, Reader, UnitValue, read_message};
+    use super::{METHOD_RETURN, ERROR, FIELD_REPLY_SERIAL};
+    use super::{FIELD_ERROR_NAME, FIELD_SIGNATURE};
+
+    fn reply(stream: &mut UnixStream, kind: u8, serial: u32,
+        error: Option<&str>, signature: &str, value: &str)
+    {
+        let mut body = Writer(Vec::new());
+        body.str(value);
+        let mut msg = Writer(Vec::new());
+        msg.u8(if cfg!(target_endian="big") { b'B' } else { b'l' });
+        msg.u8(kind);
+        msg.u8(0);
+        msg.u8(1);
+        msg.u32(body.0.len() as u32);
+        msg.u32(serial + 1000);
+        msg.array(8, |msg| {
+            msg.align(8);
+            msg.u8(FIELD_REPLY_SERIAL);
+            msg.sig("u");
+            msg.u32(serial);
+            if let Some(error) = error {
+                msg.field(FIELD_ERROR_NAME, "s", error);
+            }
+            msg.field(FIELD_SIGNATURE, "g", signature);
+        });
+        msg.align(8);
+        msg.0.extend_from_slice(&body.0);
+        stream.write_all(&msg.0).unwrap();
+    }
+
+    /// Plays both the bus and systemd: moves the process into the cgroup
+    /// named as the unit, or fails if the name is `taken.scope`
+    fn fake_systemd(mut stream: UnixStream) {
+        let mut auth = [0u8; 1];
+        let mut line = Vec::new();
+        while !line.ends_with(b"BEGIN\r\n") {
+            stream.read_exact(&mut auth).unwrap();
+            line.push(auth[0]);
+            if line.ends_with(b"\r\n") && line.starts_with(b"\0AUTH") {
+                stream.write_all(b"OK 0123456789abcdef\r\n").unwrap();
+                line.clear();
+            }
+        }
+        // serials of the client are sequential
+        read_message(&mut stream).unwrap();
+        reply(&mut stream, METHOD_RETURN, 1, None, "s", ":1.1");
+        let call = read_message(&mut stream).unwrap();
+        assert_eq!(call.signature, "ssa(sv)a(sa(sv))");
+        let name = call.first_str().unwrap();
+        if name == "taken.scope" {
+            reply(&mut stream, ERROR, 2,
+                  Some("org.freedesktop.systemd1.UnitExists"), "s",
+                  "Unit taken.scope already exists.");
+            return;
+        }
+        let marker = b"PIDs\0\x02au\0";
+        let pos = call.body.windows(marker.len())
+            .position(|x| x == marker).unwrap() + marker.len();
+        let mut reader = Reader { data: &call.body, pos,
+                                  big_endian: call.big_endian };
+        reader.u32().unwrap();  // array length
+        let pid = reader.u32().unwrap();
+        let dir = cgroup2_mount().unwrap().join(&name);
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("cgroup.procs"), pid.to_string()).unwrap();
+        reply(&mut stream, METHOD_RETURN, 2, None, "o",
+              "/org/freedesktop/systemd1/job/1");
+    }
+
+    #[test]
+    fn test_systemd_scope() {
+        let mount = match cgroup2_mount() {
+            Some(mount) => mount,
+            None => return,
+        };
+        let name = format!("unshare-test-{}", std::process::id());
+        let probe = mount.join(&name);
+        match fs::create_dir(&probe) {
+            Ok(()) => {}
+            // unprivileged
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return;
+            }
+            Err(e) => panic!("{}: {}", probe.display(), e),
+        }
+        fs::remove_dir(&probe).unwrap();
+        let socket = env::temp_dir().join(format!("{}.sock", name));
+        fs::remove_file(&socket).ok();
+        let listener = UnixListener::bind(&socket).unwrap();
+        env::set_var("DBUS_SYSTEM_BUS_ADDRESS",
+                     format!("unix:path={}", socket.display()));
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                fake_systemd(listener.accept().unwrap().0);
+            }
+        });
+
+        let mut cmd = Command::new("/bin/grep");
+        cmd.arg("^0::").arg("/proc/self/cgroup")
+            .stdout(Stdio::piped())
+            .register_systemd_scope(&name, Some("test.slice"), vec![
+                ("Delegate".into(), UnitValue::Bool(true)),
+            ]);
+        let mut child = cmd.spawn().unwrap();
+        let mut out = String::new();
+        child.take_stdout().unwrap().read_to_string(&mut out).unwrap();
+        child.wait().unwrap();
+        fs::remove_dir(mount.join(format!("{}.scope", name))).unwrap();
+        assert_eq!(out, format!("0::/{}.scope\n", name));
+
+        // the mount point created for the spawn is removed on failure
+        let scratch = env::temp_dir().join(format!("{}-scratch", name));
+        let err = cmd.register_systemd_scope("taken.scope", None, Vec::new())
+            .unshare(&[Namespace::Mount])
+            .scratch_dir("/tmp", scratch.join("work"))
+            .spawn().unwrap_err();
+        server.join().unwrap();
+        fs::remove_file(&socket).ok();
+        assert!(!scratch.exists());
+        assert!(matches!(err, Error::SystemdScope(_)), "{:?}", err);
+        assert!(err.to_string()
+            .ends_with("UnitExists: Unit taken.scope already exists."),
+            "{}", err);
+    }
+}
@@ -0,0 +1,248 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use libc::{c_int, c_void};
+
+use crate::{Command, Child};
+use crate::pipe::PipeHolder;
+use crate::socket::check_socket;
+use crate::stdio::{Fd, dup_file_cloexec};
+
+
+/// The environment variable with the descriptor of `listener_channel`
+pub const LISTENERS_FD_VAR: &str = "UNSHARE_LISTENERS_FD";
+
+/// The first descriptor of socket activation (`SD_LISTEN_FDS_START`)
+const LISTEN_FDS_START: c_int = 3;
+
+/// Maximum descriptors in a single message (`SCM_MAX_FD` of the kernel)
+const MAX_FDS: usize = 253;
+
+fn wait_readable(fd: c_int, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        // round up, so the timeout isn't zero until it's really expired
+        let ms = left.as_micros().div_ceil(1000).min(c_int::MAX as u128);
+        match unsafe { libc::poll(&mut pfd, 1, ms as c_int) } {
+            0 => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                    "the child hasn't sent its listeners in time"));
+            }
+            rc if rc > 0 => return Ok(()),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+fn recv_fds(sock: c_int) -> io::Result<Vec<OwnedFd>> {
+    let mut fds = Vec::new();
+    unsafe {
+        let mut buf = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+        let space = libc::CMSG_SPACE((MAX_FDS * mem::size_of::<c_int>())
+                                     as u32) as usize;
+        let mut cmsg_buf = vec![0u64; space.div_ceil(8)];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = space as _;
+        let rc = libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if rc == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "the child has closed the listener channel"));
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET &&
+               (*cmsg).cmsg_type == libc::SCM_RIGHTS
+            {
+                let len = (*cmsg).cmsg_len as usize -
+                    libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const c_int;
+                for i in 0..len / mem::size_of::<c_int>() {
+                    let fd = data.add(i).read_unaligned();
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            // received descriptors are closed on drop
+            return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+        }
+    }
+    Ok(fds)
+}
+
+impl Command {
+    /// Give the child a socket to hand its listening sockets over with
+    ///
+    /// The child gets one end of a `SOCK_SEQPACKET` pair at the lowest
+    /// descriptor number (starting from 3) which is not configured yet,
+    /// the number is put into the `UNSHARE_LISTENERS_FD` environment
+    /// variable (so call this after `env_clear`). On every message
+    /// received from it, the child is expected to send back a single
+    /// message with its listening sockets attached as `SCM_RIGHTS` (the
+    /// payload is ignored), and keep serving the connections accepted so
+    /// far. See `Child::extract_listeners`. Calling this again does
+    /// nothing.
+    pub fn listener_channel(&mut self) -> &mut Command {
+        if self.listener_fd.is_some() {
+            return self;
+        }
+        let target = (3..).find(|x| !self.fds.contains_key(x)).unwrap();
+        self.fds.insert(target, Fd::SeqPacket);
+        self.listener_fd = Some(target);
+        self.env(LISTENERS_FD_VAR, target.to_string())
+    }
+
+    /// Pass listening sockets to the child by the socket activation rules
+    ///
+    /// Sockets get descriptors 3, 4, ... in the order given, and
+    /// `LISTEN_FDS` and `LISTEN_PID` are set like systemd does, so the
+    /// child may use `sd_listen_fds()` (the variables are set in the
+    /// environment, so call this after `env_clear`). Along with
+    /// `Child::extract_listeners` this provides restarts which don't lose
+    /// connections: pending ones stay in the queue of the socket while the
+    /// new child starts.
+    ///
+    /// Call this before configuring other descriptors (including
+    /// `listener_channel` and `shutdown_channel`), an `InvalidInput` error
+    /// is returned if the descriptors are taken. Also returns an error if
+    /// any of the descriptors isn't a socket.
+    pub fn adopt_listeners<I>(&mut self, listeners: I)
+        -> io::Result<&mut Command>
+        where I: IntoIterator<Item=OwnedFd>
+    {
+        let listeners = listeners.into_iter().collect::<Vec<_>>();
+        for (fd, sock) in (LISTEN_FDS_START..).zip(&listeners) {
+            check_socket(sock.as_raw_fd())?;
+            if self.fds.contains_key(&fd) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("descriptor {} is configured already", fd)));
+            }
+        }
+        for (fd, sock) in (LISTEN_FDS_START..).zip(&listeners) {
+            self.fds.insert(fd, Fd::Fd(dup_file_cloexec(sock)?));
+        }
+        self.env("LISTEN_FDS", listeners.len().to_string());
+        Ok(self.env_var_with_pid("LISTEN_PID"))
+    }
+}
+
+impl Child {
+    /// Take listening sockets from the child over the `listener_channel`
+    ///
+    /// Sends a request to the child and waits up to `timeout` for the
+    /// reply. The sockets are duplicates, so the child may still accept
+    /// connections until it's shut down, pass them to the next child with
+    /// `Command::adopt_listeners`. May be called many times. Fails with
+    /// `EINVAL` if the command has no `listener_channel`, `TimedOut` if
+    /// the child doesn't reply in time, and `UnexpectedEof` (or `EPIPE`)
+    /// if it has exited.
+    pub fn extract_listeners(&mut self, timeout: Duration)
+        -> io::Result<Vec<OwnedFd>>
+    {
+        let sock = match self.listener_fd.and_then(|fd| self.fds.get(&fd)) {
+            Some(PipeHolder::Socket(sock)) => sock.as_raw_fd(),
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let rc = unsafe {
+            libc::send(sock, b"L".as_ptr() as *const c_void, 1,
+                       libc::MSG_NOSIGNAL)
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        wait_readable(sock, timeout)?;
+        recv_fds(sock)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{Command, Child};
+    use crate::mount_provider::send_fd;
+    use crate::pipe::PipeHolder;
+
+    #[test]
+    fn test_handoff() {
+        let mut pair = [0; 2];
+        assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0,
+            pair.as_mut_ptr()) }, 0);
+        let (ours, theirs) = unsafe {
+            (OwnedFd::from_raw_fd(pair[0]), OwnedFd::from_raw_fd(pair[1]))
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the old child
+        let old = thread::spawn(move || {
+            let mut byte = 0u8;
+            unsafe { libc::read(theirs.as_raw_fd(),
+                                &mut byte as *mut u8 as *mut _, 1) };
+            send_fd(theirs.as_raw_fd(), Some(listener.as_raw_fd())).unwrap();
+        });
+        let mut child = Child::from_pid(0);
+        child.fds.insert(5, PipeHolder::Socket(ours));
+        child.listener_fd = Some(5);
+        let fds = child.extract_listeners(Duration::from_secs(5)).unwrap();
+        old.join().unwrap();
+        assert_eq!(fds.len(), 1);
+        let _pending = TcpStream::connect(addr).unwrap();
+        let err = child.extract_listeners(Duration::from_secs(5))
+            .unwrap_err();
+        assert!(err.kind() == ErrorKind::UnexpectedEof ||
+                err.raw_os_error() == Some(libc::EPIPE), "{}", err);
+
+        let mut child = Command::new("/bin/sh").arg("-c")
+            .arg("[ \"$LISTEN_FDS\" = 1 ] && [ \"$LISTEN_PID\" = $$ ] && \
+                  [ -S /proc/self/fd/3 ] && \
+                  [ \"$UNSHARE_LISTENERS_FD\" = 4 ]")
+            .adopt_listeners(fds).unwrap()
+            .listener_channel()
+            .spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_no_reply() {
+        let mut child = Command::new("/bin/sleep").arg("10")
+            .listener_channel()
+            .spawn().unwrap();
+        let err = child.extract_listeners(Duration::from_millis(20))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let mut cmd = Command::new("/bin/true");
+        cmd.listener_channel();
+        let sock = TcpListener::bind("127.0.0.1:0").unwrap();
+        let err = cmd.adopt_listeners(vec![OwnedFd::from(sock)])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+use libc::pid_t;
+
+use crate::Child;
+use crate::sys;
+
+
+/// Calls `tcsetpgrp` with `SIGTTOU` blocked
+///
+/// Otherwise, the call made from the background process group stops the
+/// process.
+fn set_foreground(tty: RawFd, pgid: pid_t) -> io::Result<()> {
+    unsafe {
+        let mut block: libc::sigset_t = mem::zeroed();
+        let mut old: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut block);
+        libc::sigaddset(&mut block, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &block, &mut old);
+        let rc = libc::tcsetpgrp(tty, pgid);
+        let err = io::Error::last_os_error();
+        libc::pthread_sigmask(libc::SIG_SETMASK, &old, ptr::null_mut());
+        if rc != 0 {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+impl Child {
+    /// Put the process group of the child into foreground on the terminal
+    ///
+    /// The `tty` must be the controlling terminal of the current process
+    /// and the child must be in its own process group (see
+    /// `Command::make_group_leader`). The descriptor is duplicated and the
+    /// current foreground group is remembered for `take_terminal`.
+    ///
+    /// To resume a stopped child in foreground, call this and then send
+    /// `SIGCONT` to its process group.
+    pub fn give_terminal<F: AsRawFd>(&mut self, tty: &F) -> io::Result<()> {
+        let pgid = unsafe { libc::getpgid(self.pid) };
+        if pgid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if pgid == unsafe { libc::getpgrp() } {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "child is in the process group of the current process"));
+        }
+        let previous = unsafe { libc::tcgetpgrp(tty.as_raw_fd()) };
+        if previous < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let tty = sys::dup_cloexec(tty.as_raw_fd(), 3)
+            .map(|fd| unsafe { File::from_raw_fd(fd) })?;
+        set_foreground(tty.as_raw_fd(), pgid)?;
+        // keep the group remembered by the first call if called twice
+        let previous = self.terminal.take().map_or(previous, |(_, p)| p);
+        self.terminal = Some((tty, previous));
+        Ok(())
+    }
+
+    /// Put the process group that was in foreground before `give_terminal`
+    /// back into foreground
+    ///
+    /// This is usually done when the child is stopped (see
+    /// `Child::wait_with_options`) or has exited. Does nothing if the
+    /// terminal wasn't given to the child.
+    pub fn take_terminal(&mut self) -> io::Result<()> {
+        if let Some((tty, previous)) = self.terminal.take() {
+            if let Err(e) = set_foreground(tty.as_raw_fd(), previous) {
+                self.terminal = Some((tty, previous));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
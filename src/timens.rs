@@ -0,0 +1,91 @@
+use std::ffi::CString;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::Command;
+use crate::error::Error;
+
+
+const NANOS: i128 = 1_000_000_000;
+
+/// Reads the clock of the current time namespace
+fn clock_now(clock: libc::clockid_t) -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Formats the line of `timens_offsets` making `clock` equal to `target`
+fn offset_line(clock: libc::clockid_t, target: Duration) -> String {
+    let offset = target.as_nanos() as i128
+        - clock_now(clock).as_nanos() as i128;
+    format!("{} {} {}\n", clock,
+        offset.div_euclid(NANOS), offset.rem_euclid(NANOS))
+}
+
+impl Command {
+    /// Make the sandbox believe it has booted at `start_at`
+    ///
+    /// The child is put into a new time namespace (see `man 7
+    /// time_namespaces`) with offsets of `CLOCK_MONOTONIC` and
+    /// `CLOCK_BOOTTIME` making both clocks equal to the time since
+    /// `start_at` at the moment of `spawn()`. So the uptime and timers
+    /// of the sandbox are reproducible, which is useful for deterministic
+    /// replay and testing. The wall clock (`CLOCK_REALTIME`) isn't
+    /// virtualized by linux.
+    ///
+    /// Requires linux 5.6 and `CAP_SYS_TIME` (which root of a new user
+    /// namespace has). `spawn()` fails with `Error::TimeNamespace(ENOTSUP)`
+    /// if kernel doesn't support time namespaces, with
+    /// `Error::TimeNamespace(EINVAL)` if `start_at` is in future, and
+    /// with the `Error::TimeNamespace` if the child can't set up the
+    /// namespace.
+    pub fn virtual_clock(&mut self, start_at: SystemTime) -> &mut Command {
+        self.config.virtual_clock = Some(start_at);
+        self
+    }
+
+    /// Returns contents of `timens_offsets` written by the child
+    pub(crate) fn time_offsets(&self) -> Result<Option<CString>, Error> {
+        let start_at = match self.config.virtual_clock {
+            Some(start_at) => start_at,
+            None => return Ok(None),
+        };
+        if !Path::new("/proc/self/ns/time").exists() {
+            return Err(Error::TimeNamespace(libc::ENOTSUP));
+        }
+        let uptime = SystemTime::now().duration_since(start_at)
+            .map_err(|_| Error::TimeNamespace(libc::EINVAL))?;
+        let mut offsets = offset_line(libc::CLOCK_MONOTONIC, uptime);
+        offsets.push_str(&offset_line(libc::CLOCK_BOOTTIME, uptime));
+        Ok(Some(CString::new(offsets).unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use crate::{Command, Error};
+
+    #[test]
+    fn test_virtual_clock() {
+        // /proc/uptime is the boot time clock of the namespace
+        let start_at = SystemTime::now() - Duration::from_secs(86400 * 365);
+        let status = Command::new("/bin/sh").arg("-c")
+            .arg("read up idle < /proc/uptime; \
+                  test ${up%.*} -ge 31536000 -a ${up%.*} -lt 31536060")
+            .virtual_clock(start_at)
+            .status().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_future_start() {
+        let start_at = SystemTime::now() + Duration::from_secs(3600);
+        match Command::new("/bin/true").virtual_clock(start_at).spawn() {
+            Err(Error::TimeNamespace(code)) => assert_eq!(code, libc::EINVAL),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
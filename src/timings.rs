@@ -0,0 +1,143 @@
+use std::time::{Duration, Instant};
+
+use crate::{Command, Child};
+
+
+/// Durations of the steps of `spawn()`, measured in the parent
+///
+/// Each value is the time from the start of `spawn()` until the step was
+/// done. Returned by `Child::setup_timings` when `Command::measure` is
+/// enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupTimings {
+    cloned: Duration,
+    maps_written: Option<Duration>,
+    unfrozen: Duration,
+    executed: Duration,
+}
+
+/// Collects the time points during `spawn()`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timer {
+    start: Instant,
+    cloned: Option<Duration>,
+    maps_written: Option<Duration>,
+    unfrozen: Option<Duration>,
+}
+
+/// Step of `spawn()` marked by `Command::mark`
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Step {
+    Cloned,
+    MapsWritten,
+    Unfrozen,
+}
+
+impl SetupTimings {
+    /// The child is created (and frozen), this includes preparing the
+    /// configuration, pipes and mounts
+    pub fn cloned(&self) -> Duration {
+        self.cloned
+    }
+    /// Uid and gid maps are written, `None` if `set_id_maps` is not used
+    pub fn maps_written(&self) -> Option<Duration> {
+        self.maps_written
+    }
+    /// The setup in the parent (maps, mount providers, `before_unfreeze`)
+    /// is done and the child is woken up
+    pub fn unfrozen(&self) -> Duration {
+        self.unfrozen
+    }
+    /// The program is executed, i.e. the whole `spawn()`
+    pub fn executed(&self) -> Duration {
+        self.executed
+    }
+}
+
+impl Timer {
+    fn new() -> Timer {
+        Timer {
+            start: Instant::now(),
+            cloned: None,
+            maps_written: None,
+            unfrozen: None,
+        }
+    }
+}
+
+impl Command {
+    /// Measure the duration of the steps of `spawn()`
+    ///
+    /// The timings are returned by `Child::setup_timings`. This is used
+    /// to find out where the spawn latency goes with different
+    /// configurations (e.g. how long writing id maps or mounting takes).
+    pub fn measure(&mut self, enable: bool) -> &mut Command {
+        self.timer = if enable { Some(Timer::new()) } else { None };
+        self
+    }
+
+    /// Starts measuring, at the start of `spawn()`
+    pub(crate) fn start_timer(&mut self) {
+        if let Some(ref mut timer) = self.timer {
+            *timer = Timer::new();
+        }
+    }
+
+    pub(crate) fn mark(&mut self, step: Step) {
+        if let Some(ref mut timer) = self.timer {
+            let elapsed = Some(timer.start.elapsed());
+            match step {
+                Step::Cloned => timer.cloned = elapsed,
+                Step::MapsWritten => timer.maps_written = elapsed,
+                Step::Unfrozen => timer.unfrozen = elapsed,
+            }
+        }
+    }
+
+    /// Returns the timings when the program is executed
+    pub(crate) fn setup_timings(&self) -> Option<SetupTimings> {
+        let timer = self.timer.as_ref()?;
+        Some(SetupTimings {
+            cloned: timer.cloned?,
+            maps_written: timer.maps_written,
+            unfrozen: timer.unfrozen?,
+            executed: timer.start.elapsed(),
+        })
+    }
+}
+
+impl Child {
+    /// Returns how long the steps of `spawn()` took
+    ///
+    /// `None` unless enabled by `Command::measure`, and for children created
+    /// by `Child::from_pid`.
+    pub fn setup_timings(&self) -> Option<SetupTimings> {
+        self.setup_timings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Namespace, UidMap, GidMap};
+
+    #[test]
+    fn test_measure() {
+        let mut child = Command::new("/bin/true")
+            .unshare(&[Namespace::User])
+            .set_id_maps(
+                vec![UidMap { inside_uid: 0, outside_uid: 0, count: 1 }],
+                vec![GidMap { inside_gid: 0, outside_gid: 0, count: 1 }])
+            .measure(true)
+            .spawn().unwrap();
+        let timings = child.setup_timings().unwrap();
+        let maps = timings.maps_written().unwrap();
+        assert!(timings.cloned() <= maps);
+        assert!(maps <= timings.unfrozen());
+        assert!(timings.unfrozen() <= timings.executed());
+        child.wait().unwrap();
+
+        let mut child = Command::new("/bin/true").spawn().unwrap();
+        assert_eq!(child.setup_timings(), None);
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,445 @@
+use std::fmt::{self, Write};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+use libc::{c_int, c_void, pid_t};
+
+use crate::{Command, Signal};
+use crate::stdio::Closing;
+use crate::syscalls::{lookup, errno_name};
+use crate::sys;
+use crate::arch::PtraceRequest;
+
+
+const PTRACE_GET_SYSCALL_INFO: PtraceRequest = 0x420e;
+const PTRACE_SYSCALL_INFO_ENTRY: u8 = 1;
+const PTRACE_SYSCALL_INFO_EXIT: u8 = 2;
+const SYSCALL_STOP: c_int = libc::SIGTRAP | 0x80;
+/// Number of system calls that can be in progress at the same time,
+/// others are logged on two lines
+const MAX_PENDING: usize = 32;
+/// How much of each string argument is logged
+const MAX_STRING: usize = 128;
+
+/// Mirrors `struct ptrace_syscall_info`
+#[repr(C)]
+struct SyscallInfo {
+    op: u8,
+    // arch, instruction_pointer and stack_pointer
+    _header: [u8; 23],
+    // entry: nr, args[6]; exit: rval, is_error; seccomp: nr, args, ret_data
+    data: [u64; 8],
+}
+
+impl Command {
+    /// Write a log of the system calls made by the child to `path`
+    ///
+    /// This is a debugging aid for the cases when the program fails in the
+    /// sandbox and there is no `strace` inside. The file is truncated on
+    /// `spawn()`. Each line contains the thread id, system call with its
+    /// arguments and the result, like:
+    ///
+    /// ```text
+    /// [1234] openat(-100, "/etc/hosts", 524288, 0) = -1 ENOENT
+    /// ```
+    ///
+    /// The system calls of the setup done by this library in the child
+    /// (mounts, `pivot_root`, ...) are included, as well as the ones of
+    /// all processes and threads the child starts.
+    ///
+    /// The child is traced with `ptrace` by a helper process that is
+    /// started by `spawn()` (and reparented to init or subreaper, exits
+    /// when the traced processes exit). This requires linux 5.3, and the
+    /// child can't be traced by a debugger. The traced processes are
+    /// killed if the helper dies. The last lines may be written shortly
+    /// after the child is reaped. Errors starting the helper are reported
+    /// as `Error::DebugSyscalls`.
+    pub fn debug_syscalls<P: AsRef<Path>>(&mut self, path: P)
+        -> &mut Command
+    {
+        self.debug_syscalls = Some(path.as_ref().to_path_buf());
+        self
+    }
+}
+
+/// Formats into a fixed buffer, the rest is truncated
+struct Line {
+    buf: [u8; 512],
+    len: usize,
+}
+
+impl Line {
+    const fn new() -> Line {
+        Line { buf: [0; 512], len: 0 }
+    }
+    fn push(&mut self, data: &[u8]) {
+        let n = data.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len+n].copy_from_slice(&data[..n]);
+        self.len += n;
+    }
+    fn write_to(&self, fd: RawFd) {
+        unsafe {
+            libc::write(fd, self.buf.as_ptr() as *const c_void, self.len)
+        };
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+struct Pending {
+    tid: pid_t,
+    line: Line,
+}
+
+unsafe fn ptrace(request: PtraceRequest, pid: pid_t, addr: usize, data: usize)
+    -> libc::c_long
+{
+    libc::ptrace(request, pid, addr as *mut c_void, data as *mut c_void)
+}
+
+/// Formats nul-terminated string from the memory of the tracee
+fn push_string(line: &mut Line, tid: pid_t, addr: u64) {
+    let mut buf = [0u8; MAX_STRING];
+    let local = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: addr as usize as *mut c_void,
+        iov_len: buf.len(),
+    };
+    // partial read is fine: the string may end right before unmapped page
+    let n = unsafe {
+        libc::process_vm_readv(tid, &local, 1, &remote, 1, 0)
+    };
+    if addr == 0 || n <= 0 {
+        write!(line, "{:#x}", addr).ok();
+        return;
+    }
+    let data = &buf[..n as usize];
+    let (data, complete) = match data.iter().position(|&c| c == 0) {
+        Some(end) => (&data[..end], true),
+        None => (data, false),
+    };
+    line.push(b"\"");
+    for &c in data {
+        match c {
+            b'"' | b'\\' => line.push(&[b'\\', c]),
+            b'\n' => line.push(b"\\n"),
+            0x20..=0x7e => line.push(&[c]),
+            _ => { write!(line, "\\x{:02x}", c).ok(); }
+        }
+    }
+    line.push(if complete { b"\"" } else { b"\"..." });
+}
+
+fn push_arg(line: &mut Line, value: u64) {
+    let signed = if value <= u32::MAX as u64 {
+        // most arguments are `int`, so `AT_FDCWD` is `0xffffff9c`
+        value as u32 as i32 as i64
+    } else {
+        value as i64
+    };
+    if (-4096..0x10000).contains(&signed) {
+        write!(line, "{}", signed).ok();
+    } else {
+        write!(line, "{:#x}", value).ok();
+    }
+}
+
+fn push_signal(line: &mut Line, signo: c_int) {
+    match Signal::from_raw(signo).name() {
+        Some(name) => line.push(name.as_bytes()),
+        None => { write!(line, "signal {}", signo).ok(); }
+    }
+}
+
+/// Formats system call entry (without the result)
+fn format_entry(line: &mut Line, tid: pid_t, info: &SyscallInfo) {
+    let nr = info.data[0] as libc::c_long;
+    let args = &info.data[1..7];
+    write!(line, "[{}] ", tid).ok();
+    let (nargs, strings) = match lookup(nr) {
+        Some(call) => {
+            line.push(call.name.as_bytes());
+            (call.nargs, call.strings)
+        }
+        None => {
+            write!(line, "syscall_{}", nr).ok();
+            (args.len(), &[][..])
+        }
+    };
+    line.push(b"(");
+    for (idx, &arg) in args[..nargs].iter().enumerate() {
+        if idx > 0 {
+            line.push(b", ");
+        }
+        if strings.contains(&idx) {
+            push_string(line, tid, arg);
+        } else {
+            push_arg(line, arg);
+        }
+    }
+    line.push(b")");
+}
+
+fn push_result(line: &mut Line, info: &SyscallInfo) {
+    let rval = info.data[0] as i64;
+    let is_error = info.data[1] & 0xff != 0;
+    if is_error {
+        let errno = -rval as c_int;
+        match errno_name(errno) {
+            Some(name) => { writeln!(line, " = -1 {}", name).ok(); }
+            None => { writeln!(line, " = -1 errno {}", errno).ok(); }
+        }
+    } else if (0..0x10000).contains(&rval) {
+        writeln!(line, " = {}", rval).ok();
+    } else {
+        writeln!(line, " = {:#x}", rval).ok();
+    }
+}
+
+unsafe fn syscall_stop(tid: pid_t, log: RawFd,
+    pending: &mut [Option<Pending>; MAX_PENDING])
+{
+    let mut info: SyscallInfo = mem::zeroed();
+    let rc = ptrace(PTRACE_GET_SYSCALL_INFO, tid,
+                    mem::size_of::<SyscallInfo>(),
+                    &mut info as *mut _ as usize);
+    if rc <= 0 {
+        return;
+    }
+    let slot = pending.iter().position(|p| {
+        p.as_ref().is_some_and(|p| p.tid == tid)
+    });
+    match info.op {
+        PTRACE_SYSCALL_INFO_ENTRY => {
+            let mut line = Line::new();
+            format_entry(&mut line, tid, &info);
+            // entry without exit (e.g. `execve` by another thread)
+            if let Some(idx) = slot {
+                let mut old = pending[idx].take().unwrap().line;
+                old.push(b" = ?\n");
+                old.write_to(log);
+            }
+            match pending.iter().position(|p| p.is_none()) {
+                Some(idx) => pending[idx] = Some(Pending { tid, line }),
+                None => {
+                    line.push(b" ...\n");
+                    line.write_to(log);
+                }
+            }
+        }
+        PTRACE_SYSCALL_INFO_EXIT => {
+            let mut line = match slot {
+                Some(idx) => pending[idx].take().unwrap().line,
+                None => {
+                    let mut line = Line::new();
+                    write!(line, "[{}] <... resumed>", tid).ok();
+                    line
+                }
+            };
+            push_result(&mut line, &info);
+            line.write_to(log);
+        }
+        _ => {}
+    }
+}
+
+/// Closes all descriptors except `keep`, which must be sorted
+unsafe fn close_other_fds(keep: &[RawFd]) {
+    let mut first = 0;
+    for &fd in keep {
+        if fd > first {
+            libc::syscall(libc::SYS_close_range, first, fd - 1, 0);
+        }
+        first = fd + 1;
+    }
+    libc::syscall(libc::SYS_close_range, first, !0u32, 0);
+}
+
+unsafe fn report(status: RawFd, errno: c_int) {
+    let data = errno.to_ne_bytes();
+    libc::write(status, data.as_ptr() as *const c_void, data.len());
+}
+
+/// Attaches to `pid` and writes the log until all tracees exit
+///
+/// This runs in a forked copy of a (possibly multithreaded) parent, so
+/// it must not allocate, the same way as the code in the child.
+unsafe fn tracer(pid: pid_t, log: RawFd, status: RawFd) -> ! {
+    close_other_fds(&[log.min(status), log.max(status)]);
+    // terminal signals are for the traced process
+    for &sig in &[libc::SIGINT, libc::SIGQUIT, libc::SIGHUP, libc::SIGTSTP] {
+        libc::signal(sig, libc::SIG_IGN);
+    }
+    let options = libc::PTRACE_O_TRACESYSGOOD | libc::PTRACE_O_TRACEFORK |
+        libc::PTRACE_O_TRACEVFORK | libc::PTRACE_O_TRACECLONE |
+        libc::PTRACE_O_TRACEEXEC | libc::PTRACE_O_EXITKILL;
+    if ptrace(libc::PTRACE_SEIZE, pid, 0, options as usize) < 0 ||
+        ptrace(libc::PTRACE_INTERRUPT, pid, 0, 0) < 0
+    {
+        report(status, sys::errno());
+        libc::_exit(1);
+    }
+    let mut wstatus = 0;
+    while libc::waitpid(pid, &mut wstatus, libc::__WALL) < 0 {
+        if sys::errno() != libc::EINTR {
+            report(status, sys::errno());
+            libc::_exit(1);
+        }
+    }
+    if !libc::WIFSTOPPED(wstatus) ||
+        ptrace(libc::PTRACE_SYSCALL, pid, 0, 0) < 0
+    {
+        report(status, libc::ESRCH);
+        libc::_exit(1);
+    }
+    report(status, 0);
+    libc::close(status);
+
+    const EMPTY: Option<Pending> = None;
+    let mut pending = [EMPTY; MAX_PENDING];
+    loop {
+        let tid = libc::waitpid(-1, &mut wstatus, libc::__WALL);
+        if tid < 0 {
+            if sys::errno() == libc::EINTR {
+                continue;
+            }
+            // ECHILD: nothing left to trace
+            libc::_exit(0);
+        }
+        if libc::WIFEXITED(wstatus) || libc::WIFSIGNALED(wstatus) {
+            let slot = pending.iter_mut()
+                .find(|p| p.as_ref().is_some_and(|p| p.tid == tid));
+            if let Some(slot) = slot {
+                let mut line = slot.take().unwrap().line;
+                line.push(b" = ?\n");
+                line.write_to(log);
+            }
+            let mut line = Line::new();
+            if libc::WIFEXITED(wstatus) {
+                writeln!(line, "[{}] +++ exited with {} +++",
+                       tid, libc::WEXITSTATUS(wstatus)).ok();
+            } else {
+                write!(line, "[{}] +++ killed by ", tid).ok();
+                push_signal(&mut line, libc::WTERMSIG(wstatus));
+                line.push(b" +++\n");
+            }
+            line.write_to(log);
+            continue;
+        }
+        if !libc::WIFSTOPPED(wstatus) {
+            continue;
+        }
+        let sig = libc::WSTOPSIG(wstatus);
+        let event = (wstatus >> 16) & 0xff;
+        let (request, inject) = if sig == SYSCALL_STOP {
+            syscall_stop(tid, log, &mut pending);
+            (libc::PTRACE_SYSCALL, 0)
+        } else if event == libc::PTRACE_EVENT_STOP {
+            match sig {
+                // group stop, keep the tracee stopped until SIGCONT
+                libc::SIGSTOP | libc::SIGTSTP | libc::SIGTTIN |
+                libc::SIGTTOU => (libc::PTRACE_LISTEN, 0),
+                // new tracee or the interrupt
+                _ => (libc::PTRACE_SYSCALL, 0),
+            }
+        } else if event != 0 {
+            (libc::PTRACE_SYSCALL, 0)
+        } else {
+            let mut line = Line::new();
+            write!(line, "[{}] --- ", tid).ok();
+            push_signal(&mut line, sig);
+            line.push(b" ---\n");
+            line.write_to(log);
+            (libc::PTRACE_SYSCALL, sig)
+        };
+        ptrace(request, tid, 0, inject as usize);
+    }
+}
+
+/// Starts the process tracing frozen child `pid`, returns when attached
+pub fn start_tracer(pid: pid_t, path: &Path) -> io::Result<()> {
+    let log = File::create(path)?;
+    let (status_rd, status_wr) = sys::pipe2(libc::O_CLOEXEC)?;
+    let (status_rd, status_wr) = (Closing::new(status_rd),
+                                  Closing::new(status_wr));
+    let middle = unsafe { libc::fork() };
+    if middle < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if middle == 0 {
+        // double fork, so the tracer doesn't stay our child
+        unsafe {
+            if libc::fork() == 0 {
+                tracer(pid, log.as_raw_fd(), status_wr.as_raw_fd());
+            }
+            libc::_exit(0);
+        }
+    }
+    drop(status_wr);
+    loop {
+        match sys::waitpid(middle, 0) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            _ => break,
+        }
+    }
+    let mut data = [0u8; 4];
+    let mut got = 0;
+    while got < data.len() {
+        let n = unsafe {
+            libc::read(status_rd.as_raw_fd(),
+                data[got..].as_mut_ptr() as *mut c_void, data.len() - got)
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            // tracer could not be forked
+            return Err(io::Error::from_raw_os_error(libc::ECHILD));
+        }
+        got += n as usize;
+    }
+    match c_int::from_ne_bytes(data) {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs;
+
+    use crate::{Command, ExitStatus};
+
+    #[test]
+    fn test_log() {
+        let path = temp_dir().join(format!("unshare-trace-{}.log",
+                                           std::process::id()));
+        let status = Command::new("/bin/sh")
+            .arg("-c").arg("exec cat /nonexistent-unshare-file")
+            .debug_syscalls(&path)
+            .status().unwrap();
+        assert_eq!(status, ExitStatus::Exited(1));
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(log.lines().any(|line| {
+            line.contains("\"/nonexistent-unshare-file\"") &&
+            line.ends_with(" = -1 ENOENT")
+        }), "{}", log);
+    }
+}
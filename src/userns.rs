@@ -0,0 +1,144 @@
+use std::fs;
+
+use crate::Command;
+use crate::error::Error;
+use crate::idmap::{read_map, is_mapped};
+
+
+impl Command {
+    /// Create only the user namespace on clone, and the others afterwards
+    ///
+    /// When enabled (and the user namespace is unshared), the child is
+    /// cloned into new user and pid namespaces only, and calls `unshare`
+    /// for the rest of the namespaces after the id maps are written (and
+    /// before joining namespaces by `set_namespace`), so it does it as
+    /// the root of the user namespace. The pid namespace can't be
+    /// deferred, as `unshare` only moves the children there.
+    ///
+    /// Note that callbacks which run while the child is frozen (such as
+    /// `before_unfreeze` and `MountProvider::provide`) see the child in
+    /// the mount, network, etc. namespaces of the current process then,
+    /// and `userspace_network` fails with `Error::UserspaceNetwork(EINVAL)`.
+    /// Errors of `unshare` are reported as `Error::Unshare`.
+    pub fn userns_first(&mut self, enable: bool) -> &mut Command {
+        self.config.userns_first = enable;
+        self
+    }
+
+    /// Namespaces unshared by the child after the clone
+    pub(crate) fn deferred_namespaces(&self) -> libc::c_int {
+        let namespaces = self.config.namespaces;
+        if !self.config.userns_first || namespaces & libc::CLONE_NEWUSER == 0
+        {
+            return 0;
+        }
+        namespaces & !(libc::CLONE_NEWUSER | libc::CLONE_NEWPID)
+    }
+
+    /// Checks that the user namespace can be created with the id maps
+    ///
+    /// This catches the common cases of running in a user namespace (e.g.
+    /// CI in a rootless container), which otherwise fail with `EPERM` when
+    /// maps are written.
+    pub(crate) fn check_user_namespace(&self) -> Result<(), Error> {
+        if self.config.namespaces & libc::CLONE_NEWUSER == 0 {
+            return Ok(());
+        }
+        // the helper joins the network namespace while the child is frozen
+        if self.deferred_namespaces() & libc::CLONE_NEWNET != 0 &&
+            self.userspace_network.is_some()
+        {
+            return Err(Error::UserspaceNetwork(libc::EINVAL));
+        }
+        let max = fs::read_to_string("/proc/sys/user/max_user_namespaces")
+            .ok().and_then(|x| x.trim().parse::<u64>().ok());
+        if max == Some(0) {
+            return Err(Error::UserNamespace(libc::ENOSPC));
+        }
+        let (uids, gids) = match self.config.id_maps {
+            // privileged ops may write maps from another namespace
+            Some(_) if self.privileged_ops.is_some() => return Ok(()),
+            Some((ref uids, ref gids)) => (uids, gids),
+            None => return Ok(()),
+        };
+        if let Ok(own) = read_map("/proc/self/uid_map") {
+            if !uids.iter().all(|m| is_mapped(&own, m.outside_uid, m.count)) {
+                return Err(Error::UserNamespace(libc::EPERM));
+            }
+        }
+        if let Ok(own) = read_map("/proc/self/gid_map") {
+            if !gids.iter().all(|m| is_mapped(&own, m.outside_gid, m.count)) {
+                return Err(Error::UserNamespace(libc::EPERM));
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts the error of `clone` caused by the user namespace
+    pub(crate) fn clone_error(&self, err: Error) -> Error {
+        if self.config.namespaces & libc::CLONE_NEWUSER == 0 {
+            return err;
+        }
+        match err {
+            Error::Fork(code) if code == libc::EUSERS || code == libc::EPERM
+            => {
+                Error::UserNamespace(code)
+            }
+            err => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{Command, Error, Namespace, UidMap, GidMap};
+
+    #[test]
+    fn test_unmapped_outside_id() {
+        let own = UidMap::current().unwrap();
+        // the first id past the end of every range of the current namespace
+        let unmapped = own.iter()
+            .map(|m| m.inside_uid as u64 + m.count as u64)
+            .max().unwrap();
+        if unmapped > u32::MAX as u64 {
+            return;  // the whole range is mapped
+        }
+        let result = Command::new("/bin/true")
+            .set_id_maps(
+                vec![UidMap { inside_uid: 0, outside_uid: unmapped as u32,
+                              count: 1 }],
+                vec![GidMap { inside_gid: 0, outside_gid: 0, count: 1 }])
+            .spawn();
+        match result {
+            Err(Error::UserNamespace(code)) => assert_eq!(code, libc::EPERM),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_userns_first() {
+        let own = fs::read_link("/proc/self/ns/mnt").unwrap();
+        let mut child = match Command::new("/bin/sh").arg("-c")
+            .arg("[ $(readlink /proc/self/ns/mnt) != \"$0\" ] && \
+                  mount -t tmpfs none /tmp")
+            .arg(&own)
+            .unshare(&[Namespace::User, Namespace::Mount])
+            .set_id_maps(
+                vec![UidMap { inside_uid: 0,
+                    outside_uid: unsafe { libc::geteuid() }, count: 1 }],
+                vec![GidMap { inside_gid: 0,
+                    outside_gid: unsafe { libc::getegid() }, count: 1 }])
+            .uid(0).gid(0)
+            .userns_first(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            // user namespaces are disabled
+            Err(Error::UserNamespace(_)) => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert!(child.wait().unwrap().success());
+    }
+}
@@ -1,19 +1,106 @@
-use std::io;
-use std::os::unix::io::RawFd;
-
-use nix::Error;
-use nix::unistd::Pid;
-use nix::sys::wait::waitpid;
-use nix::sys::signal::{Signal, SIGKILL, kill};
-use nix::errno::Errno::EINTR;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::mem;
+use std::ptr;
+
 use libc::pid_t;
 
 use crate::pipe::PipeHolder;
-use crate::{Child, ExitStatus, PipeReader, PipeWriter};
+use crate::sys::waitpid;
+use crate::zombies;
+use crate::error::Error;
+use crate::process_tree::start_time;
+use crate::labels;
+use crate::{Child, ExitStatus, PipeReader, PipeWriter, Signal};
+use crate::{WaitStatus, WaitOptions, HardeningReport, WaitBackend};
 
 
 impl Child {
 
+    /// Create a handle for an already running process
+    ///
+    /// This is useful to re-adopt a process that was spawned before, for
+    /// example by the previous instance of a supervisor that has been
+    /// restarted (see also `Child::forget`).
+    ///
+    /// Signals may be sent to any process we have permissions for, but
+    /// `wait()` works only if the process is a child of the current one
+    /// (which is also true for orphans, if the current process is a
+    /// subreaper). Note: with a bare pid the protection against pid reuse
+    /// is only as good as `signal` describes (and none for the pid reused
+    /// before this call), so if you have a pidfd use `from_pidfd`.
+    #[allow(deprecated)]
+    pub fn from_pid(pid: pid_t) -> Child {
+        Child {
+            pid,
+            pidfd: None,
+            status: None,
+            fds: HashMap::new(),
+            fd_kinds: Vec::new(),
+            closed_fds: Vec::new(),
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            network_helper: None,
+            seccomp: None,
+            terminal: None,
+            hardening: HardeningReport::default(),
+            mounts: Vec::new(),
+            scratch: None,
+            spawned_at: None,
+            setup_timings: None,
+            logger: None,
+            shared_memory: None,
+            labels: BTreeMap::new(),
+            timed_out: None,
+            spawn_record: None,
+            wait_backend: WaitBackend::Sigchld,
+            shutdown_fd: None,
+            listener_fd: None,
+            proc_path: None,
+            spawn_error: None,
+            start_time: start_time(pid),
+            teardown: Default::default(),
+        }
+    }
+
+    /// Create a handle for an already running process referred by pidfd
+    ///
+    /// Works like `from_pid`, but signals are sent through the pidfd (see
+    /// `man 2 pidfd_send_signal`), so they can never be delivered to an
+    /// unrelated process if the pid has been reused.
+    pub fn from_pidfd(pidfd: File) -> Result<Child, io::Error> {
+        let mut info = String::new();
+        File::open(format!("/proc/self/fdinfo/{}", pidfd.as_raw_fd()))?
+            .read_to_string(&mut info)?;
+        let pid = info.lines()
+            .filter_map(|line| line.strip_prefix("Pid:"))
+            .filter_map(|value| value.trim().parse::<pid_t>().ok())
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                "file descriptor is not a pidfd"))?;
+        if pid <= 0 {
+            // pid is -1 when process is already dead
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        let mut child = Child::from_pid(pid);
+        child.pidfd = Some(pidfd);
+        Ok(child)
+    }
+
+    /// Stop tracking the process without killing it, and return its pid
+    ///
+    /// All pipes owned by this handle are closed. The process can be
+    /// re-adopted later with `Child::from_pid`.
+    pub fn forget(mut self) -> pid_t {
+        // userspace network helper keeps running too
+        mem::forget(self.network_helper.take());
+        mem::forget(mem::take(&mut self.teardown));
+        self.pid
+    }
+
     /// Returns pid of the process (a mirror of std method)
     pub fn id(&self) -> u32 {
         self.pid as u32
@@ -25,48 +112,195 @@ impl Child {
     }
 
     /// Synchronously wait for child to complete and return exit status
+    ///
+    /// If there is a logger (see `Command::pipe_output_to`), waits for it
+    /// to exit too. Stops and continues of the process are skipped, use
+    /// `wait_with_options` to observe them.
     pub fn wait(&mut self) -> Result<ExitStatus, io::Error> {
+        let status = match self.status {
+            Some(x) => x,
+            None => {
+                let status = self._wait()?;
+                self.reaped(status);
+                status
+            }
+        };
+        // see `Command::pipe_output_to`
+        if let Some(ref mut logger) = self.logger {
+            logger.wait()?;
+        }
+        Ok(status)
+    }
+
+    /// Wait for the process to exit, stop or continue
+    ///
+    /// This is useful for job control, e.g. when the child is a
+    /// `make_group_leader` running in foreground. Returns `None` only with
+    /// `WaitOptions::no_hang`, if the process state hasn't changed. Once
+    /// the process has exited, its status is returned on each call.
+    pub fn wait_with_options(&mut self, options: WaitOptions)
+        -> Result<Option<WaitStatus>, io::Error>
+    {
+        use crate::sys::WaitStatus as W;
+        if let Some(x) = self.status {
+            return Ok(Some(x.into()));
+        }
+        if self.wait_backend == WaitBackend::Pidfd {
+            let status = self.waitid(options.flags())?;
+            if let Some(exit) = status.and_then(|x| x.exit_status()) {
+                self.reaped(exit);
+            }
+            return Ok(status);
+        }
+        loop {
+            let status = match waitpid(self.pid, options.flags()) {
+                Ok(W::Exited(_, code)) => ExitStatus::Exited(code as i8),
+                Ok(W::Signaled(_, sig, core)) => {
+                    ExitStatus::Signaled(sig, core)
+                }
+                Ok(W::Stopped(_, sig)) => {
+                    return Ok(Some(WaitStatus::Stopped(sig)));
+                }
+                Ok(W::Continued(_)) => return Ok(Some(WaitStatus::Continued)),
+                Ok(W::StillAlive) => return Ok(None),
+                Ok(W::PtraceEvent(..)) | Ok(W::PtraceSyscall(..)) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            self.reaped(status);
+            return Ok(Some(status.into()));
+        }
+    }
+
+    /// Returns the exit status if the process has exited, without reaping
+    ///
+    /// This is `waitid(.., WEXITED|WNOHANG|WNOWAIT)`: the process is left
+    /// a zombie, so its status can be collected later by `wait()` (or by
+    /// another component waiting for the pid). Returns `None` if the
+    /// process is still running. If the handle has a pidfd, it's used
+    /// instead of the pid (requires linux 5.4).
+    pub fn peek_status(&self) -> Result<Option<ExitStatus>, io::Error> {
         if let Some(x) = self.status {
-            return Ok(x);
+            return Ok(Some(x));
         }
-        let status = self._wait()?;
+        let (idtype, id) = self.wait_id();
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        loop {
+            let rc = unsafe {
+                libc::waitid(idtype, id, &mut info,
+                    libc::WEXITED | libc::WNOHANG | libc::WNOWAIT)
+            };
+            if rc == 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        if unsafe { info.si_pid() } == 0 {
+            return Ok(None);
+        }
+        Ok(Some(ExitStatus::from_siginfo(&info)))
+    }
+
+    fn reaped(&mut self, status: ExitStatus) {
         self.status = Some(status);
-        Ok(status)
+        zombies::untrack(self.pid);
+        labels::forget(self.pid);
+        self.span_exit(status);
+        // network and mounts are useless after the process is dead
+        self.network_helper.take();
+        self.teardown = Default::default();
+    }
+
+
+    fn wait_id(&self) -> (libc::idtype_t, libc::id_t) {
+        match self.pidfd {
+            Some(ref pidfd) => {
+                (libc::P_PIDFD, pidfd.as_raw_fd() as libc::id_t)
+            }
+            None => (libc::P_PID, self.pid as libc::id_t),
+        }
     }
 
+    /// Waits by `waitid`, the pidfd is used if there is one
+    fn waitid(&self, flags: libc::c_int) -> io::Result<Option<WaitStatus>> {
+        let (idtype, id) = self.wait_id();
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        loop {
+            // `WUNTRACED` of `waitpid` is the same as `WSTOPPED`
+            let rc = unsafe {
+                libc::waitid(idtype, id, &mut info,
+                    libc::WEXITED | flags)
+            };
+            if rc == 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        if unsafe { info.si_pid() } == 0 {
+            return Ok(None);
+        }
+        let signal = || Signal::from_raw(unsafe { info.si_status() });
+        Ok(Some(match info.si_code {
+            libc::CLD_STOPPED | libc::CLD_TRAPPED => {
+                WaitStatus::Stopped(signal())
+            }
+            libc::CLD_CONTINUED => WaitStatus::Continued,
+            _ => ExitStatus::from_siginfo(&info).into(),
+        }))
+    }
 
     fn _wait(&mut self) -> Result<ExitStatus, io::Error> {
-        use nix::sys::wait::WaitStatus::*;
+        use crate::sys::WaitStatus::*;
+        if self.wait_backend == WaitBackend::Pidfd {
+            loop {
+                // ptrace stops are reported even without `WSTOPPED`
+                let status = self.waitid(0)?.and_then(|x| x.exit_status());
+                if let Some(status) = status {
+                    return Ok(status);
+                }
+            }
+        }
         loop {
-            match waitpid(Some(Pid::from_raw(self.pid)), None) {
+            match waitpid(self.pid, 0) {
                 Ok(PtraceEvent(..)) => {}
                 Ok(PtraceSyscall(..)) => {}
                 Ok(Exited(x, status)) => {
-                    assert!(i32::from(x) == self.pid);
+                    assert!(x == self.pid);
                     return Ok(ExitStatus::Exited(status as i8));
                 }
                 Ok(Signaled(x, sig, core)) => {
-                    assert!(i32::from(x) == self.pid);
+                    assert!(x == self.pid);
                     return Ok(ExitStatus::Signaled(sig, core));
                 }
-                Ok(Stopped(_, _)) => unreachable!(),
-                Ok(Continued(_)) => unreachable!(),
-                Ok(StillAlive) => unreachable!(),
-                Err(Error::Sys(EINTR)) => continue,
-                Err(Error::InvalidPath) => unreachable!(),
-                Err(Error::InvalidUtf8) => unreachable!(),
-                Err(Error::UnsupportedOperation) => {
-                    return Err(io::Error::new(io::ErrorKind::Other,
-                               "nix error: unsupported operation"));
-                }
-                Err(Error::Sys(x)) => {
-                    return Err(io::Error::from_raw_os_error(x as i32))
-                }
+                // reported if the child is traced by this process, skip
+                // them like ptrace stops, so only the exit is returned
+                Ok(Stopped(_, _)) => {}
+                Ok(Continued(_)) => {}
+                Ok(StillAlive) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
         }
     }
 
     /// Send arbitrary unix signal to the process
+    ///
+    /// Without a pidfd, the start time of the process recorded when the
+    /// handle is created is checked first. So if the child is reaped
+    /// elsewhere (e.g. by `reap_zombies` or a `SIGCHLD` handler of another
+    /// library) and the pid is reused, the error wrapping
+    /// `Error::PidReused` is returned rather than signaling an unrelated
+    /// process (`ESRCH` if the pid is not reused yet). There is still a
+    /// tiny window between the check and the signal, use `pidfd` to
+    /// close it.
     pub fn signal(&self, signal: Signal) -> Result<(), io::Error> {
         // This prevents (somewhat not-reliable) killing some other process
         // with same pid
@@ -76,21 +310,52 @@ impl Child {
                 "invalid argument: can't kill an exited process",
             ))
         }
-        kill(Pid::from_raw(self.pid), signal)
-        .map_err(|e| match e {
-            Error::Sys(x) => io::Error::from_raw_os_error(x as i32),
-            Error::InvalidPath => unreachable!(),
-            Error::InvalidUtf8 => unreachable!(),
-            Error::UnsupportedOperation => {
-                io::Error::new(io::ErrorKind::Other,
-                           "nix error: unsupported operation")
+        if let Some(ref pidfd) = self.pidfd {
+            let rc = unsafe {
+                libc::syscall(libc::SYS_pidfd_send_signal,
+                    pidfd.as_raw_fd(), signal.as_raw(),
+                    ptr::null::<libc::siginfo_t>(), 0)
+            };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(());
+        }
+        self.check_pid()?;
+        if unsafe { libc::kill(self.pid, signal.as_raw()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Checks that the pid still refers to the child (by the pidfd or the
+    /// start time), for operations by pid on a child not reaped yet
+    pub(crate) fn check_pid(&self) -> io::Result<()> {
+        if let Some(ref pidfd) = self.pidfd {
+            // signal 0 only checks that the process exists
+            let rc = unsafe {
+                libc::syscall(libc::SYS_pidfd_send_signal,
+                    pidfd.as_raw_fd(), 0,
+                    ptr::null::<libc::siginfo_t>(), 0)
+            };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(());
+        }
+        if let Some(start) = self.start_time {
+            match start_time(self.pid) {
+                Some(x) if x == start => {}
+                Some(_) => return Err(io::Error::other(Error::PidReused)),
+                None => return Err(io::Error::from_raw_os_error(libc::ESRCH)),
             }
-        })
+        }
+        Ok(())
     }
 
     /// Kill process with SIGKILL signal
     pub fn kill(&self) -> Result<(), io::Error> {
-        self.signal(SIGKILL)
+        self.signal(Signal::SIGKILL)
     }
 
     /// Returns pipe reader for a pipe declared with `file_descriptor()`
@@ -114,4 +379,117 @@ impl Child {
             _ => None,
         }
     }
+
+    /// Returns stdin of the child if it's `Stdio::piped()`
+    ///
+    /// Returns None for other configurations or when called twice
+    #[allow(deprecated)]
+    pub fn take_stdin(&mut self) -> Option<PipeWriter> {
+        self.stdin.take()
+    }
+
+    /// Returns stdout of the child if it's `Stdio::piped()`
+    ///
+    /// Returns None for other configurations or when called twice
+    #[allow(deprecated)]
+    pub fn take_stdout(&mut self) -> Option<PipeReader> {
+        self.stdout.take()
+    }
+
+    /// Returns stderr of the child if it's `Stdio::piped()`
+    ///
+    /// Returns None for other configurations or when called twice
+    #[allow(deprecated)]
+    pub fn take_stderr(&mut self) -> Option<PipeReader> {
+        self.stderr.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::ptr;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::{Command, Error, Signal, WaitStatus, WaitOptions, ExitStatus};
+    use crate::Resource;
+
+    #[test]
+    fn test_stop_continue() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let options = WaitOptions::new().stopped().continued();
+        assert_eq!(child.wait_with_options(options.no_hang()).unwrap(), None);
+        child.signal(Signal::SIGSTOP).unwrap();
+        assert_eq!(child.wait_with_options(options).unwrap(),
+                   Some(WaitStatus::Stopped(Signal::SIGSTOP)));
+        child.signal(Signal::SIGCONT).unwrap();
+        assert_eq!(child.wait_with_options(options).unwrap(),
+                   Some(WaitStatus::Continued));
+        child.kill().unwrap();
+        let killed = WaitStatus::Signaled(Signal::SIGKILL, false);
+        assert_eq!(child.wait_with_options(options).unwrap(), Some(killed));
+        assert_eq!(child.wait_with_options(options).unwrap(), Some(killed));
+    }
+
+    #[test]
+    fn test_wait_traced_stop() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let pid = child.pid();
+        // stops of a tracee are reported to its tracer even without flags
+        assert_eq!(unsafe { libc::ptrace(libc::PTRACE_SEIZE, pid,
+            ptr::null_mut::<libc::c_void>(),
+            ptr::null_mut::<libc::c_void>()) }, 0);
+        child.signal(Signal::SIGSTOP).unwrap();
+        let killer = std::thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        });
+        assert_eq!(child.wait().unwrap(),
+                   ExitStatus::Signaled(Signal::SIGKILL, false));
+        killer.join().unwrap();
+    }
+
+    #[test]
+    fn test_peek_status() {
+        let mut child = Command::new("/bin/sh").arg("-c").arg("read x; exit 3")
+            .stdin(crate::Stdio::piped())
+            .spawn().unwrap();
+        assert_eq!(child.peek_status().unwrap(), None);
+        drop(child.take_stdin());
+        let status = loop {
+            if let Some(status) = child.peek_status().unwrap() {
+                break status;
+            }
+            sleep(Duration::from_millis(10));
+        };
+        assert_eq!(status, ExitStatus::Exited(3));
+        // still a zombie
+        assert!(Path::new(&format!("/proc/{}", child.pid())).exists());
+        assert_eq!(child.peek_status().unwrap(), Some(status));
+        assert_eq!(child.wait().unwrap(), status);
+        assert!(!Path::new(&format!("/proc/{}", child.pid())).exists());
+    }
+
+    #[test]
+    fn test_pid_reused() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        assert!(child.start_time.is_some());
+        child.signal(Signal::SIGCONT).unwrap();
+        // as if another process got the pid
+        let start = child.start_time.replace(0);
+        let err = child.kill().unwrap_err();
+        assert!(matches!(err.get_ref().and_then(|e| e.downcast_ref()),
+                         Some(Error::PidReused)), "{}", err);
+        let err = child.set_rlimit(Resource::OpenFiles, 1, 1).unwrap_err();
+        assert!(matches!(err.get_ref().and_then(|e| e.downcast_ref()),
+                         Some(Error::PidReused)), "{}", err);
+        child.start_time = start;
+        child.kill().unwrap();
+        // reaped elsewhere
+        let rc = unsafe { libc::waitpid(child.pid(), ptr::null_mut(), 0) };
+        assert_eq!(rc, child.pid());
+        assert_eq!(child.kill().unwrap_err().raw_os_error(),
+                   Some(libc::ESRCH));
+    }
 }
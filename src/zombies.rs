@@ -1,12 +1,29 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
 use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use libc::pid_t;
-use nix::sys::wait::{waitpid};
-use nix::sys::wait::WaitPidFlag;
-use nix::errno::Errno::{EINTR, ECHILD};
-use nix::Error;
+use libc::{WNOHANG, WUNTRACED, WCONTINUED, EINTR, ECHILD};
 
-use crate::{ExitStatus, Signal};
+use crate::{Command, Child, ExitStatus, Signal, WaitBackend};
+use crate::sys::{self, waitpid};
+
+
+/// Children spawned by `Command::spawn` that haven't been reaped yet
+static SPAWNED: Mutex<BTreeSet<pid_t>> = Mutex::new(BTreeSet::new());
+
+pub(crate) fn track(pid: pid_t) {
+    SPAWNED.lock().unwrap_or_else(|e| e.into_inner()).insert(pid);
+}
+
+pub(crate) fn untrack(pid: pid_t) {
+    SPAWNED.lock().unwrap_or_else(|e| e.into_inner()).remove(&pid);
+}
 
 /// A non-blocking iteration over zombie processes
 ///
@@ -18,22 +35,22 @@ impl Iterator for ZombieIterator {
     type Item = (pid_t, ExitStatus);
 
     fn next(&mut self) -> Option<(pid_t, ExitStatus)> {
-        use nix::sys::wait::WaitStatus::*;
+        use crate::sys::WaitStatus::*;
         loop {
-            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            match waitpid(-1, WNOHANG) {
                 Ok(PtraceEvent(..)) => {}
                 Ok(PtraceSyscall(..)) => {}
                 Ok(Exited(pid, status)) => {
-                    return Some((pid.into(), ExitStatus::Exited(status as i8)));
+                    return Some((pid, ExitStatus::Exited(status as i8)));
                 }
                 Ok(Signaled(pid, sig, core)) => {
-                    return Some((pid.into(), ExitStatus::Signaled(sig, core)));
+                    return Some((pid, ExitStatus::Signaled(sig, core)));
                 }
                 Ok(Stopped(_, _)) => continue,
                 Ok(Continued(_)) => continue,
                 Ok(StillAlive) => return None,
-                Err(Error::Sys(EINTR)) => continue,
-                Err(Error::Sys(ECHILD)) => return None,
+                Err(ref e) if e.raw_os_error() == Some(EINTR) => continue,
+                Err(ref e) if e.raw_os_error() == Some(ECHILD) => return None,
                 Err(e) => {
                     panic!("Unexpected waitpid error: {:?}", e);
                 }
@@ -70,9 +87,69 @@ impl Iterator for ZombieIterator {
 ///   any more.
 /// * If you got `SIGCHLD` you *must* exhaust this iterator until waiting for
 ///   next signal, or you will have zombie processes around
+/// * Children spawned by other libraries are reaped too, use
+///   `reap_spawned_zombies()` to leave them alone
 pub fn reap_zombies() -> ZombieIterator { ZombieIterator(PhantomData) }
 
 
+/// A non-blocking iteration over zombies spawned by this crate
+///
+/// Use `reap_spawned_zombies()` to create one, and read docs there
+pub struct SpawnedZombieIterator(Vec<pid_t>);
+
+impl Iterator for SpawnedZombieIterator {
+    type Item = (pid_t, ExitStatus);
+
+    fn next(&mut self) -> Option<(pid_t, ExitStatus)> {
+        use crate::sys::WaitStatus::*;
+        while let Some(pid) = self.0.pop() {
+            let status = loop {
+                match waitpid(pid, WNOHANG) {
+                    Ok(Exited(_, status)) => {
+                        break Some(ExitStatus::Exited(status as i8));
+                    }
+                    Ok(Signaled(_, sig, core)) => {
+                        break Some(ExitStatus::Signaled(sig, core));
+                    }
+                    Ok(StillAlive) => break None,
+                    Ok(_) => continue,
+                    Err(ref e) if e.raw_os_error() == Some(EINTR) => continue,
+                    Err(ref e) if e.raw_os_error() == Some(ECHILD) => {
+                        // reaped by someone else
+                        untrack(pid);
+                        break None;
+                    }
+                    Err(e) => {
+                        panic!("Unexpected waitpid error: {:?}", e);
+                    }
+                }
+            };
+            if let Some(status) = status {
+                untrack(pid);
+                return Some((pid, status));
+            }
+        }
+        None
+    }
+}
+
+/// Creates iterator over zombie processes spawned by this crate
+///
+/// Works like `reap_zombies()`, but only the processes started by
+/// `Command::spawn` (and not reaped by `Child::wait` yet) are waited for,
+/// each one by its pid. So children of other libraries in the same process
+/// (e.g. `std::process::Command`) are left for them to wait for. Note that
+/// orphans reparented to a subreaper are not reaped either.
+///
+/// Every pid is checked by a separate `waitpid`, so exhausting the iterator
+/// is `O(n)` in the number of running children.
+pub fn reap_spawned_zombies() -> SpawnedZombieIterator {
+    let pids = SPAWNED.lock().unwrap_or_else(|e| e.into_inner())
+        .iter().rev().cloned().collect();
+    SpawnedZombieIterator(pids)
+}
+
+
 /// The event returned from `child_events()` iterator
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChildEvent {
@@ -96,26 +173,25 @@ impl Iterator for ChildEventsIterator {
 
     fn next(&mut self) -> Option<ChildEvent> {
         use self::ChildEvent::*;
-        use nix::sys::wait::WaitStatus::*;
-        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED |
-            WaitPidFlag::WCONTINUED;
+        use crate::sys::WaitStatus::*;
+        let flags = WNOHANG | WUNTRACED | WCONTINUED;
         loop {
-            match waitpid(None, Some(flags)) {
+            match waitpid(-1, flags) {
                 Ok(PtraceEvent(..)) => {}
                 Ok(PtraceSyscall(..)) => {}
                 Ok(Exited(pid, status)) => {
-                    return Some(Death(pid.into(),
-                                      ExitStatus::Exited(status as i8)));
+                    return Some(Death(pid, ExitStatus::Exited(status as i8)));
                 }
                 Ok(Signaled(pid, sig, core)) => {
-                    return Some(Death(pid.into(),
-                                      ExitStatus::Signaled(sig, core)));
+                    return Some(Death(pid, ExitStatus::Signaled(sig, core)));
                 }
-                Ok(Stopped(pid, sig)) => return Some(Stop(pid.into(), sig)),
-                Ok(Continued(pid)) => return Some(Continue(pid.into())),
+                Ok(Stopped(pid, sig)) => {
+                    return Some(Stop(pid, sig));
+                }
+                Ok(Continued(pid)) => return Some(Continue(pid)),
                 Ok(StillAlive) => return None,
-                Err(Error::Sys(EINTR)) => continue,
-                Err(Error::Sys(ECHILD)) => return None,
+                Err(ref e) if e.raw_os_error() == Some(EINTR) => continue,
+                Err(ref e) if e.raw_os_error() == Some(ECHILD) => return None,
                 Err(e) => {
                     panic!("Unexpected waitpid error: {:?}", e);
                 }
@@ -157,6 +233,258 @@ impl Iterator for ChildEventsIterator {
 ///   any more.
 /// * If you got `SIGCHLD` you *must* exhaust this iterator until waiting for
 ///   next signal, or you will have zombie processes around
+/// * All children are reaped, including orphaned descendants reparented to
+///   this process, which happens when it's pid 1 or a subreaper (see
+///   `Command::init_mode`). Such pids are unknown to the caller, but must
+///   be reaped anyway, so just ignore them.
 pub fn child_events() -> ChildEventsIterator {
     ChildEventsIterator(PhantomData)
 }
+
+
+/// A set of children to wait for without `SIGCHLD`
+///
+/// Children are watched by their pidfds using `epoll`, so a supervisor of
+/// many children can wait for the next one to exit with a timeout, and
+/// doesn't need to handle signals at all. Requires linux 5.3 (and 5.4 for
+/// `waitid` by pidfd).
+pub struct PidfdSet {
+    epoll: OwnedFd,
+    children: HashMap<RawFd, (pid_t, OwnedFd)>,
+}
+
+impl Command {
+    /// Open a pidfd for the child (requires linux 5.3)
+    ///
+    /// The pidfd is opened while the child is frozen, so it always refers
+    /// to the spawned process. `Child::signal`, `Child::peek_status` and
+    /// `PidfdSet::add` use it, so they can never act on an unrelated
+    /// process if the pid has been reused.
+    pub fn pidfd(&mut self) -> &mut Command {
+        self.config.pidfd = true;
+        self
+    }
+
+    /// Choose how the child is waited for
+    ///
+    /// With `WaitBackend::Pidfd` the child gets a pidfd (see
+    /// `Command::pidfd`) and `Child::wait`, `wait_with_options` and
+    /// `peek_status` use only `waitid(P_PIDFD, ..)` (requires linux 5.4),
+    /// the child isn't listed by `reap_spawned_zombies` either. So nothing
+    /// depends on `SIGCHLD` being delivered or handled, which is useful if
+    /// the host application owns the handler (or blocks the signal), use
+    /// `PidfdSet` to wait for many children. Note that the kernel still
+    /// sends `SIGCHLD` on exit (`execve` resets the exit signal), so the
+    /// handler of the host must not reap the child by `waitpid(-1, ..)`,
+    /// and `SIGCHLD` must not be ignored. Default is
+    /// `WaitBackend::Sigchld`.
+    pub fn wait_backend(&mut self, backend: WaitBackend) -> &mut Command {
+        self.config.wait_backend = backend;
+        if backend == WaitBackend::Pidfd {
+            self.config.pidfd = true;
+        }
+        self
+    }
+}
+
+impl PidfdSet {
+    /// Create an empty set
+    pub fn new() -> io::Result<PidfdSet> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PidfdSet {
+            epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+            children: HashMap::new(),
+        })
+    }
+
+    /// Add the child to the set
+    ///
+    /// The pidfd of the child is used if it's spawned with
+    /// `Command::pidfd`, otherwise a new one is opened by pid (so there
+    /// is a race if the child has already been reaped by someone else).
+    pub fn add(&mut self, child: &Child) -> io::Result<()> {
+        if child.status.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::ECHILD));
+        }
+        let fd = match child.pidfd {
+            Some(ref pidfd) => sys::dup_cloexec(pidfd.as_raw_fd(), 3)?,
+            None => sys::pidfd_open(child.pid)?,
+        };
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: fd.as_raw_fd() as u64,
+        };
+        let rc = unsafe {
+            libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_ADD,
+                            fd.as_raw_fd(), &mut event)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.children.insert(fd.as_raw_fd(), (child.pid, fd));
+        Ok(())
+    }
+
+    /// Stop watching the child, returns false if it isn't in the set
+    pub fn remove(&mut self, pid: pid_t) -> bool {
+        let fd = self.children.iter()
+            .find(|(_, &(x, _))| x == pid)
+            .map(|(&fd, _)| fd);
+        fd.and_then(|fd| self.unwatch(fd)).is_some()
+    }
+
+    fn unwatch(&mut self, fd: RawFd) -> Option<(pid_t, OwnedFd)> {
+        let item = self.children.remove(&fd)?;
+        // the pidfd of `Child` keeps the registration alive after close
+        unsafe {
+            libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_DEL,
+                            fd, ptr::null_mut());
+        }
+        Some(item)
+    }
+
+    /// Number of children in the set
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Returns true if there are no children in the set
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Wait for any child in the set to exit, and reap it
+    ///
+    /// Returns `None` if no child has exited before the `timeout` (`None`
+    /// waits indefinitely) or if the set is empty. The child is removed from
+    /// the set. As the process is reaped here, `Child::wait` can't be used
+    /// for it afterwards.
+    pub fn wait_any(&mut self, timeout: Option<Duration>)
+        -> io::Result<Option<(pid_t, ExitStatus)>>
+    {
+        if self.children.is_empty() {
+            return Ok(None);
+        }
+        let deadline = timeout.map(|x| Instant::now() + x);
+        let mut event: libc::epoll_event = unsafe { mem::zeroed() };
+        loop {
+            let millis = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(
+                        Instant::now());
+                    // round up, so we don't spin just before the deadline
+                    left.as_nanos().div_ceil(1_000_000)
+                        .min(libc::c_int::MAX as u128) as libc::c_int
+                }
+                None => -1,
+            };
+            let rc = unsafe {
+                libc::epoll_wait(self.epoll.as_raw_fd(), &mut event, 1,
+                                 millis)
+            };
+            if rc < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(EINTR) {
+                    continue;
+                }
+                return Err(err);
+            }
+            if rc == 0 {
+                return Ok(None);
+            }
+            let (pid, pidfd) = match self.unwatch(event.u64 as RawFd) {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+            let rc = unsafe {
+                libc::waitid(libc::P_PIDFD, pidfd.as_raw_fd() as libc::id_t,
+                             &mut info, libc::WEXITED | WNOHANG)
+            };
+            if rc != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            untrack(pid);
+            return Ok(Some((pid, ExitStatus::from_siginfo(&info))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::{Command, ExitStatus, Signal, WaitBackend, WaitOptions};
+    use super::{SPAWNED, SpawnedZombieIterator, PidfdSet};
+
+    fn tracked(pid: libc::pid_t) -> bool {
+        SPAWNED.lock().unwrap().contains(&pid)
+    }
+
+    #[test]
+    fn test_tracking() {
+        let mut child = Command::new("/bin/true").spawn().unwrap();
+        assert!(tracked(child.pid()));
+        child.wait().unwrap();
+        assert!(!tracked(child.pid()));
+    }
+
+    #[test]
+    fn test_reap_spawned() {
+        // other tests wait for their children, so don't touch them
+        let child = Command::new("/bin/true").spawn().unwrap();
+        let pid = child.forget();
+        let mut other = std::process::Command::new("/bin/true")
+            .spawn().unwrap();
+        let reaped = loop {
+            let reaped = SpawnedZombieIterator(vec![pid]).collect::<Vec<_>>();
+            if !reaped.is_empty() {
+                break reaped;
+            }
+            sleep(Duration::from_millis(10));
+        };
+        assert_eq!(reaped, vec![(pid, ExitStatus::Exited(0))]);
+        assert!(!tracked(pid));
+        assert!(other.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_pidfd_set() {
+        let mut set = PidfdSet::new().unwrap();
+        assert_eq!(set.wait_any(None).unwrap(), None);
+        let short = Command::new("/bin/sh").arg("-c").arg("exit 7")
+            .pidfd().spawn().unwrap();
+        let long = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        set.add(&short).unwrap();
+        set.add(&long).unwrap();
+        assert_eq!(set.wait_any(Some(Duration::from_secs(5))).unwrap(),
+                   Some((short.pid(), ExitStatus::Exited(7))));
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.wait_any(Some(Duration::from_millis(50))).unwrap(),
+                   None);
+        long.kill().unwrap();
+        assert_eq!(set.wait_any(None).unwrap(),
+            Some((long.pid(), ExitStatus::Signaled(Signal::SIGKILL, false))));
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_pidfd_backend() {
+        let mut child = Command::new("/bin/sleep").arg("10")
+            .wait_backend(WaitBackend::Pidfd).spawn().unwrap();
+        assert!(!tracked(child.pid()));
+        assert_eq!(child.wait_with_options(WaitOptions::new().no_hang())
+                   .unwrap(), None);
+        child.kill().unwrap();
+        assert_eq!(child.wait().unwrap(),
+                   ExitStatus::Signaled(Signal::SIGKILL, false));
+        let mut child = Command::new("/bin/sh").arg("-c").arg("exit 3")
+            .wait_backend(WaitBackend::Pidfd).spawn().unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(3));
+    }
+}
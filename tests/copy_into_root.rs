@@ -0,0 +1,56 @@
+//! Files copied by `Command::copy_into_root` as seen by the child
+//!
+//! The root isn't changed here, so the files are written into a temporary
+//! directory of the host.
+
+extern crate unshare;
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+use unshare::{Command, Stdio};
+
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir()
+        .join(format!("unshare-test-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn output(cmd: &mut Command) -> String {
+    let mut child = cmd.stdout(Stdio::piped()).spawn().unwrap();
+    let mut out = String::new();
+    child.take_stdout().unwrap().read_to_string(&mut out).unwrap();
+    assert!(child.wait().unwrap().success());
+    out
+}
+
+#[test]
+fn content_and_mode() {
+    let dir = temp_dir("copy");
+    fs::write(dir.join("source"), "key = value\n").unwrap();
+    // an old file is truncated
+    fs::write(dir.join("config"), "some longer content\n").unwrap();
+    let target = dir.join("config");
+    let out = output(Command::new("/bin/sh").arg("-c")
+        .arg(r#"cat "$0" && stat -c %a "$0""#).arg(&target)
+        .copy_into_root(dir.join("source"), &target, 0o640));
+    assert_eq!(out, "key = value\n640\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn entrypoint_script() {
+    let dir = temp_dir("entrypoint");
+    fs::write(dir.join("source"), "#!/bin/sh\necho \"started $1\"\n")
+        .unwrap();
+    // the program doesn't exist until the child copies it
+    let target = dir.join("entrypoint");
+    let out = output(Command::new(&target).arg("ok")
+        .copy_into_root(dir.join("source"), &target, 0o755));
+    assert_eq!(out, "started ok\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
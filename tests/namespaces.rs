@@ -0,0 +1,243 @@
+//! Spawns processes under every combination of namespaces
+//!
+//! Run with `cargo test --features integration-tests`. Works both as root
+//! and as unprivileged user, but in the latter case most combinations are
+//! expected to fail with a specific error. Set `UNSHARE_STRESS_ITERATIONS`
+//! to repeat every combination multiple times.
+#![cfg(feature="integration-tests")]
+
+extern crate libc;
+extern crate unshare;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use unshare::{Command, Child, Error, Namespace, UidMap, GidMap, ns_equal};
+
+
+const NAMESPACES: &[Namespace] = &[
+    Namespace::User,
+    Namespace::Mount,
+    Namespace::Pid,
+    Namespace::Net,
+    Namespace::Uts,
+    Namespace::Ipc,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Variant {
+    Plain,
+    Chroot,
+    Pivot,
+    IdMaps,
+}
+
+/// Outcome of spawning a process, comparable unlike `unshare::Error`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    Fork(i32),
+    ChangeRoot(i32),
+    SetIdMap(i32),
+}
+
+struct Env {
+    privileged: bool,
+    /// Errno of creating a user namespace if it's not allowed
+    userns_error: Option<i32>,
+    pivot_dir: PathBuf,
+}
+
+impl fmt::Debug for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Outcome::*;
+        match *self {
+            Success => write!(f, "Success"),
+            Fork(e) => write!(f, "Fork({})", errno_name(e)),
+            ChangeRoot(e) => write!(f, "ChangeRoot({})", errno_name(e)),
+            SetIdMap(e) => write!(f, "SetIdMap({})", errno_name(e)),
+        }
+    }
+}
+
+fn errno_name(e: i32) -> String {
+    match e {
+        libc::EPERM => "EPERM".into(),
+        libc::EINVAL => "EINVAL".into(),
+        libc::EBUSY => "EBUSY".into(),
+        libc::ENOSPC => "ENOSPC".into(),
+        _ => format!("errno {}", e),
+    }
+}
+
+fn outcome(result: Result<unshare::ExitStatus, Error>) -> Outcome {
+    match result {
+        Ok(status) => {
+            assert!(status.success(), "process failed: {}", status);
+            Outcome::Success
+        }
+        Err(Error::Fork(e)) => Outcome::Fork(e),
+        Err(Error::ChangeRoot(e)) => Outcome::ChangeRoot(e),
+        Err(Error::SetIdMap(e)) => Outcome::SetIdMap(e),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+/// Checks the child is in new namespaces exactly for `namespaces`
+fn check_isolation(child: &Child, namespaces: &[Namespace]) -> Vec<String> {
+    let own = std::process::id() as libc::pid_t;
+    NAMESPACES.iter().filter_map(|&ns| {
+        let shared = ns_equal(own, child.pid(), ns)
+            .expect("namespaces of the child");
+        if shared == namespaces.contains(&ns) {
+            Some(format!("{:?} namespace is {}", ns,
+                         if shared { "shared" } else { "not shared" }))
+        } else {
+            None
+        }
+    }).collect()
+}
+
+/// Spawns the command and checks namespaces of the running child
+fn run(mut cmd: Command, namespaces: &[Namespace])
+    -> (Outcome, Vec<String>)
+{
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => return (outcome(Err(e)), Vec::new()),
+    };
+    let problems = check_isolation(&child, namespaces);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    (Outcome::Success, problems)
+}
+
+fn command(env: &Env, namespaces: &[Namespace], variant: Variant) -> Command {
+    let mut cmd = Command::new("/bin/sleep");
+    cmd.arg("10").unshare(namespaces);
+    match variant {
+        Variant::Plain => {}
+        Variant::Chroot => {
+            cmd.chroot_dir("/");
+        }
+        Variant::Pivot => {
+            cmd.pivot_root(&env.pivot_dir, env.pivot_dir.join("old"), true);
+        }
+        Variant::IdMaps => {
+            cmd.set_id_maps(
+                vec![UidMap {
+                    inside_uid: 0,
+                    outside_uid: unsafe { libc::geteuid() },
+                    count: 1,
+                }],
+                vec![GidMap {
+                    inside_gid: 0,
+                    outside_gid: unsafe { libc::getegid() },
+                    count: 1,
+                }]);
+        }
+    }
+    cmd
+}
+
+/// Returns outcomes that are considered correct
+fn expected(env: &Env, namespaces: &[Namespace], variant: Variant)
+    -> Vec<Outcome>
+{
+    let user = namespaces.contains(&Namespace::User);
+    if user {
+        if let Some(errno) = env.userns_error {
+            return vec![Outcome::Fork(errno)];
+        }
+    } else if !env.privileged && !namespaces.is_empty() {
+        return vec![Outcome::Fork(libc::EPERM)];
+    }
+    // Process has all capabilities either in the host namespace or in the
+    // new user namespace at this point
+    let capable = env.privileged || user;
+    match variant {
+        Variant::Plain => vec![Outcome::Success],
+        Variant::Chroot if capable => vec![Outcome::Success],
+        Variant::Chroot => vec![Outcome::ChangeRoot(libc::EPERM)],
+        // pivot dir is not a mount point, the error depends on kernel
+        // version and whether it's on the same mount as current root
+        Variant::Pivot => vec![
+            Outcome::ChangeRoot(libc::EINVAL),
+            Outcome::ChangeRoot(libc::EBUSY),
+        ],
+        Variant::IdMaps if env.privileged => vec![Outcome::Success],
+        // setgroups is not denied before writing gid_map
+        Variant::IdMaps => vec![Outcome::SetIdMap(libc::EPERM)],
+    }
+}
+
+fn variants(namespaces: &[Namespace]) -> Vec<Variant> {
+    let mut result = vec![Variant::Plain, Variant::Chroot];
+    // pivot_root is never tried in the host mount namespace
+    if namespaces.contains(&Namespace::Mount) {
+        result.push(Variant::Pivot);
+    }
+    if namespaces.contains(&Namespace::User) {
+        result.push(Variant::IdMaps);
+    }
+    result
+}
+
+fn setup() -> Env {
+    let pivot_dir = env::temp_dir()
+        .join(format!("unshare-test-{}", std::process::id()));
+    fs::create_dir_all(pivot_dir.join("old")).unwrap();
+    let userns_error = match outcome(
+        Command::new("/bin/true").unshare(&[Namespace::User]).status())
+    {
+        Outcome::Success => None,
+        Outcome::Fork(e) => Some(e),
+        other => panic!("unexpected result of user namespace probe: {:?}",
+                        other),
+    };
+    Env {
+        privileged: unsafe { libc::geteuid() } == 0,
+        userns_error,
+        pivot_dir,
+    }
+}
+
+#[test]
+fn namespace_permutations() {
+    let env = setup();
+    let iterations = env::var("UNSHARE_STRESS_ITERATIONS").ok()
+        .map(|x| x.parse::<usize>().expect("number of iterations"))
+        .unwrap_or(1);
+    let mut failures = Vec::new();
+    let mut total = 0;
+    for mask in 0..(1 << NAMESPACES.len()) {
+        let namespaces = NAMESPACES.iter().enumerate()
+            .filter(|&(i, _)| mask & (1 << i) != 0)
+            .map(|(_, ns)| *ns)
+            .collect::<Vec<_>>();
+        for variant in variants(&namespaces) {
+            let expected = expected(&env, &namespaces, variant);
+            for _ in 0..iterations {
+                total += 1;
+                let (result, problems) = run(
+                    command(&env, &namespaces, variant), &namespaces);
+                if !expected.contains(&result) {
+                    failures.push(format!(
+                        "{:?} {:?}: expected {:?}, got {:?}",
+                        namespaces, variant, expected, result));
+                    break;
+                }
+                if !problems.is_empty() {
+                    failures.push(format!("{:?} {:?}: {}",
+                        namespaces, variant, problems.join(", ")));
+                    break;
+                }
+            }
+        }
+    }
+    fs::remove_dir_all(&env.pivot_dir).ok();
+    assert!(failures.is_empty(), "{} of {} spawns failed:\n{}",
+            failures.len(), total, failures.join("\n"));
+}
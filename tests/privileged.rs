@@ -0,0 +1,115 @@
+//! Delegating privileged steps of the setup to `PrivilegedOps`
+//!
+//! The ops write uid maps directly, so user namespaces must be allowed
+//! for the current user. Otherwise the tests are skipped.
+
+extern crate libc;
+extern crate unshare;
+
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+use unshare::{Command, Error, PrivilegedOps, UidMap, GidMap};
+
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type Log = Rc<RefCell<Vec<String>>>;
+
+/// Writes the maps like the default writer does, logging every call
+struct Ops {
+    log: Log,
+    fail_setup: bool,
+}
+
+impl PrivilegedOps for Ops {
+    fn write_id_maps(&mut self, pid: u32, uid_map: &[UidMap],
+        gid_map: &[GidMap])
+        -> Result<(), BoxError>
+    {
+        self.log.borrow_mut().push(format!("write_id_maps {}", pid));
+        let uid = &uid_map[0];
+        let gid = &gid_map[0];
+        fs::write(format!("/proc/{}/uid_map", pid), format!("{} {} {}",
+            uid.inside_uid, uid.outside_uid, uid.count))?;
+        fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+        fs::write(format!("/proc/{}/gid_map", pid), format!("{} {} {}",
+            gid.inside_gid, gid.outside_gid, gid.count))?;
+        Ok(())
+    }
+    fn setup(&mut self, pid: u32) -> Result<(), BoxError> {
+        self.log.borrow_mut().push(format!("setup {}", pid));
+        if self.fail_setup {
+            return Err("setup failed".into());
+        }
+        Ok(())
+    }
+}
+
+fn command(log: &Log, fail_setup: bool) -> Command {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c")
+        .arg(r#"test "$(cat /proc/self/uid_map)" = "$(printf \
+            '%10d %10d %10d' 0 "$OUTER_UID" 1)""#)
+        .env("OUTER_UID", unsafe { libc::geteuid() }.to_string())
+        .set_id_maps(
+            vec![UidMap {
+                inside_uid: 0,
+                outside_uid: unsafe { libc::geteuid() },
+                count: 1,
+            }],
+            vec![GidMap {
+                inside_gid: 0,
+                outside_gid: unsafe { libc::getegid() },
+                count: 1,
+            }])
+        .privileged_ops(Ops { log: log.clone(), fail_setup });
+    let before = log.clone();
+    cmd.before_unfreeze(move |pid| {
+        before.borrow_mut().push(format!("before_unfreeze {}", pid));
+        Ok(())
+    });
+    cmd
+}
+
+fn userns_allowed() -> bool {
+    match Command::new("/bin/true").unshare(&[unshare::Namespace::User])
+        .status()
+    {
+        Ok(_) => true,
+        Err(Error::Fork(libc::EPERM)) => false,
+        Err(e) => panic!("unexpected result of user namespace probe: {}", e),
+    }
+}
+
+#[test]
+fn ops_write_maps_before_unfreeze() {
+    if !userns_allowed() {
+        return;
+    }
+    let log = Log::default();
+    let mut child = command(&log, false).spawn().unwrap();
+    let pid = child.pid();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(*log.borrow(), vec![
+        format!("write_id_maps {}", pid),
+        format!("setup {}", pid),
+        format!("before_unfreeze {}", pid),
+    ]);
+}
+
+#[test]
+fn ops_error_aborts_spawn() {
+    if !userns_allowed() {
+        return;
+    }
+    let log = Log::default();
+    match command(&log, true).spawn() {
+        Err(Error::PrivilegedOps(e)) => assert_eq!(e.to_string(),
+                                                   "setup failed"),
+        other => panic!("unexpected result {:?}", other.map(|c| c.pid())),
+    }
+    let log = log.borrow();
+    assert_eq!(log.len(), 2, "{:?}", log);
+    assert!(log[1].starts_with("setup "));
+}